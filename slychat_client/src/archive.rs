@@ -0,0 +1,386 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use slychat_common::encryption::{derive_key, open, random_bytes, seal};
+use slychat_common::types::MessageId;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+const SALT_LEN: usize = 16;
+// Sealed under the archive key at creation, so a wrong passphrase is caught on open.
+const KEY_CHECK: &[u8] = b"slychat-archive";
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Storage(rusqlite::Error),
+    Io(std::io::Error),
+    WrongPassphrase,
+    Corrupted,
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Storage(e) => write!(f, "Archive storage error: {}", e),
+            Self::Io(e) => write!(f, "Archive IO error: {}", e),
+            Self::WrongPassphrase => write!(f, "Passphrase does not unlock the archive"),
+            Self::Corrupted => write!(f, "Archive entry could not be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<rusqlite::Error> for ArchiveError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Whether the sender of a message has been authenticated. Messages are not
+/// signed yet, so everything is currently archived as `Unverified`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Unverified,
+    Verified,
+    Failed,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Received => "received",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "sent" => Self::Sent,
+            _ => Self::Received,
+        }
+    }
+}
+
+impl Verification {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unverified => "unverified",
+            Self::Verified => "verified",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "verified" => Self::Verified,
+            "failed" => Self::Failed,
+            _ => Self::Unverified,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivedMessage {
+    /// `None` for messages archived before the server assigned ids.
+    pub id: Option<MessageId>,
+    pub room: String,
+    pub sender: Option<String>,
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub verification: Verification,
+    pub body: String,
+    /// When the message disappears, and is purged from the archive.
+    pub expires: Option<u64>,
+}
+
+// The part of a message that is encrypted at rest.
+#[derive(Serialize, Deserialize)]
+struct SealedContent {
+    sender: Option<String>,
+    body: String,
+}
+
+/// A local record of every message sent and received.
+///
+/// Senders and bodies are sealed with a key derived from the keystore
+/// passphrase; only the room, time, direction and verification status are
+/// stored in the clear so entries can be ordered without decrypting them.
+pub struct Archive {
+    conn: Connection,
+    key: Vec<u8>,
+}
+
+impl Archive {
+    pub fn open(path: &Path, passphrase: &[u8]) -> Result<Self, ArchiveError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::from_connection(Connection::open(path)?, passphrase)
+    }
+
+    fn from_connection(conn: Connection, passphrase: &[u8]) -> Result<Self, ArchiveError> {
+        // Deleted messages are overwritten rather than left in free pages.
+        conn.execute_batch(
+            "PRAGMA secure_delete = ON;
+            CREATE TABLE IF NOT EXISTS meta (
+                salt BLOB NOT NULL,
+                key_check BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                room TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                direction TEXT NOT NULL,
+                verification TEXT NOT NULL,
+                content BLOB NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);",
+        )?;
+        migrate(&conn)?;
+
+        let meta: Option<(Vec<u8>, Vec<u8>)> = conn
+            .query_row("SELECT salt, key_check FROM meta", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+
+        let key = match meta {
+            Some((salt, key_check)) => {
+                let key = derive_key(passphrase, &salt);
+                if open(&key_check, &key).as_deref() != Some(KEY_CHECK) {
+                    return Err(ArchiveError::WrongPassphrase);
+                }
+                key
+            }
+            None => {
+                let salt = random_bytes(SALT_LEN);
+                let key = derive_key(passphrase, &salt);
+                conn.execute(
+                    "INSERT INTO meta (salt, key_check) VALUES (?1, ?2)",
+                    params![salt, seal(KEY_CHECK, &key)],
+                )?;
+                key
+            }
+        };
+
+        Ok(Self { conn, key })
+    }
+
+    /// Stores a message. Messages that were already archived, e.g. when they
+    /// are fetched again from history, are ignored.
+    pub fn record(&self, message: &ArchivedMessage) -> Result<(), ArchiveError> {
+        let content = SealedContent {
+            sender: message.sender.clone(),
+            body: message.body.clone(),
+        };
+        let plaintext = serde_json::to_vec(&content).expect("Failed to serialize message.");
+
+        self.conn.execute(
+            "INSERT OR IGNORE INTO messages
+                (message_id, room, timestamp, direction, verification, content, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message.id.map(|id| id.0 as i64),
+                message.room,
+                message.timestamp as i64,
+                message.direction.as_str(),
+                message.verification.as_str(),
+                seal(&plaintext, &self.key),
+                message.expires.map(|expires| expires as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the body of message `id` after its sender edited it. Returns
+    /// whether the message was archived.
+    pub fn edit(&self, id: MessageId, body: &str) -> Result<bool, ArchiveError> {
+        let sealed: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT content FROM messages WHERE message_id = ?1",
+                params![id.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(sealed) = sealed else {
+            return Ok(false);
+        };
+        let plaintext = open(&sealed, &self.key).ok_or(ArchiveError::Corrupted)?;
+        let mut content: SealedContent =
+            serde_json::from_slice(&plaintext).map_err(|_| ArchiveError::Corrupted)?;
+
+        content.body = body.to_string();
+        let plaintext = serde_json::to_vec(&content).expect("Failed to serialize message.");
+        self.conn.execute(
+            "UPDATE messages SET content = ?1 WHERE message_id = ?2",
+            params![seal(&plaintext, &self.key), id.0 as i64],
+        )?;
+        Ok(true)
+    }
+
+    /// Removes message `id` after its sender deleted it.
+    pub fn delete(&self, id: MessageId) -> Result<(), ArchiveError> {
+        self.conn.execute(
+            "DELETE FROM messages WHERE message_id = ?1",
+            params![id.0 as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the messages that disappeared by `now`, e.g. while the client
+    /// wasn't running. Returns how many there were.
+    pub fn purge_expired(&self, now: u64) -> Result<usize, ArchiveError> {
+        let purged = self.conn.execute(
+            "DELETE FROM messages WHERE expires <= ?1",
+            params![now as i64],
+        )?;
+        Ok(purged)
+    }
+
+    /// Returns up to `limit` of the most recent messages whose sender or body
+    /// contains every whitespace separated term of `query`, ignoring case.
+    /// Results are oldest first.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<ArchivedMessage>, ArchiveError> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+
+        let mut statement = self.conn.prepare(
+            "SELECT room, timestamp, direction, verification, content, message_id, expires
+             FROM messages ORDER BY timestamp DESC, id DESC",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut matches = Vec::new();
+        while let Some(row) = rows.next()? {
+            if matches.len() >= limit {
+                break;
+            }
+
+            let sealed: Vec<u8> = row.get(4)?;
+            let plaintext = open(&sealed, &self.key).ok_or(ArchiveError::Corrupted)?;
+            let content: SealedContent =
+                serde_json::from_slice(&plaintext).map_err(|_| ArchiveError::Corrupted)?;
+
+            let haystack = format!(
+                "{} {}",
+                content.sender.as_deref().unwrap_or(""),
+                content.body
+            )
+            .to_lowercase();
+            if !terms.iter().all(|term| haystack.contains(term.as_str())) {
+                continue;
+            }
+
+            let timestamp: i64 = row.get(1)?;
+            let direction: String = row.get(2)?;
+            let verification: String = row.get(3)?;
+            let id: Option<i64> = row.get(5)?;
+            let expires: Option<i64> = row.get(6)?;
+            matches.push(ArchivedMessage {
+                id: id.map(|id| MessageId(id as u64)),
+                room: row.get(0)?,
+                sender: content.sender,
+                timestamp: timestamp as u64,
+                direction: Direction::parse(&direction),
+                verification: Verification::parse(&verification),
+                body: content.body,
+                expires: expires.map(|expires| expires as u64),
+            });
+        }
+
+        matches.reverse();
+        Ok(matches)
+    }
+}
+
+// Brings archives created by older clients up to the current schema.
+fn migrate(conn: &Connection) -> Result<(), ArchiveError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version < 1 {
+        conn.execute_batch(
+            "ALTER TABLE messages ADD COLUMN message_id INTEGER;
+            CREATE UNIQUE INDEX messages_message_id ON messages (message_id);
+            PRAGMA user_version = 1;",
+        )?;
+    }
+    if version < 2 {
+        conn.execute_batch(
+            "ALTER TABLE messages ADD COLUMN expires INTEGER;
+            PRAGMA user_version = 2;",
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(timestamp: u64, sender: &str, body: &str) -> ArchivedMessage {
+        ArchivedMessage {
+            id: Some(MessageId(timestamp)),
+            room: "waiting".to_string(),
+            sender: Some(sender.to_string()),
+            timestamp,
+            direction: Direction::Received,
+            verification: Verification::Unverified,
+            body: body.to_string(),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn search_matches_all_terms_case_insensitively() {
+        let archive =
+            Archive::from_connection(Connection::open_in_memory().unwrap(), b"pw").unwrap();
+        archive
+            .record(&message(1, "alice", "Deploy is done"))
+            .unwrap();
+        archive
+            .record(&message(2, "bob", "deploy failed again"))
+            .unwrap();
+        archive.record(&message(3, "alice", "lunch?")).unwrap();
+        archive
+            .record(&message(1, "alice", "Deploy is done"))
+            .unwrap();
+
+        let found = archive.search("DEPLOY alice", 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].body, "Deploy is done");
+
+        let found = archive.search("deploy", 10).unwrap();
+        let timestamps: Vec<u64> = found.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, vec![1, 2]);
+
+        assert!(archive.edit(MessageId(2), "deploy fixed").unwrap());
+        archive.delete(MessageId(1)).unwrap();
+        let found = archive.search("deploy", 10).unwrap();
+        let bodies: Vec<&str> = found.iter().map(|m| m.body.as_str()).collect();
+        assert_eq!(bodies, vec!["deploy fixed"]);
+        assert!(!archive.edit(MessageId(1), "gone").unwrap());
+
+        let secret = ArchivedMessage {
+            expires: Some(60),
+            ..message(4, "bob", "deploy key is hunter2")
+        };
+        archive.record(&secret).unwrap();
+        assert_eq!(archive.purge_expired(59).unwrap(), 0);
+        assert_eq!(archive.search("deploy", 10).unwrap().len(), 2);
+        assert_eq!(archive.purge_expired(60).unwrap(), 1);
+        assert_eq!(archive.search("deploy", 10).unwrap().len(), 1);
+    }
+}
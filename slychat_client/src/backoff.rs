@@ -0,0 +1,59 @@
+use slychat_common::encryption::random_bytes;
+use std::time::Duration;
+
+/// Delays between reconnection attempts. Each step doubles, from `base` up to
+/// `max`, and the actual delay is picked at random from the upper half of the
+/// step so that clients dropped together don't all come back at once.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+
+        let half = step / 2;
+        half + half.mul_f64(random_fraction())
+    }
+}
+
+// Uniformly distributed in [0, 1].
+fn random_fraction() -> f64 {
+    let bytes: [u8; 4] = random_bytes(4).try_into().unwrap();
+    u32::from_le_bytes(bytes) as f64 / u32::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        for step in [1, 2, 4, 5, 5] {
+            let step = Duration::from_secs(step);
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= step / 2 && delay <= step,
+                "{:?} for {:?}",
+                delay,
+                step
+            );
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::utils::parse_duration;
+use slychat_common::types::{PresenceState, Role, Visibility};
+use std::time::Duration;
+
+/// Reactions are a single emoji, but some emoji take several characters.
+const MAX_REACTION_CHARS: usize = 16;
+
+/// A line of user input.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Message(String),
+    /// A message that disappears once it has lasted the duration.
+    Ephemeral(Duration, String),
+    /// Answers the message whose id starts with the first string.
+    Reply(String, String),
+    /// Shows the thread of the message whose id starts with the string.
+    Thread(String),
+    /// Replaces the text of one of our messages, named like for `Reply`.
+    Edit(String, String),
+    /// Deletes one of our messages, named by the start of its id.
+    Delete(String),
+    /// Reacts to a message, named like for `Reply`, with an emoji, or takes
+    /// the reaction back if we reacted with it already.
+    React(String, String),
+    /// Shares the file at the path in the active room.
+    Attach(String),
+    /// Saves the file attached to a message, named like for `Reply`.
+    Download(String),
+    /// Gives up on the transfers in progress.
+    CancelTransfers,
+    RefreshKeys,
+    History,
+    Search(String),
+    Members,
+    Presence(PresenceState, Option<String>),
+    SentStatus,
+    ReadReceipts(bool),
+    SetRole(String, Role),
+    Roles,
+    Kick(String),
+    Capacity(usize),
+    DeleteRoom,
+    /// Gives the active room a new name.
+    Rename(String),
+    Rooms,
+    /// Sets the active room's topic, or clears it.
+    SetTopic(Option<String>),
+    /// Sets how long messages in the active room last by default, or with
+    /// `None` that they don't disappear.
+    SetMessageTtl(Option<Duration>),
+    /// A room to join, with its password if it has one.
+    Join(String, Option<String>),
+    /// Leaves the named room, or the active one.
+    Leave(Option<String>),
+    /// Makes the tab of a room we are in the active one.
+    Tab(String),
+    Tabs,
+    Invite(String),
+    Invitations,
+    /// Lists the devices we have logged in from.
+    Devices,
+    /// Revokes one of our other devices, by id or a prefix of it.
+    Revoke(String),
+    SetVisibility(Visibility),
+    /// Sets the active room's password, or removes it.
+    SetPassword(Option<String>),
+    Quit,
+    Empty,
+    Unknown(String),
+}
+
+pub fn parse(line: &str) -> Command {
+    let line = line.trim_end();
+    if !line.starts_with('/') {
+        return match line {
+            "" => Command::Empty,
+            _ => Command::Message(line.to_string()),
+        };
+    }
+
+    let (command, argument) = match line.split_once(' ') {
+        Some((c, a)) => (c, a.trim()),
+        None => (line, ""),
+    };
+    // Status text, topics and passwords are optional, everything else that takes an
+    // argument needs one.
+    let status = Some(argument.to_string()).filter(|a| !a.is_empty());
+
+    match (command, argument) {
+        ("/keys", "") => Command::RefreshKeys,
+        ("/reply", argument) => match argument.split_once(' ') {
+            Some((id, text)) if !text.trim().is_empty() => {
+                Command::Reply(id.to_string(), text.trim().to_string())
+            }
+            _ => Command::Unknown(line.to_string()),
+        },
+        ("/ephemeral", argument) => match argument.split_once(' ') {
+            Some((ttl, text)) if !text.trim().is_empty() => match parse_duration(ttl) {
+                Some(ttl) if !ttl.is_zero() => Command::Ephemeral(ttl, text.trim().to_string()),
+                _ => Command::Unknown(line.to_string()),
+            },
+            _ => Command::Unknown(line.to_string()),
+        },
+        ("/thread", id) if !id.is_empty() && !id.contains(' ') => Command::Thread(id.to_string()),
+        ("/edit", argument) => match argument.split_once(' ') {
+            Some((id, text)) if !text.trim().is_empty() => {
+                Command::Edit(id.to_string(), text.trim().to_string())
+            }
+            _ => Command::Unknown(line.to_string()),
+        },
+        ("/delete", id) if !id.is_empty() && !id.contains(' ') => Command::Delete(id.to_string()),
+        ("/react", argument) => match argument.split_once(' ') {
+            Some((id, emoji))
+                if !emoji.trim().contains(' ')
+                    && emoji.trim().chars().count() <= MAX_REACTION_CHARS =>
+            {
+                Command::React(id.to_string(), emoji.trim().to_string())
+            }
+            _ => Command::Unknown(line.to_string()),
+        },
+        ("/attach", path) if !path.is_empty() => Command::Attach(path.to_string()),
+        ("/download", id) if !id.is_empty() && !id.contains(' ') => {
+            Command::Download(id.to_string())
+        }
+        ("/cancel", "") => Command::CancelTransfers,
+        ("/history", "") => Command::History,
+        ("/search", query) if !query.is_empty() => Command::Search(query.to_string()),
+        ("/members", "") => Command::Members,
+        ("/online", _) => Command::Presence(PresenceState::Online, status),
+        ("/away", _) => Command::Presence(PresenceState::Away, status),
+        ("/dnd", _) => Command::Presence(PresenceState::DoNotDisturb, status),
+        ("/status", "") => Command::SentStatus,
+        ("/receipts", "on") => Command::ReadReceipts(true),
+        ("/receipts", "off") => Command::ReadReceipts(false),
+        ("/role", argument) => match argument.split_once(' ') {
+            Some((user, role)) => match role.trim().parse() {
+                Ok(role) => Command::SetRole(user.to_string(), role),
+                Err(_) => Command::Unknown(line.to_string()),
+            },
+            None => Command::Unknown(line.to_string()),
+        },
+        ("/roles", "") => Command::Roles,
+        ("/kick", user) if !user.is_empty() && !user.contains(' ') => {
+            Command::Kick(user.to_string())
+        }
+        ("/capacity", capacity) => match capacity.parse() {
+            Ok(capacity) => Command::Capacity(capacity),
+            Err(_) => Command::Unknown(line.to_string()),
+        },
+        ("/deleteroom", "") => Command::DeleteRoom,
+        ("/rename", name) if !name.is_empty() && !name.contains(' ') => {
+            Command::Rename(name.to_string())
+        }
+        ("/rooms", "") => Command::Rooms,
+        ("/topic", _) => Command::SetTopic(status),
+        ("/ttl", "off") => Command::SetMessageTtl(None),
+        ("/ttl", ttl) => match parse_duration(ttl) {
+            Some(ttl) if !ttl.is_zero() => Command::SetMessageTtl(Some(ttl)),
+            _ => Command::Unknown(line.to_string()),
+        },
+        ("/join", argument) if !argument.is_empty() => match argument.split_once(' ') {
+            Some((room, password)) => {
+                Command::Join(room.to_string(), Some(password.trim().to_string()))
+            }
+            None => Command::Join(argument.to_string(), None),
+        },
+        ("/leave", "") => Command::Leave(None),
+        ("/leave", room) if !room.contains(' ') => Command::Leave(Some(room.to_string())),
+        ("/tab", room) if !room.is_empty() && !room.contains(' ') => Command::Tab(room.to_string()),
+        ("/tabs", "") => Command::Tabs,
+        ("/invite", user) if !user.is_empty() && !user.contains(' ') => {
+            Command::Invite(user.to_string())
+        }
+        ("/invites", "") => Command::Invitations,
+        ("/devices", "") => Command::Devices,
+        ("/revoke", device) if !device.is_empty() && !device.contains(' ') => {
+            Command::Revoke(device.to_string())
+        }
+        ("/visibility", visibility) => match visibility.parse() {
+            Ok(visibility) => Command::SetVisibility(visibility),
+            Err(_) => Command::Unknown(line.to_string()),
+        },
+        ("/password", _) => Command::SetPassword(status),
+        ("/quit", "") => Command::Quit,
+        _ => Command::Unknown(line.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_and_messages() {
+        assert_eq!(
+            parse("hello there\n"),
+            Command::Message("hello there".into())
+        );
+        assert_eq!(
+            parse("/search deploy failed"),
+            Command::Search("deploy failed".into())
+        );
+        assert_eq!(
+            parse("/away back at 3"),
+            Command::Presence(PresenceState::Away, Some("back at 3".into()))
+        );
+        assert_eq!(
+            parse("/dnd"),
+            Command::Presence(PresenceState::DoNotDisturb, None)
+        );
+        assert_eq!(parse("/search"), Command::Unknown("/search".into()));
+        assert_eq!(
+            parse("/receipts maybe"),
+            Command::Unknown("/receipts maybe".into())
+        );
+        assert_eq!(
+            parse("/role bob moderator"),
+            Command::SetRole("bob".into(), Role::Moderator)
+        );
+        assert_eq!(
+            parse("/role bob boss"),
+            Command::Unknown("/role bob boss".into())
+        );
+        assert_eq!(parse("/capacity 12"), Command::Capacity(12));
+        assert_eq!(parse("/kick"), Command::Unknown("/kick".into()));
+        assert_eq!(
+            parse("/rename incidents"),
+            Command::Rename("incidents".into())
+        );
+        assert_eq!(
+            parse("/rename two words"),
+            Command::Unknown("/rename two words".into())
+        );
+        assert_eq!(
+            parse("/join incidents correct horse"),
+            Command::Join("incidents".into(), Some("correct horse".into()))
+        );
+        assert_eq!(parse("/join lobby"), Command::Join("lobby".into(), None));
+        assert_eq!(parse("/password"), Command::SetPassword(None));
+        assert_eq!(parse("/leave ops"), Command::Leave(Some("ops".into())));
+        assert_eq!(parse("/tab"), Command::Unknown("/tab".into()));
+        assert_eq!(parse("/tab ops"), Command::Tab("ops".into()));
+        assert_eq!(
+            parse("/topic  Nothing on fire "),
+            Command::SetTopic(Some("Nothing on fire".into()))
+        );
+        assert_eq!(
+            parse("/reply 3fa2c9 on it, restarting"),
+            Command::Reply("3fa2c9".into(), "on it, restarting".into())
+        );
+        assert_eq!(
+            parse("/reply 3fa2c9"),
+            Command::Unknown("/reply 3fa2c9".into())
+        );
+        assert_eq!(parse("/thread 3fa2c9"), Command::Thread("3fa2c9".into()));
+        assert_eq!(
+            parse("/edit 3fa2c9 fixed now"),
+            Command::Edit("3fa2c9".into(), "fixed now".into())
+        );
+        assert_eq!(parse("/delete 3fa2c9"), Command::Delete("3fa2c9".into()));
+        assert_eq!(
+            parse("/react 3fa2c9 🎉"),
+            Command::React("3fa2c9".into(), "🎉".into())
+        );
+        assert_eq!(
+            parse("/react 3fa2c9 a b"),
+            Command::Unknown("/react 3fa2c9 a b".into())
+        );
+        assert_eq!(
+            parse("/attach logs/core 2.log"),
+            Command::Attach("logs/core 2.log".into())
+        );
+        assert_eq!(
+            parse("/download 3fa2c9"),
+            Command::Download("3fa2c9".into())
+        );
+        assert_eq!(
+            parse("/ephemeral 10m pw is hunter2"),
+            Command::Ephemeral(Duration::from_secs(600), "pw is hunter2".into())
+        );
+        assert_eq!(
+            parse("/ephemeral 0 gone"),
+            Command::Unknown("/ephemeral 0 gone".into())
+        );
+        assert_eq!(
+            parse("/ttl 90"),
+            Command::SetMessageTtl(Some(Duration::from_secs(90)))
+        );
+        assert_eq!(
+            parse("/ttl 2d"),
+            Command::SetMessageTtl(Some(Duration::from_secs(172800)))
+        );
+        assert_eq!(parse("/ttl off"), Command::SetMessageTtl(None));
+        assert_eq!(parse("/ttl soon"), Command::Unknown("/ttl soon".into()));
+        assert_eq!(parse("/revoke 3fa2c9"), Command::Revoke("3fa2c9".into()));
+        assert_eq!(parse("/revoke"), Command::Unknown("/revoke".into()));
+        assert_eq!(
+            parse("/visibility hidden"),
+            Command::SetVisibility(Visibility::Hidden)
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Client configuration.
+//!
+//! Settings come from command line flags, then `SLYCHAT_*` environment
+//! variables, then a TOML file, then built-in defaults. The file is the one
+//! passed with `--config`, or `config.toml` in the data directory if present:
+//!
+//! ```toml
+//! server = "chat.example.com"
+//! port = 9001
+//! tls = true
+//! tls_ca = "/etc/slychat/ca.pem"
+//! device_name = "laptop"
+//! ```
+
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::utils;
+
+const DEFAULT_SERVER: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 9001;
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            Self::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Parser, Debug, Default)]
+#[command(name = "slychat_client", about = "Connects to a slychat server")]
+pub struct Args {
+    /// TOML configuration file. Defaults to config.toml in the data directory.
+    #[arg(short, long, env = "SLYCHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Host name or address of the server.
+    #[arg(short, long, env = "SLYCHAT_SERVER")]
+    pub server: Option<String>,
+    #[arg(short, long, env = "SLYCHAT_PORT")]
+    pub port: Option<u16>,
+    /// Directory holding keys and the message archive. Defaults to ~/.slychat.
+    #[arg(long, env = "SLYCHAT_HOME")]
+    pub data_dir: Option<PathBuf>,
+    /// Connect over TLS.
+    #[arg(long, env = "SLYCHAT_TLS")]
+    pub tls: bool,
+    /// PEM file of certificate authorities to trust instead of the system ones.
+    #[arg(long, env = "SLYCHAT_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+    /// Name this device is listed under. Defaults to the host name.
+    #[arg(long, env = "SLYCHAT_DEVICE_NAME")]
+    pub device_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: Option<String>,
+    port: Option<u16>,
+    data_dir: Option<PathBuf>,
+    tls: Option<bool>,
+    tls_ca: Option<PathBuf>,
+    device_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub server: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub tls: bool,
+    pub tls_ca: Option<PathBuf>,
+    pub device_name: String,
+}
+
+impl ClientConfig {
+    /// Builds the configuration from the process arguments and environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => {
+                let dir = args
+                    .data_dir
+                    .clone()
+                    .unwrap_or_else(utils::default_data_dir);
+                Some(dir.join(CONFIG_FILE)).filter(|p| p.exists())
+            }
+        };
+        let file = match path {
+            Some(path) => {
+                let contents =
+                    fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))?
+            }
+            None => FileConfig::default(),
+        };
+
+        Ok(Self::merge(args, file))
+    }
+
+    fn merge(args: Args, file: FileConfig) -> Self {
+        Self {
+            server: args
+                .server
+                .or(file.server)
+                .unwrap_or_else(|| DEFAULT_SERVER.to_string()),
+            port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            data_dir: args
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(utils::default_data_dir),
+            tls: args.tls || file.tls.unwrap_or(false),
+            tls_ca: args.tls_ca.or(file.tls_ca),
+            device_name: args
+                .device_name
+                .or(file.device_name)
+                .unwrap_or_else(utils::default_device_name),
+        }
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.server, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_file() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            server = "chat.example.com"
+            port = 9100
+            tls = true
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            port: Some(9200),
+            ..Args::default()
+        };
+
+        let config = ClientConfig::merge(args, file);
+        assert_eq!(config.address(), "chat.example.com:9200");
+        assert!(config.tls);
+        assert_eq!(config.data_dir, utils::default_data_dir());
+    }
+}
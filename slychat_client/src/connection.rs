@@ -0,0 +1,30 @@
+use openssl::ssl::{SslConnector, SslMethod};
+use std::error::Error;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::config::ClientConfig;
+
+/// A stream to the server, either plain TCP or TLS.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+pub async fn connect(config: &ClientConfig) -> Result<Box<dyn Connection>, Box<dyn Error>> {
+    let stream = TcpStream::connect(config.address()).await?;
+    if !config.tls {
+        return Ok(Box::new(stream));
+    }
+
+    let mut connector = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca) = &config.tls_ca {
+        connector.set_ca_file(ca)?;
+    }
+    let ssl = connector.build().configure()?.into_ssl(&config.server)?;
+
+    let mut stream = SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream).connect().await?;
+    Ok(Box::new(stream))
+}
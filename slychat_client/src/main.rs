@@ -1,20 +1,84 @@
-use bytes::BytesMut;
-use slychat_common::encryption::{decrypt, encrypt, KeyData};
-use slychat_common::transport::{read_command, send_command, TransportError};
-use slychat_common::types::{APICommand, APIRequest, APIResponse, Response, UserKey};
-use std::io::{self, Read};
+use archive::{Archive, ArchivedMessage, Direction, Verification};
+use backoff::Backoff;
+use commands::Command;
+use config::ClientConfig;
+use connection::Connection;
+use receipts::Receipts;
+use sequence::{SequenceTracker, Sequencing};
+use slychat_common::encryption::{decrypt, encrypt, fingerprint, sign, verify, KeyData};
+use slychat_common::transport::{send_command, CommandReader};
+use slychat_common::types::{
+    deletion_data, login_data, APIRequest, APIResponse, ChatRoomId, DeviceInfo, DeviceKey,
+    EncryptedCopy, Heartbeat, Invitation, LoginSession, MessageContent, MessageId, Presence,
+    PublishedMessage, Reaction, ReceiptKind, Response, RoleAssignment, RoomInfo, RoomKeys, UserId,
+    Visibility,
+};
+use slychat_common::validation::validate_username;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant};
+use tabs::{Held, Tabs};
+use threads::{short_id as short_message_id, Posted, Threads};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::sleep_until;
+use transfers::{Downloaded, Transfers, Uploaded};
 
-const DEFAULT_PORT: i32 = 9001;
+const HISTORY_PAGE: usize = 20;
+const SEARCH_LIMIT: usize = 50;
+/// How long a member is shown as typing after their last notification.
+const TYPING_DISPLAY: Duration = Duration::from_secs(5);
+const RECONNECT_BASE: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+/// How long a reconnection attempt may take before it is given up on.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
 
-type RoomKeys = Vec<UserKey>;
-type LockedRoomKeys = Arc<Mutex<RoomKeys>>;
+type Reader = CommandReader<ReadHalf<Box<dyn Connection>>>;
+type Writer = WriteHalf<Box<dyn Connection>>;
+type LockedTabs = Arc<Mutex<Tabs>>;
+type LockedArchive = Arc<Mutex<Archive>>;
+type LockedReceipts = Arc<Mutex<Receipts>>;
+type LockedTransfers = Arc<Mutex<Transfers>>;
 
+/// State shared between the chatroom listener and the stdin listener.
+#[derive(Clone)]
+struct Shared {
+    // The rooms we are in.
+    tabs: LockedTabs,
+    archive: LockedArchive,
+    receipts: LockedReceipts,
+    transfers: LockedTransfers,
+    // Set once the user asked to quit, so the dropped connection isn't retried.
+    quitting: Arc<AtomicBool>,
+}
+
+/// Why the connection to the server ended.
+enum Disconnect {
+    Lost,
+    /// The server is going away or sent us away. Carries how long to wait
+    /// before coming back, if it expects us to.
+    Shutdown(Option<Duration>),
+}
+
+mod archive;
+mod backoff;
+mod commands;
+mod config;
+mod connection;
+mod receipts;
+mod sequence;
+mod tabs;
+mod threads;
+mod transfers;
 mod utils;
 
 fn generate_key(passphrase_opt: Option<&str>) -> KeyData {
@@ -25,105 +89,253 @@ fn generate_key(passphrase_opt: Option<&str>) -> KeyData {
     KeyData::from_passphrase(passphrase.as_bytes())
 }
 
-async fn refresh_roomkeys(
-    stream: &mut TcpStream,
-    roomkeys: &mut LockedRoomKeys,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let message = APIRequest::RefreshRoomKeysRequest;
+/// Unlocks the user's identity keystore, creating it on first use.
+fn load_keys(data_dir: &Path, username: &str, passphrase: &str) -> KeyData {
+    unlock_or_create(&utils::keystore_path(data_dir, username), passphrase)
+}
 
-    send_command(stream, &message).await?;
+/// Unlocks this device's keystore, creating it on first use, and links it to
+/// the user's `identity`. Keys have to outlive the session, otherwise history
+/// encrypted for them could never be read again.
+fn load_device(
+    data_dir: &Path,
+    username: &str,
+    passphrase: &str,
+    identity: &KeyData,
+    name: &str,
+) -> (KeyData, DeviceKey) {
+    let keys = unlock_or_create(&utils::device_key_path(data_dir, username), passphrase);
+    let device = DeviceKey {
+        user: username.to_string(),
+        name: name.to_string(),
+        identity: identity.public.clone(),
+        public: keys.public.clone(),
+        signature: sign(&keys.public, &identity.private, &identity.passphrase),
+    };
+    (keys, device)
+}
 
-    let response = match read_command(stream).await? {
-        APIResponse::RefreshRoomKeysResponse(r) => r,
-        val => {
-            eprintln!("Unexpected response: {:?}", val);
-            panic!()
+fn unlock_or_create(path: &Path, passphrase: &str) -> KeyData {
+    match KeyData::load(path, passphrase.as_bytes()) {
+        Ok(keys) => keys,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("Generating new keys at {}", path.display());
+            let keys = generate_key(Some(passphrase));
+            if let Err(e) = keys.save(path) {
+                eprintln!("Failed to save keys: {}", e);
+            }
+            keys
         }
-    };
+        Err(e) => {
+            eprintln!("Failed to unlock keystore {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+}
 
-    match response {
-        Response::Success(keys) => {
-            let mut keymap = roomkeys.lock().unwrap();
+fn open_archive(data_dir: &Path, username: &str, passphrase: &str) -> LockedArchive {
+    let path = utils::archive_path(data_dir, username);
 
-            keymap.clear();
-            for key in keys {
-                keymap.push(key);
+    match Archive::open(&path, passphrase.as_bytes()) {
+        Ok(archive) => {
+            // Messages that disappeared while we were away go first.
+            if let Err(e) = archive.purge_expired(utils::unix_millis()) {
+                eprintln!("Failed to purge expired messages: {}", e);
             }
-            println!("Found roomkeys! Updated to: {:?}", keymap)
+            Arc::new(Mutex::new(archive))
         }
-        Response::Error(e) => {
-            eprintln!("Error refreshing roomkeys: {}", e);
+        Err(e) => {
+            eprintln!("Failed to open archive {}: {}", path.display(), e);
             exit(1);
         }
     }
+}
 
-    Ok(())
+fn archive_message(archive: &LockedArchive, message: ArchivedMessage) {
+    if let Err(e) = archive.lock().unwrap().record(&message) {
+        eprintln!("Failed to archive message: {}", e);
+    }
 }
 
-async fn greet(
-    stream: &mut TcpStream,
-    username: String,
+fn update_roomkeys(response: Response<RoomKeys>, tabs: &LockedTabs) {
+    match response {
+        Response::Success(RoomKeys { room, mut keys }) => {
+            // Devices the server can't show were linked by their user's
+            // identity get no copies.
+            keys.retain(|key| {
+                let linked = key.is_linked();
+                if !linked {
+                    eprintln!("Ignoring unsigned device {} of {}", key.name, key.user);
+                }
+                linked
+            });
+            let devices: Vec<String> = keys
+                .iter()
+                .map(|k| format!("{} ({})", k.user, k.name))
+                .collect();
+            println!("Found roomkeys for #{}! Updated to: {:?}", room, devices);
+            if let Some(tab) = tabs.lock().unwrap().get_mut(&room) {
+                tab.keys = keys;
+            }
+        }
+        Response::Error(e) => eprintln!("Error refreshing roomkeys: {}", e),
+    }
+}
+
+/// Logs in, or resumes the session `resume` names along with the latest
+/// sequence number seen in each of its rooms. The server's challenge is
+/// signed with `keys`, the private half of `key`.
+async fn greet<R, W>(
+    reader: &mut CommandReader<R>,
+    writer: &mut W,
+    key: DeviceKey,
     keys: &KeyData,
-) -> Result<(), TransportError> {
-    let user_data = UserKey {
-        user: username,
-        public: keys.public.clone(),
+    resume: Option<(String, HashMap<ChatRoomId, u64>)>,
+) -> Result<LoginSession, Box<dyn Error>>
+where
+    R: AsyncReadExt + Send + Unpin,
+    W: AsyncWriteExt + Send + Unpin + 'static,
+{
+    let nonce = match reader.read().await? {
+        APIResponse::LoginChallenge(nonce) => nonce,
+        other => return Err(format!("Expected a login challenge, got {:?}", other).into()),
     };
-    send_command(stream, &APIRequest::LoginRequest(user_data)).await?;
+    let signature = sign(&login_data(&nonce), &keys.private, &keys.passphrase);
+    let greeting = match resume {
+        None => APIRequest::LoginRequest { key, signature },
+        Some((token, after)) => APIRequest::ResumeRequest {
+            key,
+            signature,
+            token,
+            after,
+        },
+    };
+    send_command(writer, &greeting).await?;
+
+    match reader.read().await? {
+        APIResponse::LoginResponse(Response::Success(login)) => Ok(login),
+        APIResponse::LoginResponse(Response::Error(e)) => {
+            Err(format!("Login failed: {}", e).into())
+        }
+        other => Err(format!("Unexpected response to login: {:?}", other).into()),
+    }
+}
 
-    match read_command(stream).await? {
-        APIResponse::LoginResponse(r) => {
-            if let Response::Success(()) = r {
-                println!("Login Succeeded.");
-                Ok(())
-            } else {
-                panic!("Login failed.")
+/// Connects to the server and logs in as `device`, whose private key is
+/// `keys`.
+async fn login(
+    config: &ClientConfig,
+    device: &DeviceKey,
+    keys: &KeyData,
+    resume: Option<(String, HashMap<ChatRoomId, u64>)>,
+) -> Result<(Reader, Writer, LoginSession), Box<dyn Error>> {
+    let stream = connection::connect(config).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = CommandReader::new(reader);
+
+    let login = greet(&mut reader, &mut writer, device.clone(), keys, resume).await?;
+    Ok((reader, writer, login))
+}
+
+/// Logs back in after the connection dropped, retrying until it works.
+async fn reconnect(
+    config: &ClientConfig,
+    device: &DeviceKey,
+    keys: &KeyData,
+    resume: (String, HashMap<ChatRoomId, u64>),
+) -> (Reader, Writer, LoginSession) {
+    let mut backoff = Backoff::new(RECONNECT_BASE, RECONNECT_MAX);
+    loop {
+        let attempt = login(config, device, keys, Some(resume.clone()));
+        let result = match tokio::time::timeout(LOGIN_TIMEOUT, attempt).await {
+            Ok(result) => result,
+            Err(_) => Err("Timed out".into()),
+        };
+        match result {
+            Ok(connection) => return connection,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                eprintln!(
+                    "Reconnect failed ({}). Retrying in {:.1}s.",
+                    e,
+                    delay.as_secs_f32()
+                );
+                tokio::time::sleep(delay).await;
             }
         }
-        _ => panic!(),
     }
 }
 
 fn get_username() -> String {
-    println!("Enter Username: >");
+    loop {
+        let username = prompt("Enter Username: >");
+        match validate_username(&username) {
+            Ok(()) => return username,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn get_passphrase() -> String {
+    prompt("Enter Passphrase: >")
+}
+
+fn prompt(message: &str) -> String {
+    println!("{}", message);
     let mut buffer = String::new();
-    let n = std::io::stdin().read_line(&mut buffer).unwrap();
+    std::io::stdin().read_line(&mut buffer).unwrap();
 
-    buffer[..n].to_string()
+    buffer.trim_end().to_string()
 }
 
 #[tokio::main]
 async fn main() {
-    // We run a thing
-    // let r: RoomKeys = Vec::new();
-    let username = get_username();
-
-    let passphrase = "hello";
-    let keys = generate_key(passphrase.into());
-
-    let mut stream = match TcpStream::connect(format!("127.0.0.1:{}", DEFAULT_PORT)).await {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to connect to chat-server.");
-            exit(1)
+    let config = match ClientConfig::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
         }
     };
 
+    let username = get_username();
+    let passphrase = get_passphrase();
+    let identity = load_keys(&config.data_dir, &username, &passphrase);
+    let (keys, device) = load_device(
+        &config.data_dir,
+        &username,
+        &passphrase,
+        &identity,
+        &config.device_name,
+    );
+    let archive = open_archive(&config.data_dir, &username, &passphrase);
+
     // Greet the server
     println!("Greeting!");
-    if greet(&mut stream, username, &keys).await.is_err() {
-        eprintln!("Error in greeting");
-        exit(1);
+    let (mut reader, mut writer, mut session) = match login(&config, &device, &keys, None).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!(
+                "Failed to connect to chat-server at {}: {}",
+                config.address(),
+                e
+            );
+            exit(1)
+        }
     };
+    println!("Login Succeeded. Joined {}", room_list(&session.rooms));
 
-    println!("Awaiting room keys");
-    let mut room_keys: LockedRoomKeys = Arc::new(Mutex::new(Vec::new()));
-    if refresh_roomkeys(&mut stream, &mut room_keys).await.is_err() {
-        eprintln!("Error Getting RoomKeys");
-        exit(1);
-    }
+    let mut tabs = Tabs::default();
+    tabs.sync(&session.rooms);
+    let shared = Shared {
+        tabs: Arc::new(Mutex::new(tabs)),
+        archive,
+        receipts: Arc::new(Mutex::new(Receipts::default())),
+        transfers: Arc::new(Mutex::new(Transfers::default())),
+        quitting: Arc::new(AtomicBool::new(false)),
+    };
 
-    let (reader, writer) = tokio::io::split(stream);
+    let (requests, mut request_receiver) = mpsc::channel::<APIRequest>(64);
 
     /*
         Processes:
@@ -132,44 +344,1001 @@ async fn main() {
             2. Stdin Listener
                 Listens to messages on StdIn. Encrypts messages with appropriate keys and sends them.
     */
-    tokio::spawn(async move { chatroom_listener(reader, &keys).await });
-    // tokio::spawn(async move { stdin_listener(writer) })
+    let keys = Arc::new(keys);
+    stdin_listener(
+        requests.clone(),
+        shared.clone(),
+        username.clone(),
+        keys.clone(),
+        utils::downloads_dir(&config.data_dir),
+    );
+
+    let mut sequences = SequenceTracker::default();
+    loop {
+        let (stop, stopped) = oneshot::channel();
+        let socket_writer = socket_writer(writer, request_receiver, stopped);
+
+        // Events may arrive ahead of the answers, so these are handled by
+        // the listener like everything else.
+        let mut catch_up: Vec<APIRequest> = session
+            .rooms
+            .iter()
+            .map(|room| APIRequest::RefreshRoomKeysRequest(room.to_string()))
+            .collect();
+        let active = shared.tabs.lock().unwrap().active().cloned();
+        if let Some(room) = active.filter(|_| !session.resumed) {
+            // Catch up on whatever was said while we were away. On resuming
+            // the server replays it instead.
+            catch_up.push(APIRequest::FetchHistoryRequest {
+                room: room.to_string(),
+                before: None,
+                limit: HISTORY_PAGE,
+            });
+        }
+        // Transfers pick up where the server says they got to.
+        catch_up.extend(shared.transfers.lock().unwrap().resume());
+        for request in catch_up {
+            if requests.send(request).await.is_err() {
+                eprintln!("Error catching up");
+            }
+        }
+
+        let disconnect = chatroom_listener(
+            reader,
+            &username,
+            &keys,
+            &requests,
+            &shared,
+            &mut sequences,
+            session.heartbeat,
+        )
+        .await;
+        let _ = stop.send(());
+        request_receiver = socket_writer.await.expect("Socket writer failed");
+
+        if shared.quitting.load(Ordering::SeqCst) {
+            exit(0);
+        }
+        match disconnect {
+            Disconnect::Lost => println!("Connection lost. Reconnecting..."),
+            Disconnect::Shutdown(None) => exit(0),
+            Disconnect::Shutdown(Some(after)) => tokio::time::sleep(after).await,
+        }
+
+        // Where we are now, which may not be where the session started.
+        let after = shared
+            .tabs
+            .lock()
+            .unwrap()
+            .list()
+            .into_iter()
+            .filter_map(|(room, _)| Some((room.clone(), sequences.latest(room)?)))
+            .collect();
+        let resume = (session.resume_token, after);
+        (reader, writer, session) = reconnect(&config, &device, &keys, resume).await;
+        if session.resumed {
+            println!("Reconnected to {}", room_list(&session.rooms));
+        } else {
+            println!(
+                "Reconnected as a new session. Joined {}",
+                room_list(&session.rooms)
+            );
+        }
+        shared.tabs.lock().unwrap().sync(&session.rooms);
+    }
 }
 
+/// Handles events from the server until the connection ends, pinging the
+/// server as often as `heartbeat` asks.
 async fn chatroom_listener<T: AsyncReadExt + Unpin + Send>(
-    mut socket_reader: T,
+    mut socket_reader: CommandReader<T>,
+    username: &str,
     my_keys: &KeyData,
-) {
-    let mut buffer: Vec<u8> = vec![0; 1024];
+    requests: &mpsc::Sender<APIRequest>,
+    shared: &Shared,
+    sequences: &mut SequenceTracker,
+    heartbeat: Heartbeat,
+) -> Disconnect {
+    let mut typing: HashMap<UserId, Instant> = HashMap::new();
+    let mut ping = tokio::time::interval(heartbeat.interval());
+    let mut last_heard = tokio::time::Instant::now();
+
     loop {
-        buffer.clear();
+        let response = select! {
+            read = socket_reader.read() => match read {
+                Ok(r) => r,
+                Err(_) => return Disconnect::Lost,
+            },
+            _ = ping.tick() => {
+                // If requests are backed up the connection is in trouble
+                // anyway, and the silence will show.
+                let _ = requests.try_send(APIRequest::Ping);
+                continue;
+            }
+            _ = sleep_until(last_heard + heartbeat.timeout()) => {
+                println!(
+                    "*** No word from the server in {}s.",
+                    heartbeat.timeout().as_secs()
+                );
+                return Disconnect::Lost;
+            }
+        };
+        last_heard = tokio::time::Instant::now();
+
+        match response {
+            APIResponse::PublishMessage(published) => {
+                match sequences.observe(&published.room, published.sequence) {
+                    Sequencing::Gap { missing } => {
+                        println!("--- Missed {} message(s), fetching ---", missing);
+                        let backfill = APIRequest::FetchHistoryRequest {
+                            room: published.room.to_string(),
+                            before: Some(published.sequence),
+                            limit: missing as usize,
+                        };
+                        if requests.send(backfill).await.is_err() {
+                            eprintln!("Error requesting missed messages");
+                        }
+                    }
+                    Sequencing::Stale => continue,
+                    Sequencing::InOrder => {}
+                }
 
-        let data = match socket_reader.read(&mut buffer).await {
-            Ok(n) if n > 0 => &buffer[..n],
-            _ => {
-                eprintln!("Error Reading Socket. Disconnecting.");
-                exit(1)
+                let content = match decrypt_message(&published, my_keys) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let expires = content.expires(&published);
+                if expires.is_some_and(|expires| expires <= utils::unix_millis()) {
+                    continue;
+                }
+                // Reactions only ever show as part of what they react to.
+                if let Some(reaction) = &content.reaction {
+                    show_reaction(&published, reaction, &shared.tabs);
+                    continue;
+                }
+                let own = published.sender == username.to_string();
+                let shown = show_message(&published, &content, own, &shared.tabs);
+
+                if own {
+                    shared
+                        .receipts
+                        .lock()
+                        .unwrap()
+                        .track_sent(published.id, &content.body);
+                } else {
+                    let ack = APIRequest::AcknowledgeMessage {
+                        room: published.room.to_string(),
+                        id: published.id,
+                        kind: ReceiptKind::Delivered,
+                    };
+                    if requests.send(ack).await.is_err() {
+                        eprintln!("Error acknowledging message");
+                    }
+                    if shown {
+                        shared
+                            .receipts
+                            .lock()
+                            .unwrap()
+                            .mark_shown(published.room.clone(), published.id);
+                    }
+                }
+                archive_message(&shared.archive, to_archived(&published, &content, username));
+                if let Some(expires) = expires {
+                    expire_later(published.room.clone(), published.id, expires, shared);
+                }
+            }
+            APIResponse::MessageEdited(published) => {
+                let Some(content) = decrypt_message(&published, my_keys) else {
+                    continue;
+                };
+                let (room, sender) = (&published.room, &published.sender);
+                let signed = signed_by_sender(room, sender, &shared.tabs, |key| {
+                    content.is_edit_by(key, room, published.id)
+                });
+                if !signed {
+                    eprintln!(
+                        "Ignoring an edit of {} not signed by {}.",
+                        short_message_id(published.id),
+                        published.sender
+                    );
+                    continue;
+                }
+                let own = published.sender == username.to_string();
+                show_message(&published, &content, own, &shared.tabs);
+                let edited = shared
+                    .archive
+                    .lock()
+                    .unwrap()
+                    .edit(published.id, &content.body);
+                match edited {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let archived = to_archived(&published, &content, username);
+                        archive_message(&shared.archive, archived)
+                    }
+                    Err(e) => eprintln!("Failed to archive edit: {}", e),
+                }
+                if let Some(expires) = content.expires(&published) {
+                    expire_later(published.room.clone(), published.id, expires, shared);
+                }
+            }
+            APIResponse::MessageDeleted {
+                room,
+                id,
+                sender,
+                signature,
+            } => {
+                let signed = signed_by_sender(&room, &sender, &shared.tabs, |key| {
+                    verify(&deletion_data(&room, id), &signature, &key.public)
+                });
+                if !signed {
+                    eprintln!(
+                        "Ignoring a deletion of {} not signed by {}.",
+                        short_message_id(id),
+                        sender
+                    );
+                    continue;
+                }
+                forget_message(&room, id, shared);
+                println!(
+                    "* {} deleted message {} in #{}",
+                    sender,
+                    short_message_id(id),
+                    room
+                );
+            }
+            APIResponse::FetchHistoryResponse(Response::Success(entries)) => {
+                render_history(&entries, username, my_keys, shared);
+                for entry in &entries {
+                    sequences.advance(&entry.room, entry.sequence);
+                }
+                if let Some(oldest) = entries.first() {
+                    if let Some(tab) = shared.tabs.lock().unwrap().get_mut(&oldest.room) {
+                        let cursor = &mut tab.history_cursor;
+                        *cursor = Some(cursor.map_or(oldest.sequence, |c| c.min(oldest.sequence)));
+                    }
+                }
+            }
+            APIResponse::FetchHistoryResponse(Response::Error(e)) => {
+                eprintln!("Error fetching history: {}", e)
+            }
+            APIResponse::CreateBlobResponse(Response::Success(progress))
+            | APIResponse::UploadChunkResponse(Response::Success(progress)) => {
+                let uploaded = shared.transfers.lock().unwrap().uploaded(progress);
+                let request = match uploaded {
+                    Some(Uploaded::Next(request)) => request,
+                    Some(Uploaded::Done(room, attachment)) => {
+                        let tabs = shared.tabs.lock().unwrap();
+                        let Some(tab) = tabs.get(&room) else {
+                            eprintln!("Left #{} before {} could be shared.", room, attachment.name);
+                            continue;
+                        };
+                        println!("Uploaded {}.", attachment.name);
+                        let content = MessageContent::attachment(attachment);
+                        APIRequest::SendMessageRequest {
+                            room: room.to_string(),
+                            copies: encrypt_copies(&content, &tab.keys),
+                            ttl_secs: None,
+                        }
+                    }
+                    None => continue,
+                };
+                if requests.send(request).await.is_err() {
+                    eprintln!("Error uploading attachment");
+                }
+            }
+            APIResponse::CreateBlobResponse(Response::Error(e))
+            | APIResponse::UploadChunkResponse(Response::Error(e)) => {
+                if let Some(name) = shared.transfers.lock().unwrap().cancel_upload() {
+                    eprintln!("Could not upload {}: {}", name, e);
+                }
+            }
+            APIResponse::DownloadChunkResponse(Response::Success(chunk)) => {
+                let downloaded = shared.transfers.lock().unwrap().downloaded(chunk);
+                match downloaded {
+                    Ok(Some(Downloaded::Next(request))) => {
+                        if requests.send(request).await.is_err() {
+                            eprintln!("Error downloading attachment");
+                        }
+                    }
+                    Ok(Some(Downloaded::Done(path, file))) => match std::fs::write(&path, file) {
+                        Ok(()) => println!("Saved {}.", path.display()),
+                        Err(e) => eprintln!("Could not save {}: {}", path.display(), e),
+                    },
+                    Ok(None) => {}
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            APIResponse::DownloadChunkResponse(Response::Error(e)) => {
+                if let Some(name) = shared.transfers.lock().unwrap().cancel_download() {
+                    eprintln!("Could not download {}: {}", name, e);
+                }
+            }
+            APIResponse::RefreshRoomKeysResponse(r) => update_roomkeys(r, &shared.tabs),
+            APIResponse::PresenceUpdate(presence) => {
+                typing.remove(&presence.user);
+                println!("* {}", describe_presence(&presence));
+            }
+            APIResponse::TypingNotification { room, user } => {
+                if shared.tabs.lock().unwrap().is_active(&room) {
+                    println!("({} is typing…)", user);
+                }
+                typing.insert(user, Instant::now());
+            }
+            APIResponse::ListMembersResponse(Response::Success(members)) => {
+                render_members(&members, &typing)
+            }
+            APIResponse::ListRolesResponse(Response::Success(roles)) => render_roles(&roles),
+            APIResponse::ListRoomsResponse(Response::Success(rooms)) => {
+                render_rooms(&rooms, &shared.tabs.lock().unwrap())
+            }
+            APIResponse::ListInvitationsResponse(Response::Success(invitations)) => {
+                render_invitations(&invitations)
+            }
+            APIResponse::JoinRoomResponse(Response::Success(room)) => {
+                println!("Joined #{}", room);
+                open_room(room, shared, requests).await;
+            }
+            APIResponse::LeaveRoomResponse(Response::Success(room)) => {
+                println!("Left #{}", room);
+                close_tab(&room, shared);
+            }
+            APIResponse::RemovedFromRoom { room, reason } => {
+                println!("*** {}.", reason);
+                close_tab(&room, shared);
+            }
+            APIResponse::AddedToRoom { room } => {
+                println!("*** You joined #{} on another device.", room);
+                open_room(room, shared, requests).await;
+            }
+            APIResponse::ListDevicesResponse(Response::Success(devices)) => {
+                render_devices(&devices, &keys_id(my_keys))
+            }
+            APIResponse::RevokeDeviceResponse(Response::Success(device)) => {
+                println!("Revoked {} ({}).", device.name, short_id(&device.id))
+            }
+            APIResponse::InviteUserResponse(Response::Success(())) => println!("Invitation sent."),
+            APIResponse::SetVisibilityResponse(Response::Success(())) => {
+                println!("Room visibility changed.")
+            }
+            APIResponse::SetPasswordResponse(Response::Success(())) => {
+                println!("Room password changed.")
+            }
+            APIResponse::SetTopicResponse(Response::Success(())) => {}
+            APIResponse::TopicChanged { room, topic, by } => match topic {
+                Some(topic) => println!("* {} set the topic of #{} to: {}", by, room, topic),
+                None => println!("* {} cleared the topic of #{}", by, room),
+            },
+            APIResponse::RenameRoomResponse(Response::Success(())) => {}
+            APIResponse::RoomRenamed { from, to, by } => {
+                println!("* {} renamed #{} to #{}", by, from, to);
+                shared.tabs.lock().unwrap().rename(&from, &to);
+                sequences.rename(&from, &to);
+            }
+            APIResponse::SetMessageTtlResponse(Response::Success(())) => {}
+            APIResponse::MessageTtlChanged { room, ttl_secs, by } => match ttl_secs {
+                Some(ttl) => println!(
+                    "* {} made messages in #{} disappear after {}",
+                    by,
+                    room,
+                    utils::format_duration(ttl)
+                ),
+                None => println!("* {} made messages in #{} stay", by, room),
+            },
+            APIResponse::Invited(Invitation { room, from }) => println!(
+                "*** {} invited you to #{}. Type /join {} to accept.",
+                from, room, room
+            ),
+            APIResponse::SetPresenceResponse(Response::Error(e))
+            | APIResponse::ListMembersResponse(Response::Error(e))
+            | APIResponse::ListRolesResponse(Response::Error(e))
+            | APIResponse::SetRoleResponse(Response::Error(e))
+            | APIResponse::KickFromRoomResponse(Response::Error(e))
+            | APIResponse::SetCapacityResponse(Response::Error(e))
+            | APIResponse::DeleteRoomResponse(Response::Error(e))
+            | APIResponse::RenameRoomResponse(Response::Error(e))
+            | APIResponse::ListRoomsResponse(Response::Error(e))
+            | APIResponse::JoinRoomResponse(Response::Error(e))
+            | APIResponse::LeaveRoomResponse(Response::Error(e))
+            | APIResponse::InviteUserResponse(Response::Error(e))
+            | APIResponse::ListInvitationsResponse(Response::Error(e))
+            | APIResponse::SetVisibilityResponse(Response::Error(e))
+            | APIResponse::SetPasswordResponse(Response::Error(e))
+            | APIResponse::SetTopicResponse(Response::Error(e))
+            | APIResponse::SetMessageTtlResponse(Response::Error(e))
+            | APIResponse::ListDevicesResponse(Response::Error(e))
+            | APIResponse::RevokeDeviceResponse(Response::Error(e))
+            | APIResponse::EditMessageResponse(Response::Error(e))
+            | APIResponse::DeleteMessageResponse(Response::Error(e)) => eprintln!("{}", e),
+            APIResponse::RoleChanged { room, assignment } => println!(
+                "* {}'s role in #{} is now {}",
+                assignment.user, room, assignment.role
+            ),
+            APIResponse::DeliveredReceipt(receipt) => {
+                let mut receipts = shared.receipts.lock().unwrap();
+                if let Some(message) = receipts.record(ReceiptKind::Delivered, &receipt) {
+                    println!("  ✓ \"{}\" {}", message.preview, message.status());
+                }
+            }
+            APIResponse::ReadReceipt(receipt) => {
+                let mut receipts = shared.receipts.lock().unwrap();
+                if let Some(message) = receipts.record(ReceiptKind::Read, &receipt) {
+                    println!("  ✓✓ \"{}\" {}", message.preview, message.status());
+                }
             }
+            APIResponse::SendMessageResponse(Response::Error(e)) => {
+                eprintln!("Error sending message: {}", e)
+            }
+            APIResponse::RateLimited { retry_after } => {
+                let wait = Duration::from_millis(retry_after);
+                // The limited request may have been a chunk, which would
+                // leave its transfer waiting for an answer forever.
+                let transfers = shared.transfers.clone();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(wait).await;
+                    let resumed = transfers.lock().unwrap().resume();
+                    for request in resumed {
+                        let _ = requests.send(request).await;
+                    }
+                });
+                if !shared.transfers.lock().unwrap().is_active() {
+                    eprintln!("Slow down! Try again in {:.1}s.", wait.as_secs_f32());
+                }
+            }
+            APIResponse::ServerShutdown {
+                reason,
+                reconnect_after,
+            } => {
+                match reconnect_after {
+                    Some(secs) => println!("{}. Reconnecting in {}s.", reason, secs),
+                    None => println!("{}.", reason),
+                }
+                return Disconnect::Shutdown(reconnect_after.map(Duration::from_secs));
+            }
+            APIResponse::Kicked { reason, reconnect } => {
+                println!("*** {}.", reason);
+                // Rejoining straight away lands in a fresh session.
+                return Disconnect::Shutdown(reconnect.then_some(Duration::ZERO));
+            }
+            APIResponse::ServerNotice(text) => println!("*** Notice from the server: {}", text),
+            APIResponse::Rejected { reason } => {
+                eprintln!("*** Rejected by the server: {}", reason);
+                return Disconnect::Lost;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Opens a tab for a room just joined, then fetches its keys and recent
+// history.
+async fn open_room(room: ChatRoomId, shared: &Shared, requests: &mpsc::Sender<APIRequest>) {
+    shared.tabs.lock().unwrap().open(&room);
+
+    let catch_up = [
+        APIRequest::RefreshRoomKeysRequest(room.to_string()),
+        APIRequest::FetchHistoryRequest {
+            room: room.to_string(),
+            before: None,
+            limit: HISTORY_PAGE,
+        },
+    ];
+    for request in catch_up {
+        if requests.send(request).await.is_err() {
+            eprintln!("Error catching up on #{}", room);
+        }
+    }
+}
+
+// Closes the tab of a room we are no longer in.
+fn close_tab(room: &ChatRoomId, shared: &Shared) {
+    if let Some(active) = shared.tabs.lock().unwrap().close(room) {
+        println!("Now in #{}", active);
+    }
+}
+
+// Shows a message if its tab is open, and holds it for the tab otherwise.
+// Returns whether it was shown.
+fn show_message(
+    published: &PublishedMessage,
+    content: &MessageContent,
+    own: bool,
+    tabs: &LockedTabs,
+) -> bool {
+    let mut tabs = tabs.lock().unwrap();
+    let line = match tabs.get_mut(&published.room) {
+        Some(tab) => remember(published, content, &mut tab.threads),
+        None => format_message(
+            &Posted::new(published, content.clone()),
+            &Threads::default(),
+        ),
+    };
+    if tabs.is_active(&published.room) {
+        println!("{}", line);
+        return true;
+    }
+    let held = Held {
+        line,
+        id: published.id,
+        unread: !own,
+    };
+    if tabs.hold(&published.room, held) == Some(1) {
+        println!(
+            "(New messages in #{}. Type /tab {} to read them.)",
+            published.room, published.room
+        );
+    }
+    false
+}
+
+fn room_list(rooms: &[ChatRoomId]) -> String {
+    let names: Vec<String> = rooms.iter().map(|room| format!("#{}", room)).collect();
+    names.join(", ")
+}
+
+// Decrypts our copy of a message. Copies that were damaged, tampered with or
+// encrypted for another device are dropped.
+fn decrypt_message(published: &PublishedMessage, my_keys: &KeyData) -> Option<MessageContent> {
+    let Some(decrypted) = decrypt(&published.message, &my_keys.private, &my_keys.passphrase) else {
+        eprintln!(
+            "Dropping message {} from {}: it could not be decrypted.",
+            short_message_id(published.id),
+            published.sender
+        );
+        return None;
+    };
+    MessageContent::from_plaintext(&decrypted)
+}
+
+// Remembers a message for its room's threads and formats it.
+fn remember(
+    published: &PublishedMessage,
+    content: &MessageContent,
+    threads: &mut Threads,
+) -> String {
+    let posted = Posted::new(published, content.clone());
+    let line = format_message(&posted, threads);
+    threads.record(posted);
+    line
+}
+
+// Formats a message, with the message it answers if it is a reply.
+fn format_message(posted: &Posted, threads: &Threads) -> String {
+    let body = match &posted.content.attachment {
+        Some(attachment) => format!(
+            "📎 {} ({})",
+            attachment.name,
+            utils::format_size(attachment.size)
+        ),
+        None => posted.content.body.clone(),
+    };
+    let reply_to = posted.content.reply_to.map(|id| match threads.get(id) {
+        Some(parent) => {
+            let quote = utils::preview(&parent.content.body);
+            format!(" ↳ {} \"{}\"", parent.sender, quote)
+        }
+        None => format!(" ↳ {}", short_message_id(id)),
+    });
+    let edited = if posted.content.edit_signature.is_some() {
+        " (edited)"
+    } else {
+        ""
+    };
+    let expires = posted
+        .expires
+        .map(|expires| format!(" (disappears {})", utils::format_timestamp(expires)));
+    format!(
+        "[{}] {} {}{}{}{}: {}",
+        utils::format_timestamp(posted.timestamp),
+        short_message_id(posted.id),
+        posted.sender,
+        reply_to.unwrap_or_default(),
+        edited,
+        expires.unwrap_or_default(),
+        body
+    )
+}
+
+// Tallies a reaction, then shows the message's reactions if its tab is open
+// and holds them for the tab otherwise.
+fn show_reaction(published: &PublishedMessage, reaction: &Reaction, tabs: &LockedTabs) {
+    let mut tabs = tabs.lock().unwrap();
+    let Some(tab) = tabs.get_mut(&published.room) else {
+        return;
+    };
+    let sender = published.sender.as_str();
+    let Some(posted) = tab.threads.react(sender, reaction, published.timestamp) else {
+        return;
+    };
+    let line = match format_reactions(posted) {
+        Some(reactions) => format!("  {} {}", short_message_id(posted.id), reactions),
+        None => format!("  {} no reactions", short_message_id(posted.id)),
+    };
+    if tabs.is_active(&published.room) {
+        println!("{}", line);
+        return;
+    }
+    let held = Held {
+        line,
+        id: published.id,
+        unread: false,
+    };
+    tabs.hold(&published.room, held);
+}
+
+// Each emoji on a message with who added it, if there are any.
+fn format_reactions(posted: &Posted) -> Option<String> {
+    let summary = posted.reactions.summary();
+    if summary.is_empty() {
+        return None;
+    }
+    let reactions: Vec<String> = summary
+        .into_iter()
+        .map(|(emoji, users)| format!("{} {}", emoji, users.join(", ")))
+        .collect();
+    Some(reactions.join("  "))
+}
+
+// Prints a message, and its reactions under it.
+fn print_message(posted: &Posted, indent: &str, threads: &Threads) {
+    println!("{}{}", indent, format_message(posted, threads));
+    if let Some(reactions) = format_reactions(posted) {
+        println!("{}    {}", indent, reactions);
+    }
+}
+
+fn render_thread(thread: &[&Posted], root: MessageId, threads: &Threads) {
+    println!("--- Thread {} ---", short_message_id(root));
+    if thread.first().is_none_or(|first| first.id != root) {
+        println!("(The start of the thread is no longer remembered.)");
+    }
+    for posted in thread {
+        let indent = if posted.id == root { "" } else { "  " };
+        print_message(posted, indent, threads);
+    }
+    println!("--- End of thread ---");
+}
+
+// Whether `check` holds for one of `sender`'s devices in `room`, as far as
+// the room's keys are known.
+fn signed_by_sender(
+    room: &ChatRoomId,
+    sender: &UserId,
+    tabs: &LockedTabs,
+    check: impl Fn(&DeviceKey) -> bool,
+) -> bool {
+    let tabs = tabs.lock().unwrap();
+    let keys = tabs
+        .get(room)
+        .map(|tab| tab.keys.as_slice())
+        .unwrap_or_default();
+    keys.iter()
+        .filter(|key| key.user == sender.as_str())
+        .any(check)
+}
+
+// Removes every trace of a deleted or expired message: from its room's
+// threads, from what is held for its tab, from the receipts of our own and
+// from the archive.
+fn forget_message(room: &ChatRoomId, id: MessageId, shared: &Shared) {
+    let mut tabs = shared.tabs.lock().unwrap();
+    if let Some(tab) = tabs.get_mut(room) {
+        tab.threads.remove(id);
+    }
+    tabs.discard(room, id);
+    shared.receipts.lock().unwrap().forget(id);
+    if let Err(e) = shared.archive.lock().unwrap().delete(id) {
+        eprintln!("Failed to remove message from the archive: {}", e);
+    }
+}
+
+// Forgets a message once it disappears, and says so if it was on show.
+fn expire_later(room: ChatRoomId, id: MessageId, expires: u64, shared: &Shared) {
+    let wait = Duration::from_millis(expires.saturating_sub(utils::unix_millis()));
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(wait).await;
+        // A message scheduled twice, e.g. when edited, is only reported once.
+        let known = shared
+            .tabs
+            .lock()
+            .unwrap()
+            .get(&room)
+            .is_some_and(|tab| tab.threads.get(id).is_some());
+        forget_message(&room, id, &shared);
+        if known {
+            println!(
+                "* Message {} in #{} disappeared",
+                short_message_id(id),
+                room
+            );
+        }
+    });
+}
+
+// Finds one of our own messages among the latest of a room.
+fn own_message<'a>(threads: &'a Threads, id: &str, username: &str) -> Result<&'a Posted, String> {
+    let message = threads.find(id).map_err(|e| e.to_string())?;
+    if message.sender.as_str() != username {
+        return Err("You can only change your own messages.".to_string());
+    }
+    Ok(message)
+}
+
+// One copy of `content` for every device in `keys`.
+fn encrypt_copies(content: &MessageContent, keys: &[DeviceKey]) -> Vec<EncryptedCopy> {
+    let plaintext = content.to_plaintext();
+    keys.iter()
+        .filter_map(|key| {
+            let Some(message) = encrypt(&plaintext, &key.public) else {
+                eprintln!(
+                    "Not sending to {}'s device {}: its key is unusable.",
+                    key.user, key.name
+                );
+                return None;
+            };
+            Some(EncryptedCopy {
+                recipient: key.user.as_str().into(),
+                device: key.id(),
+                message,
+            })
+        })
+        .collect()
+}
+
+fn to_archived(
+    published: &PublishedMessage,
+    content: &MessageContent,
+    username: &str,
+) -> ArchivedMessage {
+    let direction = if published.sender == username.to_string() {
+        Direction::Sent
+    } else {
+        Direction::Received
+    };
+
+    ArchivedMessage {
+        id: Some(published.id),
+        room: published.room.to_string(),
+        sender: Some(published.sender.to_string()),
+        timestamp: published.timestamp,
+        direction,
+        verification: Verification::Unverified,
+        body: content.body.clone(),
+        expires: content.expires(published),
+    }
+}
+
+fn render_history(
+    entries: &[PublishedMessage],
+    username: &str,
+    my_keys: &KeyData,
+    shared: &Shared,
+) {
+    if entries.is_empty() {
+        println!("--- No earlier history ---");
+        return;
+    }
+
+    let now = utils::unix_millis();
+    let decrypted: Vec<(&PublishedMessage, MessageContent)> = entries
+        .iter()
+        .filter_map(|entry| Some((entry, decrypt_message(entry, my_keys)?)))
+        .filter(|(entry, content)| content.expires(entry).is_none_or(|expires| expires > now))
+        .collect();
+    let mut tabs = shared.tabs.lock().unwrap();
+    let mut scratch = Threads::default();
+    let threads = match tabs.get_mut(&entries[0].room) {
+        Some(tab) => &mut tab.threads,
+        None => &mut scratch,
+    };
+    // Messages are remembered and their reactions tallied before any are
+    // shown, so each comes with the reactions in this page.
+    for (entry, content) in &decrypted {
+        if content.reaction.is_none() {
+            threads.record(Posted::new(entry, content.clone()));
+        }
+    }
+    for (entry, content) in &decrypted {
+        if let Some(reaction) = &content.reaction {
+            threads.react(entry.sender.as_str(), reaction, entry.timestamp);
+        }
+    }
+
+    println!("--- History of #{} ---", entries[0].room);
+    for (entry, content) in decrypted {
+        if content.reaction.is_some() {
+            continue;
+        }
+        if let Some(posted) = threads.get(entry.id) {
+            print_message(posted, "", threads);
+        }
+        archive_message(&shared.archive, to_archived(entry, &content, username));
+        if let Some(expires) = content.expires(entry) {
+            expire_later(entry.room.clone(), entry.id, expires, shared);
+        }
+    }
+    println!("--- End of history ---");
+}
+
+fn render_search_results(results: &[ArchivedMessage]) {
+    if results.is_empty() {
+        println!("--- No matches ---");
+        return;
+    }
+
+    println!("--- Search results ---");
+    for message in results {
+        let sender = match message.direction {
+            Direction::Sent => "me",
+            Direction::Received => message.sender.as_deref().unwrap_or("?"),
         };
+        let verification = match message.verification {
+            Verification::Verified => "",
+            Verification::Unverified => " (unverified)",
+            Verification::Failed => " (VERIFICATION FAILED)",
+        };
+        println!(
+            "[{}] #{} {}: {}{}",
+            utils::format_timestamp(message.timestamp),
+            message.room,
+            sender,
+            message.body,
+            verification
+        );
+    }
+    println!("--- End of results ---");
+}
+
+fn describe_presence(presence: &Presence) -> String {
+    match &presence.status {
+        Some(status) => format!("{} is {}: {}", presence.user, presence.state, status),
+        None => format!("{} is {}", presence.user, presence.state),
+    }
+}
 
-        let decrypted = decrypt(data.into(), &my_keys.private, &my_keys.passphrase);
-        if let Ok(output) = str::from_utf8(&decrypted) {
-            println!("{}", output)
+fn render_members(members: &[Presence], typing: &HashMap<UserId, Instant>) {
+    println!("--- Members ---");
+    for member in members {
+        let is_typing = typing
+            .get(&member.user)
+            .is_some_and(|at| at.elapsed() < TYPING_DISPLAY);
+        let suffix = if is_typing { " (typing…)" } else { "" };
+        println!("{}{}", describe_presence(member), suffix);
+    }
+    println!("--- End of members ---");
+}
+
+fn render_roles(roles: &[RoleAssignment]) {
+    println!("--- Roles ---");
+    for RoleAssignment { user, role } in roles {
+        println!("{} ({})", user, role);
+    }
+    println!("--- End of roles ---");
+}
+
+fn render_rooms(rooms: &[RoomInfo], tabs: &Tabs) {
+    println!("--- Rooms ---");
+    for room in rooms {
+        let mut flags = vec![format!("{}/{}", room.members, room.capacity)];
+        if room.visibility != Visibility::Public {
+            flags.push(room.visibility.to_string());
+        }
+        if room.password {
+            flags.push("password".to_string());
+        }
+        if let Some(ttl) = room.message_ttl_secs {
+            flags.push(format!("messages last {}", utils::format_duration(ttl)));
+        }
+        if tabs.is_active(&room.name) {
+            flags.push("here".to_string());
+        } else if tabs.get(&room.name).is_some() {
+            flags.push("joined".to_string());
         }
+        let topic = room.topic.as_deref().map(|t| format!(": {}", t));
+        println!(
+            "#{} ({}){}",
+            room.name,
+            flags.join(", "),
+            topic.unwrap_or_default()
+        );
+
+        if let Some(description) = &room.description {
+            println!("    {}", description);
+        }
+        let owners: Vec<&str> = room.owners.iter().map(|o| o.as_str()).collect();
+        let owned = match owners.is_empty() {
+            true => String::new(),
+            false => format!(" by {}", owners.join(", ")),
+        };
+        println!(
+            "    created {}{}",
+            utils::format_timestamp(room.created),
+            owned
+        );
     }
+    println!("--- End of rooms ---");
 }
 
-async fn stdin_listener<T>(mut socket_writer: T, keys_mutex: LockedRoomKeys)
-where
-    T: AsyncWriteExt + Send + Unpin + 'static,
-{
+fn render_invitations(invitations: &[Invitation]) {
+    if invitations.is_empty() {
+        println!("No invitations.");
+    }
+    for Invitation { room, from } in invitations {
+        println!("#{} (from {})", room, from);
+    }
+}
+
+// Enough of a device id to tell devices apart, and to revoke one with.
+fn short_id(id: &str) -> &str {
+    &id[..id.len().min(12)]
+}
+
+// The id of the device `keys` belong to.
+fn keys_id(keys: &KeyData) -> String {
+    fingerprint(&keys.public)
+}
+
+fn render_devices(devices: &[DeviceInfo], current: &str) {
+    println!("--- Devices ---");
+    for device in devices {
+        let state = match (device.id == current, device.online) {
+            (true, _) => "this device".to_string(),
+            (false, true) => "online".to_string(),
+            (false, false) => format!("last seen {}", utils::format_timestamp(device.last_seen)),
+        };
+        println!("{} {} ({})", short_id(&device.id), device.name, state);
+    }
+    println!("--- End of devices ---");
+}
+
+fn render_tabs(tabs: &Tabs) {
+    for (room, held) in tabs.list() {
+        let marker = if tabs.is_active(room) { "*" } else { " " };
+        match held {
+            0 => println!("{} #{}", marker, room),
+            held => println!("{} #{} ({} new)", marker, room, held),
+        }
+    }
+}
+
+// Opens the tab for `room` and shows what arrived for it in the background.
+fn switch_tab(room: &ChatRoomId, shared: &Shared) {
+    let Some(held) = shared.tabs.lock().unwrap().activate(room) else {
+        eprintln!("You are not in #{}. Type /join {} to join it.", room, room);
+        return;
+    };
+    println!("--- #{} ---", room);
+    let mut receipts = shared.receipts.lock().unwrap();
+    for Held { line, id, unread } in held {
+        println!("{}", line);
+        if unread {
+            receipts.mark_shown(room.clone(), id);
+        }
+    }
+}
+
+fn render_receipts(receipts: &Receipts) {
+    println!("--- Sent messages ---");
+    for message in receipts.sent() {
+        println!("\"{}\" {}", message.preview, message.status());
+    }
+    println!("--- End of sent messages ---");
+}
+
+fn stdin_listener(
+    requests: mpsc::Sender<APIRequest>,
+    shared: Shared,
+    username: String,
+    keys: Arc<KeyData>,
+    downloads: PathBuf,
+) {
     /*  The StdIn Listener Process
      1. A blocking thread that listens to user input. The resulting user input
          is parsed, encrypted via the established chatserver keys, and
          sent to an async process which is responsible for writing the messages.
     */
-    let (thread_writer, thread_reader) = std::sync::mpsc::channel();
-
     thread::spawn(move || loop {
         let mut buf = String::new();
 
@@ -177,35 +1346,321 @@ where
             println!("Invalid Input");
             continue;
         };
-        // TODO: Buf parser into message to send or command.
-        {
-            let keys = keys_mutex.lock().unwrap();
+        let line = buf.trim_end();
 
-            let user_messages: Vec<Vec<u8>> = keys
-                .iter()
-                .map(|UserKey { user, public }| {
-                    let message = encrypt(&buf, public);
-                    serde_json::to_vec(&APIRequest::SendMessageRequest(user.to_string(), message))
-                        .expect("Failed to serialize message.")
-                })
-                .collect();
+        // Anything shown before the user interacted with the client has been seen.
+        let unread = shared.receipts.lock().unwrap().take_unread();
+        for (room, id) in unread {
+            let ack = APIRequest::AcknowledgeMessage {
+                room: room.to_string(),
+                id,
+                kind: ReceiptKind::Read,
+            };
+            // The connection task is gone, so the client is shutting down.
+            if requests.blocking_send(ack).is_err() {
+                return;
+            }
+        }
 
-            for message in user_messages {
-                thread_writer
-                    .send(message)
-                    .expect("Failure sending message");
+        // Room commands apply to the active tab. There is always one once
+        // logged in, since nobody can leave the waiting room.
+        let (room, history_cursor) = {
+            let tabs = shared.tabs.lock().unwrap();
+            let room = tabs.active().map(ChatRoomId::to_string).unwrap_or_default();
+            (room, tabs.current().and_then(|tab| tab.history_cursor))
+        };
+        let request = match commands::parse(line) {
+            Command::Empty => continue,
+            Command::RefreshKeys => APIRequest::RefreshRoomKeysRequest(room),
+            Command::History => APIRequest::FetchHistoryRequest {
+                room,
+                before: history_cursor,
+                limit: HISTORY_PAGE,
+            },
+            Command::Quit => {
+                shared.quitting.store(true, Ordering::SeqCst);
+                APIRequest::Logout
+            }
+            Command::Members => APIRequest::ListMembersRequest(room),
+            Command::SetRole(user, role) => APIRequest::SetRoleRequest { room, user, role },
+            Command::Roles => APIRequest::ListRolesRequest(room),
+            Command::Kick(user) => APIRequest::KickFromRoomRequest { room, user },
+            Command::Capacity(capacity) => APIRequest::SetCapacityRequest { room, capacity },
+            Command::DeleteRoom => APIRequest::DeleteRoomRequest(room),
+            Command::Rename(name) => APIRequest::RenameRoomRequest { room, name },
+            Command::Rooms => APIRequest::ListRoomsRequest,
+            Command::SetTopic(topic) => APIRequest::SetTopicRequest { room, topic },
+            Command::SetMessageTtl(ttl) => APIRequest::SetMessageTtlRequest {
+                room,
+                ttl_secs: ttl.map(|ttl| ttl.as_secs()),
+            },
+            Command::Join(room, password) => APIRequest::JoinRoomRequest { room, password },
+            Command::Leave(other) => APIRequest::LeaveRoomRequest(other.unwrap_or(room)),
+            Command::Tab(room) => {
+                switch_tab(&room.into(), &shared);
+                continue;
+            }
+            Command::Tabs => {
+                render_tabs(&shared.tabs.lock().unwrap());
+                continue;
+            }
+            Command::Invite(user) => APIRequest::InviteUserRequest { room, user },
+            Command::Invitations => APIRequest::ListInvitationsRequest,
+            Command::Devices => APIRequest::ListDevicesRequest,
+            Command::Revoke(device) => APIRequest::RevokeDeviceRequest(device),
+            Command::SetVisibility(visibility) => {
+                APIRequest::SetVisibilityRequest { room, visibility }
+            }
+            Command::SetPassword(password) => APIRequest::SetPasswordRequest { room, password },
+            Command::Presence(state, status) => APIRequest::SetPresenceRequest { state, status },
+            Command::SentStatus => {
+                render_receipts(&shared.receipts.lock().unwrap());
+                continue;
+            }
+            Command::ReadReceipts(enabled) => {
+                shared.receipts.lock().unwrap().send_read_receipts = enabled;
+                APIRequest::SetReadReceiptsRequest(enabled)
+            }
+            Command::Search(query) => {
+                match shared.archive.lock().unwrap().search(&query, SEARCH_LIMIT) {
+                    Ok(results) => render_search_results(&results),
+                    Err(e) => eprintln!("Search failed: {}", e),
+                }
+                continue;
+            }
+            Command::Unknown(command) => {
+                eprintln!("Unknown command: {}", command);
+                continue;
+            }
+            Command::Message(text) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let keys = tabs
+                    .current()
+                    .map(|tab| tab.keys.as_slice())
+                    .unwrap_or_default();
+                // One copy for every device of every member, ours included.
+                let copies = encrypt_copies(&MessageContent::text(text), keys);
+                APIRequest::SendMessageRequest {
+                    room,
+                    copies,
+                    ttl_secs: None,
+                }
+            }
+            Command::Ephemeral(ttl, text) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let keys = tabs
+                    .current()
+                    .map(|tab| tab.keys.as_slice())
+                    .unwrap_or_default();
+                // The server is told too, so it drops its copies in time.
+                let content = MessageContent {
+                    ttl_secs: Some(ttl.as_secs()),
+                    ..MessageContent::text(text)
+                };
+                APIRequest::SendMessageRequest {
+                    room,
+                    copies: encrypt_copies(&content, keys),
+                    ttl_secs: content.ttl_secs,
+                }
+            }
+            Command::Reply(id, text) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let Some(tab) = tabs.current() else { continue };
+                match tab.threads.find(&id) {
+                    Ok(parent) => {
+                        let content = MessageContent::reply(text, parent.id, &parent.content);
+                        let copies = encrypt_copies(&content, &tab.keys);
+                        APIRequest::SendMessageRequest {
+                            room,
+                            copies,
+                            ttl_secs: None,
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            Command::Edit(id, text) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let Some(tab) = tabs.current() else { continue };
+                let message = match own_message(&tab.threads, &id, &username) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                // Replies stay where they were in their thread.
+                let mut content = MessageContent {
+                    body: text,
+                    edit_signature: None,
+                    ..message.content.clone()
+                };
+                let signed = content.edit_data(&room.as_str().into(), message.id);
+                content.edit_signature = Some(sign(&signed, &keys.private, &keys.passphrase));
+                APIRequest::EditMessageRequest {
+                    id: message.id,
+                    copies: encrypt_copies(&content, &tab.keys),
+                    room,
+                }
+            }
+            Command::Delete(id) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let Some(tab) = tabs.current() else { continue };
+                let message = match own_message(&tab.threads, &id, &username) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                let signed = deletion_data(&room.as_str().into(), message.id);
+                APIRequest::DeleteMessageRequest {
+                    id: message.id,
+                    signature: sign(&signed, &keys.private, &keys.passphrase),
+                    room,
+                }
+            }
+            Command::Attach(path) => {
+                let path = Path::new(&path);
+                let file = match std::fs::read(path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        eprintln!("Could not read {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let name = path.file_name().map_or_else(
+                    || "attachment".to_string(),
+                    |name| name.to_string_lossy().into_owned(),
+                );
+                let size = utils::format_size(file.len() as u64);
+                let mut transfers = shared.transfers.lock().unwrap();
+                match transfers.upload(room.as_str().into(), name.clone(), &file) {
+                    Ok(request) => {
+                        println!("Uploading {} ({})…", name, size);
+                        request
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            Command::Download(id) => {
+                let attachment = {
+                    let tabs = shared.tabs.lock().unwrap();
+                    let Some(tab) = tabs.current() else { continue };
+                    match tab.threads.find(&id) {
+                        Ok(message) => message.content.attachment.clone(),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        }
+                    }
+                };
+                let Some(attachment) = attachment else {
+                    eprintln!("That message has no attachment.");
+                    continue;
+                };
+                if let Err(e) = std::fs::create_dir_all(&downloads) {
+                    eprintln!("Could not create {}: {}", downloads.display(), e);
+                    continue;
+                }
+                let path = transfers::download_path(&downloads, &attachment.name);
+                let name = attachment.name.clone();
+                match shared.transfers.lock().unwrap().download(attachment, path) {
+                    Ok(request) => {
+                        println!("Downloading {}…", name);
+                        request
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            Command::CancelTransfers => {
+                let mut transfers = shared.transfers.lock().unwrap();
+                let upload = transfers.cancel_upload();
+                let download = transfers.cancel_download();
+                for name in upload.iter().chain(&download) {
+                    println!("Cancelled the transfer of {}.", name);
+                }
+                if upload.is_none() && download.is_none() {
+                    println!("Nothing is being transferred.");
+                }
+                continue;
+            }
+            Command::React(id, emoji) => {
+                let tabs = shared.tabs.lock().unwrap();
+                let Some(tab) = tabs.current() else { continue };
+                let message = match tab.threads.find(&id) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                // Reacting with the same emoji again takes it back.
+                let reaction = Reaction {
+                    to: message.id,
+                    removed: message.reactions.has(&username, &emoji),
+                    emoji,
+                };
+                let copies = encrypt_copies(&MessageContent::reaction(reaction), &tab.keys);
+                APIRequest::SendMessageRequest {
+                    room,
+                    copies,
+                    ttl_secs: None,
+                }
+            }
+            Command::Thread(id) => {
+                let tabs = shared.tabs.lock().unwrap();
+                if let Some(tab) = tabs.current() {
+                    match tab.threads.find(&id) {
+                        Ok(message) => {
+                            let root = message.thread();
+                            render_thread(&tab.threads.thread(root), root, &tab.threads);
+                        }
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                continue;
             }
         };
+
+        if requests.blocking_send(request).is_err() {
+            return;
+        }
     });
+}
 
+/// Writes requests from the rest of the client to the socket, in order, until
+/// the socket fails or `stop` fires. Hands the queue back for the next
+/// connection.
+fn socket_writer<T>(
+    mut socket_writer: T,
+    mut requests: mpsc::Receiver<APIRequest>,
+    mut stop: oneshot::Receiver<()>,
+) -> JoinHandle<mpsc::Receiver<APIRequest>>
+where
+    T: AsyncWriteExt + Send + Unpin + 'static,
+{
     tokio::spawn(async move {
         loop {
-            let thread_message = thread_reader.recv().unwrap();
-
-            if socket_writer.write_all(&thread_message).await.is_err() {
-                eprintln!("Error writing message to socket: {:?}", thread_message);
+            let request = select! {
+                request = requests.recv() => request,
+                _ = &mut stop => break,
+            };
+            let Some(request) = request else { break };
+            if send_command(&mut socket_writer, &request).await.is_err() {
+                eprintln!("Error writing message to socket: {:?}", request);
                 break;
             }
         }
-    });
+        requests
+    })
 }
@@ -0,0 +1,142 @@
+use crate::utils;
+use slychat_common::types::{ChatRoomId, MessageId, Receipt, ReceiptKind};
+use std::collections::{BTreeSet, VecDeque};
+
+/// How many of our own messages keep their delivery status.
+const TRACKED_MESSAGES: usize = 100;
+
+pub struct SentMessage {
+    pub id: MessageId,
+    pub preview: String,
+    pub delivered: BTreeSet<String>,
+    pub read: BTreeSet<String>,
+}
+
+impl SentMessage {
+    pub fn status(&self) -> String {
+        // Everyone who read a message also received it.
+        let unread: Vec<&str> = self
+            .delivered
+            .difference(&self.read)
+            .map(String::as_str)
+            .collect();
+
+        let mut parts = Vec::new();
+        if !self.read.is_empty() {
+            let readers: Vec<&str> = self.read.iter().map(String::as_str).collect();
+            parts.push(format!("read by {}", readers.join(", ")));
+        }
+        if !unread.is_empty() {
+            parts.push(format!("delivered to {}", unread.join(", ")));
+        }
+        if parts.is_empty() {
+            "sent".to_string()
+        } else {
+            parts.join("; ")
+        }
+    }
+}
+
+/// Delivery state on both sides of a conversation: receipts collected for our
+/// own messages, and received messages we have yet to report as read.
+pub struct Receipts {
+    sent: VecDeque<SentMessage>,
+    unread: Vec<(ChatRoomId, MessageId)>,
+    pub send_read_receipts: bool,
+}
+
+impl Default for Receipts {
+    fn default() -> Self {
+        Self {
+            sent: VecDeque::new(),
+            unread: Vec::new(),
+            send_read_receipts: true,
+        }
+    }
+}
+
+impl Receipts {
+    pub fn track_sent(&mut self, id: MessageId, body: &str) {
+        if self.sent.len() == TRACKED_MESSAGES {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentMessage {
+            id,
+            preview: utils::preview(body),
+            delivered: BTreeSet::new(),
+            read: BTreeSet::new(),
+        });
+    }
+
+    /// Stops tracking a message that was deleted or disappeared, preview
+    /// and all.
+    pub fn forget(&mut self, id: MessageId) {
+        self.sent.retain(|m| m.id != id);
+    }
+
+    /// Applies a receipt to one of our messages, returning the message if it
+    /// is still tracked and the receipt changed its status. Each of a user's
+    /// devices acknowledges a message, so the same receipt may come in more
+    /// than once.
+    pub fn record(&mut self, kind: ReceiptKind, receipt: &Receipt) -> Option<&SentMessage> {
+        let message = self.sent.iter_mut().find(|m| m.id == receipt.id)?;
+        let user = receipt.user.to_string();
+
+        let mut changed = message.delivered.insert(user.clone());
+        if kind == ReceiptKind::Read {
+            changed |= message.read.insert(user);
+        }
+        changed.then_some(&*message)
+    }
+
+    pub fn sent(&self) -> impl Iterator<Item = &SentMessage> {
+        self.sent.iter()
+    }
+
+    pub fn mark_shown(&mut self, room: ChatRoomId, id: MessageId) {
+        if self.send_read_receipts {
+            self.unread.push((room, id));
+        }
+    }
+
+    /// Takes the messages shown since the user last interacted with the client.
+    pub fn take_unread(&mut self) -> Vec<(ChatRoomId, MessageId)> {
+        std::mem::take(&mut self.unread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(id: u64, user: &str) -> Receipt {
+        Receipt {
+            id: MessageId(id),
+            room: "waiting".into(),
+            user: user.into(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn status_follows_receipts() {
+        let mut receipts = Receipts::default();
+        receipts.track_sent(MessageId(1), "hello");
+        assert_eq!(receipts.sent().next().unwrap().status(), "sent");
+
+        receipts.record(ReceiptKind::Delivered, &receipt(1, "bob"));
+        receipts.record(ReceiptKind::Delivered, &receipt(1, "carol"));
+        let status = receipts
+            .record(ReceiptKind::Read, &receipt(1, "bob"))
+            .unwrap()
+            .status();
+        assert_eq!(status, "read by bob; delivered to carol");
+        assert!(receipts
+            .record(ReceiptKind::Delivered, &receipt(1, "carol"))
+            .is_none());
+
+        assert!(receipts
+            .record(ReceiptKind::Read, &receipt(2, "bob"))
+            .is_none());
+    }
+}
@@ -0,0 +1,74 @@
+use slychat_common::types::ChatRoomId;
+use std::collections::HashMap;
+
+/// Where a message falls relative to the latest one seen in its room.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sequencing {
+    /// The next message in the room, or the first one seen there.
+    InOrder,
+    /// `missing` messages between the latest one seen and this one never arrived.
+    Gap { missing: u64 },
+    /// Not newer than the latest message seen, e.g. a duplicate.
+    Stale,
+}
+
+/// Tracks the latest sequence number seen in each room so live messages
+/// that skip ahead can be detected and backfilled from history.
+#[derive(Default)]
+pub struct SequenceTracker {
+    latest: HashMap<ChatRoomId, u64>,
+}
+
+impl SequenceTracker {
+    pub fn observe(&mut self, room: &ChatRoomId, sequence: u64) -> Sequencing {
+        let sequencing = match self.latest.get(room) {
+            None => Sequencing::InOrder,
+            Some(&latest) if sequence <= latest => return Sequencing::Stale,
+            Some(&latest) if sequence == latest + 1 => Sequencing::InOrder,
+            Some(&latest) => Sequencing::Gap {
+                missing: sequence - latest - 1,
+            },
+        };
+
+        self.latest.insert(room.clone(), sequence);
+        sequencing
+    }
+
+    /// The latest sequence number seen in `room`.
+    pub fn latest(&self, room: &ChatRoomId) -> Option<u64> {
+        self.latest.get(room).copied()
+    }
+
+    /// Carries what was seen in room `from` over to its new name `to`.
+    pub fn rename(&mut self, from: &ChatRoomId, to: &ChatRoomId) {
+        if let Some(latest) = self.latest.remove(from) {
+            self.latest.insert(to.clone(), latest);
+        }
+    }
+
+    /// Records a message fetched from history. Fetched messages fill gaps
+    /// rather than open them, so this never reports one.
+    pub fn advance(&mut self, room: &ChatRoomId, sequence: u64) {
+        let latest = self.latest.entry(room.clone()).or_insert(sequence);
+        *latest = (*latest).max(sequence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_gaps_and_stale_messages() {
+        let mut tracker = SequenceTracker::default();
+        let room: ChatRoomId = "waiting".into();
+
+        assert_eq!(tracker.observe(&room, 4), Sequencing::InOrder);
+        assert_eq!(tracker.observe(&room, 5), Sequencing::InOrder);
+        assert_eq!(tracker.observe(&room, 8), Sequencing::Gap { missing: 2 });
+        assert_eq!(tracker.observe(&room, 6), Sequencing::Stale);
+
+        tracker.advance(&room, 3);
+        assert_eq!(tracker.observe(&room, 9), Sequencing::InOrder);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::threads::Threads;
+use slychat_common::types::{ChatRoomId, DeviceKey, MessageId};
+use std::collections::BTreeMap;
+
+/// A message that arrived for a tab while another one was open.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Held {
+    pub line: String,
+    pub id: MessageId,
+    /// Set for messages from others, which count as read once shown.
+    pub unread: bool,
+}
+
+/// What the client knows about one of the rooms it is in.
+#[derive(Default)]
+pub struct Tab {
+    /// The keys of every device in the room.
+    pub keys: Vec<DeviceKey>,
+    /// Sequence number of the oldest history entry shown so far, used to page
+    /// backwards.
+    pub history_cursor: Option<u64>,
+    /// The room's latest messages, for replies and threads.
+    pub threads: Threads,
+    held: Vec<Held>,
+}
+
+/// The rooms the client is in, one tab each. Messages for the active tab are
+/// shown as they arrive, those for the others are held until their tab is
+/// opened.
+#[derive(Default)]
+pub struct Tabs {
+    tabs: BTreeMap<ChatRoomId, Tab>,
+    active: Option<ChatRoomId>,
+}
+
+impl Tabs {
+    /// Matches the tabs to the rooms the server says we are in, keeping what
+    /// is known about rooms we are still in. The active tab stays open if its
+    /// room is among them, otherwise the first one is opened.
+    pub fn sync(&mut self, rooms: &[ChatRoomId]) {
+        self.tabs.retain(|room, _| rooms.contains(room));
+        for room in rooms {
+            self.tabs.entry(room.clone()).or_default();
+        }
+        if !self
+            .active
+            .as_ref()
+            .is_some_and(|a| self.tabs.contains_key(a))
+        {
+            self.active = self.tabs.keys().next().cloned();
+        }
+    }
+
+    /// Adds a tab for `room`, if there isn't one yet, and opens it. Returns
+    /// what was held for it.
+    pub fn open(&mut self, room: &ChatRoomId) -> Vec<Held> {
+        self.tabs.entry(room.clone()).or_default();
+        self.activate(room).unwrap_or_default()
+    }
+
+    /// Removes the tab for `room`. If it was open, the first remaining one is
+    /// opened instead and returned.
+    pub fn close(&mut self, room: &ChatRoomId) -> Option<ChatRoomId> {
+        self.tabs.remove(room);
+        if self.active.as_ref() != Some(room) {
+            return None;
+        }
+        self.active = self.tabs.keys().next().cloned();
+        self.active.clone()
+    }
+
+    /// Moves the tab for `from`, and everything held for it, to `to`.
+    pub fn rename(&mut self, from: &ChatRoomId, to: &ChatRoomId) {
+        if let Some(tab) = self.tabs.remove(from) {
+            self.tabs.insert(to.clone(), tab);
+        }
+        if self.active.as_ref() == Some(from) {
+            self.active = Some(to.clone());
+        }
+    }
+
+    /// Opens the tab for `room`, returning what was held for it, or `None`
+    /// if there is no such tab.
+    pub fn activate(&mut self, room: &ChatRoomId) -> Option<Vec<Held>> {
+        let tab = self.tabs.get_mut(room)?;
+        self.active = Some(room.clone());
+        Some(std::mem::take(&mut tab.held))
+    }
+
+    pub fn active(&self) -> Option<&ChatRoomId> {
+        self.active.as_ref()
+    }
+
+    pub fn is_active(&self, room: &ChatRoomId) -> bool {
+        self.active.as_ref() == Some(room)
+    }
+
+    pub fn get(&self, room: &ChatRoomId) -> Option<&Tab> {
+        self.tabs.get(room)
+    }
+
+    pub fn get_mut(&mut self, room: &ChatRoomId) -> Option<&mut Tab> {
+        self.tabs.get_mut(room)
+    }
+
+    /// The open tab, if there is one.
+    pub fn current(&self) -> Option<&Tab> {
+        self.tabs.get(self.active.as_ref()?)
+    }
+
+    /// Holds `message` until `room`'s tab is opened, in place of the held
+    /// message with the same id if there is one. Returns how many messages
+    /// are now held for it, or `None` if there is no such tab.
+    pub fn hold(&mut self, room: &ChatRoomId, message: Held) -> Option<usize> {
+        let tab = self.tabs.get_mut(room)?;
+        match tab.held.iter_mut().find(|held| held.id == message.id) {
+            Some(held) => held.line = message.line,
+            None => tab.held.push(message),
+        }
+        Some(tab.held.len())
+    }
+
+    /// Drops message `id` from what is held for `room`.
+    pub fn discard(&mut self, room: &ChatRoomId, id: MessageId) {
+        if let Some(tab) = self.tabs.get_mut(room) {
+            tab.held.retain(|held| held.id != id);
+        }
+    }
+
+    /// Every tab with the number of messages held for it, sorted by room.
+    pub fn list(&self) -> Vec<(&ChatRoomId, usize)> {
+        self.tabs
+            .iter()
+            .map(|(room, tab)| (room, tab.held.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(id: u64, line: &str) -> Held {
+        Held {
+            line: line.to_string(),
+            id: MessageId(id),
+            unread: true,
+        }
+    }
+
+    #[test]
+    fn background_tabs_hold_messages_until_opened() {
+        let (waiting, ops) = (ChatRoomId::from("waiting"), ChatRoomId::from("ops"));
+        let mut tabs = Tabs::default();
+        tabs.sync(std::slice::from_ref(&waiting));
+        assert!(tabs.is_active(&waiting));
+
+        assert!(tabs.open(&ops).is_empty());
+        assert_eq!(tabs.hold(&waiting, held(1, "hi")), Some(1));
+        assert_eq!(tabs.hold(&waiting, held(2, "anyone?")), Some(2));
+        assert_eq!(
+            tabs.hold(&waiting, held(3, "my password is hunter2")),
+            Some(3)
+        );
+        assert_eq!(tabs.hold(&"lobby".into(), held(4, "lost")), None);
+        // Edits replace what is held, deletions drop it.
+        assert_eq!(tabs.hold(&waiting, held(1, "hi all")), Some(3));
+        tabs.discard(&waiting, MessageId(3));
+        assert_eq!(tabs.list(), vec![(&ops, 0), (&waiting, 2)]);
+
+        assert_eq!(
+            tabs.activate(&waiting),
+            Some(vec![held(1, "hi all"), held(2, "anyone?")])
+        );
+        assert_eq!(tabs.list(), vec![(&ops, 0), (&waiting, 0)]);
+
+        let incidents = ChatRoomId::from("incidents");
+        tabs.hold(&ops, held(5, "paging"));
+        tabs.rename(&ops, &incidents);
+        assert_eq!(tabs.list(), vec![(&incidents, 1), (&waiting, 0)]);
+        assert!(tabs.is_active(&waiting));
+        let ops = incidents;
+
+        assert_eq!(tabs.close(&ops), None);
+        assert_eq!(tabs.close(&waiting), None);
+        tabs.sync(&[ops.clone(), waiting.clone()]);
+        assert!(tabs.is_active(&ops));
+        assert_eq!(tabs.close(&ops), Some(waiting));
+    }
+}
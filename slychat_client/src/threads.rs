@@ -0,0 +1,252 @@
+use slychat_common::types::{MessageContent, MessageId, PublishedMessage, Reaction, UserId};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+
+/// How many messages of a room can be replied to or shown as part of a thread.
+const REMEMBERED_MESSAGES: usize = 500;
+/// How much of a message id is shown, and enough to reply to it.
+const SHORT_ID_LEN: usize = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ThreadError {
+    NotFound(String),
+    // More than one remembered message matches the prefix given.
+    Ambiguous(String),
+}
+
+impl Display for ThreadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "No recent message matches {}.", id),
+            Self::Ambiguous(id) => write!(f, "More than one message matches {}.", id),
+        }
+    }
+}
+
+impl std::error::Error for ThreadError {}
+
+/// Who reacted to a message, and with what.
+#[derive(Debug, Clone, Default)]
+pub struct Reactions {
+    // By emoji and then user: the timestamp of their latest reaction with
+    // it, and whether it added the emoji.
+    latest: BTreeMap<String, BTreeMap<String, (u64, bool)>>,
+}
+
+impl Reactions {
+    /// Applies `user`'s reaction, sent at `timestamp`. Anything older than
+    /// what is known for the user and emoji, e.g. when fetched again with
+    /// history, is ignored.
+    pub fn apply(&mut self, user: &str, reaction: &Reaction, timestamp: u64) {
+        let users = self.latest.entry(reaction.emoji.clone()).or_default();
+        let latest = users.entry(user.to_string()).or_insert((timestamp, false));
+        if latest.0 <= timestamp {
+            *latest = (timestamp, !reaction.removed);
+        }
+    }
+
+    pub fn has(&self, user: &str, emoji: &str) -> bool {
+        self.latest
+            .get(emoji)
+            .and_then(|users| users.get(user))
+            .is_some_and(|(_, added)| *added)
+    }
+
+    /// Each emoji still on the message with the users who added it.
+    pub fn summary(&self) -> Vec<(&str, Vec<&str>)> {
+        self.latest
+            .iter()
+            .map(|(emoji, users)| {
+                let added: Vec<&str> = users
+                    .iter()
+                    .filter(|(_, (_, added))| *added)
+                    .map(|(user, _)| user.as_str())
+                    .collect();
+                (emoji.as_str(), added)
+            })
+            .filter(|(_, users)| !users.is_empty())
+            .collect()
+    }
+}
+
+/// A decrypted message of a room.
+#[derive(Debug, Clone)]
+pub struct Posted {
+    pub id: MessageId,
+    pub sender: UserId,
+    pub timestamp: u64,
+    pub content: MessageContent,
+    pub reactions: Reactions,
+    /// When the message disappears, if it does.
+    pub expires: Option<u64>,
+}
+
+impl Posted {
+    pub fn new(published: &PublishedMessage, content: MessageContent) -> Self {
+        Self {
+            id: published.id,
+            sender: published.sender.clone(),
+            timestamp: published.timestamp,
+            expires: content.expires(published),
+            content,
+            reactions: Reactions::default(),
+        }
+    }
+
+    /// The thread a reply to this message belongs to.
+    pub fn thread(&self) -> MessageId {
+        self.content.thread.unwrap_or(self.id)
+    }
+}
+
+/// The beginning of a message id, as shown next to messages.
+pub fn short_id(id: MessageId) -> String {
+    id.to_string()[..SHORT_ID_LEN].to_string()
+}
+
+/// The latest messages of a room, so replies can name the message they answer
+/// and threads can be shown on their own.
+#[derive(Default)]
+pub struct Threads {
+    messages: VecDeque<Posted>,
+}
+
+impl Threads {
+    /// Remembers a message. One already known, e.g. when fetched again with
+    /// history or edited, has its content replaced.
+    pub fn record(&mut self, posted: Posted) {
+        if let Some(known) = self.messages.iter_mut().find(|m| m.id == posted.id) {
+            known.content = posted.content;
+            return;
+        }
+        if self.messages.len() == REMEMBERED_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(posted);
+    }
+
+    /// Applies a reaction `sender` sent at `timestamp`. Returns the message
+    /// reacted to, or `None` if it isn't remembered.
+    pub fn react(&mut self, sender: &str, reaction: &Reaction, timestamp: u64) -> Option<&Posted> {
+        let posted = self.messages.iter_mut().find(|m| m.id == reaction.to)?;
+        posted.reactions.apply(sender, reaction, timestamp);
+        Some(posted)
+    }
+
+    /// Forgets message `id`, after it was deleted.
+    pub fn remove(&mut self, id: MessageId) {
+        self.messages.retain(|m| m.id != id);
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<&Posted> {
+        self.messages.iter().find(|m| m.id == id)
+    }
+
+    /// Finds the message whose id starts with `prefix`.
+    pub fn find(&self, prefix: &str) -> Result<&Posted, ThreadError> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut matching = self
+            .messages
+            .iter()
+            .filter(|m| m.id.to_string().starts_with(&prefix));
+        match (matching.next(), matching.next()) {
+            (Some(posted), None) => Ok(posted),
+            (Some(_), Some(_)) => Err(ThreadError::Ambiguous(prefix)),
+            (None, _) => Err(ThreadError::NotFound(prefix)),
+        }
+    }
+
+    /// The message that started thread `root`, if it is still remembered,
+    /// followed by the replies in it, oldest first.
+    pub fn thread(&self, root: MessageId) -> Vec<&Posted> {
+        let mut thread: Vec<&Posted> = self
+            .messages
+            .iter()
+            .filter(|m| m.id == root || m.content.thread == Some(root))
+            .collect();
+        thread.sort_by_key(|m| (m.id != root, m.timestamp));
+        thread
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn posted(id: u64, timestamp: u64, content: MessageContent) -> Posted {
+        Posted {
+            id: MessageId(id),
+            sender: "alice".into(),
+            timestamp,
+            content,
+            reactions: Reactions::default(),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn replies_join_the_thread_of_their_parent() {
+        let mut threads = Threads::default();
+        let root = posted(0xab01 << 48, 1, MessageContent::text("disk full".into()));
+        let reply = MessageContent::reply("on it".into(), root.id, &root.content);
+        let reply = posted(0xab02 << 48, 3, reply);
+        // Answering the reply stays in the thread it started from.
+        let nested = MessageContent::reply("cleaned up".into(), reply.id, &reply.content);
+        assert_eq!(nested.thread, Some(root.id));
+        threads.record(posted(
+            0xcd01 << 48,
+            2,
+            MessageContent::text("lunch?".into()),
+        ));
+        threads.record(posted(0xab03 << 48, 4, nested));
+        threads.record(reply);
+        threads.record(root.clone());
+
+        let ids: Vec<MessageId> = threads.thread(root.id).iter().map(|m| m.id).collect();
+        assert_eq!(
+            ids,
+            vec![
+                MessageId(0xab01 << 48),
+                MessageId(0xab02 << 48),
+                MessageId(0xab03 << 48)
+            ]
+        );
+
+        assert_eq!(threads.find("AB01").unwrap().id, root.id);
+        assert!(matches!(
+            threads.find("ab0"),
+            Err(ThreadError::Ambiguous(_))
+        ));
+        assert!(matches!(threads.find("ff"), Err(ThreadError::NotFound(_))));
+        assert_eq!(short_id(root.id), "ab0100");
+    }
+
+    #[test]
+    fn reactions_are_tallied_by_emoji_and_latest_wins() {
+        let mut threads = Threads::default();
+        threads.record(posted(1, 1, MessageContent::text("deployed".into())));
+        let reaction = |emoji: &str, removed| Reaction {
+            to: MessageId(1),
+            emoji: emoji.to_string(),
+            removed,
+        };
+
+        threads.react("bob", &reaction("🎉", false), 2);
+        threads.react("carol", &reaction("🎉", false), 3);
+        threads.react("carol", &reaction("👀", false), 4);
+        threads.react("bob", &reaction("🎉", true), 5);
+        // Bob's reaction comes in again with older history.
+        let posted = threads.react("bob", &reaction("🎉", false), 2).unwrap();
+        assert_eq!(
+            posted.reactions.summary(),
+            vec![("🎉", vec!["carol"]), ("👀", vec!["carol"])]
+        );
+        assert!(!posted.reactions.has("bob", "🎉"));
+
+        let elsewhere = Reaction {
+            to: MessageId(2),
+            ..reaction("🎉", false)
+        };
+        assert!(threads.react("bob", &elsewhere, 6).is_none());
+    }
+}
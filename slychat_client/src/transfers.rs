@@ -0,0 +1,352 @@
+use openssl::sha::sha256;
+use slychat_common::encryption::{open, seal, symmetric_key, SEAL_OVERHEAD};
+use slychat_common::types::{
+    APIRequest, Attachment, BlobChunk, BlobId, BlobProgress, ChatRoomId, MAX_BLOB_CHUNK,
+};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// How much of a file goes into each sealed chunk, so every chunk fills a
+/// request to the server.
+const FILE_CHUNK: usize = MAX_BLOB_CHUNK - SEAL_OVERHEAD;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransferError {
+    // Only one upload and one download run at a time.
+    Busy,
+    Empty(String),
+    // A chunk didn't decrypt, or the whole file doesn't match its digest.
+    Corrupt(String),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Busy => write!(f, "Wait for the transfer in progress to finish."),
+            Self::Empty(name) => write!(f, "{} is empty.", name),
+            Self::Corrupt(name) => write!(f, "{} was damaged or tampered with.", name),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// What to do after the server took a chunk.
+#[derive(Debug)]
+pub enum Uploaded {
+    Next(APIRequest),
+    /// The upload is complete and can be shared in the room.
+    Done(ChatRoomId, Attachment),
+}
+
+/// What to do after the server sent a chunk.
+#[derive(Debug)]
+pub enum Downloaded {
+    Next(APIRequest),
+    /// The whole file, checked against its digest, to save at the path.
+    Done(PathBuf, Vec<u8>),
+}
+
+// A file sealed in full up front, then sent chunk by chunk.
+struct Upload {
+    room: ChatRoomId,
+    name: String,
+    size: u64,
+    key: Vec<u8>,
+    sha256: Vec<u8>,
+    sealed: Vec<u8>,
+    blob: Option<BlobId>,
+    // How much the server said it holds.
+    received: u64,
+}
+
+impl Upload {
+    fn next_chunk(&self, blob: BlobId) -> APIRequest {
+        let start = self.received as usize;
+        let end = (start + MAX_BLOB_CHUNK).min(self.sealed.len());
+        APIRequest::UploadChunkRequest {
+            blob,
+            offset: self.received,
+            data: self.sealed[start..end].to_vec(),
+        }
+    }
+}
+
+// A file fetched chunk by chunk, and only written out once it checks out.
+struct Download {
+    attachment: Attachment,
+    path: PathBuf,
+    file: Vec<u8>,
+    // How much of the blob was fetched.
+    offset: u64,
+}
+
+impl Download {
+    fn next_chunk(&self) -> APIRequest {
+        APIRequest::DownloadChunkRequest {
+            blob: self.attachment.blob,
+            offset: self.offset,
+            length: MAX_BLOB_CHUNK as u64,
+        }
+    }
+}
+
+/// The attachment being uploaded and the one being downloaded. Chunks are
+/// requested one at a time as the previous one is answered, and answers
+/// that don't move a transfer forward, e.g. to a chunk requested again
+/// after a reconnect, are ignored.
+#[derive(Default)]
+pub struct Transfers {
+    upload: Option<Upload>,
+    download: Option<Download>,
+}
+
+impl Transfers {
+    /// Seals `file` with a fresh key, to share in `room` as `name`. Returns
+    /// the request that starts the upload.
+    pub fn upload(
+        &mut self,
+        room: ChatRoomId,
+        name: String,
+        file: &[u8],
+    ) -> Result<APIRequest, TransferError> {
+        if self.upload.is_some() {
+            return Err(TransferError::Busy);
+        }
+        if file.is_empty() {
+            return Err(TransferError::Empty(name));
+        }
+        let key = symmetric_key();
+        let sealed: Vec<u8> = file
+            .chunks(FILE_CHUNK)
+            .flat_map(|chunk| seal(chunk, &key))
+            .collect();
+        let request = APIRequest::CreateBlobRequest {
+            room: room.to_string(),
+            size: sealed.len() as u64,
+        };
+        self.upload = Some(Upload {
+            room,
+            name,
+            size: file.len() as u64,
+            key,
+            sha256: sha256(file).to_vec(),
+            sealed,
+            blob: None,
+            received: 0,
+        });
+        Ok(request)
+    }
+
+    /// Handles the server's answer to `CreateBlobRequest` or
+    /// `UploadChunkRequest`.
+    pub fn uploaded(&mut self, progress: BlobProgress) -> Option<Uploaded> {
+        let upload = self.upload.as_mut()?;
+        match upload.blob {
+            None => upload.blob = Some(progress.blob),
+            Some(blob) if blob != progress.blob || progress.received <= upload.received => {
+                return None
+            }
+            Some(_) => {}
+        }
+        upload.received = progress.received;
+        if progress.received < progress.size {
+            return Some(Uploaded::Next(upload.next_chunk(progress.blob)));
+        }
+        let upload = self.upload.take()?;
+        let attachment = Attachment {
+            blob: progress.blob,
+            name: upload.name,
+            size: upload.size,
+            key: upload.key,
+            sha256: upload.sha256,
+        };
+        Some(Uploaded::Done(upload.room, attachment))
+    }
+
+    /// Gives up on the upload. Returns the name of the file if there was one.
+    pub fn cancel_upload(&mut self) -> Option<String> {
+        self.upload.take().map(|upload| upload.name)
+    }
+
+    /// Starts fetching `attachment`, to save it at `path`.
+    pub fn download(
+        &mut self,
+        attachment: Attachment,
+        path: PathBuf,
+    ) -> Result<APIRequest, TransferError> {
+        if self.download.is_some() {
+            return Err(TransferError::Busy);
+        }
+        let download = Download {
+            attachment,
+            path,
+            file: Vec::new(),
+            offset: 0,
+        };
+        let request = download.next_chunk();
+        self.download = Some(download);
+        Ok(request)
+    }
+
+    /// Handles a chunk sent in answer to `DownloadChunkRequest`. The download
+    /// is given up on if the chunk turns out to be damaged.
+    pub fn downloaded(&mut self, chunk: BlobChunk) -> Result<Option<Downloaded>, TransferError> {
+        let Some(download) = self.download.as_mut() else {
+            return Ok(None);
+        };
+        if chunk.blob != download.attachment.blob || chunk.offset != download.offset {
+            return Ok(None);
+        }
+        let name = download.attachment.name.clone();
+        let Some(opened) = open(&chunk.data, &download.attachment.key) else {
+            self.download = None;
+            return Err(TransferError::Corrupt(name));
+        };
+        download.file.extend(opened);
+        download.offset += chunk.data.len() as u64;
+        if download.offset < chunk.size {
+            return Ok(Some(Downloaded::Next(download.next_chunk())));
+        }
+        let download = self.download.take().unwrap();
+        let attachment = download.attachment;
+        if download.file.len() as u64 != attachment.size
+            || sha256(&download.file).as_slice() != attachment.sha256
+        {
+            return Err(TransferError::Corrupt(name));
+        }
+        Ok(Some(Downloaded::Done(download.path, download.file)))
+    }
+
+    /// Gives up on the download. Returns the name of the file if there was
+    /// one.
+    pub fn cancel_download(&mut self) -> Option<String> {
+        self.download
+            .take()
+            .map(|download| download.attachment.name)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.upload.is_some() || self.download.is_some()
+    }
+
+    /// Requests the next chunk of each transfer again, for when the request
+    /// may have been lost with the connection or to rate limiting. The server
+    /// says how far an upload got, so it resumes from there.
+    pub fn resume(&self) -> Vec<APIRequest> {
+        let upload = self.upload.as_ref().map(|upload| match upload.blob {
+            Some(blob) => upload.next_chunk(blob),
+            // Blobs are only named once created, so one whose creation went
+            // unanswered is started over.
+            None => APIRequest::CreateBlobRequest {
+                room: upload.room.to_string(),
+                size: upload.sealed.len() as u64,
+            },
+        });
+        let download = self.download.as_ref().map(Download::next_chunk);
+        upload.into_iter().chain(download).collect()
+    }
+}
+
+/// Where to save a download called `name` in `dir`: under the last part of
+/// the name, so it can't point elsewhere, and numbered if the file exists.
+pub fn download_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "attachment".to_string());
+    let mut path = dir.join(&name);
+    let mut copy = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({})", name, copy));
+        copy += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_survive_the_round_trip_and_tampering_is_caught() {
+        let file: Vec<u8> = (0..FILE_CHUNK * 2 + 10).map(|i| i as u8).collect();
+        let mut sender = Transfers::default();
+        let Ok(APIRequest::CreateBlobRequest { size, .. }) =
+            sender.upload("ops".into(), "core.log".to_string(), &file)
+        else {
+            panic!("Upload did not start");
+        };
+        assert_eq!(
+            sender
+                .upload("ops".into(), "x".to_string(), b"x")
+                .unwrap_err(),
+            TransferError::Busy
+        );
+
+        // Plays the server, holding what was uploaded.
+        let blob = BlobId(7);
+        let mut stored = Vec::new();
+        let mut progress = BlobProgress {
+            blob,
+            received: 0,
+            size,
+        };
+        let attachment = loop {
+            match sender.uploaded(progress).unwrap() {
+                Uploaded::Next(APIRequest::UploadChunkRequest { offset, data, .. }) => {
+                    assert_eq!(offset, stored.len() as u64);
+                    stored.extend(data);
+                    // The same answer twice only moves the upload once.
+                    assert!(sender.uploaded(progress).is_none());
+                    progress.received = stored.len() as u64;
+                }
+                Uploaded::Done(room, attachment) => {
+                    assert_eq!(room, ChatRoomId::from("ops"));
+                    break attachment;
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        };
+        assert_eq!(stored.len() as u64, size);
+
+        let chunk = |offset: u64, stored: &[u8]| {
+            let end = (offset as usize + MAX_BLOB_CHUNK).min(stored.len());
+            BlobChunk {
+                blob,
+                offset,
+                size,
+                data: stored[offset as usize..end].to_vec(),
+            }
+        };
+        let mut receiver = Transfers::default();
+        let path = PathBuf::from("core.log");
+        receiver.download(attachment.clone(), path.clone()).unwrap();
+        let mut offset = 0;
+        let saved = loop {
+            match receiver
+                .downloaded(chunk(offset, &stored))
+                .unwrap()
+                .unwrap()
+            {
+                Downloaded::Next(APIRequest::DownloadChunkRequest { offset: next, .. }) => {
+                    offset = next
+                }
+                Downloaded::Done(saved_at, saved) => {
+                    assert_eq!(saved_at, path);
+                    break saved;
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        };
+        assert_eq!(saved, file);
+
+        stored[5] ^= 1;
+        receiver.download(attachment, path).unwrap();
+        assert_eq!(
+            receiver.downloaded(chunk(0, &stored)).unwrap_err(),
+            TransferError::Corrupt("core.log".to_string())
+        );
+        assert!(receiver.resume().is_empty());
+    }
+}
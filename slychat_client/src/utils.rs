@@ -0,0 +1,131 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PREVIEW_CHARS: usize = 24;
+
+/// Directory holding the client's keystore unless configured otherwise, `~/.slychat`.
+pub fn default_data_dir() -> PathBuf {
+    let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".slychat")
+}
+
+/// Name of this device unless configured otherwise: the host name.
+pub fn default_device_name() -> String {
+    let hostname = env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok());
+    hostname
+        // The server takes names of up to 32 characters.
+        .map(|name| name.trim().chars().take(32).collect::<String>())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// The user's identity key, which every one of their devices is signed with.
+pub fn keystore_path(data_dir: &Path, username: &str) -> PathBuf {
+    data_dir.join("keys").join(format!("{}.pem", username))
+}
+
+/// The key of this device, which messages to the user here are encrypted for.
+pub fn device_key_path(data_dir: &Path, username: &str) -> PathBuf {
+    data_dir
+        .join("keys")
+        .join(format!("{}.device.pem", username))
+}
+
+pub fn archive_path(data_dir: &Path, username: &str) -> PathBuf {
+    data_dir.join("archive").join(format!("{}.db", username))
+}
+
+/// Where downloaded attachments are saved.
+pub fn downloads_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("downloads")
+}
+
+/// A size in bytes the way people read it, e.g. `1.5 MB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Parses a duration like `90s`, `10m`, `2h` or `7d`. A bare number is in
+/// seconds.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => text.split_at(at),
+        None => (text, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(scale)?;
+    Some(Duration::from_secs(secs))
+}
+
+/// The largest whole unit a duration in seconds fits, e.g. `2h`, the way
+/// `parse_duration` reads it.
+pub fn format_duration(secs: u64) -> String {
+    let units = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")];
+    match units
+        .iter()
+        .find(|(scale, _)| secs >= *scale && secs.is_multiple_of(*scale))
+    {
+        Some((scale, unit)) => format!("{}{}", secs / scale, unit),
+        None => format!("{}s", secs),
+    }
+}
+
+/// Milliseconds since the unix epoch, like the server's timestamps.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The beginning of a message body, to quote it by.
+pub fn preview(body: &str) -> String {
+    let mut preview: String = body.chars().take(PREVIEW_CHARS).collect();
+    if body.chars().count() > PREVIEW_CHARS {
+        preview.push('…');
+    }
+    preview
+}
+
+/// Formats milliseconds since the unix epoch as `YYYY-MM-DD HH:MM:SS` (UTC).
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (hour, minute, second) = (rem / 3600, (rem % 3600) / 60, rem % 60);
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    )
+}
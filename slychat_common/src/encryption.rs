@@ -1,5 +1,14 @@
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
 use openssl::rsa::{Padding, Rsa};
-use openssl::symm::Cipher;
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fs;
+use std::io;
+use std::path::Path;
 
 pub struct KeyData {
     pub public: Vec<u8>,
@@ -13,33 +22,169 @@ impl KeyData {
 
         KeyData {
             private: rsa
-                .private_key_to_pem_passphrase(Cipher::aes_128_cbc(), passphrase.into())
+                .private_key_to_pem_passphrase(Cipher::aes_128_cbc(), passphrase)
                 .unwrap(),
             public: rsa.public_key_to_pem().unwrap(),
             passphrase: passphrase.into(),
         }
     }
+
+    /// Loads a passphrase-protected private key written by `save`. The public
+    /// key is derived from the private key, so only one file is kept on disk.
+    pub fn load(path: &Path, passphrase: &[u8]) -> io::Result<Self> {
+        let private = fs::read(path)?;
+        let rsa = Rsa::private_key_from_pem_passphrase(&private, passphrase)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(KeyData {
+            public: rsa
+                .public_key_to_pem()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            private,
+            passphrase: passphrase.into(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, &self.private)
+    }
 }
 
-pub fn encrypt(message: &str, key: &Vec<u8>) -> Vec<u8> {
-    let rsa = Rsa::public_key_from_pem(key).expect("Could not create RSA from key.");
+/// Encrypts `message` for the holder of the private half of `key`. RSA only
+/// fits a few dozen bytes, so it encrypts a fresh message key and the message
+/// itself is sealed with that. The output is `encrypted key || sealed message`.
+/// Returns `None` if `key` is not a usable RSA public key.
+pub fn encrypt(message: &str, key: &[u8]) -> Option<Vec<u8>> {
+    let rsa = Rsa::public_key_from_pem(key).ok()?;
+    let message_key = random_bytes(SYMMETRIC_KEY_LEN);
     let mut buf: Vec<u8> = vec![0; rsa.size() as usize];
-    let _ = rsa
-        .public_encrypt(message.as_bytes(), &mut buf, Padding::PKCS1)
-        .unwrap();
-    buf
+    rsa.public_encrypt(&message_key, &mut buf, Padding::PKCS1)
+        .ok()?;
+    Some([buf, seal(message.as_bytes(), &message_key)].concat())
+}
+
+/// Reverses `encrypt` with a passphrase-protected private key. Returns
+/// `None` if the message was not encrypted for that key, or was truncated or
+/// tampered with.
+pub fn decrypt(encrypted: &[u8], key: &[u8], passphrase: &[u8]) -> Option<Vec<u8>> {
+    let rsa = Rsa::private_key_from_pem_passphrase(key, passphrase).ok()?;
+    let size = rsa.size() as usize;
+    if encrypted.len() < size {
+        return None;
+    }
+    let (wrapped, sealed) = encrypted.split_at(size);
+    let mut message_key: Vec<u8> = vec![0; size];
+    let n = rsa
+        .private_decrypt(wrapped, &mut message_key, Padding::PKCS1)
+        .ok()?;
+    message_key.truncate(n);
+    open(sealed, &message_key)
 }
 
-pub fn decrypt(encrypted: Vec<u8>, key: &Vec<u8>, passphrase: &Vec<u8>) -> Vec<u8> {
+/// Signs `data` with a passphrase-protected private key, using SHA-256.
+pub fn sign(data: &[u8], key: &[u8], passphrase: &[u8]) -> Vec<u8> {
     let rsa =
         Rsa::private_key_from_pem_passphrase(key, passphrase).expect("Could not generate key");
-    let mut buf: Vec<u8> = vec![0; rsa.size() as usize];
-    let _ = rsa
-        .private_decrypt(&encrypted, &mut buf, Padding::PKCS1)
-        .unwrap();
+    let pkey = PKey::from_rsa(rsa).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(data).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+/// Checks a signature made by `sign`. Malformed keys or signatures simply
+/// don't verify.
+pub fn verify(data: &[u8], signature: &[u8], key: &[u8]) -> bool {
+    let Ok(pkey) = Rsa::public_key_from_pem(key).and_then(PKey::from_rsa) else {
+        return false;
+    };
+    Verifier::new(MessageDigest::sha256(), &pkey)
+        .and_then(|mut verifier| {
+            verifier.update(data)?;
+            verifier.verify(signature)
+        })
+        .unwrap_or(false)
+}
+
+/// Identifies a public key: the hex encoded SHA-256 of its PEM encoding.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    sha256(public_key)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+const SYMMETRIC_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KDF_ITERATIONS: usize = 100_000;
+/// How much longer `seal` makes what it seals.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    rand_bytes(&mut buf).expect("Could not generate random bytes.");
     buf
 }
 
+/// A fresh random key for `seal`/`open`.
+pub fn symmetric_key() -> Vec<u8> {
+    random_bytes(SYMMETRIC_KEY_LEN)
+}
+
+/// Derives a key for `seal`/`open` from a passphrase with PBKDF2-HMAC-SHA256.
+pub fn derive_key(passphrase: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut key = vec![0; SYMMETRIC_KEY_LEN];
+    pbkdf2_hmac(
+        passphrase,
+        salt,
+        KDF_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .expect("Could not derive key.");
+    key
+}
+
+/// Encrypts with AES-256-GCM. The output is `nonce || ciphertext || tag`.
+pub fn seal(plaintext: &[u8], key: &[u8]) -> Vec<u8> {
+    let nonce = random_bytes(NONCE_LEN);
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        plaintext,
+        &mut tag,
+    )
+    .expect("Could not seal data.");
+
+    [nonce, ciphertext, tag.to_vec()].concat()
+}
+
+/// Reverses `seal`. Returns `None` if the data was not sealed with `key` or
+/// has been tampered with.
+pub fn open(sealed: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -51,13 +196,60 @@ mod tests {
         let initial_message = "test message";
 
         let key = KeyData::from_passphrase("test".as_bytes());
-        let encrypted = encrypt(initial_message, &key.public);
-        let decrypted_message = decrypt(encrypted, &key.private, &key.passphrase);
+        let encrypted = encrypt(initial_message, &key.public).unwrap();
+        let decrypted_message = decrypt(&encrypted, &key.private, &key.passphrase).unwrap();
 
         let str_decrypted_message = str::from_utf8(&decrypted_message)
             .expect("Failed to decrypt message.")
             .replace("\x00", "");
         // assert_ne!(initial_message, encrypted_message);
         assert_eq!(initial_message, str_decrypted_message);
+
+        // Far more than the RSA key could encrypt on its own.
+        let long_message = "incident update ".repeat(64);
+        let encrypted = encrypt(&long_message, &key.public).unwrap();
+        let decrypted_message = decrypt(&encrypted, &key.private, &key.passphrase);
+        assert_eq!(Some(long_message.into_bytes()), decrypted_message);
+
+        assert!(encrypt(initial_message, b"not a key").is_none());
+    }
+
+    #[test]
+    fn damaged_or_misdirected_messages_do_not_decrypt() {
+        let key = KeyData::from_passphrase(b"test");
+        let other = KeyData::from_passphrase(b"test");
+        let encrypted = encrypt("deploy key", &key.public).unwrap();
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, &key.private, &key.passphrase).is_none());
+        let mut wrapped = encrypted.clone();
+        wrapped[0] ^= 1;
+        assert!(decrypt(&wrapped, &key.private, &key.passphrase).is_none());
+        let truncated = &encrypted[..encrypted.len() / 2];
+        assert!(decrypt(truncated, &key.private, &key.passphrase).is_none());
+        assert!(decrypt(&[], &key.private, &key.passphrase).is_none());
+        assert!(decrypt(&encrypted, &other.private, &other.passphrase).is_none());
+    }
+
+    #[test]
+    fn signatures_only_verify_with_the_signing_key() {
+        let key = KeyData::from_passphrase(b"test");
+        let other = KeyData::from_passphrase(b"test");
+        let signature = sign(b"device key", &key.private, &key.passphrase);
+
+        assert!(verify(b"device key", &signature, &key.public));
+        assert!(!verify(b"device key", &signature, &other.public));
+        assert!(!verify(b"other key", &signature, &key.public));
+        assert!(!verify(b"device key", &signature, b"not a key"));
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let key = derive_key(b"passphrase", b"salt");
+        let sealed = seal(b"archived message", &key);
+
+        assert_eq!(open(&sealed, &key).unwrap(), b"archived message");
+        assert!(open(&sealed, &derive_key(b"wrong", b"salt")).is_none());
     }
 }
@@ -1,8 +1,7 @@
 pub mod encryption;
 pub mod transport;
 pub mod types;
+pub mod validation;
 
 #[cfg(test)]
-mod tests {
-    use super::*;
-}
+mod tests {}
@@ -5,12 +5,19 @@ use serde_json::json;
 use serde_json::Value;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_serde::formats::SymmetricalJson;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+/// Largest frame a `CommandReader` accepts unless told otherwise.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 1024 * 1024;
 
 #[derive(Debug, Clone)]
 pub enum TransportError {
     WriteError,
     ReadError(String),
+    /// The peer announced a frame longer than the reader's limit. The stream
+    /// can't be resynchronised after this, so the connection should be
+    /// closed.
+    FrameTooLarge(usize),
 }
 
 impl std::fmt::Display for TransportError {
@@ -18,6 +25,7 @@ impl std::fmt::Display for TransportError {
         match self {
             Self::WriteError => write!(f, "Invalid Write Transport Operation"),
             Self::ReadError(message) => write!(f, "Invalid Read Transport Operation. {}", message),
+            Self::FrameTooLarge(max) => write!(f, "Frame exceeds the limit of {} bytes", max),
         }
     }
 }
@@ -33,11 +41,13 @@ where
     let mut serialized =
         tokio_serde::SymmetricallyFramed::new(length_delimited, SymmetricalJson::default());
 
-    serialized.send(json!(command)).await.unwrap();
-    Ok(())
+    serialized
+        .send(json!(command))
+        .await
+        .map_err(|_| TransportError::WriteError)
 }
 
-pub async fn read_command<'de, R, C>(reader: &mut R) -> Result<C, TransportError>
+pub async fn read_command<R, C>(reader: &mut R) -> Result<C, TransportError>
 where
     R: AsyncReadExt + Send + Unpin + 'static,
     C: APICommand + DeserializeOwned,
@@ -59,3 +69,57 @@ where
         }
     }
 }
+
+/// A long-lived reader for a stream of commands.
+///
+/// Unlike `read_command`, the frame buffer lives as long as the reader, so bytes
+/// belonging to the next frame are never dropped and `read` is safe to use
+/// inside `select!`.
+pub struct CommandReader<R> {
+    frames: FramedRead<R, LengthDelimitedCodec>,
+    max_frame_length: usize,
+}
+
+impl<R> CommandReader<R>
+where
+    R: AsyncReadExt + Send + Unpin,
+{
+    pub fn new(reader: R) -> Self {
+        Self::with_max_frame_length(reader, DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    /// Creates a reader that fails with `FrameTooLarge` instead of buffering
+    /// any frame longer than `max_frame_length` bytes.
+    pub fn with_max_frame_length(reader: R, max_frame_length: usize) -> Self {
+        let codec = LengthDelimitedCodec::builder()
+            .max_frame_length(max_frame_length)
+            .new_codec();
+        Self {
+            frames: FramedRead::new(reader, codec),
+            max_frame_length,
+        }
+    }
+
+    pub async fn read<C>(&mut self) -> Result<C, TransportError>
+    where
+        C: APICommand + DeserializeOwned,
+    {
+        match self.frames.try_next().await {
+            Ok(Some(frame)) => serde_json::from_slice::<C>(&frame)
+                .map_err(|_| TransportError::ReadError("Error deserializing".to_string())),
+            Ok(None) => Err(TransportError::ReadError("No data".to_string())),
+            Err(e) if is_frame_too_large(&e) => {
+                Err(TransportError::FrameTooLarge(self.max_frame_length))
+            }
+            Err(e) => {
+                eprintln!("Failed to read command. Got Error: {}", e);
+                Err(TransportError::ReadError("Error reading".to_string()))
+            }
+        }
+    }
+}
+
+fn is_frame_too_large(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>())
+}
@@ -1,42 +1,853 @@
+use crate::encryption;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio_serde::{Deserializer, Serializer};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct UserKey {
+/// The key of one of a user's devices. Every device has its own key pair,
+/// linked to the user's long-lived identity key by `signature`, the device's
+/// public key signed with the identity key. Messages are encrypted to each
+/// device separately.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceKey {
     pub user: String,
+    /// Chosen by the user to tell their devices apart, e.g. "laptop".
+    pub name: String,
+    pub identity: Vec<u8>,
     pub public: Vec<u8>,
+    pub signature: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl DeviceKey {
+    /// Identifies the device: the fingerprint of its public key.
+    pub fn id(&self) -> String {
+        encryption::fingerprint(&self.public)
+    }
+
+    /// Whether the device key was signed by the identity key it claims.
+    pub fn is_linked(&self) -> bool {
+        encryption::verify(&self.public, &self.signature, &self.identity)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response<T> {
     Success(T),
     Error(String),
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[serde(transparent)]
+pub struct UserId(String);
+
+impl UserId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<S> From<S> for UserId
+where
+    S: Into<String>,
+{
+    fn from(s: S) -> Self {
+        Self(s.into())
+    }
+}
+
+impl PartialEq<String> for UserId {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+impl Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[serde(transparent)]
+pub struct ChatRoomId(String);
+
+impl ChatRoomId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<S> From<S> for ChatRoomId
+where
+    S: Into<String>,
+{
+    fn from(s: S) -> Self {
+        Self(s.into())
+    }
+}
+
+impl Display for ChatRoomId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Server-assigned identifier of a message, shared by every recipient's copy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(transparent)]
+pub struct MessageId(pub u64);
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Server-assigned identifier of an uploaded attachment.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[serde(transparent)]
+pub struct BlobId(pub u64);
+
+impl Display for BlobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Largest piece of an attachment uploaded or downloaded in one request.
+pub const MAX_BLOB_CHUNK: usize = 64 * 1024;
+
+/// How many of the `size` bytes of attachment `blob` the server holds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobProgress {
+    pub blob: BlobId,
+    pub received: u64,
+    pub size: u64,
+}
+
+/// The bytes of attachment `blob` from `offset` on, out of `size`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobChunk {
+    pub blob: BlobId,
+    pub offset: u64,
+    pub size: u64,
+    pub data: Vec<u8>,
+}
+
+/// A message encrypted for a single device of a recipient, named by its id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedCopy {
+    pub recipient: UserId,
+    pub device: String,
+    pub message: Vec<u8>,
+}
+
+/// A recipient's copy of a message along with the metadata the server
+/// attached to it. `sequence` increases by one for every message published
+/// in `room`; `timestamp` is the time the server received the message, in
+/// milliseconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PublishedMessage {
+    pub id: MessageId,
+    pub room: ChatRoomId,
+    pub sender: UserId,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub message: Vec<u8>,
+    /// When the server drops the message, in milliseconds since the unix
+    /// epoch, if it disappears: after the time to live it was sent with or
+    /// the room's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// What a message says. Clients serialize it with `to_plaintext` and encrypt
+/// the result for every device, so the server never sees any of it, threads
+/// included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageContent {
+    pub body: String,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+    /// The message that started the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<MessageId>,
+    /// Set on content that replaced what a message said before: the
+    /// signature of the editing device over `edit_data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_signature: Option<Vec<u8>>,
+    /// Makes the message a reaction to another one rather than something to
+    /// show on its own. The body is empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reaction: Option<Reaction>,
+    /// A file shared with the message. The body is its name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// How long after it was sent the message disappears, in seconds.
+    /// Recipients go by this even if the server kept the message longer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+/// An emoji added to, or taken back from, message `to`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Reaction {
+    pub to: MessageId,
+    pub emoji: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+}
+
+/// A file uploaded as blob `blob`, sealed in chunks with `key` so only the
+/// recipients of the message carrying it can read it. `size` and `sha256`
+/// describe the file itself, to check it against once downloaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub blob: BlobId,
+    pub name: String,
+    pub size: u64,
+    pub key: Vec<u8>,
+    pub sha256: Vec<u8>,
+}
+
+impl MessageContent {
+    pub fn text(body: String) -> Self {
+        Self {
+            body,
+            reply_to: None,
+            thread: None,
+            edit_signature: None,
+            reaction: None,
+            attachment: None,
+            ttl_secs: None,
+        }
+    }
+
+    pub fn attachment(attachment: Attachment) -> Self {
+        Self {
+            body: attachment.name.clone(),
+            attachment: Some(attachment),
+            ..Self::text(String::new())
+        }
+    }
+
+    pub fn reaction(reaction: Reaction) -> Self {
+        Self {
+            reaction: Some(reaction),
+            ..Self::text(String::new())
+        }
+    }
+
+    /// A reply to message `id`, whose content is `parent`, in the thread
+    /// `parent` belongs to or the one it starts.
+    pub fn reply(body: String, id: MessageId, parent: &MessageContent) -> Self {
+        Self {
+            body,
+            reply_to: Some(id),
+            thread: Some(parent.thread.unwrap_or(id)),
+            edit_signature: None,
+            reaction: None,
+            attachment: None,
+            ttl_secs: None,
+        }
+    }
+
+    /// What the author signs to make this the new content of message `id`
+    /// in `room`.
+    pub fn edit_data(&self, room: &ChatRoomId, id: MessageId) -> Vec<u8> {
+        let unsigned = Self {
+            edit_signature: None,
+            ..self.clone()
+        };
+        format!("edit\0{}\0{}\0{}", room, id, unsigned.to_plaintext()).into_bytes()
+    }
+
+    /// Whether this is an edit signed by the device with `key`, for message
+    /// `id` in `room`.
+    pub fn is_edit_by(&self, key: &DeviceKey, room: &ChatRoomId, id: MessageId) -> bool {
+        self.edit_signature.as_ref().is_some_and(|signature| {
+            encryption::verify(&self.edit_data(room, id), signature, &key.public)
+        })
+    }
+
+    /// When the message disappears, given the copy it came in: at whichever
+    /// comes first of its own time to live and the server's expiry.
+    pub fn expires(&self, published: &PublishedMessage) -> Option<u64> {
+        let ttl = self
+            .ttl_secs
+            .map(|ttl| published.timestamp.saturating_add(ttl.saturating_mul(1000)));
+        match (ttl, published.expires) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn to_plaintext(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize message content.")
+    }
+
+    /// Parses decrypted plaintext, or returns `None` if it isn't message
+    /// content.
+    pub fn from_plaintext(plaintext: &[u8]) -> Option<Self> {
+        serde_json::from_slice(plaintext).ok()
+    }
+}
+
+/// What a device signs to log in on a connection that was sent
+/// `LoginChallenge(nonce)`.
+pub fn login_data(nonce: &[u8]) -> Vec<u8> {
+    [b"login\0".as_slice(), nonce].concat()
+}
+
+/// What the author signs to delete message `id` from `room`.
+pub fn deletion_data(room: &ChatRoomId, id: MessageId) -> Vec<u8> {
+    format!("delete\0{}\0{}", room, id).into_bytes()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+/// Tells the sender of message `id` that `user` received or read it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    pub id: MessageId,
+    pub room: ChatRoomId,
+    pub user: UserId,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceState {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+impl Display for PresenceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Online => write!(f, "online"),
+            Self::Away => write!(f, "away"),
+            Self::DoNotDisturb => write!(f, "do not disturb"),
+            Self::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+    pub user: UserId,
+    pub state: PresenceState,
+    pub status: Option<String>,
+}
+
+/// A member's standing in a room, from least to most trusted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// May read but not post.
+    Guest,
+    Member,
+    Moderator,
+    Owner,
+}
+
+/// Something a member may or may not do in a room.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Post,
+    Invite,
+    /// Remove a lower ranked member from the room.
+    Kick,
+    /// Give the room a new name.
+    Rename,
+    SetTopic,
+    SetCapacity,
+    /// Change who may join: the room's visibility and password.
+    ChangeAccess,
+    Delete,
+    /// Change the role of a lower ranked member.
+    ManageRoles,
+    /// Change how long messages last unless their sender says otherwise.
+    SetMessageTtl,
+}
+
+impl Role {
+    /// The permission matrix.
+    pub fn allows(self, permission: Permission) -> bool {
+        use Permission::*;
+        match self {
+            Role::Owner => true,
+            Role::Moderator => {
+                matches!(permission, Post | Invite | Kick | SetTopic | ManageRoles)
+            }
+            Role::Member => matches!(permission, Post),
+            Role::Guest => false,
+        }
+    }
+
+    /// Whether a member with this role may kick a member with `other`, or
+    /// change their role. Owners may act on anyone, everyone else only on
+    /// members ranked below them.
+    pub fn outranks(self, other: Role) -> bool {
+        self == Role::Owner || self > other
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Guest => write!(f, "guest"),
+            Self::Member => write!(f, "member"),
+            Self::Moderator => write!(f, "moderator"),
+            Self::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "guest" => Ok(Self::Guest),
+            "member" => Ok(Self::Member),
+            "moderator" => Ok(Self::Moderator),
+            "owner" => Ok(Self::Owner),
+            _ => Err(format!(
+                "Unknown role {}, expected guest, member, moderator or owner",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoleAssignment {
+    pub user: UserId,
+    pub role: Role,
+}
+
+/// Who can find a room and who can join it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed, and open to anyone who knows the password if there is one.
+    #[default]
+    Public,
+    /// Listed, but only invited users may join.
+    Private,
+    /// Not listed, and only invited users may join.
+    Hidden,
+}
+
+impl Display for Visibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Public => write!(f, "public"),
+            Self::Private => write!(f, "private"),
+            Self::Hidden => write!(f, "hidden"),
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Self::Public),
+            "private" => Ok(Self::Private),
+            "hidden" => Ok(Self::Hidden),
+            _ => Err(format!(
+                "Unknown visibility {}, expected public, private or hidden",
+                s
+            )),
+        }
+    }
+}
+
+/// The public keys of a room's members, to encrypt messages to it with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomKeys {
+    pub room: ChatRoomId,
+    pub keys: Vec<DeviceKey>,
+}
+
+/// A room as shown in listings, so users can pick one without joining it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub name: ChatRoomId,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// Milliseconds since the unix epoch.
+    pub created: u64,
+    pub owners: Vec<UserId>,
+    pub members: usize,
+    pub capacity: usize,
+    pub visibility: Visibility,
+    /// Whether joining without an invitation takes a password.
+    pub password: bool,
+    /// How long messages last unless their sender says otherwise, in
+    /// seconds, if they disappear.
+    pub message_ttl_secs: Option<u64>,
+}
+
+/// An invitation to join a room, held by the server until it is used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invitation {
+    pub room: ChatRoomId,
+    pub from: UserId,
+}
+
+/// One of the caller's devices, as listed by `ListDevicesRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub online: bool,
+    /// When the device last logged in or out, in milliseconds since the unix
+    /// epoch.
+    pub last_seen: u64,
+}
+
+/// How often the client pings the server, and how many pings in a row may go
+/// missing before either side gives up on the connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval_secs: u64,
+    pub missed_limit: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval_secs: 15,
+            missed_limit: 3,
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    /// How long a connection may stay silent before it is considered dead.
+    pub fn timeout(&self) -> Duration {
+        self.interval() * self.missed_limit
+    }
+}
+
+/// Sent back on login.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginSession {
+    /// The rooms the user is in: the waiting room for a new session, or
+    /// wherever they were for a resumed one.
+    pub rooms: Vec<ChatRoomId>,
+    /// Presented with `ResumeRequest` to pick the session back up after the
+    /// connection drops.
+    pub resume_token: String,
+    /// Whether an earlier session was resumed rather than a new one started.
+    pub resumed: bool,
+    pub heartbeat: Heartbeat,
+}
+
 pub trait APICommand: Serialize + DeserializeOwned {}
 
 // Client Side
 #[derive(Serialize, Deserialize, Debug)]
 pub enum APIRequest {
-    LoginRequest(UserKey),
-    RefreshRoomKeysRequest,
-    SendMessageRequest(String, Vec<u8>),
+    /// Logs in as the device with `key`. `signature` is the device's
+    /// signature of `login_data` for the connection's challenge.
+    LoginRequest {
+        key: DeviceKey,
+        signature: Vec<u8>,
+    },
+    /// Logs in again after a dropped connection, returning the user to their
+    /// previous rooms. Messages in each room with a sequence number above the
+    /// one given for it in `after` are replayed before anything else, all
+    /// retained ones for rooms not given.
+    ResumeRequest {
+        key: DeviceKey,
+        signature: Vec<u8>,
+        token: String,
+        after: HashMap<ChatRoomId, u64>,
+    },
+    /// Fetches the public keys of everyone in a room the caller is in.
+    RefreshRoomKeysRequest(String),
+    /// Publishes one message to `room`, encrypted separately for each
+    /// recipient. With `ttl_secs`, which should match the one in the content,
+    /// the server drops the message once it has lasted that many seconds,
+    /// otherwise after the room's default if it has one.
+    SendMessageRequest {
+        room: String,
+        copies: Vec<EncryptedCopy>,
+        ttl_secs: Option<u64>,
+    },
+    /// Replaces message `id`, which the caller sent, with new content
+    /// encrypted for each recipient device. The content carries the editing
+    /// device's signature. Copies kept for devices not given a new one are
+    /// dropped.
+    EditMessageRequest {
+        room: String,
+        id: MessageId,
+        copies: Vec<EncryptedCopy>,
+    },
+    /// Deletes message `id`, which the caller sent, from the room's history
+    /// and from whatever is still queued for its recipients. `signature` is
+    /// the caller's device signature over `deletion_data`.
+    DeleteMessageRequest {
+        room: String,
+        id: MessageId,
+        signature: Vec<u8>,
+    },
+    /// Starts an upload of an attachment of `size` encrypted bytes, to be
+    /// shared in `room`. Needs `Post`. Answered with the new blob's progress.
+    CreateBlobRequest {
+        room: String,
+        size: u64,
+    },
+    /// Adds `data` to blob `blob`, which the caller is uploading. It is only
+    /// written if `offset` is where what the server holds so far ends, but
+    /// the answer says where that is either way, so an interrupted upload
+    /// picks up from there.
+    UploadChunkRequest {
+        blob: BlobId,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Fetches up to `length` bytes of a fully uploaded blob, from `offset`.
+    /// The caller has to be in the room it was shared in.
+    DownloadChunkRequest {
+        blob: BlobId,
+        offset: u64,
+        length: u64,
+    },
+    /// Pages backwards through a room's history. Returns at most `limit` of
+    /// the caller's copies with a sequence number below `before`, or the most
+    /// recent copies if `before` is `None`, ordered by sequence number.
+    FetchHistoryRequest {
+        room: String,
+        before: Option<u64>,
+        limit: usize,
+    },
+    /// Sent by a recipient once a message was decrypted (`Delivered`) or shown
+    /// to the user (`Read`). Relayed to the message's sender.
+    AcknowledgeMessage {
+        room: String,
+        id: MessageId,
+        kind: ReceiptKind,
+    },
+    /// Enables or disables relaying of the caller's read receipts.
+    SetReadReceiptsRequest(bool),
+    SetPresenceRequest {
+        state: PresenceState,
+        status: Option<String>,
+    },
+    /// Tells the rest of a room the caller is typing. Not answered.
+    TypingRequest(String),
+    /// Lists the members of a room the caller is in, with their presence.
+    ListMembersRequest(String),
+    /// Changes a member's role in `room`. Needs `ManageRoles`, and the caller
+    /// has to outrank both the member's current and new role.
+    SetRoleRequest {
+        room: String,
+        user: String,
+        role: Role,
+    },
+    /// Lists the members of a room with their roles.
+    ListRolesRequest(String),
+    /// Removes a member from `room`.
+    KickFromRoomRequest {
+        room: String,
+        user: String,
+    },
+    SetCapacityRequest {
+        room: String,
+        capacity: usize,
+    },
+    /// Deletes a room, removing everyone from it.
+    DeleteRoomRequest(String),
+    /// Gives `room` the new name `name`. Needs `Rename`.
+    RenameRoomRequest {
+        room: String,
+        name: String,
+    },
+    /// Lists the rooms that aren't hidden.
     ListRoomsRequest,
-    JoinRoomRequest(String),
-    LeaveRoom,
+    /// Sets the topic of `room`, or clears it with `None`. Needs `SetTopic`.
+    SetTopicRequest {
+        room: String,
+        topic: Option<String>,
+    },
+    /// Adds the caller to `room`, on top of the rooms they are in already.
+    /// Rooms that aren't public need an invitation, and a room with a
+    /// password needs it from anyone who wasn't invited.
+    JoinRoomRequest {
+        room: String,
+        password: Option<String>,
+    },
+    LeaveRoomRequest(String),
+    /// Lets `user` into `room`. Needs `Invite`. The invitation is kept until
+    /// it is used, and shown to the user when they next log in if they
+    /// aren't connected.
+    InviteUserRequest {
+        room: String,
+        user: String,
+    },
+    /// Lists the caller's unused invitations.
+    ListInvitationsRequest,
+    SetVisibilityRequest {
+        room: String,
+        visibility: Visibility,
+    },
+    /// Sets how long messages sent to `room` last unless their sender says
+    /// otherwise, or with `None` keeps them for the room's retention. Needs
+    /// `SetMessageTtl`. Messages sent before are not affected.
+    SetMessageTtlRequest {
+        room: String,
+        ttl_secs: Option<u64>,
+    },
+    /// Sets or, with `None`, removes the password of `room`.
+    SetPasswordRequest {
+        room: String,
+        password: Option<String>,
+    },
+    /// Lists the devices the caller has logged in from.
+    ListDevicesRequest,
+    /// Revokes one of the caller's other devices, given its id or a unique
+    /// prefix of it. A revoked device is disconnected and can't log in again.
+    RevokeDeviceRequest(String),
     Logout,
+    /// Sent every heartbeat interval to show the client is still there.
+    /// Answered with `Pong`.
+    Ping,
 }
 
 impl APICommand for APIRequest {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum APIResponse {
-    LoginResponse(Response<()>),
-    RefreshRoomKeysResponse(Response<Vec<UserKey>>),
-    SendMessageResponse(Response<()>),
-    PublishMessage(Vec<u8>),
-    ListRoomsResponse(Response<Vec<String>>),
-    JoinRoomResponse(Response<()>),
+    /// Sent as soon as a connection opens. The greeting has to be signed for
+    /// it, so a device key seen elsewhere can't be used to log in.
+    LoginChallenge(Vec<u8>),
+    LoginResponse(Response<LoginSession>),
+    RefreshRoomKeysResponse(Response<RoomKeys>),
+    SendMessageResponse(Response<MessageId>),
+    PublishMessage(PublishedMessage),
+    EditMessageResponse(Response<()>),
+    DeleteMessageResponse(Response<()>),
+    /// A recipient's copy of the new content of an edited message, which
+    /// keeps its id, sequence number and timestamp.
+    MessageEdited(PublishedMessage),
+    /// `sender` deleted message `id`. `signature` is theirs, over
+    /// `deletion_data`.
+    MessageDeleted {
+        room: ChatRoomId,
+        id: MessageId,
+        sender: UserId,
+        signature: Vec<u8>,
+    },
+    CreateBlobResponse(Response<BlobProgress>),
+    UploadChunkResponse(Response<BlobProgress>),
+    DownloadChunkResponse(Response<BlobChunk>),
+    FetchHistoryResponse(Response<Vec<PublishedMessage>>),
+    DeliveredReceipt(Receipt),
+    ReadReceipt(Receipt),
+    SetReadReceiptsResponse(Response<()>),
+    SetPresenceResponse(Response<()>),
+    PresenceUpdate(Presence),
+    TypingNotification {
+        room: ChatRoomId,
+        user: UserId,
+    },
+    ListMembersResponse(Response<Vec<Presence>>),
+    SetRoleResponse(Response<()>),
+    ListRolesResponse(Response<Vec<RoleAssignment>>),
+    KickFromRoomResponse(Response<()>),
+    SetCapacityResponse(Response<()>),
+    DeleteRoomResponse(Response<()>),
+    RenameRoomResponse(Response<()>),
+    /// `by` renamed room `from` to `to`.
+    RoomRenamed {
+        from: ChatRoomId,
+        to: ChatRoomId,
+        by: UserId,
+    },
+    /// A member of `room` was given a new role.
+    RoleChanged {
+        room: ChatRoomId,
+        assignment: RoleAssignment,
+    },
+    ListRoomsResponse(Response<Vec<RoomInfo>>),
+    SetTopicResponse(Response<()>),
+    /// `by` changed the topic of `room`.
+    TopicChanged {
+        room: ChatRoomId,
+        topic: Option<String>,
+        by: UserId,
+    },
+    /// Names the room joined.
+    JoinRoomResponse(Response<ChatRoomId>),
+    /// Names the room left.
+    LeaveRoomResponse(Response<ChatRoomId>),
+    /// The user was removed from `room` by someone else, or the room was
+    /// deleted.
+    RemovedFromRoom {
+        room: ChatRoomId,
+        reason: String,
+    },
+    InviteUserResponse(Response<()>),
+    ListInvitationsResponse(Response<Vec<Invitation>>),
+    SetVisibilityResponse(Response<()>),
+    SetPasswordResponse(Response<()>),
+    SetMessageTtlResponse(Response<()>),
+    /// `by` changed how long messages sent to `room` last by default.
+    MessageTtlChanged {
+        room: ChatRoomId,
+        ttl_secs: Option<u64>,
+        by: UserId,
+    },
+    /// The user was invited to a room.
+    Invited(Invitation),
+    ListDevicesResponse(Response<Vec<DeviceInfo>>),
+    /// Describes the device revoked.
+    RevokeDeviceResponse(Response<DeviceInfo>),
+    /// The user joined `room` on another of their devices.
+    AddedToRoom {
+        room: ChatRoomId,
+    },
+    /// The server is going away. `reconnect_after` is in seconds, and absent
+    /// if the server is not expected back.
+    ServerShutdown {
+        reason: String,
+        reconnect_after: Option<u64>,
+    },
+    Pong,
+    /// The request was dropped because the client is sending too fast. It
+    /// may be retried after `retry_after` milliseconds.
+    RateLimited {
+        retry_after: u64,
+    },
+    /// Sent before the server closes a connection that sent something it
+    /// can't accept at all, such as a request over the frame size limit.
+    Rejected {
+        reason: String,
+    },
+    /// An operator disconnected the client. It should only log back in on
+    /// its own if `reconnect` is set.
+    Kicked {
+        reason: String,
+        reconnect: bool,
+    },
+    /// A message from the server's operators to everyone connected.
+    ServerNotice(String),
 }
 
 impl APICommand for APIResponse {}
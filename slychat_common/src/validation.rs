@@ -0,0 +1,112 @@
+//! Rules for the names users and rooms may go by. Applied by the server to
+//! everything a client sends, and by the client before it sends anything.
+
+use std::fmt::Display;
+
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+/// Names no user may take and no client may create a room under, so they
+/// can't be mistaken for the server or its built-in rooms.
+pub const RESERVED_NAMES: &[&str] = &["waiting", "server", "admin", "system", "everyone"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong(usize),
+    InvalidCharacter(char),
+    Reserved(String),
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Name must not be empty"),
+            Self::TooLong(max) => write!(f, "Name must be at most {} characters", max),
+            Self::InvalidCharacter(c) => write!(
+                f,
+                "Name may only contain letters, digits, '-', '_' and '.', not {:?}",
+                c
+            ),
+            Self::Reserved(name) => write!(f, "{} is a reserved name", name),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Checks a username: up to `MAX_USERNAME_LENGTH` ASCII letters, digits,
+/// `-`, `_` and `.`, starting with a letter or digit, and not reserved.
+pub fn validate_username(name: &str) -> Result<(), NameError> {
+    validate_name(name, MAX_USERNAME_LENGTH)?;
+    if is_reserved(name) {
+        return Err(NameError::Reserved(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks a room name against the same rules as usernames, but allows up to
+/// `MAX_ROOM_NAME_LENGTH` characters. Reserved names are allowed, since the
+/// server's own rooms go by them; see `is_reserved`.
+pub fn validate_room_name(name: &str) -> Result<(), NameError> {
+    validate_name(name, MAX_ROOM_NAME_LENGTH)
+}
+
+/// Whether `name` is one of `RESERVED_NAMES`, ignoring case.
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+fn validate_name(name: &str, max_length: usize) -> Result<(), NameError> {
+    let Some(first) = name.chars().next() else {
+        return Err(NameError::Empty);
+    };
+    if name.len() > max_length {
+        return Err(NameError::TooLong(max_length));
+    }
+    if !first.is_ascii_alphanumeric() {
+        return Err(NameError::InvalidCharacter(first));
+    }
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        Some(c) => Err(NameError::InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_checked_for_length_charset_and_reservation() {
+        assert_eq!(validate_username("alice_01"), Ok(()));
+        assert_eq!(validate_username(""), Err(NameError::Empty));
+        assert_eq!(
+            validate_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)),
+            Err(NameError::TooLong(MAX_USERNAME_LENGTH))
+        );
+        assert_eq!(
+            validate_username("al ice"),
+            Err(NameError::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            validate_username(".alice"),
+            Err(NameError::InvalidCharacter('.'))
+        );
+        assert_eq!(
+            validate_username("Waiting"),
+            Err(NameError::Reserved("Waiting".to_string()))
+        );
+
+        assert_eq!(validate_room_name("waiting"), Ok(()));
+        assert_eq!(
+            validate_room_name("incidents\u{202e}"),
+            Err(NameError::InvalidCharacter('\u{202e}'))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Message throughput as the number of busy rooms grows. Every room runs in its
+//! own task, so with enough cores the total throughput should grow with the
+//! number of rooms instead of staying flat.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use slychat_common::types::{DeviceKey, EncryptedCopy};
+use slychat_server::chatroom::{ChatRoom, SimpleChatRoom};
+use slychat_server::outbox::{outbox, OutboxSettings};
+use slychat_server::room::{self, RoomHandle};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+const MEMBERS: usize = 8;
+const MESSAGES_PER_ROOM: usize = 200;
+const MESSAGE_SIZE: usize = 128;
+
+fn member(i: usize) -> String {
+    format!("member{}", i)
+}
+
+// Each member's only device. Rooms don't check signatures, so it has none.
+fn device(i: usize) -> DeviceKey {
+    DeviceKey {
+        user: member(i),
+        name: "bench".to_string(),
+        identity: vec![],
+        public: member(i).into_bytes(),
+        signature: vec![],
+    }
+}
+
+// Builds a room whose members drain their queues as fast as they can.
+fn busy_room(id: usize) -> RoomHandle {
+    let mut chatroom = SimpleChatRoom::build(format!("room{}", id), MEMBERS);
+    // Keep history from piling up over thousands of iterations.
+    chatroom.set_retention(Duration::from_secs(1));
+    for i in 0..MEMBERS {
+        let (sender, mut receiver) = outbox(OutboxSettings::default());
+        chatroom.register_user(device(i), sender).unwrap();
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+    }
+    room::spawn(chatroom)
+}
+
+fn copies() -> Vec<EncryptedCopy> {
+    (0..MEMBERS)
+        .map(|i| EncryptedCopy {
+            recipient: member(i).into(),
+            device: device(i).id(),
+            message: vec![0; MESSAGE_SIZE],
+        })
+        .collect()
+}
+
+async fn flood(rooms: &[RoomHandle]) {
+    let senders: Vec<_> = rooms
+        .iter()
+        .cloned()
+        .map(|room| {
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES_PER_ROOM {
+                    room.publish(&member(0), copies(), None).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for sender in senders {
+        sender.await.unwrap();
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fanout");
+
+    for room_count in [1, 2, 4, 8] {
+        let rooms: Vec<RoomHandle> =
+            runtime.block_on(async { (0..room_count).map(busy_room).collect() });
+
+        group.throughput(Throughput::Elements(
+            (room_count * MESSAGES_PER_ROOM) as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(room_count),
+            &rooms,
+            |b, rooms| b.to_async(&runtime).iter(|| flood(rooms)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
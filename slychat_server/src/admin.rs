@@ -0,0 +1,364 @@
+//! Operator interface, served on a Unix socket that only the user running the
+//! server can connect to. Commands are sent one per line and answered with
+//! one or more lines of text, for example with
+//! `socat - UNIX-CONNECT:slychat_data/admin.sock`:
+//!
+//! ```text
+//! connections
+//! rooms
+//! kick <user> [reason]
+//! ban <user|key|ip> <value> [reason]
+//! unban <user|key|ip> <value>
+//! bans
+//! mute <room> <user>
+//! unmute <room> <user>
+//! set-role <room> <user> <guest|member|moderator|owner>
+//! delete-room <room>
+//! notice <text>
+//! ```
+
+use log::{info, warn};
+use std::fs;
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use slychat_common::types::Role;
+
+use crate::bans::{BanTarget, Bans};
+use crate::handle::ServerHandle;
+
+const HELP: &str = "\
+connections                      list connected devices
+rooms                            list rooms and their members
+kick <user> [reason]             disconnect a user
+ban <user|key|ip> <value> [reason]
+                                 ban and disconnect a user, key or address
+unban <user|key|ip> <value>      lift a ban
+bans                             list bans
+mute <room> <user>               stop a user from publishing to a room
+unmute <room> <user>             let a muted user publish again
+set-role <room> <user> <role>    make a user a guest, member, moderator or owner of a room
+delete-room <room>               delete a room, removing everyone from it
+notice <text>                    send a notice to everyone connected";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    Help,
+    Connections,
+    Rooms,
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        target: BanTarget,
+        reason: Option<String>,
+    },
+    Unban(BanTarget),
+    Bans,
+    Mute {
+        room: String,
+        user: String,
+        muted: bool,
+    },
+    SetRole {
+        room: String,
+        user: String,
+        role: Role,
+    },
+    DeleteRoom(String),
+    Notice(String),
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let mut arg = |name: &str| {
+            words
+                .next()
+                .map(str::to_string)
+                .ok_or_else(|| format!("{} needs a {}", command, name))
+        };
+        let parsed = match command {
+            "help" => Self::Help,
+            "connections" => Self::Connections,
+            "rooms" => Self::Rooms,
+            "bans" => Self::Bans,
+            "kick" => Self::Kick {
+                user: arg("user")?,
+                reason: rest(line, 2),
+            },
+            "ban" => Self::Ban {
+                target: BanTarget::parse(&arg("target kind")?, &arg("target")?)?,
+                reason: rest(line, 3),
+            },
+            "unban" => Self::Unban(BanTarget::parse(&arg("target kind")?, &arg("target")?)?),
+            "mute" | "unmute" => Self::Mute {
+                room: arg("room")?,
+                user: arg("user")?,
+                muted: command == "mute",
+            },
+            "set-role" => Self::SetRole {
+                room: arg("room")?,
+                user: arg("user")?,
+                role: arg("role")?.parse()?,
+            },
+            "delete-room" => Self::DeleteRoom(arg("room")?),
+            "notice" => Self::Notice(rest(line, 1).ok_or("notice needs a text")?),
+            "" => return Err("Empty command, try help".to_string()),
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        };
+        Ok(parsed)
+    }
+}
+
+// The text of `line` after its first `skip` words, if there is any.
+fn rest(line: &str, skip: usize) -> Option<String> {
+    let mut rest = line.trim_start();
+    for _ in 0..skip {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    let rest = rest.trim_end();
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+/// Binds the admin socket at `path`, replacing one left behind by an earlier
+/// run, and makes it accessible to the owner only.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    // The socket is created inside a directory only we can enter, and moved
+    // into place once its permissions are tightened, so nobody else can
+    // connect in between.
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&staging);
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+/// Serves operators connecting to `listener` until the task is dropped.
+pub async fn serve(listener: UnixListener, server: ServerHandle, bans: Bans) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        let bans = bans.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_operator(stream, &server, &bans).await {
+                warn!("Admin connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_operator(stream: UnixStream, server: &ServerHandle, bans: &Bans) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let output = match AdminCommand::parse(&line) {
+            Ok(command) => {
+                info!("Admin: {}", line.trim());
+                execute(command, server, bans)
+                    .await
+                    .unwrap_or_else(|e| format!("error: {}", e))
+            }
+            Err(e) => format!("error: {}", e),
+        };
+        writer.write_all(output.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Carries out `command`, returning what to show the operator.
+pub async fn execute(
+    command: AdminCommand,
+    server: &ServerHandle,
+    bans: &Bans,
+) -> Result<String, String> {
+    let output = match command {
+        AdminCommand::Help => HELP.to_string(),
+        AdminCommand::Connections => {
+            let connections = server.connections().await.map_err(|e| e.to_string())?;
+            let lines: Vec<String> = connections
+                .iter()
+                .map(|c| {
+                    let rooms: Vec<String> =
+                        c.rooms.iter().map(|room| format!("#{}", room)).collect();
+                    format!(
+                        "{} ({}) {} {} {} {}",
+                        c.user,
+                        c.device,
+                        c.address,
+                        rooms.join(","),
+                        c.presence,
+                        c.key
+                    )
+                })
+                .collect();
+            listing(lines, "No one is connected")
+        }
+        AdminCommand::Rooms => {
+            let rooms = server.rooms().await.map_err(|e| e.to_string())?;
+            let mut lines = Vec::new();
+            for room in rooms {
+                let (Ok(info), Ok(members)) = (room.info().await, room.members(None).await) else {
+                    continue;
+                };
+                let names: Vec<&str> = members.iter().map(|m| m.as_str()).collect();
+                lines.push(format!(
+                    "#{} ({}/{}, {}) {}",
+                    room.id,
+                    names.len(),
+                    info.capacity,
+                    info.visibility,
+                    names.join(" ")
+                ));
+            }
+            listing(lines, "No rooms")
+        }
+        AdminCommand::Kick { user, reason } => {
+            let reason = reason.unwrap_or_else(|| "You were kicked by an operator".to_string());
+            let kicked = server
+                .kick(BanTarget::User(user.clone()), &reason)
+                .await
+                .map_err(|e| e.to_string())?;
+            if kicked.is_empty() {
+                return Err(format!("{} is not connected", user));
+            }
+            format!("Kicked {}", user)
+        }
+        AdminCommand::Ban { target, reason } => {
+            let added = bans.ban(&target).map_err(|e| e.to_string())?;
+            let reason = reason.unwrap_or_else(|| "You are banned from this server".to_string());
+            let kicked = server
+                .kick(target.clone(), &reason)
+                .await
+                .map_err(|e| e.to_string())?;
+            let verb = if added { "Banned" } else { "Already banned:" };
+            format!("{} {}, disconnected {}", verb, target, kicked.len())
+        }
+        AdminCommand::Unban(target) => match bans.unban(&target).map_err(|e| e.to_string())? {
+            true => format!("Unbanned {}", target),
+            false => return Err(format!("{} is not banned", target)),
+        },
+        AdminCommand::Bans => {
+            let lines = bans.list().iter().map(BanTarget::to_string).collect();
+            listing(lines, "No bans")
+        }
+        AdminCommand::Mute { room, user, muted } => {
+            let handle = server.get_room(&room).await.map_err(|e| e.to_string())?;
+            handle
+                .set_muted(&user, muted)
+                .await
+                .map_err(|e| e.to_string())?;
+            let verb = if muted { "Muted" } else { "Unmuted" };
+            format!("{} {} in #{}", verb, user, room)
+        }
+        AdminCommand::SetRole { room, user, role } => {
+            let handle = server.get_room(&room).await.map_err(|e| e.to_string())?;
+            handle
+                .set_role(None, &user, role)
+                .await
+                .map_err(|e| e.to_string())?;
+            format!("{}'s role in #{} is now {}", user, room, role)
+        }
+        AdminCommand::DeleteRoom(room) => {
+            server.delete_room(&room).await.map_err(|e| e.to_string())?;
+            format!("Deleted #{}", room)
+        }
+        AdminCommand::Notice(text) => {
+            let sent = server.notice(&text).await.map_err(|e| e.to_string())?;
+            format!("Notice sent to {} connection(s)", sent)
+        }
+    };
+    Ok(output)
+}
+
+fn listing(lines: Vec<String>, empty: &str) -> String {
+    if lines.is_empty() {
+        empty.to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed_with_optional_reasons() {
+        assert_eq!(
+            AdminCommand::parse("kick  mallory  spamming the  room "),
+            Ok(AdminCommand::Kick {
+                user: "mallory".to_string(),
+                reason: Some("spamming the  room".to_string()),
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("ban ip 192.0.2.7"),
+            Ok(AdminCommand::Ban {
+                target: BanTarget::Address([192, 0, 2, 7].into()),
+                reason: None,
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("unmute incidents bob"),
+            Ok(AdminCommand::Mute {
+                room: "incidents".to_string(),
+                user: "bob".to_string(),
+                muted: false,
+            })
+        );
+        assert!(AdminCommand::parse("ban planet mars").is_err());
+        assert!(AdminCommand::parse("mute incidents").is_err());
+        assert!(AdminCommand::parse("notice").is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_is_only_accessible_to_the_owner() {
+        let dir = std::env::temp_dir().join(format!("slychat-admin-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        fs::write(&path, "left behind").unwrap();
+
+        let _listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).await.unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use crate::storage::write_json_atomically;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Something that can be banned from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanTarget {
+    User(String),
+    /// A public key, by its fingerprint.
+    Key(String),
+    Address(IpAddr),
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::User(user) => write!(f, "user {}", user),
+            Self::Key(fingerprint) => write!(f, "key {}", fingerprint),
+            Self::Address(ip) => write!(f, "ip {}", ip),
+        }
+    }
+}
+
+impl BanTarget {
+    /// Parses a target from its kind (`user`, `key` or `ip`) and value.
+    pub fn parse(kind: &str, value: &str) -> Result<Self, String> {
+        match kind {
+            "user" => Ok(Self::User(value.to_string())),
+            "key" => Ok(Self::Key(value.to_ascii_lowercase())),
+            "ip" => IpAddr::from_str(value)
+                .map(Self::Address)
+                .map_err(|_| format!("{} is not an IP address", value)),
+            _ => Err(format!(
+                "Unknown ban target {}, expected user, key or ip",
+                kind
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct Banned {
+    users: BTreeSet<String>,
+    keys: BTreeSet<String>,
+    addresses: BTreeSet<IpAddr>,
+}
+
+/// The server's ban list, shared between the accept loop, the server actor
+/// and the admin socket. Backed by a JSON file if loaded from one, which is
+/// rewritten on every change.
+#[derive(Clone, Default)]
+pub struct Bans {
+    path: Option<PathBuf>,
+    banned: Arc<Mutex<Banned>>,
+}
+
+impl Bans {
+    /// Loads the ban list kept at `path`. A missing file is an empty list.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let banned = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Banned::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            banned: Arc::new(Mutex::new(banned)),
+        })
+    }
+
+    /// Adds a ban. Returns false if it was already in place.
+    pub fn ban(&self, target: &BanTarget) -> io::Result<bool> {
+        self.update(|banned| match target {
+            BanTarget::User(user) => banned.users.insert(user.clone()),
+            BanTarget::Key(fingerprint) => banned.keys.insert(fingerprint.clone()),
+            BanTarget::Address(ip) => banned.addresses.insert(*ip),
+        })
+    }
+
+    /// Lifts a ban. Returns false if there was none.
+    pub fn unban(&self, target: &BanTarget) -> io::Result<bool> {
+        self.update(|banned| match target {
+            BanTarget::User(user) => banned.users.remove(user),
+            BanTarget::Key(fingerprint) => banned.keys.remove(fingerprint),
+            BanTarget::Address(ip) => banned.addresses.remove(ip),
+        })
+    }
+
+    pub fn is_banned(&self, target: &BanTarget) -> bool {
+        let banned = self.banned.lock().unwrap();
+        match target {
+            BanTarget::User(user) => banned.users.contains(user),
+            BanTarget::Key(fingerprint) => banned.keys.contains(fingerprint),
+            BanTarget::Address(ip) => banned.addresses.contains(ip),
+        }
+    }
+
+    pub fn list(&self) -> Vec<BanTarget> {
+        let banned = self.banned.lock().unwrap();
+        let users = banned.users.iter().cloned().map(BanTarget::User);
+        let keys = banned.keys.iter().cloned().map(BanTarget::Key);
+        let addresses = banned.addresses.iter().copied().map(BanTarget::Address);
+        users.chain(keys).chain(addresses).collect()
+    }
+
+    // Applies `change` and saves the list if it changed anything.
+    fn update(&self, change: impl FnOnce(&mut Banned) -> bool) -> io::Result<bool> {
+        let mut banned = self.banned.lock().unwrap();
+        if !change(&mut banned) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            write_json_atomically(path, &*banned)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_a_reload() {
+        let dir = std::env::temp_dir().join(format!("slychat-bans-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bans.json");
+
+        let bans = Bans::load(&path).unwrap();
+        let mallory = BanTarget::User("mallory".to_string());
+        let address = BanTarget::parse("ip", "192.0.2.7").unwrap();
+        assert!(bans.ban(&mallory).unwrap());
+        assert!(!bans.ban(&mallory).unwrap());
+        assert!(bans.ban(&address).unwrap());
+        assert!(bans.unban(&address).unwrap());
+
+        let reloaded = Bans::load(&path).unwrap();
+        assert!(reloaded.is_banned(&mallory));
+        assert!(!reloaded.is_banned(&address));
+        assert_eq!(reloaded.list(), vec![mallory]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
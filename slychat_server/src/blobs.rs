@@ -0,0 +1,319 @@
+use crate::storage::write_json_atomically;
+use serde::{Deserialize, Serialize};
+use slychat_common::encryption::random_bytes;
+use slychat_common::types::{BlobChunk, BlobId, BlobProgress, ChatRoomId, MAX_BLOB_CHUNK};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const DEFAULT_MAX_ATTACHMENT: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_ATTACHMENT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug)]
+pub enum BlobError {
+    // Also what anyone but the uploader gets for adding to a blob.
+    NotFound(BlobId),
+    TooLarge(u64),
+    // The chunk would run past the size given when the upload started.
+    Overrun(BlobId),
+    Incomplete(BlobId),
+    Storage(io::Error),
+}
+
+impl Display for BlobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(blob) => write!(f, "No attachment {}.", blob),
+            Self::TooLarge(max) => write!(
+                f,
+                "Attachments must be between 1 and {} bytes once encrypted.",
+                max
+            ),
+            Self::Overrun(blob) => write!(f, "Chunk runs past the end of attachment {}.", blob),
+            Self::Incomplete(blob) => write!(f, "Attachment {} is still being uploaded.", blob),
+            Self::Storage(e) => write!(f, "Could not store attachment: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<io::Error> for BlobError {
+    fn from(e: io::Error) -> Self {
+        Self::Storage(e)
+    }
+}
+
+/// Limits on the attachments the server keeps.
+#[derive(Debug, Clone, Copy)]
+pub struct BlobSettings {
+    /// Largest attachment accepted, in encrypted bytes.
+    pub max_size: u64,
+    /// How long an attachment is kept after its upload started.
+    pub retention: Duration,
+}
+
+impl Default for BlobSettings {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_ATTACHMENT,
+            retention: DEFAULT_ATTACHMENT_RETENTION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Blob {
+    room: ChatRoomId,
+    uploader: String,
+    size: u64,
+    /// Milliseconds since the unix epoch.
+    created: u64,
+    // Taken from the length of the file when loading.
+    #[serde(skip)]
+    received: u64,
+}
+
+impl Blob {
+    fn progress(&self, blob: BlobId) -> BlobProgress {
+        BlobProgress {
+            blob,
+            received: self.received,
+            size: self.size,
+        }
+    }
+}
+
+/// Encrypted attachments, one file each in a directory, shared between
+/// connections. Only clients can decrypt them. The index of who uploaded
+/// what, and for which room, is kept in a JSON file next to them that is
+/// rewritten whenever an upload starts or attachments expire.
+#[derive(Clone)]
+pub struct Blobs {
+    dir: PathBuf,
+    settings: BlobSettings,
+    index: Arc<Mutex<BTreeMap<BlobId, Blob>>>,
+}
+
+impl Blobs {
+    /// Loads the attachments kept in `dir`. A missing directory means there
+    /// are none. Interrupted uploads resume from what made it to disk.
+    pub fn load(dir: &Path, settings: BlobSettings) -> io::Result<Self> {
+        let mut index: BTreeMap<BlobId, Blob> = match fs::read(dir.join("index.json")) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        for (id, blob) in index.iter_mut() {
+            let path = blob_path(dir, *id);
+            let len = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+            blob.received = len.min(blob.size);
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            settings,
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+
+    /// Starts an upload of `size` bytes by `uploader`, to share in `room`.
+    /// Attachments past their retention are dropped first.
+    pub fn create(
+        &self,
+        room: &ChatRoomId,
+        uploader: &str,
+        size: u64,
+        now: u64,
+    ) -> Result<BlobProgress, BlobError> {
+        if size == 0 || size > self.settings.max_size {
+            return Err(BlobError::TooLarge(self.settings.max_size));
+        }
+        let mut index = self.index.lock().unwrap();
+        self.expire(&mut index, now);
+        let id = loop {
+            let bytes: [u8; 8] = random_bytes(8).try_into().unwrap();
+            let id = BlobId(u64::from_le_bytes(bytes));
+            if !index.contains_key(&id) {
+                break id;
+            }
+        };
+        fs::create_dir_all(&self.dir)?;
+        File::create(blob_path(&self.dir, id))?;
+        let blob = Blob {
+            room: room.clone(),
+            uploader: uploader.to_string(),
+            size,
+            created: now,
+            received: 0,
+        };
+        let progress = blob.progress(id);
+        index.insert(id, blob);
+        self.save(&index)?;
+        Ok(progress)
+    }
+
+    /// Adds `data` to `uploader`'s blob `id` if `offset` is where it ends.
+    pub fn upload(
+        &self,
+        uploader: &str,
+        id: BlobId,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<BlobProgress, BlobError> {
+        let mut index = self.index.lock().unwrap();
+        let blob = index
+            .get_mut(&id)
+            .filter(|blob| blob.uploader == uploader)
+            .ok_or(BlobError::NotFound(id))?;
+        if offset != blob.received || data.is_empty() {
+            return Ok(blob.progress(id));
+        }
+        if blob.received + data.len() as u64 > blob.size {
+            return Err(BlobError::Overrun(id));
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(blob_path(&self.dir, id))?;
+        // Cuts off whatever a failed write may have left past the end.
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        blob.received += data.len() as u64;
+        Ok(blob.progress(id))
+    }
+
+    /// The room blob `id` was shared in, once it is fully uploaded.
+    pub fn room(&self, id: BlobId, now: u64) -> Result<ChatRoomId, BlobError> {
+        let index = self.index.lock().unwrap();
+        let blob = index
+            .get(&id)
+            .filter(|blob| !self.expired(blob, now))
+            .ok_or(BlobError::NotFound(id))?;
+        if blob.received < blob.size {
+            return Err(BlobError::Incomplete(id));
+        }
+        Ok(blob.room.clone())
+    }
+
+    /// Moves the attachments shared in room `from` over to `to`, so its
+    /// members can still download them once it is renamed.
+    pub fn rename_room(&self, from: &ChatRoomId, to: &ChatRoomId) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        let mut moved = false;
+        for blob in index.values_mut().filter(|blob| &blob.room == from) {
+            blob.room = to.clone();
+            moved = true;
+        }
+        if moved {
+            self.save(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `length` bytes, and at most `MAX_BLOB_CHUNK`, of blob
+    /// `id` from `offset`.
+    pub fn download(&self, id: BlobId, offset: u64, length: u64) -> Result<BlobChunk, BlobError> {
+        let size = match self.index.lock().unwrap().get(&id) {
+            Some(blob) if blob.received < blob.size => return Err(BlobError::Incomplete(id)),
+            Some(blob) => blob.size,
+            None => return Err(BlobError::NotFound(id)),
+        };
+        let length = length
+            .min(MAX_BLOB_CHUNK as u64)
+            .min(size.saturating_sub(offset));
+        let mut file = File::open(blob_path(&self.dir, id))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length as usize];
+        file.read_exact(&mut data)?;
+        Ok(BlobChunk {
+            blob: id,
+            offset,
+            size,
+            data,
+        })
+    }
+
+    fn expired(&self, blob: &Blob, now: u64) -> bool {
+        blob.created + self.settings.retention.as_millis() as u64 <= now
+    }
+
+    // Drops the blobs past their retention, files and all.
+    fn expire(&self, index: &mut BTreeMap<BlobId, Blob>, now: u64) {
+        index.retain(|id, blob| {
+            if !self.expired(blob, now) {
+                return true;
+            }
+            if let Err(e) = fs::remove_file(blob_path(&self.dir, *id)) {
+                log::warn!("Could not remove expired attachment {}: {}", id, e);
+            }
+            false
+        });
+    }
+
+    fn save(&self, index: &BTreeMap<BlobId, Blob>) -> io::Result<()> {
+        write_json_atomically(&self.dir.join("index.json"), index)
+    }
+}
+
+fn blob_path(dir: &Path, id: BlobId) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_resume_after_a_reload_and_expire() {
+        let dir = std::env::temp_dir().join(format!("slychat-blobs-{}", std::process::id()));
+        let settings = BlobSettings {
+            max_size: 10,
+            retention: Duration::from_secs(1),
+        };
+        let room = ChatRoomId::from("incidents");
+        let blobs = Blobs::load(&dir, settings).unwrap();
+        assert!(matches!(
+            blobs.create(&room, "alice", 11, 0),
+            Err(BlobError::TooLarge(10))
+        ));
+        let id = blobs.create(&room, "alice", 6, 0).unwrap().blob;
+        assert!(matches!(
+            blobs.upload("bob", id, 0, b"abc"),
+            Err(BlobError::NotFound(_))
+        ));
+        assert_eq!(blobs.upload("alice", id, 0, b"abc").unwrap().received, 3);
+        assert!(matches!(blobs.room(id, 0), Err(BlobError::Incomplete(_))));
+
+        // The connection dropped and the upload is picked back up.
+        let blobs = Blobs::load(&dir, settings).unwrap();
+        assert_eq!(blobs.upload("alice", id, 0, b"abc").unwrap().received, 3);
+        assert!(matches!(
+            blobs.upload("alice", id, 3, b"defg"),
+            Err(BlobError::Overrun(_))
+        ));
+        assert_eq!(blobs.upload("alice", id, 3, b"def").unwrap().received, 6);
+        assert_eq!(blobs.room(id, 999).unwrap(), room);
+        assert_eq!(blobs.download(id, 2, 3).unwrap().data, b"cde");
+        assert_eq!(blobs.download(id, 4, 100).unwrap().data, b"ef");
+
+        // Starting another upload clears out the expired one.
+        assert!(matches!(blobs.room(id, 1000), Err(BlobError::NotFound(_))));
+        blobs.create(&room, "alice", 1, 1000).unwrap();
+        assert!(matches!(
+            blobs.download(id, 0, 1),
+            Err(BlobError::NotFound(_))
+        ));
+        assert!(!blob_path(&dir, id).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,34 @@
+use crate::outbox::Outbox;
+use crate::password::PasswordHash;
+use crate::server::{unix_millis, UserMessage};
+use futures::future::join_all;
 use log::info;
-use slychat_common::types::UserKey;
-use std::collections::{HashMap, HashSet};
+use slychat_common::encryption::random_bytes;
+use slychat_common::types::{
+    APIResponse, ChatRoomId, DeviceKey, EncryptedCopy, MessageId, PublishedMessage, ReceiptKind,
+    Role, RoomInfo, UserId, Visibility,
+};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::Display;
-use std::hash::Hash;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long a room keeps message history unless configured otherwise.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long a room waits for a member's queue to free up before skipping them.
+pub const DEFAULT_PUBLISH_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone)]
 pub enum ChatRoomError {
     RegistrationFailure(Option<&'static str>),
     UserAlreadyExists(String),
     MessageError(Option<&'static str>),
+    // Capacity can't be set below this.
+    CapacityTooLow(usize),
+    // Why a user may not join.
+    AccessDenied(String),
 }
 
 impl Display for ChatRoomError {
@@ -26,29 +45,242 @@ impl Display for ChatRoomError {
             ChatRoomError::UserAlreadyExists(message) => {
                 write!(f, "User {} Already Exists", message)
             }
+            ChatRoomError::CapacityTooLow(minimum) => {
+                write!(f, "Capacity must be at least {}", minimum)
+            }
+            ChatRoomError::AccessDenied(reason) => write!(f, "{}", reason),
         }
     }
 }
 
 impl Error for ChatRoomError {}
 
-pub trait ChatRoom {
+/// Who an event pushed by a room is for: one device of a member, or all of
+/// them if `device` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    pub user: UserId,
+    pub device: Option<String>,
+}
+
+impl From<UserId> for Recipient {
+    fn from(user: UserId) -> Self {
+        Self { user, device: None }
+    }
+}
+
+/// A room's state. Each room is driven by its own task, see `room::spawn`.
+pub trait ChatRoom: Send + Sync + 'static {
     fn build(id: String, capacity: usize) -> Self;
-    fn register_user(&mut self, username: &String, key: Vec<u8>) -> Result<(), ChatRoomError>;
-    fn unregister_user(&mut self, username: &String) -> Result<(), ChatRoomError>;
+    fn id(&self) -> &str;
+    /// Gives the room a new name, which its history is then published under.
+    fn rename(&mut self, id: &str);
+    /// Adds the device with `key` to its user, who becomes a member if they
+    /// weren't one. `sender` is the outbound queue of the device's connection.
+    /// A device whose previous connection is gone is given the new one
+    /// instead.
+    fn register_user(&mut self, key: DeviceKey, sender: Outbox) -> Result<(), ChatRoomError>;
+    /// Removes a member along with all their devices.
+    fn unregister_user(&mut self, username: &str) -> Result<(), ChatRoomError>;
+    /// Removes `username`'s device `device` if its connection is gone, or
+    /// with `None` every such device. A member left without devices is
+    /// removed. Returns whether they were.
+    fn forget_devices(&mut self, username: &str, device: Option<&str>) -> bool;
+    fn is_registered(&self, username: &str) -> bool;
+    /// Whether `username` is a member with an open connection on any device.
+    /// Devices that dropped off keep their place, and keep receiving copies of
+    /// messages, until they reconnect or are forgotten.
+    fn is_connected(&self, username: &str) -> bool;
+    fn members(&self) -> Vec<&String>;
+    /// `username`'s role. Anyone who wasn't given one is a `Member`. Roles
+    /// are kept when a member leaves, so they return with the same standing.
+    fn role(&self, username: &str) -> Role;
+    fn set_role(&mut self, username: &str, role: Role);
+    /// Fails if the room already holds more members than `capacity`.
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), ChatRoomError>;
+    fn visibility(&self) -> Visibility;
+    fn set_visibility(&mut self, visibility: Visibility);
+    /// Requires joiners who weren't invited to give `password`, or with
+    /// `None` stops asking for one.
+    fn set_password(&mut self, password: Option<PasswordHash>);
+    /// Lets `username` in, whatever the visibility and password, until they
+    /// next join.
+    fn invite(&mut self, username: &str);
+    /// Takes back `username`'s role and invitation, so they are admitted like
+    /// anyone else should they come back.
+    fn revoke(&mut self, username: &str);
+    /// Checks that `username` may join. Returns the hash of the password they
+    /// still have to give, if any. Anyone invited, and owners and moderators,
+    /// are let in without one.
+    fn admit(&self, username: &str) -> Result<Option<&PasswordHash>, ChatRoomError>;
+    /// The room as shown in listings.
+    fn info(&self) -> RoomInfo;
+    fn set_topic(&mut self, topic: Option<String>);
+    fn set_description(&mut self, description: Option<String>);
+
+    /// The keys of every device of every member.
+    fn get_roomkeys(&self) -> Result<Vec<&DeviceKey>, ChatRoomError>;
+    /// Pushes each message to its recipient's connections, concurrently. A
+    /// device whose queue stays full is skipped once the room's publish
+    /// timeout passes, so one slow consumer can't hold up the room for long.
+    /// Returns the members with a device that was skipped.
+    fn publish_message(
+        &self,
+        messages: Vec<(Recipient, APIResponse)>,
+    ) -> impl Future<Output = Vec<UserId>> + Send;
+    /// Pushes `message` to every device of every member other than `except`
+    /// without waiting. Devices whose queue is full miss it.
+    fn notify(&self, except: &str, message: APIResponse);
+
+    /// Assigns a message its id and the room's next sequence number, and
+    /// retains the copies addressed to current members' devices. Returns the
+    /// copies to deliver, keyed by recipient device. `timestamp` is in
+    /// milliseconds since the unix epoch. The message expires after `ttl`,
+    /// or the room's default time to live if it has one.
+    fn store_message(
+        &mut self,
+        sender: &str,
+        copies: Vec<EncryptedCopy>,
+        ttl: Option<Duration>,
+        timestamp: u64,
+    ) -> Result<Vec<(Recipient, PublishedMessage)>, ChatRoomError>;
+    /// Replaces the retained copies of message `id`, which `sender`
+    /// published, with `copies`. Copies kept for devices not given a new one
+    /// are dropped, so nothing goes on showing what was replaced. Returns the
+    /// new copies to deliver, keyed by recipient device.
+    fn edit_message(
+        &mut self,
+        sender: &str,
+        id: MessageId,
+        copies: Vec<EncryptedCopy>,
+    ) -> Result<Vec<(Recipient, PublishedMessage)>, ChatRoomError>;
+    /// Drops every copy of message `id`, which `sender` published, from the
+    /// history and from the queues of members' devices.
+    fn delete_message(&mut self, sender: &str, id: MessageId) -> Result<(), ChatRoomError>;
+    /// Returns up to `limit` of the retained messages for `recipient`'s
+    /// `device` with a sequence number below `before`, in sequence order.
+    fn fetch_history(
+        &self,
+        recipient: &str,
+        device: &str,
+        before: Option<u64>,
+        limit: usize,
+        now: u64,
+    ) -> Vec<PublishedMessage>;
+    /// Returns up to `limit` of the retained messages for `recipient`'s
+    /// `device` with a sequence number above `after`, oldest first.
+    fn fetch_since(
+        &self,
+        recipient: &str,
+        device: &str,
+        after: Option<u64>,
+        limit: usize,
+        now: u64,
+    ) -> Vec<PublishedMessage>;
+    /// Returns a copy of message `id` for any of `recipient`'s devices, if it
+    /// is still retained.
+    fn find_message(&self, recipient: &str, id: MessageId) -> Option<&PublishedMessage>;
+    /// Records that `recipient` acknowledged message `id` as `kind`. Returns
+    /// false if they had already, or the message is no longer retained.
+    fn acknowledge(&mut self, recipient: &str, id: MessageId, kind: ReceiptKind) -> bool;
+    fn set_retention(&mut self, retention: Duration);
+    /// How long messages sent without a time to live of their own last, or
+    /// with `None`, that they last for the room's retention.
+    fn set_message_ttl(&mut self, ttl: Option<Duration>);
+    /// When messages are next due to expire, if any are. Nothing may be left
+    /// to expire by then, if those messages were deleted meanwhile.
+    fn next_expiry(&self) -> Option<u64>;
+    /// Drops every copy of the messages that expired by `now`, from the
+    /// history and from the queues of members' devices. Returns how many
+    /// messages there were.
+    fn expire_messages(&mut self, now: u64) -> usize;
+}
+
+struct StoredMessage {
+    recipient: UserId,
+    device: String,
+    published: PublishedMessage,
+    // The receipts the recipient sent for it so far.
+    acknowledged: Vec<ReceiptKind>,
+}
 
-    fn get_roomkeys(&self) -> Result<Vec<(&String, &Vec<u8>)>, ChatRoomError>;
-    fn publish_message(&self, message: &'static str) -> Result<(), ChatRoomError>;
+fn generate_message_id() -> MessageId {
+    let bytes: [u8; 8] = random_bytes(8).try_into().unwrap();
+    MessageId(u64::from_le_bytes(bytes))
+}
+
+pub struct Device {
+    pub key: DeviceKey,
+    pub sender: Outbox,
+}
+
+/// A member of a room, with each of their devices in it by device id.
+#[derive(Default)]
+pub struct Member {
+    pub devices: HashMap<String, Device>,
+}
+
+impl Member {
+    fn is_connected(&self) -> bool {
+        self.devices
+            .values()
+            .any(|device| !device.sender.is_closed())
+    }
 }
 
 pub struct SimpleChatRoom {
     pub id: String,
     pub capacity: usize,
     pub current_size: usize,
+    pub retention: Duration,
+    pub message_ttl: Option<Duration>,
+    pub publish_timeout: Duration,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// Milliseconds since the unix epoch.
+    pub created: u64,
+
+    pub registered_users: HashMap<String, Member>,
+    // Everyone with a role other than `Member`.
+    roles: HashMap<String, Role>,
+    visibility: Visibility,
+    password: Option<PasswordHash>,
+    invited: HashSet<String>,
+    next_sequence: u64,
+    // Ordered by timestamp, so expired messages are always at the front.
+    history: VecDeque<StoredMessage>,
+    // When messages with a time to live expire, soonest first. Messages that
+    // were deleted or pruned before then leave their time behind.
+    expiries: BinaryHeap<Reverse<u64>>,
+}
+
+impl SimpleChatRoom {
+    fn retention_cutoff(&self, now: u64) -> u64 {
+        now.saturating_sub(self.retention.as_millis() as u64)
+    }
+
+    fn prune_history(&mut self, now: u64) {
+        let cutoff = self.retention_cutoff(now);
+        while matches!(self.history.front(), Some(m) if m.published.timestamp < cutoff) {
+            self.history.pop_front();
+        }
+    }
 
-    pub registered_users: HashMap<String, Vec<u8>>,
-    // A vec rather than a HashMap because we need to iterate through it a lot
-    // user_collection: Arc<Mutex<UserRouter>>,
+    // Checks that message `id` is retained and was published by `sender`.
+    // Messages of others look just like ones that are gone.
+    fn check_author(&self, sender: &str, id: MessageId) -> Result<(), ChatRoomError> {
+        let authored = self
+            .history
+            .iter()
+            .any(|m| m.published.id == id && m.published.sender.as_str() == sender);
+        if authored {
+            Ok(())
+        } else {
+            Err(ChatRoomError::MessageError(Some(
+                ": no such message of yours in this room",
+            )))
+        }
+    }
 }
 
 impl ChatRoom for SimpleChatRoom {
@@ -57,28 +289,69 @@ impl ChatRoom for SimpleChatRoom {
             id,
             capacity,
             current_size: 0,
+            retention: DEFAULT_RETENTION,
+            message_ttl: None,
+            publish_timeout: DEFAULT_PUBLISH_TIMEOUT,
+            topic: None,
+            description: None,
+            created: unix_millis(),
             registered_users: HashMap::new(),
+            roles: HashMap::new(),
+            visibility: Visibility::Public,
+            password: None,
+            invited: HashSet::new(),
+            next_sequence: 1,
+            history: VecDeque::new(),
+            expiries: BinaryHeap::new(),
         }
     }
 
-    fn register_user(&mut self, username: &String, key: Vec<u8>) -> Result<(), ChatRoomError> {
-        info!("Registering user {} into {}", username, self.id);
-        // Create a channel for sending the user messages
-        let (sender, receiver) = tokio::sync::mpsc::channel::<String>(1024);
-        // let username = &user.user_data.user;
+    fn id(&self) -> &str {
+        &self.id
+    }
 
-        // Check if user already exists in chatroom
-        if self.registered_users.contains_key(username) {
-            Err(ChatRoomError::UserAlreadyExists(username.into()))
-        } else {
-            self.registered_users.insert(username.into(), key);
-            Ok(())
+    fn rename(&mut self, id: &str) {
+        self.id = id.to_string();
+        for stored in &mut self.history {
+            stored.published.room = id.into();
+        }
+    }
+
+    fn register_user(&mut self, key: DeviceKey, sender: Outbox) -> Result<(), ChatRoomError> {
+        let username = key.user.clone();
+        info!(
+            "Registering user {} ({}) into {}",
+            username, key.name, self.id
+        );
+
+        if !self.registered_users.contains_key(&username) {
+            if self.current_size >= self.capacity {
+                return Err(ChatRoomError::RegistrationFailure(Some(": room is full")));
+            }
+            self.registered_users
+                .insert(username.clone(), Member::default());
+            self.current_size += 1;
+            self.invited.remove(&username);
+        }
+
+        // The same device can't be in the room twice
+        let member = self.registered_users.get_mut(&username).unwrap();
+        let id = key.id();
+        if member
+            .devices
+            .get(&id)
+            .is_some_and(|d| !d.sender.is_closed())
+        {
+            return Err(ChatRoomError::UserAlreadyExists(username));
         }
+        member.devices.insert(id, Device { key, sender });
+        Ok(())
     }
 
-    fn unregister_user(&mut self, username: &String) -> Result<(), ChatRoomError> {
+    fn unregister_user(&mut self, username: &str) -> Result<(), ChatRoomError> {
         if self.registered_users.contains_key(username) {
             self.registered_users.remove(username);
+            self.current_size -= 1;
             Ok(())
         } else {
             Err(ChatRoomError::RegistrationFailure(Some(
@@ -87,12 +360,697 @@ impl ChatRoom for SimpleChatRoom {
         }
     }
 
-    fn publish_message(&self, message: &'static str) -> Result<(), ChatRoomError> {
-        todo!()
+    fn forget_devices(&mut self, username: &str, device: Option<&str>) -> bool {
+        let Some(member) = self.registered_users.get_mut(username) else {
+            return false;
+        };
+        member
+            .devices
+            .retain(|id, d| device.is_some_and(|device| device != id) || !d.sender.is_closed());
+        if !member.devices.is_empty() {
+            return false;
+        }
+        self.registered_users.remove(username);
+        self.current_size -= 1;
+        true
+    }
+
+    fn is_registered(&self, username: &str) -> bool {
+        self.registered_users.contains_key(username)
     }
 
-    fn get_roomkeys(&self) -> Result<Vec<(&String, &Vec<u8>)>, ChatRoomError> {
-        let roomkeys: Vec<(&String, &Vec<u8>)> = self.registered_users.iter().collect();
+    fn is_connected(&self, username: &str) -> bool {
+        self.registered_users
+            .get(username)
+            .is_some_and(Member::is_connected)
+    }
+
+    fn members(&self) -> Vec<&String> {
+        self.registered_users.keys().collect()
+    }
+
+    fn role(&self, username: &str) -> Role {
+        self.roles.get(username).copied().unwrap_or(Role::Member)
+    }
+
+    fn set_role(&mut self, username: &str, role: Role) {
+        if role == Role::Member {
+            self.roles.remove(username);
+        } else {
+            self.roles.insert(username.into(), role);
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), ChatRoomError> {
+        let minimum = self.current_size.max(1);
+        if capacity < minimum {
+            return Err(ChatRoomError::CapacityTooLow(minimum));
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    fn set_visibility(&mut self, visibility: Visibility) {
+        self.visibility = visibility;
+    }
+
+    fn set_password(&mut self, password: Option<PasswordHash>) {
+        self.password = password;
+    }
+
+    fn invite(&mut self, username: &str) {
+        self.invited.insert(username.into());
+    }
+
+    fn revoke(&mut self, username: &str) {
+        self.roles.remove(username);
+        self.invited.remove(username);
+    }
+
+    fn admit(&self, username: &str) -> Result<Option<&PasswordHash>, ChatRoomError> {
+        let staff = matches!(self.role(username), Role::Owner | Role::Moderator);
+        if self.invited.contains(username) || staff {
+            return Ok(None);
+        }
+        if self.visibility != Visibility::Public {
+            return Err(ChatRoomError::AccessDenied(format!(
+                "#{} is invite only.",
+                self.id
+            )));
+        }
+        Ok(self.password.as_ref())
+    }
+
+    fn info(&self) -> RoomInfo {
+        let mut owners: Vec<UserId> = self
+            .roles
+            .iter()
+            .filter(|(_, role)| **role == Role::Owner)
+            .map(|(user, _)| user.into())
+            .collect();
+        owners.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        RoomInfo {
+            name: self.id.clone().into(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            created: self.created,
+            owners,
+            members: self.current_size,
+            capacity: self.capacity,
+            visibility: self.visibility,
+            password: self.password.is_some(),
+            message_ttl_secs: self.message_ttl.map(|ttl| ttl.as_secs()),
+        }
+    }
+
+    fn set_topic(&mut self, topic: Option<String>) {
+        self.topic = topic;
+    }
+
+    fn set_description(&mut self, description: Option<String>) {
+        self.description = description;
+    }
+
+    fn publish_message(
+        &self,
+        messages: Vec<(Recipient, APIResponse)>,
+    ) -> impl Future<Output = Vec<UserId>> + Send {
+        let timeout = self.publish_timeout;
+        let mut sends = Vec::new();
+        for (Recipient { user, device }, message) in messages {
+            let Some(member) = self.registered_users.get(user.as_str()) else {
+                continue;
+            };
+            let channels = member
+                .devices
+                .iter()
+                .filter(|(id, _)| device.as_ref().is_none_or(|device| device == *id))
+                .map(|(_, d)| d.sender.clone())
+                // Disconnected devices catch up when they resume.
+                .filter(|channel| !channel.is_closed());
+            for channel in channels {
+                let user_message = UserMessage {
+                    user_id: user.clone(),
+                    message: message.clone(),
+                };
+                let user = user.clone();
+                sends.push(async move {
+                    match channel.send(user_message, timeout).await {
+                        Ok(()) => None,
+                        Err(_) => Some(user),
+                    }
+                });
+            }
+        }
+
+        async move { join_all(sends).await.into_iter().flatten().collect() }
+    }
+
+    fn notify(&self, except: &str, message: APIResponse) {
+        for (member, Member { devices }) in &self.registered_users {
+            if member == except {
+                continue;
+            }
+            for Device { sender, .. } in devices.values() {
+                let _ = sender.try_send(UserMessage {
+                    user_id: member.into(),
+                    message: message.clone(),
+                });
+            }
+        }
+    }
+
+    fn get_roomkeys(&self) -> Result<Vec<&DeviceKey>, ChatRoomError> {
+        let roomkeys: Vec<&DeviceKey> = self
+            .registered_users
+            .values()
+            .flat_map(|member| member.devices.values().map(|d| &d.key))
+            .collect();
         Ok(roomkeys)
     }
+
+    fn store_message(
+        &mut self,
+        sender: &str,
+        copies: Vec<EncryptedCopy>,
+        ttl: Option<Duration>,
+        timestamp: u64,
+    ) -> Result<Vec<(Recipient, PublishedMessage)>, ChatRoomError> {
+        // Copies for devices that left since the sender last refreshed its
+        // keys are dropped.
+        let copies: Vec<EncryptedCopy> = copies
+            .into_iter()
+            .filter(|c| {
+                self.registered_users
+                    .get(c.recipient.as_str())
+                    .is_some_and(|m| m.devices.contains_key(&c.device))
+            })
+            .collect();
+        if copies.is_empty() {
+            return Err(ChatRoomError::MessageError(Some(
+                "No recipients are in chatroom.",
+            )));
+        }
+
+        let id = generate_message_id();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.prune_history(timestamp);
+
+        let expires = ttl
+            .or(self.message_ttl)
+            .map(|ttl| timestamp.saturating_add(ttl.as_millis() as u64));
+        if let Some(expires) = expires {
+            self.expiries.push(Reverse(expires));
+        }
+        let room: ChatRoomId = self.id.clone().into();
+        let deliveries: Vec<(Recipient, PublishedMessage)> = copies
+            .into_iter()
+            .map(|copy| {
+                let published = PublishedMessage {
+                    id,
+                    room: room.clone(),
+                    sender: sender.into(),
+                    sequence,
+                    timestamp,
+                    message: copy.message,
+                    expires,
+                };
+                let recipient = Recipient {
+                    user: copy.recipient,
+                    device: Some(copy.device),
+                };
+                (recipient, published)
+            })
+            .collect();
+
+        for (recipient, published) in &deliveries {
+            self.history.push_back(StoredMessage {
+                recipient: recipient.user.clone(),
+                device: recipient.device.clone().unwrap_or_default(),
+                published: published.clone(),
+                acknowledged: Vec::new(),
+            });
+        }
+        Ok(deliveries)
+    }
+
+    fn edit_message(
+        &mut self,
+        sender: &str,
+        id: MessageId,
+        copies: Vec<EncryptedCopy>,
+    ) -> Result<Vec<(Recipient, PublishedMessage)>, ChatRoomError> {
+        self.check_author(sender, id)?;
+
+        let mut deliveries = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.history.len());
+        for mut stored in self.history.drain(..) {
+            if stored.published.id != id {
+                kept.push_back(stored);
+                continue;
+            }
+            let copy = copies
+                .iter()
+                .find(|c| c.recipient == stored.recipient && c.device == stored.device);
+            // Devices the editor had no key for lose the old content.
+            let Some(copy) = copy else { continue };
+            stored.published.message = copy.message.clone();
+            let recipient = Recipient {
+                user: stored.recipient.clone(),
+                device: Some(stored.device.clone()),
+            };
+            deliveries.push((recipient, stored.published.clone()));
+            kept.push_back(stored);
+        }
+        self.history = kept;
+        Ok(deliveries)
+    }
+
+    fn delete_message(&mut self, sender: &str, id: MessageId) -> Result<(), ChatRoomError> {
+        self.check_author(sender, id)?;
+        self.history.retain(|m| m.published.id != id);
+
+        let mut discarded = 0;
+        for Member { devices } in self.registered_users.values() {
+            for Device { sender, .. } in devices.values() {
+                discarded += sender.discard(|queued| match &queued.message {
+                    APIResponse::PublishMessage(p) | APIResponse::MessageEdited(p) => p.id == id,
+                    _ => false,
+                });
+            }
+        }
+        info!(
+            "Room {}: {} deleted message {} ({} queued copies dropped)",
+            self.id, sender, id, discarded
+        );
+        Ok(())
+    }
+
+    fn fetch_history(
+        &self,
+        recipient: &str,
+        device: &str,
+        before: Option<u64>,
+        limit: usize,
+        now: u64,
+    ) -> Vec<PublishedMessage> {
+        let cutoff = self.retention_cutoff(now);
+        let mut entries: Vec<PublishedMessage> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|m| m.recipient.as_str() == recipient && m.device == device)
+            .filter(|m| m.published.timestamp >= cutoff && !is_expired(&m.published, now))
+            .filter(|m| before.is_none_or(|b| m.published.sequence < b))
+            .take(limit)
+            .map(|m| m.published.clone())
+            .collect();
+
+        entries.reverse();
+        entries
+    }
+
+    fn fetch_since(
+        &self,
+        recipient: &str,
+        device: &str,
+        after: Option<u64>,
+        limit: usize,
+        now: u64,
+    ) -> Vec<PublishedMessage> {
+        let cutoff = self.retention_cutoff(now);
+        self.history
+            .iter()
+            .filter(|m| m.recipient.as_str() == recipient && m.device == device)
+            .filter(|m| m.published.timestamp >= cutoff && !is_expired(&m.published, now))
+            .filter(|m| after.is_none_or(|a| m.published.sequence > a))
+            .take(limit)
+            .map(|m| m.published.clone())
+            .collect()
+    }
+
+    fn find_message(&self, recipient: &str, id: MessageId) -> Option<&PublishedMessage> {
+        self.history
+            .iter()
+            .rev()
+            .find(|m| m.published.id == id && m.recipient.as_str() == recipient)
+            .map(|m| &m.published)
+    }
+
+    fn acknowledge(&mut self, recipient: &str, id: MessageId, kind: ReceiptKind) -> bool {
+        // The same copy `find_message` returns.
+        let stored = self
+            .history
+            .iter_mut()
+            .rev()
+            .find(|m| m.published.id == id && m.recipient.as_str() == recipient);
+        match stored {
+            Some(stored) if !stored.acknowledged.contains(&kind) => {
+                stored.acknowledged.push(kind);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set_retention(&mut self, retention: Duration) {
+        self.retention = retention;
+    }
+
+    fn set_message_ttl(&mut self, ttl: Option<Duration>) {
+        self.message_ttl = ttl;
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.expiries.peek().map(|Reverse(expires)| *expires)
+    }
+
+    fn expire_messages(&mut self, now: u64) -> usize {
+        while matches!(self.expiries.peek(), Some(Reverse(expires)) if *expires <= now) {
+            self.expiries.pop();
+        }
+        let mut expired = HashSet::new();
+        self.history.retain(|m| {
+            if is_expired(&m.published, now) {
+                expired.insert(m.published.id);
+                return false;
+            }
+            true
+        });
+
+        // Copies still on their way go too, along with ones that expired in
+        // the queue after the history was last swept.
+        let mut discarded = 0;
+        for Member { devices } in self.registered_users.values() {
+            for Device { sender, .. } in devices.values() {
+                discarded += sender.discard(|queued| match &queued.message {
+                    APIResponse::PublishMessage(p) | APIResponse::MessageEdited(p) => {
+                        is_expired(p, now)
+                    }
+                    _ => false,
+                });
+            }
+        }
+        if !expired.is_empty() || discarded > 0 {
+            info!(
+                "Room {}: {} message(s) expired ({} queued copies dropped)",
+                self.id,
+                expired.len(),
+                discarded
+            );
+        }
+        expired.len()
+    }
+}
+
+fn is_expired(published: &PublishedMessage, now: u64) -> bool {
+    published.expires.is_some_and(|expires| expires <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::unsigned_key;
+    use crate::outbox::{outbox, OutboxSettings, SlowConsumerPolicy};
+
+    fn member() -> Outbox {
+        outbox(OutboxSettings::default()).0
+    }
+
+    fn laptop(user: &str) -> DeviceKey {
+        unsigned_key(user, "laptop")
+    }
+
+    // A copy for the recipient's laptop.
+    fn copy(recipient: &str, message: u8) -> EncryptedCopy {
+        EncryptedCopy {
+            recipient: recipient.into(),
+            device: laptop(recipient).id(),
+            message: vec![message],
+        }
+    }
+
+    fn room_with_history() -> SimpleChatRoom {
+        let mut room = SimpleChatRoom::build("test".to_string(), 8);
+        room.register_user(laptop("alice"), member()).unwrap();
+        room.register_user(laptop("bob"), member()).unwrap();
+
+        for t in 1..=5 {
+            let copies = vec![copy("alice", t), copy("bob", t + 10)];
+            room.store_message("bob", copies, None, t as u64 * 1000)
+                .unwrap();
+        }
+        room
+    }
+
+    #[test]
+    fn store_message_assigns_one_id_and_sequence_per_message() {
+        let mut room = room_with_history();
+
+        let copies = vec![copy("alice", 0), copy("bob", 0), copy("carol", 0)];
+        let deliveries = room.store_message("alice", copies, None, 6000).unwrap();
+
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries.iter().all(|(_, p)| p.sequence == 6));
+        assert_eq!(deliveries[0].1.id, deliveries[1].1.id);
+        assert_eq!(deliveries[0].1.sender.as_str(), "alice");
+    }
+
+    #[test]
+    fn fetch_history_pages_backwards_per_recipient() {
+        let room = room_with_history();
+
+        let latest = room.fetch_history("alice", &laptop("alice").id(), None, 2, 5000);
+        let sequences: Vec<u64> = latest.iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![4, 5]);
+        assert!(latest.iter().all(|m| m.message[0] < 10));
+
+        let older = room.fetch_history("alice", &laptop("alice").id(), Some(4), 10, 5000);
+        let sequences: Vec<u64> = older.iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn disconnected_member_keeps_their_place_until_they_rejoin() {
+        let mut room = room_with_history();
+        let (carol, carol_rx) = outbox(OutboxSettings::default());
+        room.register_user(laptop("carol"), carol).unwrap();
+        drop(carol_rx);
+        assert!(!room.is_connected("carol"));
+
+        room.store_message("alice", vec![copy("carol", 1)], None, 6000)
+            .unwrap();
+        let (carol, _carol_rx) = outbox(OutboxSettings::default());
+        room.register_user(laptop("carol"), carol.clone()).unwrap();
+        assert!(room.is_connected("carol"));
+        assert!(room.register_user(laptop("carol"), carol).is_err());
+
+        let missed = room.fetch_since("carol", &laptop("carol").id(), Some(5), 10, 6000);
+        let sequences: Vec<u64> = missed.iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![6]);
+    }
+
+    #[test]
+    fn each_device_gets_its_own_copies() {
+        let mut room = room_with_history();
+        let (desktop, desktop_rx) = outbox(OutboxSettings::default());
+        room.register_user(unsigned_key("alice", "desktop"), desktop)
+            .unwrap();
+        assert_eq!(room.info().members, 2);
+        assert_eq!(room.get_roomkeys().unwrap().len(), 3);
+
+        let to_desktop = EncryptedCopy {
+            device: unsigned_key("alice", "desktop").id(),
+            ..copy("alice", 7)
+        };
+        let deliveries = room
+            .store_message("bob", vec![copy("alice", 6), to_desktop], None, 6000)
+            .unwrap();
+        assert_eq!(deliveries.len(), 2);
+        let desktop_id = unsigned_key("alice", "desktop").id();
+        let on_desktop = room.fetch_since("alice", &desktop_id, None, 10, 6000);
+        assert_eq!(on_desktop.len(), 1);
+        assert_eq!(on_desktop[0].message, vec![7]);
+
+        // Only devices whose connection is gone are forgotten, and the member
+        // with the last of them.
+        assert!(!room.forget_devices("alice", None));
+        assert_eq!(room.get_roomkeys().unwrap().len(), 2);
+        assert!(!room.forget_devices("alice", Some(&desktop_id)));
+        drop(desktop_rx);
+        assert!(room.forget_devices("alice", Some(&desktop_id)));
+        assert!(!room.is_registered("alice"));
+    }
+
+    #[test]
+    fn only_authors_edit_and_delete_and_nothing_is_left_behind() {
+        let mut room = room_with_history();
+        let (carol, mut carol_rx) = outbox(OutboxSettings::default());
+        room.register_user(laptop("carol"), carol.clone()).unwrap();
+        let copies = vec![copy("alice", 9), copy("bob", 9), copy("carol", 9)];
+        let published = room.store_message("bob", copies, None, 6000).unwrap();
+        let id = published[0].1.id;
+        for (_, p) in published {
+            carol
+                .try_send(UserMessage {
+                    user_id: "carol".into(),
+                    message: APIResponse::PublishMessage(p),
+                })
+                .unwrap();
+        }
+
+        assert!(room
+            .edit_message("alice", id, vec![copy("alice", 8)])
+            .is_err());
+        // Bob only re-encrypted for alice, so his own copy goes.
+        let edited = room
+            .edit_message("bob", id, vec![copy("alice", 8)])
+            .unwrap();
+        assert_eq!(edited.len(), 1);
+        assert_eq!(edited[0].1.sequence, 6);
+        let alice = room.fetch_since("alice", &laptop("alice").id(), Some(5), 10, 6000);
+        assert_eq!(alice[0].message, vec![8]);
+        assert!(room
+            .fetch_since("bob", &laptop("bob").id(), Some(5), 10, 6000)
+            .is_empty());
+
+        assert!(room.acknowledge("alice", id, ReceiptKind::Read));
+        assert!(!room.acknowledge("alice", id, ReceiptKind::Read));
+        assert!(room.acknowledge("alice", id, ReceiptKind::Delivered));
+        assert!(!room.acknowledge("bob", id, ReceiptKind::Read));
+
+        assert!(room.delete_message("alice", id).is_err());
+        room.delete_message("bob", id).unwrap();
+        assert!(!room.acknowledge("carol", id, ReceiptKind::Read));
+        assert!(room.find_message("alice", id).is_none());
+        assert!(room.find_message("carol", id).is_none());
+        assert!(carol_rx.try_recv().is_none());
+        assert!(room.delete_message("bob", id).is_err());
+    }
+
+    #[test]
+    fn history_expires_after_retention() {
+        let mut room = room_with_history();
+        room.set_retention(Duration::from_secs(2));
+
+        let visible = room.fetch_history("bob", &laptop("bob").id(), None, 10, 5000);
+        let sequences: Vec<u64> = visible.iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![3, 4, 5]);
+
+        room.store_message("bob", vec![copy("bob", 0)], None, 6000)
+            .unwrap();
+        assert_eq!(room.history.len(), 5);
+    }
+
+    #[test]
+    fn messages_expire_after_their_ttl_or_the_rooms_default() {
+        let mut room = room_with_history();
+        let (carol, mut carol_rx) = outbox(OutboxSettings::default());
+        room.register_user(laptop("carol"), carol.clone()).unwrap();
+        room.set_message_ttl(Some(Duration::from_secs(60)));
+
+        let short = Some(Duration::from_secs(10));
+        let published = room
+            .store_message("bob", vec![copy("carol", 1)], short, 6000)
+            .unwrap();
+        assert_eq!(published[0].1.expires, Some(16_000));
+        carol
+            .try_send(UserMessage {
+                user_id: "carol".into(),
+                message: APIResponse::PublishMessage(published[0].1.clone()),
+            })
+            .unwrap();
+        let published = room
+            .store_message("bob", vec![copy("carol", 2)], None, 7000)
+            .unwrap();
+        assert_eq!(published[0].1.expires, Some(67_000));
+        assert_eq!(room.next_expiry(), Some(16_000));
+
+        // Expired messages are hidden even before they are swept.
+        let carol_id = laptop("carol").id();
+        assert_eq!(
+            room.fetch_since("carol", &carol_id, None, 10, 16_000).len(),
+            1
+        );
+        assert_eq!(room.expire_messages(16_000), 1);
+        assert!(carol_rx.try_recv().is_none());
+        assert_eq!(room.next_expiry(), Some(67_000));
+        assert_eq!(room.history.len(), 11);
+    }
+
+    #[tokio::test]
+    async fn publish_skips_members_whose_queue_stays_full() {
+        let mut room = SimpleChatRoom::build("test".to_string(), 8);
+        room.publish_timeout = Duration::from_millis(10);
+        let settings = OutboxSettings {
+            capacity: 1,
+            policy: SlowConsumerPolicy::Block,
+        };
+        let (alice, mut alice_rx) = outbox(settings);
+        let (bob, _bob_rx) = outbox(settings);
+        room.register_user(laptop("alice"), alice).unwrap();
+        room.register_user(laptop("bob"), bob).unwrap();
+
+        let published = room
+            .store_message("alice", vec![copy("alice", 1), copy("bob", 1)], None, 1000)
+            .unwrap();
+        let messages: Vec<(Recipient, APIResponse)> = published
+            .into_iter()
+            .map(|(user, p)| (user, APIResponse::PublishMessage(p)))
+            .collect();
+        assert!(room.publish_message(messages.clone()).await.is_empty());
+
+        // Alice keeps up, bob never reads.
+        alice_rx.recv().await.unwrap();
+        let skipped = room.publish_message(messages).await;
+        assert_eq!(skipped, vec![UserId::from("bob")]);
+    }
+
+    #[test]
+    fn invitations_and_passwords_decide_who_is_admitted() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 8);
+        let password = PasswordHash::new("hunter2");
+        room.set_password(Some(password.clone()));
+        assert!(room.admit("alice").unwrap() == Some(&password));
+
+        room.set_visibility(Visibility::Private);
+        room.set_role("olivia", Role::Owner);
+        room.set_role("gus", Role::Guest);
+        room.invite("bob");
+        assert!(room.admit("alice").is_err());
+        assert!(room.admit("gus").is_err());
+        assert!(room.admit("olivia").unwrap().is_none());
+        assert!(room.admit("bob").unwrap().is_none());
+
+        // Joining uses the invitation up.
+        room.register_user(laptop("bob"), member()).unwrap();
+        room.unregister_user("bob").unwrap();
+        assert!(room.admit("bob").is_err());
+    }
+
+    #[test]
+    fn info_describes_the_room_without_its_secrets() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 4);
+        room.register_user(laptop("alice"), member()).unwrap();
+        room.set_role("olivia", Role::Owner);
+        room.set_role("alice", Role::Owner);
+        room.set_topic(Some("Nothing on fire".to_string()));
+        room.set_password(Some(PasswordHash::new("hunter2")));
+
+        let info = room.info();
+        assert_eq!(info.name, ChatRoomId::from("ops"));
+        assert_eq!(info.topic.as_deref(), Some("Nothing on fire"));
+        assert_eq!(
+            info.owners,
+            vec![UserId::from("alice"), UserId::from("olivia")]
+        );
+        assert_eq!((info.members, info.capacity), (1, 4));
+        assert!(info.password);
+    }
 }
@@ -0,0 +1,561 @@
+//! Server configuration.
+//!
+//! Settings are read from, in order of precedence: command line flags,
+//! `SLYCHAT_*` environment variables, a TOML file passed with `--config`, and
+//! finally built-in defaults. Rooms to create at startup can only be given in
+//! the file:
+//!
+//! ```toml
+//! bind = "0.0.0.0"
+//! port = 9001
+//! log_level = "info"
+//! default_capacity = 64
+//! waiting_room = "waiting"
+//! storage_dir = "/var/lib/slychat"
+//! admin_socket = "/run/slychat/admin.sock"
+//! outbound_queue = 64
+//! slow_consumer = "drop-oldest"
+//! shutdown_timeout_secs = 10
+//! reconnect_after_secs = 5
+//! heartbeat_interval_secs = 15
+//! missed_heartbeats = 3
+//! user_rate = 5.0
+//! user_burst = 20
+//! ip_rate = 20.0
+//! ip_burst = 60
+//! max_connections_per_ip = 8
+//! max_frame_length = 1048576
+//! max_ciphertext = 65536
+//! max_attachment_size = 16777216
+//! attachment_retention_hours = 168
+//!
+//! [tls]
+//! cert = "/etc/slychat/cert.pem"
+//! key = "/etc/slychat/key.pem"
+//!
+//! [[rooms]]
+//! name = "incidents"
+//! topic = "Nothing on fire"
+//! description = "Coordination during outages"
+//! capacity = 16
+//! retention_hours = 720
+//! owner = "alice"
+//! moderators = ["bob"]
+//! visibility = "private"
+//! password = "correct horse"
+//! ```
+//!
+//! A room's `password` is only kept as a salted hash once the server is up,
+//! but the file holding it should still be readable by the server alone.
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use slychat_common::transport::DEFAULT_MAX_FRAME_LENGTH;
+use slychat_common::types::{Heartbeat, Visibility};
+use slychat_common::validation::validate_room_name;
+use std::fmt::Display;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::blobs::{BlobSettings, DEFAULT_ATTACHMENT_RETENTION, DEFAULT_MAX_ATTACHMENT};
+use crate::listeners::{ConnectionSettings, DEFAULT_MAX_CIPHERTEXT};
+use crate::outbox::{OutboxSettings, SlowConsumerPolicy, DEFAULT_OUTBOUND_QUEUE};
+use crate::ratelimit::{RateLimit, DEFAULT_MAX_CONNECTIONS_PER_IP};
+use crate::server::{DEFAULT_CAPACITY, WAITING_ROOM};
+use crate::shutdown::{DEFAULT_RECONNECT_AFTER, DEFAULT_SHUTDOWN_TIMEOUT};
+
+const DEFAULT_BIND: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 9001;
+const DEFAULT_STORAGE_DIR: &str = "slychat_data";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            Self::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            Self::Invalid(s) => write!(f, "Invalid configuration: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Parser, Debug, Default)]
+#[command(name = "slychat_server", about = "Runs a slychat server")]
+pub struct Args {
+    /// TOML configuration file.
+    #[arg(short, long, env = "SLYCHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to accept connections on.
+    #[arg(long, env = "SLYCHAT_BIND")]
+    pub bind: Option<IpAddr>,
+    #[arg(short, long, env = "SLYCHAT_PORT")]
+    pub port: Option<u16>,
+    /// One of off, error, warn, info, debug, trace.
+    #[arg(long, env = "SLYCHAT_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// Capacity of rooms that don't configure their own.
+    #[arg(long, env = "SLYCHAT_DEFAULT_CAPACITY")]
+    pub default_capacity: Option<usize>,
+    /// Room every user is placed in after logging in.
+    #[arg(long, env = "SLYCHAT_WAITING_ROOM")]
+    pub waiting_room: Option<String>,
+    /// PEM certificate chain. Enables TLS together with `--tls-key`.
+    #[arg(long, env = "SLYCHAT_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long, env = "SLYCHAT_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Directory for state that should survive restarts.
+    #[arg(long, env = "SLYCHAT_STORAGE_DIR")]
+    pub storage_dir: Option<PathBuf>,
+    /// Unix socket for operator commands. Defaults to `admin.sock` in the
+    /// storage directory.
+    #[arg(long, env = "SLYCHAT_ADMIN_SOCKET")]
+    pub admin_socket: Option<PathBuf>,
+    /// Events queued per connection before the slow-consumer policy applies.
+    #[arg(long, env = "SLYCHAT_OUTBOUND_QUEUE")]
+    pub outbound_queue: Option<usize>,
+    /// What to do with a connection whose outbound queue is full.
+    #[arg(long, env = "SLYCHAT_SLOW_CONSUMER", value_enum)]
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+    /// Seconds connections get to drain before the server exits on SIGINT or
+    /// SIGTERM.
+    #[arg(long, env = "SLYCHAT_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Seconds clients are told to wait before reconnecting after a shutdown.
+    #[arg(long, env = "SLYCHAT_RECONNECT_AFTER")]
+    pub reconnect_after_secs: Option<u64>,
+    /// Seconds between the pings clients are asked to send.
+    #[arg(long, env = "SLYCHAT_HEARTBEAT_INTERVAL")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Pings in a row a client may miss before it is disconnected.
+    #[arg(long, env = "SLYCHAT_MISSED_HEARTBEATS")]
+    pub missed_heartbeats: Option<u32>,
+    /// Requests per second a user may send, sustained.
+    #[arg(long, env = "SLYCHAT_USER_RATE")]
+    pub user_rate: Option<f64>,
+    /// Requests a user may send in a burst.
+    #[arg(long, env = "SLYCHAT_USER_BURST")]
+    pub user_burst: Option<u32>,
+    /// Requests per second all connections from one address may send, sustained.
+    #[arg(long, env = "SLYCHAT_IP_RATE")]
+    pub ip_rate: Option<f64>,
+    /// Requests all connections from one address may send in a burst.
+    #[arg(long, env = "SLYCHAT_IP_BURST")]
+    pub ip_burst: Option<u32>,
+    #[arg(long, env = "SLYCHAT_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Largest request, in bytes, a client may send. Clients sending more
+    /// are disconnected.
+    #[arg(long, env = "SLYCHAT_MAX_FRAME_LENGTH")]
+    pub max_frame_length: Option<usize>,
+    /// Largest encrypted copy of a message, in bytes.
+    #[arg(long, env = "SLYCHAT_MAX_CIPHERTEXT")]
+    pub max_ciphertext: Option<usize>,
+    /// Largest attachment, in bytes once encrypted.
+    #[arg(long, env = "SLYCHAT_MAX_ATTACHMENT_SIZE")]
+    pub max_attachment_size: Option<u64>,
+    /// Hours attachments are kept after their upload started.
+    #[arg(long, env = "SLYCHAT_ATTACHMENT_RETENTION")]
+    pub attachment_retention_hours: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    log_level: Option<String>,
+    default_capacity: Option<usize>,
+    waiting_room: Option<String>,
+    storage_dir: Option<PathBuf>,
+    admin_socket: Option<PathBuf>,
+    outbound_queue: Option<usize>,
+    slow_consumer: Option<SlowConsumerPolicy>,
+    shutdown_timeout_secs: Option<u64>,
+    reconnect_after_secs: Option<u64>,
+    heartbeat_interval_secs: Option<u64>,
+    missed_heartbeats: Option<u32>,
+    user_rate: Option<f64>,
+    user_burst: Option<u32>,
+    ip_rate: Option<f64>,
+    ip_burst: Option<u32>,
+    max_connections_per_ip: Option<usize>,
+    max_frame_length: Option<usize>,
+    max_ciphertext: Option<usize>,
+    max_attachment_size: Option<u64>,
+    attachment_retention_hours: Option<u64>,
+    tls: Option<TlsConfig>,
+    rooms: Vec<RoomConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A room created when the server starts.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub capacity: Option<usize>,
+    pub retention_hours: Option<u64>,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub moderators: Vec<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    pub password: Option<String>,
+}
+
+impl RoomConfig {
+    /// How long the room keeps messages, if configured. `None` also for a
+    /// number of hours too large to count in seconds, which `validate`
+    /// rejects.
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_hours.and_then(from_hours)
+    }
+}
+
+fn from_hours(hours: u64) -> Option<Duration> {
+    hours.checked_mul(60 * 60).map(Duration::from_secs)
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub log_level: LevelFilter,
+    pub default_capacity: usize,
+    pub waiting_room: String,
+    pub rooms: Vec<RoomConfig>,
+    pub tls: Option<TlsConfig>,
+    pub storage_dir: PathBuf,
+    pub admin_socket: PathBuf,
+    pub connection: ConnectionSettings,
+    pub blobs: BlobSettings,
+    /// Shared by every connection from the same address.
+    pub ip_limit: RateLimit,
+    pub max_connections_per_ip: usize,
+    pub shutdown_timeout: Duration,
+    pub reconnect_after: Duration,
+    // As configured, so `validate` can tell a value too large from the
+    // default.
+    attachment_retention_hours: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the process arguments and environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => FileConfig::default(),
+        };
+        Self::merge(args, file)
+    }
+
+    fn merge(args: Args, file: FileConfig) -> Result<Self, ConfigError> {
+        let log_level = match (args.log_level, file.log_level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => LevelFilter::from_str(&level)
+                .map_err(|_| ConfigError::Invalid(format!("unknown log level {}", level)))?,
+            (None, None) => LevelFilter::Info,
+        };
+
+        let tls = match (args.tls_cert, args.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            _ => file.tls,
+        };
+
+        let storage_dir = args
+            .storage_dir
+            .or(file.storage_dir)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STORAGE_DIR));
+        let default_heartbeat = Heartbeat::default();
+        let attachment_retention_hours = args
+            .attachment_retention_hours
+            .or(file.attachment_retention_hours);
+        let config = Self {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| DEFAULT_BIND.parse().unwrap()),
+            port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            log_level,
+            default_capacity: args
+                .default_capacity
+                .or(file.default_capacity)
+                .unwrap_or(DEFAULT_CAPACITY),
+            waiting_room: args
+                .waiting_room
+                .or(file.waiting_room)
+                .unwrap_or_else(|| WAITING_ROOM.to_string()),
+            rooms: file.rooms,
+            tls,
+            admin_socket: args
+                .admin_socket
+                .or(file.admin_socket)
+                .unwrap_or_else(|| storage_dir.join("admin.sock")),
+            storage_dir,
+            connection: ConnectionSettings {
+                outbound: OutboxSettings {
+                    capacity: args
+                        .outbound_queue
+                        .or(file.outbound_queue)
+                        .unwrap_or(DEFAULT_OUTBOUND_QUEUE),
+                    policy: args
+                        .slow_consumer
+                        .or(file.slow_consumer)
+                        .unwrap_or_default(),
+                },
+                heartbeat: Heartbeat {
+                    interval_secs: args
+                        .heartbeat_interval_secs
+                        .or(file.heartbeat_interval_secs)
+                        .unwrap_or(default_heartbeat.interval_secs),
+                    missed_limit: args
+                        .missed_heartbeats
+                        .or(file.missed_heartbeats)
+                        .unwrap_or(default_heartbeat.missed_limit),
+                },
+                user_limit: RateLimit {
+                    per_second: args
+                        .user_rate
+                        .or(file.user_rate)
+                        .unwrap_or(RateLimit::USER_DEFAULT.per_second),
+                    burst: args
+                        .user_burst
+                        .or(file.user_burst)
+                        .unwrap_or(RateLimit::USER_DEFAULT.burst),
+                },
+                max_frame_length: args
+                    .max_frame_length
+                    .or(file.max_frame_length)
+                    .unwrap_or(DEFAULT_MAX_FRAME_LENGTH),
+                max_ciphertext: args
+                    .max_ciphertext
+                    .or(file.max_ciphertext)
+                    .unwrap_or(DEFAULT_MAX_CIPHERTEXT),
+            },
+            blobs: BlobSettings {
+                max_size: args
+                    .max_attachment_size
+                    .or(file.max_attachment_size)
+                    .unwrap_or(DEFAULT_MAX_ATTACHMENT),
+                retention: attachment_retention_hours
+                    .map_or(Some(DEFAULT_ATTACHMENT_RETENTION), from_hours)
+                    .unwrap_or_default(),
+            },
+            ip_limit: RateLimit {
+                per_second: args
+                    .ip_rate
+                    .or(file.ip_rate)
+                    .unwrap_or(RateLimit::IP_DEFAULT.per_second),
+                burst: args
+                    .ip_burst
+                    .or(file.ip_burst)
+                    .unwrap_or(RateLimit::IP_DEFAULT.burst),
+            },
+            max_connections_per_ip: args
+                .max_connections_per_ip
+                .or(file.max_connections_per_ip)
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP),
+            shutdown_timeout: args
+                .shutdown_timeout_secs
+                .or(file.shutdown_timeout_secs)
+                .map_or(DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+            reconnect_after: args
+                .reconnect_after_secs
+                .or(file.reconnect_after_secs)
+                .map_or(DEFAULT_RECONNECT_AFTER, Duration::from_secs),
+            attachment_retention_hours,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.connection.outbound.capacity == 0 {
+            return Err(ConfigError::Invalid(
+                "outbound_queue must be at least 1".to_string(),
+            ));
+        }
+        if self.connection.heartbeat.interval_secs == 0
+            || self.connection.heartbeat.missed_limit == 0
+        {
+            return Err(ConfigError::Invalid(
+                "heartbeat_interval_secs and missed_heartbeats must be at least 1".to_string(),
+            ));
+        }
+        for limit in [self.connection.user_limit, self.ip_limit] {
+            if limit.per_second <= 0.0 || limit.burst == 0 {
+                return Err(ConfigError::Invalid(
+                    "rates and bursts must be positive".to_string(),
+                ));
+            }
+        }
+        if self.max_connections_per_ip == 0 {
+            return Err(ConfigError::Invalid(
+                "max_connections_per_ip must be at least 1".to_string(),
+            ));
+        }
+        if self.connection.max_ciphertext == 0
+            || self.connection.max_ciphertext >= self.connection.max_frame_length
+        {
+            return Err(ConfigError::Invalid(
+                "max_ciphertext must be at least 1 and below max_frame_length".to_string(),
+            ));
+        }
+        if self
+            .attachment_retention_hours
+            .is_some_and(|hours| from_hours(hours).is_none())
+        {
+            return Err(ConfigError::Invalid(
+                "attachment_retention_hours is too large".to_string(),
+            ));
+        }
+        if self.blobs.max_size == 0 || self.blobs.retention.is_zero() {
+            return Err(ConfigError::Invalid(
+                "max_attachment_size and attachment_retention_hours must be at least 1".to_string(),
+            ));
+        }
+        if self.default_capacity == 0 {
+            return Err(ConfigError::Invalid(
+                "default_capacity must be at least 1".to_string(),
+            ));
+        }
+        validate_room_name(&self.waiting_room)
+            .map_err(|e| ConfigError::Invalid(format!("waiting_room: {}", e)))?;
+        for room in &self.rooms {
+            validate_room_name(&room.name)
+                .map_err(|e| ConfigError::Invalid(format!("room {:?}: {}", room.name, e)))?;
+            if room.name == self.waiting_room {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} is the waiting room",
+                    room.name
+                )));
+            }
+            if room.capacity == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} must have a capacity of at least 1",
+                    room.name
+                )));
+            }
+            if room.retention_hours.is_some() && room.retention().is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} has a retention_hours too large",
+                    room.name
+                )));
+            }
+            if room.password.as_ref().is_some_and(|p| p.is_empty()) {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} has an empty password",
+                    room.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where bans are kept across restarts.
+    pub fn bans_path(&self) -> PathBuf {
+        self.storage_dir.join("bans.json")
+    }
+
+    /// Where the devices users logged in from are kept across restarts.
+    pub fn devices_path(&self) -> PathBuf {
+        self.storage_dir.join("devices.json")
+    }
+
+    /// Where uploaded attachments are kept.
+    pub fn blobs_dir(&self) -> PathBuf {
+        self.storage_dir.join("attachments")
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_file_which_overrides_defaults() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            port = 9100
+            log_level = "warn"
+            default_capacity = 8
+            slow_consumer = "disconnect"
+
+            [[rooms]]
+            name = "incidents"
+            retention_hours = 2
+            visibility = "hidden"
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            port: Some(9200),
+            ..Args::default()
+        };
+
+        let config = ServerConfig::merge(args, file).unwrap();
+        assert_eq!(config.port, 9200);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.default_capacity, 8);
+        assert_eq!(config.waiting_room, WAITING_ROOM);
+        assert_eq!(
+            config.connection.outbound.policy,
+            SlowConsumerPolicy::Disconnect
+        );
+        assert_eq!(config.rooms[0].retention(), Some(Duration::from_secs(7200)));
+        assert_eq!(config.rooms[0].visibility, Visibility::Hidden);
+    }
+
+    #[test]
+    fn retention_too_large_to_count_is_rejected() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            [[rooms]]
+            name = "incidents"
+            retention_hours = 9223372036854775807
+            "#,
+        )
+        .unwrap();
+        assert!(ServerConfig::merge(Args::default(), file).is_err());
+
+        let args = Args {
+            attachment_retention_hours: Some(u64::MAX / 60),
+            ..Args::default()
+        };
+        assert!(ServerConfig::merge(args, FileConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::storage::write_json_atomically;
+use serde::{Deserialize, Serialize};
+use slychat_common::types::{DeviceInfo, DeviceKey};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum DeviceError {
+    /// The device key was signed by another identity than the one the user
+    /// first logged in with.
+    IdentityMismatch,
+    Revoked,
+    NotFound(String),
+    // More than one device matches the prefix given.
+    Ambiguous(String),
+    Storage(io::Error),
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IdentityMismatch => {
+                write!(
+                    f,
+                    "This username is registered to a different identity key."
+                )
+            }
+            Self::Revoked => write!(f, "This device was revoked."),
+            Self::NotFound(id) => write!(f, "No device matches {}.", id),
+            Self::Ambiguous(id) => write!(f, "More than one device matches {}.", id),
+            Self::Storage(e) => write!(f, "Could not record devices: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<io::Error> for DeviceError {
+    fn from(e: io::Error) -> Self {
+        Self::Storage(e)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Device {
+    name: String,
+    last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct UserDevices {
+    // The identity key every device of the user has to be signed by, pinned
+    // when the user first logs in.
+    identity: Vec<u8>,
+    devices: BTreeMap<String, Device>,
+    revoked: BTreeSet<String>,
+}
+
+/// The devices each user has logged in from, by device id. Owned by the
+/// server actor and backed by a JSON file if loaded from one, which is
+/// rewritten on every change.
+#[derive(Default)]
+pub struct Devices {
+    path: Option<PathBuf>,
+    users: BTreeMap<String, UserDevices>,
+}
+
+impl Devices {
+    /// Loads the devices kept at `path`. A missing file means none are known.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let users = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            users,
+        })
+    }
+
+    /// Records that the device with `key` logged in at `now`, in milliseconds
+    /// since the unix epoch. The first device a user logs in with pins their
+    /// identity key; devices signed by any other, or revoked ones, are
+    /// refused.
+    pub fn enroll(&mut self, key: &DeviceKey, now: u64) -> Result<(), DeviceError> {
+        let id = key.id();
+        let user = self.users.entry(key.user.clone()).or_default();
+        if user.identity.is_empty() {
+            user.identity = key.identity.clone();
+        } else if user.identity != key.identity {
+            return Err(DeviceError::IdentityMismatch);
+        }
+        if user.revoked.contains(&id) {
+            return Err(DeviceError::Revoked);
+        }
+        user.devices.insert(
+            id,
+            Device {
+                name: key.name.clone(),
+                last_seen: now,
+            },
+        );
+        self.save()
+    }
+
+    /// Records that `user`'s device `id` went offline at `now`.
+    pub fn seen(&mut self, user: &str, id: &str, now: u64) -> Result<(), DeviceError> {
+        let device = self
+            .users
+            .get_mut(user)
+            .and_then(|user| user.devices.get_mut(id));
+        match device {
+            Some(device) => device.last_seen = now,
+            None => return Err(DeviceError::NotFound(id.to_string())),
+        }
+        self.save()
+    }
+
+    /// `user`'s devices, most recently seen first. None are shown as online.
+    pub fn list(&self, user: &str) -> Vec<DeviceInfo> {
+        let mut devices: Vec<DeviceInfo> = self
+            .users
+            .get(user)
+            .into_iter()
+            .flat_map(|user| &user.devices)
+            .map(|(id, device)| DeviceInfo {
+                id: id.clone(),
+                name: device.name.clone(),
+                online: false,
+                last_seen: device.last_seen,
+            })
+            .collect();
+        devices.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.id.cmp(&b.id)));
+        devices
+    }
+
+    /// Finds `user`'s device whose id starts with `prefix`.
+    pub fn find(&self, user: &str, prefix: &str) -> Result<DeviceInfo, DeviceError> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut matching = self
+            .list(user)
+            .into_iter()
+            .filter(|device| device.id.starts_with(&prefix));
+        match (matching.next(), matching.next()) {
+            (Some(device), None) => Ok(device),
+            (Some(_), Some(_)) => Err(DeviceError::Ambiguous(prefix)),
+            (None, _) => Err(DeviceError::NotFound(prefix)),
+        }
+    }
+
+    /// Forgets `user`'s device `id` and refuses it from now on.
+    pub fn revoke(&mut self, user: &str, id: &str) -> Result<(), DeviceError> {
+        let Some(devices) = self.users.get_mut(user) else {
+            return Err(DeviceError::NotFound(id.to_string()));
+        };
+        if devices.devices.remove(id).is_none() {
+            return Err(DeviceError::NotFound(id.to_string()));
+        }
+        devices.revoked.insert(id.to_string());
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), DeviceError> {
+        if let Some(path) = &self.path {
+            write_json_atomically(path, &self.users)?;
+        }
+        Ok(())
+    }
+}
+
+/// A device key for tests, which is not actually signed. Its id depends on
+/// `name`, so each name is a different device.
+#[cfg(test)]
+pub(crate) fn unsigned_key(user: &str, name: &str) -> DeviceKey {
+    DeviceKey {
+        user: user.to_string(),
+        name: name.to_string(),
+        identity: user.as_bytes().to_vec(),
+        public: name.as_bytes().to_vec(),
+        signature: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_are_pinned_and_revoked_devices_refused() {
+        let dir = std::env::temp_dir().join(format!("slychat-devices-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("devices.json");
+
+        let mut devices = Devices::load(&path).unwrap();
+        let laptop = unsigned_key("alice", "laptop");
+        let desktop = unsigned_key("alice", "desktop");
+        devices.enroll(&laptop, 1000).unwrap();
+        devices.enroll(&desktop, 2000).unwrap();
+        let impostor = DeviceKey {
+            identity: b"mallory".to_vec(),
+            ..unsigned_key("alice", "phone")
+        };
+        assert!(matches!(
+            devices.enroll(&impostor, 3000),
+            Err(DeviceError::IdentityMismatch)
+        ));
+
+        let names: Vec<String> = devices.list("alice").into_iter().map(|d| d.name).collect();
+        assert_eq!(names, vec!["desktop", "laptop"]);
+        assert!(matches!(
+            devices.find("alice", ""),
+            Err(DeviceError::Ambiguous(_))
+        ));
+        let id = devices.find("alice", &laptop.id()[..8]).unwrap().id;
+        devices.revoke("alice", &id).unwrap();
+
+        let mut reloaded = Devices::load(&path).unwrap();
+        assert!(matches!(
+            reloaded.enroll(&laptop, 4000),
+            Err(DeviceError::Revoked)
+        ));
+        assert_eq!(reloaded.list("alice").len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+use slychat_common::types::{
+    ChatRoomId, DeviceInfo, DeviceKey, Invitation, Presence, PresenceState, UserId,
+};
+use std::net::IpAddr;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::bans::BanTarget;
+use crate::outbox::{Outbox, QueueMetrics};
+use crate::room::{Broadcast, RoomHandle};
+use crate::server::{Announcement, ConnectionInfo, Login, ServerError};
+
+/// The channel an actor sends its answer to a command back on.
+pub type Reply<T> = oneshot::Sender<Result<T, ServerError>>;
+
+/// Sends the command built by `command` to an actor and waits for its reply.
+pub(crate) async fn request<C, T>(
+    actor: &Sender<C>,
+    command: impl FnOnce(Reply<T>) -> C,
+) -> Result<T, ServerError> {
+    let (reply, response) = oneshot::channel();
+    actor
+        .send(command(reply))
+        .await
+        .map_err(|_| ServerError::Unavailable)?;
+    response.await.map_err(|_| ServerError::Unavailable)?
+}
+
+/// A request to the server actor. Each variant mirrors a `Server` method and
+/// carries the channel its result is sent back on.
+pub enum ServerCommand {
+    RegisterUser {
+        key: DeviceKey,
+        address: IpAddr,
+        sender: Outbox,
+        resume: Option<String>,
+        reply: Reply<Login>,
+    },
+    UnregisterUser {
+        user: String,
+        device: String,
+        resumable: bool,
+        reply: Reply<Option<Announcement>>,
+    },
+    AnnouncePresence {
+        user: String,
+        reply: Reply<Option<Announcement>>,
+    },
+    SetPresence {
+        user: String,
+        state: PresenceState,
+        status: Option<String>,
+        reply: Reply<Option<Announcement>>,
+    },
+    SetReadReceipts {
+        user: String,
+        enabled: bool,
+        reply: Reply<()>,
+    },
+    SendsReadReceipts {
+        user: String,
+        reply: Reply<bool>,
+    },
+    Typing {
+        user: String,
+        room: String,
+        reply: Reply<Option<Broadcast>>,
+    },
+    PresenceOf {
+        users: Vec<UserId>,
+        reply: Reply<Vec<Presence>>,
+    },
+    GetRoom {
+        room: String,
+        reply: Reply<RoomHandle>,
+    },
+    WaitingRoom {
+        reply: Reply<RoomHandle>,
+    },
+    JoinedRoom {
+        user: String,
+        device: String,
+        room: ChatRoomId,
+        reply: Reply<Option<Broadcast>>,
+    },
+    LeftRoom {
+        user: String,
+        device: String,
+        room: ChatRoomId,
+        reply: Reply<()>,
+    },
+    Invite {
+        user: String,
+        invitation: Invitation,
+        reply: Reply<()>,
+    },
+    Invitations {
+        user: String,
+        reply: Reply<Vec<Invitation>>,
+    },
+    QueueMetrics {
+        reply: Reply<Vec<(UserId, QueueMetrics)>>,
+    },
+    Connections {
+        reply: Reply<Vec<ConnectionInfo>>,
+    },
+    Rooms {
+        reply: Reply<Vec<RoomHandle>>,
+    },
+    Kick {
+        target: BanTarget,
+        reason: String,
+        reply: Reply<Vec<UserId>>,
+    },
+    DeleteRoom {
+        room: String,
+        reply: Reply<()>,
+    },
+    RenameRoom {
+        room: String,
+        name: String,
+        by: String,
+        reply: Reply<()>,
+    },
+    RemoveFromRoom {
+        user: String,
+        room: String,
+        reason: String,
+        reply: Reply<()>,
+    },
+    Notice {
+        text: String,
+        reply: Reply<usize>,
+    },
+    ListDevices {
+        user: String,
+        reply: Reply<Vec<DeviceInfo>>,
+    },
+    RevokeDevice {
+        user: String,
+        device: String,
+        current: String,
+        reply: Reply<DeviceInfo>,
+    },
+}
+
+/// Creates the command channel between connection tasks and the server actor.
+/// The receiver is handed to `Server`, which then runs `receive_loop`.
+pub fn channel(buffer: usize) -> (ServerHandle, Receiver<ServerCommand>) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    (ServerHandle { sender }, receiver)
+}
+
+/// Cheap to clone handle that connection tasks use to talk to the server actor.
+/// Room scoped requests go straight to the room, see `RoomHandle`.
+#[derive(Clone)]
+pub struct ServerHandle {
+    sender: Sender<ServerCommand>,
+}
+
+impl ServerHandle {
+    /// Registers the device with `key` connected from `address`, resuming its
+    /// earlier session if `resume` is its token. The caller still has to join
+    /// the rooms it was placed in.
+    pub async fn register_user(
+        &self,
+        key: DeviceKey,
+        address: IpAddr,
+        sender: Outbox,
+        resume: Option<String>,
+    ) -> Result<Login, ServerError> {
+        request(&self.sender, |reply| ServerCommand::RegisterUser {
+            key,
+            address,
+            sender,
+            resume,
+            reply,
+        })
+        .await
+    }
+
+    /// Unregisters one of `user`'s devices. If `resumable`, it keeps its
+    /// place in their rooms for a while in case it reconnects.
+    pub async fn unregister_user(
+        &self,
+        user: &str,
+        device: &str,
+        resumable: bool,
+    ) -> Result<Option<Announcement>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::UnregisterUser {
+            user: user.to_string(),
+            device: device.to_string(),
+            resumable,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn announce_presence(&self, user: &str) -> Result<Option<Announcement>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::AnnouncePresence {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_presence(
+        &self,
+        user: &str,
+        state: PresenceState,
+        status: Option<String>,
+    ) -> Result<Option<Announcement>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::SetPresence {
+            user: user.to_string(),
+            state,
+            status,
+            reply,
+        })
+        .await
+    }
+
+    /// Lets others know when `user` reads their messages, on every device,
+    /// or stops to.
+    pub async fn set_read_receipts(&self, user: &str, enabled: bool) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::SetReadReceipts {
+            user: user.to_string(),
+            enabled,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn sends_read_receipts(&self, user: &str) -> Result<bool, ServerError> {
+        request(&self.sender, |reply| ServerCommand::SendsReadReceipts {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn typing(&self, user: &str, room: &str) -> Result<Option<Broadcast>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Typing {
+            user: user.to_string(),
+            room: room.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn presence_of(&self, users: Vec<UserId>) -> Result<Vec<Presence>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::PresenceOf {
+            users,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn queue_metrics(&self) -> Result<Vec<(UserId, QueueMetrics)>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::QueueMetrics { reply }).await
+    }
+
+    pub async fn get_room(&self, room: &str) -> Result<RoomHandle, ServerError> {
+        request(&self.sender, |reply| ServerCommand::GetRoom {
+            room: room.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn waiting_room(&self) -> Result<RoomHandle, ServerError> {
+        request(&self.sender, |reply| ServerCommand::WaitingRoom { reply }).await
+    }
+
+    /// Records that `user` is now in `room`, which they have already joined
+    /// from `device`. Returns the notification announcing them there.
+    pub async fn joined_room(
+        &self,
+        user: &str,
+        device: &str,
+        room: ChatRoomId,
+    ) -> Result<Option<Broadcast>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::JoinedRoom {
+            user: user.to_string(),
+            device: device.to_string(),
+            room,
+            reply,
+        })
+        .await
+    }
+
+    /// Records that `user` is no longer in `room`, which they have already
+    /// left from `device`.
+    pub async fn left_room(
+        &self,
+        user: &str,
+        device: &str,
+        room: ChatRoomId,
+    ) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::LeftRoom {
+            user: user.to_string(),
+            device: device.to_string(),
+            room,
+            reply,
+        })
+        .await
+    }
+
+    /// Holds `invitation` for `user` and tells them about it.
+    pub async fn invite(&self, user: &str, invitation: Invitation) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::Invite {
+            user: user.to_string(),
+            invitation,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn invitations(&self, user: &str) -> Result<Vec<Invitation>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Invitations {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn connections(&self) -> Result<Vec<ConnectionInfo>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Connections { reply }).await
+    }
+
+    pub async fn rooms(&self) -> Result<Vec<RoomHandle>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Rooms { reply }).await
+    }
+
+    /// Disconnects everyone matching `target`. Returns who was kicked.
+    pub async fn kick(&self, target: BanTarget, reason: &str) -> Result<Vec<UserId>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Kick {
+            target,
+            reason: reason.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn delete_room(&self, room: &str) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::DeleteRoom {
+            room: room.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Registers `room` under `name` instead, for everyone in it or invited
+    /// to it. Whether `by` may rename it is up to the room.
+    pub async fn rename_room(&self, room: &str, name: &str, by: &str) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::RenameRoom {
+            room: room.to_string(),
+            name: name.to_string(),
+            by: by.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Takes `user` out of `room`, if they are in it, along with their role
+    /// and any invitation there.
+    pub async fn remove_from_room(
+        &self,
+        user: &str,
+        room: &str,
+        reason: &str,
+    ) -> Result<(), ServerError> {
+        request(&self.sender, |reply| ServerCommand::RemoveFromRoom {
+            user: user.to_string(),
+            room: room.to_string(),
+            reason: reason.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Sends a notice to every connected user. Returns how many got it.
+    pub async fn notice(&self, text: &str) -> Result<usize, ServerError> {
+        request(&self.sender, |reply| ServerCommand::Notice {
+            text: text.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn list_devices(&self, user: &str) -> Result<Vec<DeviceInfo>, ServerError> {
+        request(&self.sender, |reply| ServerCommand::ListDevices {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Revokes `user`'s device whose id starts with `device`, unless it is
+    /// the `current` one.
+    pub async fn revoke_device(
+        &self,
+        user: &str,
+        device: &str,
+        current: &str,
+    ) -> Result<DeviceInfo, ServerError> {
+        request(&self.sender, |reply| ServerCommand::RevokeDevice {
+            user: user.to_string(),
+            device: device.to_string(),
+            current: current.to_string(),
+            reply,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatroom::SimpleChatRoom;
+    use crate::devices::unsigned_key;
+    use crate::outbox::{outbox, OutboxSettings};
+    use crate::server::Server;
+    use slychat_common::types::{APIResponse, EncryptedCopy};
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn requests_are_answered_by_the_actors() {
+        let (handle, rx) = channel(8);
+        let server = tokio::spawn(Server::<SimpleChatRoom>::build(rx).receive_loop());
+
+        let (alice, mut alice_rx) = outbox(OutboxSettings::default());
+        let laptop = unsigned_key("alice", "laptop");
+        let room = handle
+            .register_user(laptop.clone(), LOCALHOST, alice.clone(), None)
+            .await
+            .unwrap()
+            .rooms
+            .remove(0);
+        room.join(laptop.clone(), alice).await.unwrap();
+        let (other, _other_rx) = outbox(OutboxSettings::default());
+        let duplicate = handle.register_user(laptop.clone(), LOCALHOST, other, None);
+        assert!(duplicate.await.is_err());
+
+        let copies = vec![EncryptedCopy {
+            recipient: "alice".into(),
+            device: laptop.id(),
+            message: vec![1],
+        }];
+        let id = room.publish("alice", copies, None).await.unwrap();
+        match alice_rx.recv().await.unwrap().message {
+            APIResponse::PublishMessage(m) => assert_eq!((m.id, m.room), (id, room.id.clone())),
+            other => panic!("Unexpected delivery {:?}", other),
+        }
+
+        drop((handle, room));
+        server.await.unwrap();
+    }
+}
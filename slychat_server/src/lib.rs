@@ -0,0 +1,17 @@
+pub mod admin;
+pub mod bans;
+pub mod blobs;
+pub mod chatroom;
+pub mod config;
+pub mod devices;
+pub mod handle;
+pub mod listeners;
+pub mod membership;
+pub mod outbox;
+pub mod password;
+pub mod ratelimit;
+pub mod room;
+pub mod server;
+pub mod shutdown;
+pub mod storage;
+pub mod throttle;
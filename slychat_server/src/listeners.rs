@@ -1,20 +1,30 @@
 use std::fmt::Display;
 
-use log::info;
-use slychat_common::transport::{read_command, send_command, TransportError};
-use slychat_common::types::{APICommand, APIRequest, APIResponse, Response, UserKey};
-use tokio::select;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    net::TcpStream,
+use log::{info, warn};
+use slychat_common::encryption::{random_bytes, verify};
+use slychat_common::transport::{
+    send_command, CommandReader, TransportError, DEFAULT_MAX_FRAME_LENGTH,
 };
-
-use crate::server::{UserId, UserMessage};
-use crate::ServerMutex;
-use crate::{
-    chatroom::ChatRoom,
-    server::{self, Server},
+use slychat_common::types::{
+    deletion_data, login_data, APIRequest, APIResponse, BlobChunk, BlobId, ChatRoomId, DeviceKey,
+    Heartbeat, Invitation, LoginSession, Permission, ReceiptKind, Response, RoomInfo, Visibility,
+    MAX_BLOB_CHUNK,
 };
+use slychat_common::validation::{validate_room_name, validate_username};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::select;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use crate::blobs::Blobs;
+use crate::handle::ServerHandle;
+use crate::outbox::{outbox, Outbox, OutboxReceiver, OutboxSettings};
+use crate::ratelimit::{ConnectionPermit, RateLimit, UserLimits, Verdict};
+use crate::room::RoomHandle;
+use crate::server::{unix_millis, Login, ServerError};
+use crate::shutdown::{ShutdownListener, ShutdownNotice};
 
 #[derive(Debug, Clone)]
 pub enum ListenerError {
@@ -33,65 +43,521 @@ impl Display for ListenerError {
 
 impl std::error::Error for ListenerError {}
 
-pub async fn process<G: ChatRoom>(
-    socket: TcpStream,
-    server: ServerMutex<G>,
-) -> Result<(), ListenerError> {
-    let (mut reader, mut writer) = tokio::io::split(socket);
+pub const DEFAULT_MAX_CIPHERTEXT: usize = 64 * 1024;
+/// Comfortably above the size of any RSA public key a client would use.
+const MAX_PUBLIC_KEY_LEN: usize = 4096;
+/// Likewise for signatures made with such a key.
+const MAX_SIGNATURE_LEN: usize = 1024;
+const MAX_DEVICE_NAME_LEN: usize = 32;
+const LOGIN_CHALLENGE_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 128;
+const MAX_TOPIC_LEN: usize = 256;
+/// A year, well past how long any room keeps its history.
+const MAX_MESSAGE_TTL_SECS: u64 = 365 * 24 * 60 * 60;
 
-    let (sender, mut receiver) = tokio::sync::mpsc::channel(64);
+/// Settings shared by every connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    pub outbound: OutboxSettings,
+    /// Clients that stay silent for longer than the heartbeat timeout are
+    /// disconnected.
+    pub heartbeat: Heartbeat,
+    /// How fast a single user may send requests.
+    pub user_limit: RateLimit,
+    /// Clients sending a longer frame are disconnected.
+    pub max_frame_length: usize,
+    /// Messages with a larger copy for any recipient are rejected.
+    pub max_ciphertext: usize,
+}
 
-    // Handle greeting from socket
-    let key = wait_for_greeting(&mut reader, &writer).await?;
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            outbound: OutboxSettings::default(),
+            heartbeat: Heartbeat::default(),
+            user_limit: RateLimit::USER_DEFAULT,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_ciphertext: DEFAULT_MAX_CIPHERTEXT,
+        }
+    }
+}
 
-    if register_user(&key.user, key.public, &mut writer, sender, &server)
-        .await
-        .is_err()
+/// Serves a single client connection, either a plain TCP or a TLS stream.
+/// `permit` ties the connection to the limits of the address it came from,
+/// and `users` holds the limits of whoever logs in on it.
+/// When `shutdown` fires, queued events are written out and the client is
+/// told the server is going away.
+pub async fn process<S>(
+    socket: S,
+    server: ServerHandle,
+    blobs: Blobs,
+    settings: ConnectionSettings,
+    permit: ConnectionPermit,
+    users: UserLimits,
+    mut shutdown: ShutdownListener,
+) -> Result<(), ListenerError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = CommandReader::with_max_frame_length(reader, settings.max_frame_length);
+
+    let (sender, mut receiver) = outbox(settings.outbound);
+    let timeout = settings.heartbeat.timeout();
+
+    // The greeting has to be signed for a fresh challenge, so it can't be
+    // replayed from another connection.
+    let challenge = random_bytes(LOGIN_CHALLENGE_LEN);
+    if let Err(e) = send_command(&mut writer, &APIResponse::LoginChallenge(challenge.clone())).await
     {
-        eprintln!("Registration Failed");
+        return Err(ListenerError::Transport(e));
     }
 
+    // Handle greeting from socket
+    let greeting = select! {
+        greeting = wait_for_greeting(&mut reader) => greeting,
+        _ = sleep(timeout) => return Err(ListenerError::Error("No greeting")),
+        _ = shutdown.notified() => return Ok(()),
+    };
+    let Greeting {
+        key,
+        signature,
+        resume,
+    } = match greeting {
+        Ok(greeting) => greeting,
+        Err(ListenerError::Transport(e @ TransportError::FrameTooLarge(_))) => {
+            reject(&mut writer, &e, timeout).await;
+            return Err(ListenerError::Transport(e));
+        }
+        Err(e) => return Err(e),
+    };
+    if let Err(reason) = check_key(&key).and_then(|()| check_login(&key, &challenge, &signature)) {
+        let response = APIResponse::LoginResponse(Response::Error(reason));
+        let _ = send_command(&mut writer, &response).await;
+        return Err(ListenerError::Error("Invalid greeting"));
+    }
+    let (token, after) = resume.unzip();
+
+    let device = key.id();
+    let login = register_user(
+        &key,
+        permit.ip(),
+        token,
+        settings.heartbeat,
+        &mut writer,
+        sender.clone(),
+        &server,
+    );
+    let login = match login.await {
+        Ok(login) => login,
+        Err(e) => {
+            warn!("Registration of {} failed: {}", key.user, e);
+            return Err(ListenerError::Error("Registration failed"));
+        }
+    };
+    if login.resumed {
+        // Catch up on what was said while the client was away, ahead of
+        // anything already queued.
+        let after = after.unwrap_or_default();
+        for room in &login.rooms {
+            let missed = room
+                .replay(&key.user, &device, after.get(&room.id).copied())
+                .await;
+            for message in missed.unwrap_or_default() {
+                let event = APIResponse::PublishMessage(message);
+                if send_command(&mut writer, &event).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    if let Ok(Some(arrival)) = server.announce_presence(&key.user).await {
+        arrival.send().await;
+    }
+
+    let mut session = Session {
+        user: key.user.clone(),
+        device,
+        key,
+        sender,
+    };
+    // Whether the session is over for good because the client logged out or
+    // was kicked, rather than the connection dropping.
+    let mut ended = false;
+    let mut last_heard = Instant::now();
+    let mut limiter = users.limiter(&session.user.as_str().into(), permit);
+
     // Start main loop
-    let mut buffer: Vec<u8> = vec![0; 1024];
     loop {
-        buffer.clear();
-
         select! {
-            data = read_command(&mut reader) => {
-                match process_socket_read(data,  &key.user, &server) {
+            data = reader.read() => {
+                last_heard = Instant::now();
+                if let Err(e @ TransportError::FrameTooLarge(_)) = &data {
+                    warn!("{} sent an oversized frame. Disconnecting.", session.user);
+                    reject(&mut writer, e, timeout).await;
+                    break;
+                }
+                if let Ok(request) = &data {
+                    match limiter.check(request, last_heard.into_std()) {
+                        Verdict::Allow => {}
+                        Verdict::Limited(retry_after) => {
+                            let limited = APIResponse::RateLimited {
+                                retry_after: retry_after.as_millis() as u64,
+                            };
+                            if !write_response(&mut writer, &limited, &receiver, timeout).await {
+                                break;
+                            }
+                            continue;
+                        }
+                        Verdict::Flooding => {
+                            warn!("{} keeps flooding. Disconnecting.", session.user);
+                            break;
+                        }
+                    }
+                }
+                let handled = match data {
+                    Ok(request) => match check_request(request, &settings) {
+                        Ok(request) => process_socket_read(Ok(request), &mut session, &server, &blobs).await,
+                        Err(rejection) => Ok(rejection),
+                    },
+                    Err(e) => process_socket_read(Err(e), &mut session, &server, &blobs).await,
+                };
+                match handled {
                     Ok(SocketReadHandle::Response(r)) => {
-                        if send_command(&mut writer, &r).await.is_err() {
-                        eprintln!("Error encoding command: {:?}", r)
+                        if !write_response(&mut writer, &r, &receiver, timeout).await {
+                            break;
                         }
                     },
-                    Ok(_) => {println!("Logout Requested. Logging out safely."); break}
-                    Err(_) => {eprintln!("Error occurred during socket read. Forcing logout");break},
+                    Ok(SocketReadHandle::NoResponse) => {},
+                    Ok(SocketReadHandle::Logout) => {
+                        info!("{} logged out", session.user);
+                        ended = true;
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Could not read from {}: {}. Forcing logout", session.user, e);
+                        break;
+                    }
                 }
             },
             message_data = receiver.recv() => {
-                if let Some(message) = message_data {
-                    // Format message and send to socket
-                    assert!(message.user_id == key.user);
+                let Some(message) = message_data else {
+                    warn!("{} fell too far behind. Disconnecting.", session.user);
+                    break;
+                };
+                // Format message and send to socket
+                assert!(message.user_id == session.user);
 
-                    let response = APIResponse::PublishMessage(message.message);
-                    if send_command(&mut writer, &response).await.is_err() {
-                    eprintln!("Error encoding command: {:?}", response)
-                    }
+                if !write_response(&mut writer, &message.message, &receiver, timeout).await {
+                    break;
+                }
+                if let APIResponse::Kicked { .. } = message.message {
+                    ended = true;
+                    break;
                 }
             }
-            // data = test = receiver.recv() {
+            _ = sleep_until(last_heard + timeout) => {
+                warn!(
+                    "{} missed {} heartbeats. Disconnecting.",
+                    session.user, settings.heartbeat.missed_limit
+                );
+                break;
+            }
+            notice = shutdown.notified() => {
+                if flush_outbox(&mut writer, &mut receiver, timeout).await {
+                    let event = shutdown_event(notice);
+                    write_response(&mut writer, &event, &receiver, timeout).await;
+                }
+                break;
+            }
+        };
+    }
 
-            // }
+    // Lets a resumed session take over the user's places in their rooms, or
+    // the server give them up if the session is over.
+    drop(receiver);
+    match server
+        .unregister_user(&session.user, &session.device, !ended)
+        .await
+    {
+        Ok(Some(departure)) => departure.send().await,
+        Ok(None) => {}
+        Err(e) => warn!("Failed to unregister {}: {}", session.user, e),
+    }
 
-        };
+    Ok(())
+}
+
+// Writes `response` to the client. Returns false if the client was
+// disconnected for falling behind while the write was stuck, or the write
+// took longer than `timeout`.
+async fn write_response<S: AsyncWrite + Send + 'static>(
+    writer: &mut WriteHalf<S>,
+    response: &APIResponse,
+    receiver: &OutboxReceiver,
+    timeout: Duration,
+) -> bool {
+    select! {
+        written = send_command(writer, response) => {
+            if written.is_err() {
+                eprintln!("Error encoding command: {:?}", response)
+            }
+            true
+        }
+        _ = receiver.closed() => false,
+        _ = sleep(timeout) => {
+            warn!("Write to client stalled. Disconnecting.");
+            false
+        }
     }
-    // TODO: Unregister user here.
+}
 
+// Writes out whatever is still queued for the client. Returns false if the
+// client was disconnected meanwhile.
+async fn flush_outbox<S: AsyncWrite + Send + 'static>(
+    writer: &mut WriteHalf<S>,
+    receiver: &mut OutboxReceiver,
+    timeout: Duration,
+) -> bool {
+    while let Some(message) = receiver.try_recv() {
+        if !write_response(writer, &message.message, receiver, timeout).await {
+            return false;
+        }
+    }
+    true
+}
+
+// Tells the client why it is being disconnected.
+async fn reject<S: AsyncWrite + Send + 'static>(
+    writer: &mut WriteHalf<S>,
+    error: &TransportError,
+    timeout: Duration,
+) {
+    let rejected = APIResponse::Rejected {
+        reason: error.to_string(),
+    };
+    let _ = tokio::time::timeout(timeout, send_command(writer, &rejected)).await;
+}
+
+fn check_key(key: &DeviceKey) -> Result<(), String> {
+    validate_username(&key.user).map_err(|e| e.to_string())?;
+    if key.public.len() > MAX_PUBLIC_KEY_LEN || key.identity.len() > MAX_PUBLIC_KEY_LEN {
+        return Err(format!(
+            "Public keys must be at most {} bytes",
+            MAX_PUBLIC_KEY_LEN
+        ));
+    }
+    if key.signature.len() > MAX_SIGNATURE_LEN {
+        return Err(format!(
+            "Device signature must be at most {} bytes",
+            MAX_SIGNATURE_LEN
+        ));
+    }
+    let name = key.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_DEVICE_NAME_LEN {
+        return Err(format!(
+            "Device name must be between 1 and {} characters",
+            MAX_DEVICE_NAME_LEN
+        ));
+    }
+    if !key.is_linked() {
+        return Err("Device key is not signed by the identity key".to_string());
+    }
+    Ok(())
+}
+
+// Checks that the greeting was signed by the device it names, for this
+// connection's `challenge`.
+fn check_login(key: &DeviceKey, challenge: &[u8], signature: &[u8]) -> Result<(), String> {
+    if signature.len() > MAX_SIGNATURE_LEN
+        || !verify(&login_data(challenge), signature, &key.public)
+    {
+        return Err("Login is not signed by the device key".to_string());
+    }
     Ok(())
 }
 
+// Passes `request` through if it is within the connection's limits, and
+// otherwise returns the error response to send in its place.
+fn check_request(
+    request: APIRequest,
+    settings: &ConnectionSettings,
+) -> Result<APIRequest, SocketReadHandle> {
+    let problem = match &request {
+        APIRequest::SendMessageRequest { copies, .. }
+        | APIRequest::EditMessageRequest { copies, .. }
+            if copies
+                .iter()
+                .any(|copy| copy.message.len() > settings.max_ciphertext) =>
+        {
+            Some(format!(
+                "Encrypted message must be at most {} bytes",
+                settings.max_ciphertext
+            ))
+        }
+        APIRequest::UploadChunkRequest { data, .. } if data.len() > MAX_BLOB_CHUNK => Some(
+            format!("Attachment chunks must be at most {} bytes", MAX_BLOB_CHUNK),
+        ),
+        APIRequest::InviteUserRequest { room, user } => validate_room_name(room)
+            .and(validate_username(user))
+            .err()
+            .map(|e| e.to_string()),
+        APIRequest::RenameRoomRequest { room, name } => validate_room_name(room)
+            .and(validate_room_name(name))
+            .err()
+            .map(|e| e.to_string()),
+        APIRequest::JoinRoomRequest {
+            password: Some(password),
+            ..
+        }
+        | APIRequest::SetPasswordRequest {
+            password: Some(password),
+            ..
+        } if password.is_empty() || password.len() > MAX_PASSWORD_LEN => Some(format!(
+            "Password must be between 1 and {} bytes",
+            MAX_PASSWORD_LEN
+        )),
+        APIRequest::SetTopicRequest {
+            topic: Some(topic), ..
+        } if topic.chars().count() > MAX_TOPIC_LEN => Some(format!(
+            "Topic must be at most {} characters",
+            MAX_TOPIC_LEN
+        )),
+        APIRequest::SendMessageRequest {
+            ttl_secs: Some(ttl),
+            ..
+        }
+        | APIRequest::SetMessageTtlRequest {
+            ttl_secs: Some(ttl),
+            ..
+        } if *ttl == 0 || *ttl > MAX_MESSAGE_TTL_SECS => Some(format!(
+            "Messages must last between 1 and {} seconds",
+            MAX_MESSAGE_TTL_SECS
+        )),
+        _ => named_room(&request)
+            .and_then(|room| validate_room_name(room).err())
+            .map(|e| e.to_string()),
+    };
+    match problem {
+        Some(reason) => Err(rejection(&request, reason)),
+        None => Ok(request),
+    }
+}
+
+// The room a request names, if any.
+fn named_room(request: &APIRequest) -> Option<&str> {
+    match request {
+        APIRequest::SendMessageRequest { room, .. }
+        | APIRequest::EditMessageRequest { room, .. }
+        | APIRequest::DeleteMessageRequest { room, .. }
+        | APIRequest::CreateBlobRequest { room, .. }
+        | APIRequest::RefreshRoomKeysRequest(room)
+        | APIRequest::TypingRequest(room)
+        | APIRequest::ListMembersRequest(room)
+        | APIRequest::FetchHistoryRequest { room, .. }
+        | APIRequest::AcknowledgeMessage { room, .. }
+        | APIRequest::JoinRoomRequest { room, .. }
+        | APIRequest::LeaveRoomRequest(room)
+        | APIRequest::InviteUserRequest { room, .. }
+        | APIRequest::SetVisibilityRequest { room, .. }
+        | APIRequest::SetPasswordRequest { room, .. }
+        | APIRequest::SetTopicRequest { room, .. }
+        | APIRequest::SetMessageTtlRequest { room, .. }
+        | APIRequest::SetRoleRequest { room, .. }
+        | APIRequest::ListRolesRequest(room)
+        | APIRequest::KickFromRoomRequest { room, .. }
+        | APIRequest::SetCapacityRequest { room, .. }
+        | APIRequest::DeleteRoomRequest(room)
+        | APIRequest::RenameRoomRequest { room, .. } => Some(room),
+        _ => None,
+    }
+}
+
+// The error response to `request`. Requests that are never answered get none.
+fn rejection(request: &APIRequest, reason: String) -> SocketReadHandle {
+    let response = match request {
+        APIRequest::SendMessageRequest { .. } => {
+            APIResponse::SendMessageResponse(Response::Error(reason))
+        }
+        APIRequest::EditMessageRequest { .. } => {
+            APIResponse::EditMessageResponse(Response::Error(reason))
+        }
+        APIRequest::DeleteMessageRequest { .. } => {
+            APIResponse::DeleteMessageResponse(Response::Error(reason))
+        }
+        APIRequest::CreateBlobRequest { .. } => {
+            APIResponse::CreateBlobResponse(Response::Error(reason))
+        }
+        APIRequest::UploadChunkRequest { .. } => {
+            APIResponse::UploadChunkResponse(Response::Error(reason))
+        }
+        APIRequest::RefreshRoomKeysRequest(_) => {
+            APIResponse::RefreshRoomKeysResponse(Response::Error(reason))
+        }
+        APIRequest::ListMembersRequest(_) => {
+            APIResponse::ListMembersResponse(Response::Error(reason))
+        }
+        APIRequest::LeaveRoomRequest(_) => APIResponse::LeaveRoomResponse(Response::Error(reason)),
+        APIRequest::FetchHistoryRequest { .. } => {
+            APIResponse::FetchHistoryResponse(Response::Error(reason))
+        }
+        APIRequest::JoinRoomRequest { .. } => {
+            APIResponse::JoinRoomResponse(Response::Error(reason))
+        }
+        APIRequest::InviteUserRequest { .. } => {
+            APIResponse::InviteUserResponse(Response::Error(reason))
+        }
+        APIRequest::SetVisibilityRequest { .. } => {
+            APIResponse::SetVisibilityResponse(Response::Error(reason))
+        }
+        APIRequest::SetPasswordRequest { .. } => {
+            APIResponse::SetPasswordResponse(Response::Error(reason))
+        }
+        APIRequest::SetTopicRequest { .. } => {
+            APIResponse::SetTopicResponse(Response::Error(reason))
+        }
+        APIRequest::SetMessageTtlRequest { .. } => {
+            APIResponse::SetMessageTtlResponse(Response::Error(reason))
+        }
+        APIRequest::SetRoleRequest { .. } => APIResponse::SetRoleResponse(Response::Error(reason)),
+        APIRequest::ListRolesRequest(_) => APIResponse::ListRolesResponse(Response::Error(reason)),
+        APIRequest::KickFromRoomRequest { .. } => {
+            APIResponse::KickFromRoomResponse(Response::Error(reason))
+        }
+        APIRequest::SetCapacityRequest { .. } => {
+            APIResponse::SetCapacityResponse(Response::Error(reason))
+        }
+        APIRequest::DeleteRoomRequest(_) => {
+            APIResponse::DeleteRoomResponse(Response::Error(reason))
+        }
+        APIRequest::RenameRoomRequest { .. } => {
+            APIResponse::RenameRoomResponse(Response::Error(reason))
+        }
+        _ => return SocketReadHandle::NoResponse,
+    };
+    response.into()
+}
+
+fn shutdown_event(notice: ShutdownNotice) -> APIResponse {
+    APIResponse::ServerShutdown {
+        reason: notice.reason,
+        reconnect_after: notice.reconnect_after.map(|after| after.as_secs()),
+    }
+}
+
+/// Per connection state.
+struct Session {
+    user: String,
+    // Id of the device connected.
+    device: String,
+    key: DeviceKey,
+    // Queue of events for this connection, handed to each room it joins.
+    sender: Outbox,
+}
+
 enum SocketReadHandle {
     Response(APIResponse),
+    NoResponse,
     Logout,
 }
 
@@ -101,43 +567,331 @@ impl From<APIResponse> for SocketReadHandle {
     }
 }
 
-fn process_socket_read<G: ChatRoom>(
+async fn process_socket_read(
     socket_input: Result<APIRequest, TransportError>,
-    user: &String,
-    server: &ServerMutex<G>,
+    session: &mut Session,
+    server: &ServerHandle,
+    blobs: &Blobs,
 ) -> Result<SocketReadHandle, &'static str> {
+    let user = session.user.as_str();
     match socket_input {
         Ok(command) => match command {
-            APIRequest::LoginRequest(_) => Ok(APIResponse::LoginResponse(Response::Error(
-                "Already logged in.".to_string(),
-            ))
-            .into()),
+            APIRequest::LoginRequest { .. } | APIRequest::ResumeRequest { .. } => Ok(
+                APIResponse::LoginResponse(Response::Error("Already logged in.".to_string()))
+                    .into(),
+            ),
             APIRequest::Logout => Ok(SocketReadHandle::Logout),
-            APIRequest::RefreshRoomKeysRequest => {
-                // TODO: this is a bit messy. Probably better to move this into a function.
-                let s = server.lock().unwrap();
-                let resp = match s.get_active_room(user) {
-                    Ok(active_room) => {
-                        match s.chat_rooms.get(active_room).unwrap().get_roomkeys() {
-                            Ok(v) => Response::Success(
-                                v.iter()
-                                    .map(|(user, key)| UserKey {
-                                        user: (*user).clone(),
-                                        public: key.to_vec(),
-                                    })
-                                    .collect(),
-                            ),
-                            _ => Response::Error("Unable to get roomkeys".to_string()),
+            APIRequest::Ping => Ok(APIResponse::Pong.into()),
+            APIRequest::RefreshRoomKeysRequest(room) => {
+                let resp = match server.get_room(&room).await {
+                    Ok(room) => room.room_keys(user).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::RefreshRoomKeysResponse(to_response(resp)).into())
+            }
+            APIRequest::SendMessageRequest {
+                room,
+                copies,
+                ttl_secs,
+            } => {
+                let ttl = ttl_secs.map(Duration::from_secs);
+                let published = match server.get_room(&room).await {
+                    Ok(room) => room.publish(user, copies, ttl).await,
+                    Err(e) => Err(e),
+                };
+                let resp = match published {
+                    Ok(id) => Response::Success(id),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::SendMessageResponse(resp).into())
+            }
+            APIRequest::EditMessageRequest { room, id, copies } => {
+                let edited = match server.get_room(&room).await {
+                    Ok(room) => room.edit(user, id, copies).await,
+                    Err(e) => Err(e),
+                };
+                let resp = match edited {
+                    Ok(()) => Response::Success(()),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::EditMessageResponse(resp).into())
+            }
+            APIRequest::DeleteMessageRequest {
+                room,
+                id,
+                signature,
+            } => {
+                let signed = deletion_data(&room.as_str().into(), id);
+                let deleted = if !verify(&signed, &signature, &session.key.public) {
+                    Err(ServerError::UserError(
+                        "The deletion is not signed by this device.".to_string(),
+                    ))
+                } else {
+                    match server.get_room(&room).await {
+                        Ok(room) => room.delete(user, id, signature).await,
+                        Err(e) => Err(e),
+                    }
+                };
+                let resp = match deleted {
+                    Ok(()) => Response::Success(()),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::DeleteMessageResponse(resp).into())
+            }
+            APIRequest::CreateBlobRequest { room, size } => {
+                let allowed = match server.get_room(&room).await {
+                    Ok(handle) => handle.authorize(user, Permission::Post, None).await,
+                    Err(e) => Err(e),
+                };
+                let resp = match allowed {
+                    Ok(()) => match blobs.create(&room.into(), user, size, unix_millis()) {
+                        Ok(progress) => Response::Success(progress),
+                        Err(e) => Response::Error(e.to_string()),
+                    },
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::CreateBlobResponse(resp).into())
+            }
+            APIRequest::UploadChunkRequest { blob, offset, data } => {
+                let resp = match blobs.upload(user, blob, offset, &data) {
+                    Ok(progress) => Response::Success(progress),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::UploadChunkResponse(resp).into())
+            }
+            APIRequest::DownloadChunkRequest {
+                blob,
+                offset,
+                length,
+            } => {
+                let chunk = download_chunk(user, blob, offset, length, server, blobs).await;
+                Ok(APIResponse::DownloadChunkResponse(to_response(chunk)).into())
+            }
+            APIRequest::FetchHistoryRequest {
+                room,
+                before,
+                limit,
+            } => {
+                let history = match server.get_room(&room).await {
+                    Ok(room) => {
+                        room.fetch_history(user, &session.device, before, limit)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                let resp = match history {
+                    Ok(entries) => Response::Success(entries),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::FetchHistoryResponse(resp).into())
+            }
+            APIRequest::AcknowledgeMessage { room, id, kind } => {
+                if kind == ReceiptKind::Read
+                    && !matches!(server.sends_read_receipts(user).await, Ok(true))
+                {
+                    return Ok(SocketReadHandle::NoResponse);
+                }
+                let acknowledged = match server.get_room(&room).await {
+                    Ok(room) => room.acknowledge(user, id, kind).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = acknowledged {
+                    warn!("Dropping receipt from {}: {}", user, e);
+                }
+                Ok(SocketReadHandle::NoResponse)
+            }
+            APIRequest::SetReadReceiptsRequest(enabled) => {
+                let set = server.set_read_receipts(user, enabled).await;
+                Ok(APIResponse::SetReadReceiptsResponse(to_response(set)).into())
+            }
+            APIRequest::SetPresenceRequest { state, status } => {
+                let resp = match server.set_presence(user, state, status).await {
+                    Ok(update) => {
+                        if let Some(update) = update {
+                            update.send().await;
                         }
+                        Response::Success(())
+                    }
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::SetPresenceResponse(resp).into())
+            }
+            APIRequest::TypingRequest(room) => {
+                if let Ok(Some(typing)) = server.typing(user, &room).await {
+                    typing.send().await;
+                }
+                Ok(SocketReadHandle::NoResponse)
+            }
+            APIRequest::ListMembersRequest(room) => {
+                let members = match server.get_room(&room).await {
+                    Ok(room) => room.members(Some(user)).await,
+                    Err(e) => Err(e),
+                };
+                let members = match members {
+                    Ok(members) => server.presence_of(members).await,
+                    Err(e) => Err(e),
+                };
+                let resp = match members {
+                    Ok(m) => Response::Success(m),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                Ok(APIResponse::ListMembersResponse(resp).into())
+            }
+            APIRequest::SetRoleRequest {
+                room,
+                user: member,
+                role,
+            } => {
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_role(Some(user), &member, role).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetRoleResponse(to_response(changed)).into())
+            }
+            APIRequest::ListRolesRequest(room) => {
+                let roles = match server.get_room(&room).await {
+                    Ok(room) => room.roles(user).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::ListRolesResponse(to_response(roles)).into())
+            }
+            APIRequest::KickFromRoomRequest { room, user: member } => {
+                let allowed = match server.get_room(&room).await {
+                    Ok(handle) => {
+                        handle
+                            .authorize(user, Permission::Kick, Some(&member))
+                            .await
                     }
-                    Err(_) => Response::Error("Invalid Chatroom".to_string()),
+                    Err(e) => Err(e),
+                };
+                let kicked = match allowed {
+                    Ok(()) => {
+                        let reason = format!("You were removed from #{} by {}", room, user);
+                        server.remove_from_room(&member, &room, &reason).await
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::KickFromRoomResponse(to_response(kicked)).into())
+            }
+            APIRequest::SetCapacityRequest { room, capacity } => {
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_capacity(user, capacity).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetCapacityResponse(to_response(changed)).into())
+            }
+            APIRequest::DeleteRoomRequest(room) => {
+                let allowed = match server.get_room(&room).await {
+                    Ok(handle) => handle.authorize(user, Permission::Delete, None).await,
+                    Err(e) => Err(e),
+                };
+                let deleted = match allowed {
+                    Ok(()) => server.delete_room(&room).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::DeleteRoomResponse(to_response(deleted)).into())
+            }
+            APIRequest::RenameRoomRequest { room, name } => {
+                let allowed = match server.get_room(&room).await {
+                    Ok(handle) => handle.authorize(user, Permission::Rename, None).await,
+                    Err(e) => Err(e),
                 };
-                Ok(APIResponse::RefreshRoomKeysResponse(resp).into())
+                let renamed = match allowed {
+                    Ok(()) => server.rename_room(&room, &name, user).await,
+                    Err(e) => Err(e),
+                };
+                if renamed.is_ok() {
+                    let (from, to) = (ChatRoomId::from(&room), ChatRoomId::from(&name));
+                    if let Err(e) = blobs.rename_room(&from, &to) {
+                        warn!(
+                            "Could not move the attachments of {} to {}: {}",
+                            room, name, e
+                        );
+                    }
+                }
+                Ok(APIResponse::RenameRoomResponse(to_response(renamed)).into())
+            }
+            APIRequest::ListRoomsRequest => {
+                let rooms = list_rooms(user, server).await;
+                Ok(APIResponse::ListRoomsResponse(to_response(rooms)).into())
+            }
+            APIRequest::SetTopicRequest { room, topic } => {
+                // A blank topic clears it.
+                let topic = topic.filter(|topic| !topic.trim().is_empty());
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_topic(user, topic).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetTopicResponse(to_response(changed)).into())
+            }
+            APIRequest::SetMessageTtlRequest { room, ttl_secs } => {
+                let ttl = ttl_secs.map(Duration::from_secs);
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_message_ttl(user, ttl).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetMessageTtlResponse(to_response(changed)).into())
+            }
+            APIRequest::JoinRoomRequest { room, password } => {
+                let joined = match server.get_room(&room).await {
+                    Ok(room) => join_room(session, room, password, server).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::JoinRoomResponse(to_response(joined)).into())
+            }
+            APIRequest::LeaveRoomRequest(room) => {
+                let left = match server.get_room(&room).await {
+                    Ok(room) => leave_room(user, &session.device, room, server).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::LeaveRoomResponse(to_response(left)).into())
+            }
+            APIRequest::InviteUserRequest {
+                room,
+                user: invitee,
+            } => {
+                let invited = match server.get_room(&room).await {
+                    Ok(handle) => handle.invite(user, &invitee).await,
+                    Err(e) => Err(e),
+                };
+                let invited = match invited {
+                    Ok(()) => {
+                        let invitation = Invitation {
+                            room: room.into(),
+                            from: user.into(),
+                        };
+                        server.invite(&invitee, invitation).await
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::InviteUserResponse(to_response(invited)).into())
+            }
+            APIRequest::ListInvitationsRequest => {
+                let invitations = server.invitations(user).await;
+                Ok(APIResponse::ListInvitationsResponse(to_response(invitations)).into())
+            }
+            APIRequest::SetVisibilityRequest { room, visibility } => {
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_visibility(user, visibility).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetVisibilityResponse(to_response(changed)).into())
+            }
+            APIRequest::SetPasswordRequest { room, password } => {
+                let changed = match server.get_room(&room).await {
+                    Ok(room) => room.set_password(user, password.as_deref()).await,
+                    Err(e) => Err(e),
+                };
+                Ok(APIResponse::SetPasswordResponse(to_response(changed)).into())
+            }
+            APIRequest::ListDevicesRequest => {
+                let devices = server.list_devices(user).await;
+                Ok(APIResponse::ListDevicesResponse(to_response(devices)).into())
+            }
+            APIRequest::RevokeDeviceRequest(device) => {
+                let revoked = server.revoke_device(user, &device, &session.device).await;
+                Ok(APIResponse::RevokeDeviceResponse(to_response(revoked)).into())
             }
-            APIRequest::SendMessageRequest(_, _) => todo!(),
-            APIRequest::ListRoomsRequest => todo!(),
-            APIRequest::JoinRoomRequest(_) => todo!(),
-            APIRequest::LeaveRoom => todo!(),
         },
         Err(e) => {
             eprintln!("Error reading from socket: {}", e);
@@ -146,15 +900,128 @@ fn process_socket_read<G: ChatRoom>(
     }
 }
 
-async fn wait_for_greeting(
-    reader: &mut ReadHalf<TcpStream>,
-    writer: &WriteHalf<TcpStream>,
-) -> Result<UserKey, ListenerError> {
-    match read_command(reader).await {
+// Reads part of blob `id` for `user`, who has to be in the room it was
+// shared in.
+async fn download_chunk(
+    user: &str,
+    id: BlobId,
+    offset: u64,
+    length: u64,
+    server: &ServerHandle,
+    blobs: &Blobs,
+) -> Result<BlobChunk, ServerError> {
+    let room = blobs
+        .room(id, unix_millis())
+        .map_err(|e| ServerError::UserError(e.to_string()))?;
+    // Only members may list the members, so this fails for everyone else.
+    server
+        .get_room(room.as_str())
+        .await?
+        .members(Some(user))
+        .await?;
+    blobs
+        .download(id, offset, length)
+        .map_err(|e| ServerError::UserError(e.to_string()))
+}
+
+// Adds the session to `room`, which has to admit the user. Returns the
+// room's name.
+async fn join_room(
+    session: &Session,
+    room: RoomHandle,
+    password: Option<String>,
+    server: &ServerHandle,
+) -> Result<ChatRoomId, ServerError> {
+    let user = session.user.as_str();
+    room.enter(session.key.clone(), session.sender.clone(), password)
+        .await?;
+    let arrival = server
+        .joined_room(user, &session.device, room.id.clone())
+        .await?;
+    if let Some(arrival) = arrival {
+        arrival.send().await;
+    }
+    info!("{} joined {}", user, room.id);
+    Ok(room.id)
+}
+
+// Takes the session out of `room`. Everyone stays in the waiting room, so
+// there is always somewhere to come back to. Returns the room's name.
+async fn leave_room(
+    user: &str,
+    device: &str,
+    room: RoomHandle,
+    server: &ServerHandle,
+) -> Result<ChatRoomId, ServerError> {
+    let waiting = server.waiting_room().await?;
+    if room.id == waiting.id {
+        return Err(ServerError::UserError(
+            "You can't leave the waiting room.".to_string(),
+        ));
+    }
+    server.left_room(user, device, room.id.clone()).await?;
+    room.leave(user).await?;
+    info!("{} left {}", user, room.id);
+    Ok(room.id)
+}
+
+// The rooms that aren't hidden, and hidden ones `user` is in.
+async fn list_rooms(user: &str, server: &ServerHandle) -> Result<Vec<RoomInfo>, ServerError> {
+    let mut rooms = Vec::new();
+    for room in server.rooms().await? {
+        // Rooms deleted while the list is put together are left out.
+        let Ok(info) = room.info().await else {
+            continue;
+        };
+        if info.visibility != Visibility::Hidden || room.members(Some(user)).await.is_ok() {
+            rooms.push(info);
+        }
+    }
+    Ok(rooms)
+}
+
+fn to_response<T>(result: Result<T, ServerError>) -> Response<T> {
+    match result {
+        Ok(value) => Response::Success(value),
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+/// The request a connection opened with.
+struct Greeting {
+    key: DeviceKey,
+    // Of the connection's login challenge, by the device key.
+    signature: Vec<u8>,
+    // Token of the session to resume, and the last sequence number the client
+    // saw in each of its rooms.
+    resume: Option<(String, HashMap<ChatRoomId, u64>)>,
+}
+
+async fn wait_for_greeting<S: AsyncRead + Send + 'static>(
+    reader: &mut CommandReader<ReadHalf<S>>,
+) -> Result<Greeting, ListenerError> {
+    match reader.read().await {
         Ok(command) => match command {
-            APIRequest::LoginRequest(user_key) => {
-                println!("Found User: {}", &user_key.user);
-                Ok(user_key)
+            APIRequest::LoginRequest { key, signature } => {
+                info!("Found User: {}", &key.user);
+                Ok(Greeting {
+                    key,
+                    signature,
+                    resume: None,
+                })
+            }
+            APIRequest::ResumeRequest {
+                key,
+                signature,
+                token,
+                after,
+            } => {
+                info!("Resuming User: {}", &key.user);
+                Ok(Greeting {
+                    key,
+                    signature,
+                    resume: Some((token, after)),
+                })
             }
             _ => Err(ListenerError::Error(
                 "Expected greeting, got different command",
@@ -164,23 +1031,449 @@ async fn wait_for_greeting(
     }
 }
 
-async fn register_user<G: ChatRoom>(
-    user: &String,
-    public_key: Vec<u8>,
-    writer: &mut WriteHalf<TcpStream>,
-    sender: tokio::sync::mpsc::Sender<UserMessage>,
-    server_mutex: &ServerMutex<G>,
-) -> Result<(), Box<dyn std::error::Error>> {
+// Registers the user with the server and joins them to the rooms they were
+// placed in, then tells the client how that went.
+async fn register_user<S: AsyncWrite + Send + 'static>(
+    key: &DeviceKey,
+    address: IpAddr,
+    resume: Option<String>,
+    heartbeat: Heartbeat,
+    writer: &mut WriteHalf<S>,
+    sender: Outbox,
+    server: &ServerHandle,
+) -> Result<Login, Box<dyn std::error::Error>> {
+    let registration = match server
+        .register_user(key.clone(), address, sender.clone(), resume)
+        .await
     {
-        let mut server = server_mutex.lock().unwrap();
-        server.register_user(user, sender, public_key)?;
+        Ok(login) => join_rooms(key, sender, login, server).await,
+        Err(e) => Err(e),
+    };
+
+    let response = match &registration {
+        Ok(login) => Response::Success(LoginSession {
+            rooms: login.rooms.iter().map(|room| room.id.clone()).collect(),
+            resume_token: login.resume_token.clone(),
+            resumed: login.resumed,
+            heartbeat,
+        }),
+        Err(e) => Response::Error(e.to_string()),
+    };
+
+    if let Err(e) = send_command(writer, &APIResponse::LoginResponse(response)).await {
+        return Err(Box::new(ListenerError::Transport(e)));
     }
+    Ok(registration?)
+}
 
-    match send_command(writer, &APIResponse::LoginResponse(Response::Success(()))).await {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Box::new(ListenerError::Transport(e)))?,
+// Joins a freshly registered device to the rooms of its login. A new session
+// that can't get into the waiting room is given up. A resumed one just loses
+// the rooms it can't get back into.
+async fn join_rooms(
+    key: &DeviceKey,
+    sender: Outbox,
+    mut login: Login,
+    server: &ServerHandle,
+) -> Result<Login, ServerError> {
+    let (user, device) = (key.user.as_str(), key.id());
+    let mut joined = Vec::new();
+    for room in login.rooms {
+        match room.join(key.clone(), sender.clone()).await {
+            Ok(()) => joined.push(room),
+            Err(e) if login.resumed => {
+                warn!("{} could not rejoin {}: {}", user, room.id, e);
+                let _ = server.left_room(user, &device, room.id).await;
+            }
+            Err(e) => {
+                // A full room; don't keep the name reserved.
+                let _ = server.unregister_user(user, &device, false).await;
+                return Err(e);
+            }
+        }
     }
+    login.rooms = joined;
+    Ok(login)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::blobs::BlobSettings;
+    use crate::chatroom::SimpleChatRoom;
+    use crate::handle;
+    use crate::outbox::SlowConsumerPolicy;
+    use crate::ratelimit::IpLimits;
+    use crate::server::Server;
+    use crate::shutdown::shutdown;
+    use slychat_common::encryption::{sign, KeyData};
+    use slychat_common::types::EncryptedCopy;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Client {
+        reader: CommandReader<ReadHalf<DuplexStream>>,
+        writer: WriteHalf<DuplexStream>,
+        connection: JoinHandle<Result<(), ListenerError>>,
+        device: String,
+    }
+
+    // Attachments kept in a temporary directory of the test's own.
+    fn blobs(test: &str) -> (Blobs, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("slychat-{}-{}", test, std::process::id()));
+        (Blobs::load(&dir, BlobSettings::default()).unwrap(), dir)
+    }
+
+    // A device key for `user`, linked to an identity of its own, along with
+    // the device's private key.
+    fn device_key(user: &str) -> (KeyData, DeviceKey) {
+        let identity = KeyData::from_passphrase(b"test");
+        let device = KeyData::from_passphrase(b"test");
+        let key = DeviceKey {
+            user: user.to_string(),
+            name: "laptop".to_string(),
+            signature: sign(&device.public, &identity.private, &identity.passphrase),
+            identity: identity.public,
+            public: device.public.clone(),
+        };
+        (device, key)
+    }
+
+    // Answers the connection's login challenge as `device`.
+    async fn sign_challenge(
+        reader: &mut CommandReader<ReadHalf<DuplexStream>>,
+        device: &KeyData,
+    ) -> Vec<u8> {
+        match reader.read().await.unwrap() {
+            APIResponse::LoginChallenge(nonce) => {
+                sign(&login_data(&nonce), &device.private, &device.passphrase)
+            }
+            other => panic!("Expected a login challenge: {:?}", other),
+        }
+    }
+
+    // Opens an in-process connection without logging in.
+    fn open(
+        server: &ServerHandle,
+        blobs: &Blobs,
+        settings: ConnectionSettings,
+        shutdown: &ShutdownListener,
+    ) -> Client {
+        let (client, socket) = duplex(4096);
+        let limits = IpLimits::new(settings.user_limit, 1);
+        let permit = limits.admit([127, 0, 0, 1].into()).unwrap();
+        let connection = tokio::spawn(process(
+            socket,
+            server.clone(),
+            blobs.clone(),
+            settings,
+            permit,
+            UserLimits::new(settings.user_limit),
+            shutdown.clone(),
+        ));
+        let (reader, writer) = tokio::io::split(client);
+        Client {
+            reader: CommandReader::new(reader),
+            writer,
+            connection,
+            device: String::new(),
+        }
+    }
+
+    // Connects an in-process client and logs it in.
+    async fn connect(
+        server: &ServerHandle,
+        blobs: &Blobs,
+        user: &str,
+        settings: ConnectionSettings,
+        shutdown: &ShutdownListener,
+    ) -> Client {
+        let mut client = open(server, blobs, settings, shutdown);
+        let (keys, key) = device_key(user);
+        client.device = key.id();
+        let signature = sign_challenge(&mut client.reader, &keys).await;
+        send_command(
+            &mut client.writer,
+            &APIRequest::LoginRequest { key, signature },
+        )
+        .await
+        .unwrap();
+        match client.reader.read().await.unwrap() {
+            APIResponse::LoginResponse(Response::Success(_)) => {}
+            other => panic!("Login failed: {:?}", other),
+        }
+        client
+    }
+
+    #[tokio::test]
+    async fn device_keys_only_log_in_with_a_fresh_signature() {
+        let (server, rx) = handle::channel(8);
+        tokio::spawn(Server::<SimpleChatRoom>::build(rx).receive_loop());
+        let (_trigger, listener) = shutdown();
+        let (blobs, _) = blobs("replayed-login");
+        let (keys, key) = device_key("alice");
+        let mallory = KeyData::from_passphrase(b"test");
+
+        // Alice's key signed for another connection, and signed by someone
+        // who only has her public key.
+        let stale = sign(&login_data(b"elsewhere"), &keys.private, &keys.passphrase);
+        for forged in [false, true] {
+            let settings = ConnectionSettings::default();
+            let mut client = open(&server, &blobs, settings, &listener);
+            let signature = if forged {
+                sign_challenge(&mut client.reader, &mallory).await
+            } else {
+                let _: APIResponse = client.reader.read().await.unwrap();
+                stale.clone()
+            };
+            let greeting = APIRequest::LoginRequest {
+                key: key.clone(),
+                signature,
+            };
+            send_command(&mut client.writer, &greeting).await.unwrap();
+            let response: APIResponse = client.reader.read().await.unwrap();
+            assert!(matches!(
+                response,
+                APIResponse::LoginResponse(Response::Error(_))
+            ));
+            assert!(client.connection.await.unwrap().is_err());
+        }
+
+        connect(
+            &server,
+            &blobs,
+            "bob",
+            ConnectionSettings::default(),
+            &listener,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_is_disconnected_without_stalling_the_room() {
+        let (server, rx) = handle::channel(8);
+        tokio::spawn(Server::<SimpleChatRoom>::build(rx).receive_loop());
+        let settings = ConnectionSettings {
+            outbound: OutboxSettings {
+                capacity: 4,
+                policy: SlowConsumerPolicy::Disconnect,
+            },
+            // Alice is allowed to flood, the room must hold up regardless.
+            user_limit: RateLimit {
+                per_second: 1000.0,
+                burst: 1000,
+            },
+            ..ConnectionSettings::default()
+        };
+        let (_trigger, listener) = shutdown();
+
+        // Logs in and then never reads again.
+        let (blobs, _) = blobs("slow-consumer");
+        let stalled = connect(&server, &blobs, "stalled", settings, &listener).await;
+        let mut alice = connect(&server, &blobs, "alice", settings, &listener).await;
+
+        let flood = async {
+            for _ in 0..100 {
+                let copies = [("alice", &alice.device), ("stalled", &stalled.device)]
+                    .map(|(recipient, device)| EncryptedCopy {
+                        recipient: recipient.into(),
+                        device: device.clone(),
+                        message: vec![0; 512],
+                    })
+                    .to_vec();
+                let request = APIRequest::SendMessageRequest {
+                    room: "waiting".to_string(),
+                    copies,
+                    ttl_secs: None,
+                };
+                send_command(&mut alice.writer, &request).await.unwrap();
+
+                // Alice keeps up with her own traffic.
+                loop {
+                    let response: APIResponse = alice.reader.read().await.unwrap();
+                    if let APIResponse::SendMessageResponse(r) = response {
+                        assert!(matches!(r, Response::Success(_)));
+                        break;
+                    }
+                }
+            }
+        };
+        timeout(TIMEOUT, flood)
+            .await
+            .expect("Room stalled behind the slow consumer");
+
+        let disconnected = timeout(TIMEOUT, stalled.connection)
+            .await
+            .expect("Slow consumer was not disconnected");
+        disconnected.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_evicted_after_missing_heartbeats() {
+        let (server, rx) = handle::channel(8);
+        tokio::spawn(Server::<SimpleChatRoom>::build(rx).receive_loop());
+        let settings = ConnectionSettings {
+            heartbeat: Heartbeat {
+                interval_secs: 1,
+                missed_limit: 1,
+            },
+            ..ConnectionSettings::default()
+        };
+        let (_trigger, listener) = shutdown();
+        let (blobs, _) = blobs("silent-client");
+
+        let mut alice = connect(&server, &blobs, "alice", settings, &listener).await;
+        send_command(&mut alice.writer, &APIRequest::Ping)
+            .await
+            .unwrap();
+        let pong: APIResponse = alice.reader.read().await.unwrap();
+        assert!(matches!(pong, APIResponse::Pong));
+
+        // Then alice goes quiet, as if her end of the connection vanished.
+        let evicted = timeout(TIMEOUT, alice.connection)
+            .await
+            .expect("Silent client was not evicted");
+        evicted.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_payloads_are_rejected() {
+        let (server, rx) = handle::channel(8);
+        tokio::spawn(Server::<SimpleChatRoom>::build(rx).receive_loop());
+        let settings = ConnectionSettings {
+            // Room for the login, with its keys and signature.
+            max_frame_length: 8192,
+            max_ciphertext: 256,
+            ..ConnectionSettings::default()
+        };
+        let (_trigger, listener) = shutdown();
+        let (blobs, _) = blobs("oversized");
+        let mut alice = connect(&server, &blobs, "alice", settings, &listener).await;
+
+        let copies = vec![EncryptedCopy {
+            recipient: "alice".into(),
+            device: alice.device.clone(),
+            message: vec![0; 257],
+        }];
+        let request = APIRequest::SendMessageRequest {
+            room: "waiting".to_string(),
+            copies,
+            ttl_secs: None,
+        };
+        send_command(&mut alice.writer, &request).await.unwrap();
+        let response: APIResponse = alice.reader.read().await.unwrap();
+        assert!(matches!(
+            response,
+            APIResponse::SendMessageResponse(Response::Error(_))
+        ));
+
+        // Too large to even be read gets the client disconnected.
+        let copies = vec![EncryptedCopy {
+            recipient: "alice".into(),
+            device: alice.device.clone(),
+            message: vec![0; 8192],
+        }];
+        let request = APIRequest::SendMessageRequest {
+            room: "waiting".to_string(),
+            copies,
+            ttl_secs: None,
+        };
+        // The server hangs up before reading all of it.
+        let _ = send_command(&mut alice.writer, &request).await;
+        let response: APIResponse = alice.reader.read().await.unwrap();
+        assert!(matches!(response, APIResponse::Rejected { .. }));
+        let disconnected = timeout(TIMEOUT, alice.connection)
+            .await
+            .expect("Client was not disconnected");
+        disconnected.unwrap().unwrap();
+    }
+
+    // Reads events until one `answer` picks out.
+    async fn answer<T>(client: &mut Client, answer: impl Fn(APIResponse) -> Option<T>) -> T {
+        loop {
+            let response: APIResponse = client.reader.read().await.unwrap();
+            if let Some(answered) = answer(response) {
+                return answered;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn attachments_resume_and_are_only_shared_with_the_room() {
+        let (server, rx) = handle::channel(8);
+        let mut actor = Server::<SimpleChatRoom>::build(rx);
+        actor.create_chatroom("ops".to_string(), 8).unwrap();
+        tokio::spawn(actor.receive_loop());
+        let settings = ConnectionSettings::default();
+        let (_trigger, listener) = shutdown();
+        let (blobs, dir) = blobs("attachments");
+        let mut alice = connect(&server, &blobs, "alice", settings, &listener).await;
+        let mut bob = connect(&server, &blobs, "bob", settings, &listener).await;
+        let join = APIRequest::JoinRoomRequest {
+            room: "ops".to_string(),
+            password: None,
+        };
+        let joined = |r| match r {
+            APIResponse::JoinRoomResponse(Response::Success(_)) => Some(()),
+            _ => None,
+        };
+        send_command(&mut alice.writer, &join).await.unwrap();
+        answer(&mut alice, joined).await;
+
+        let create = APIRequest::CreateBlobRequest {
+            room: "ops".to_string(),
+            size: 6,
+        };
+        send_command(&mut alice.writer, &create).await.unwrap();
+        let created = answer(&mut alice, |r| match r {
+            APIResponse::CreateBlobResponse(Response::Success(progress)) => Some(progress),
+            _ => None,
+        })
+        .await;
+        let uploaded = |r| match r {
+            APIResponse::UploadChunkResponse(Response::Success(progress)) => Some(progress),
+            _ => None,
+        };
+        let downloaded = |r| match r {
+            APIResponse::DownloadChunkResponse(r) => Some(r),
+            _ => None,
+        };
+        let blob = created.blob;
+        for (offset, data) in [(0, b"abc"), (0, b"abc"), (3, b"def")] {
+            let upload = APIRequest::UploadChunkRequest {
+                blob,
+                offset,
+                data: data.to_vec(),
+            };
+            send_command(&mut alice.writer, &upload).await.unwrap();
+            let progress = answer(&mut alice, uploaded).await;
+            // The repeated chunk, as sent again after a reconnect, is skipped.
+            assert_eq!(progress.received, offset + 3);
+        }
+
+        let download = APIRequest::DownloadChunkRequest {
+            blob,
+            offset: 2,
+            length: 100,
+        };
+        send_command(&mut bob.writer, &download).await.unwrap();
+        assert!(matches!(
+            answer(&mut bob, downloaded).await,
+            Response::Error(_)
+        ));
+
+        send_command(&mut bob.writer, &join).await.unwrap();
+        answer(&mut bob, joined).await;
+        send_command(&mut bob.writer, &download).await.unwrap();
+        match answer(&mut bob, downloaded).await {
+            Response::Success(chunk) => assert_eq!(chunk.data, b"cdef"),
+            Response::Error(e) => panic!("Download failed: {}", e),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,37 +1,316 @@
-use chatroom::{ChatRoom, SimpleChatRoom};
-use log::LevelFilter;
-use server::Server;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use simple_logger::SimpleLogger;
-use std::sync::{Arc, Mutex};
+use slychat_common::types::Role;
+use slychat_server::admin;
+use slychat_server::bans::{BanTarget, Bans};
+use slychat_server::blobs::Blobs;
+use slychat_server::chatroom::{ChatRoom, SimpleChatRoom};
+use slychat_server::config::{ServerConfig, TlsConfig};
+use slychat_server::devices::Devices;
+use slychat_server::handle::{self, ServerCommand, ServerHandle};
+use slychat_server::listeners;
+use slychat_server::password::PasswordHash;
+use slychat_server::ratelimit::{IpLimits, UserLimits};
+use slychat_server::server::Server;
+use slychat_server::shutdown::{self, ShutdownNotice};
+use std::pin::{pin, Pin};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{timeout, Instant};
+use tokio_openssl::SslStream;
 
-mod chatroom;
-mod listeners;
-mod server;
+fn build_acceptor(tls: &TlsConfig) -> Result<SslAcceptor, openssl::error::ErrorStack> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_certificate_chain_file(&tls.cert)?;
+    acceptor.set_private_key_file(&tls.key, SslFiletype::PEM)?;
+    acceptor.check_private_key()?;
+    Ok(acceptor.build())
+}
+
+// Commands queued for the server actor before connection tasks wait to send.
+const COMMAND_BUFFER: usize = 256;
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+// Pause after a failed accept, which usually means we're out of file
+// descriptors and retrying at once would only spin.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+// How long a client gets to finish the TLS handshake before it's dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-const IP: &str = "127.0.0.1";
-const PORT: usize = 9001;
+fn build_server(
+    config: &ServerConfig,
+    rx: Receiver<ServerCommand>,
+    bans: Bans,
+    devices: Devices,
+) -> Server<SimpleChatRoom> {
+    let mut server: Server<SimpleChatRoom> =
+        Server::with_waiting_room(rx, &config.waiting_room, config.default_capacity);
+    server.set_bans(bans);
+    server.set_devices(devices);
 
-type ServerMutex<G: ChatRoom> = Arc<Mutex<Server<G>>>;
-use log::{Level, Metadata, Record};
+    for room in &config.rooms {
+        let capacity = room.capacity.unwrap_or(config.default_capacity);
+        let mut chatroom = SimpleChatRoom::build(room.name.clone(), capacity);
+        if let Some(retention) = room.retention() {
+            chatroom.set_retention(retention);
+        }
+        for moderator in &room.moderators {
+            chatroom.set_role(moderator, Role::Moderator);
+        }
+        if let Some(owner) = &room.owner {
+            chatroom.set_role(owner, Role::Owner);
+        }
+        chatroom.set_topic(room.topic.clone());
+        chatroom.set_description(room.description.clone());
+        chatroom.set_visibility(room.visibility);
+        chatroom.set_password(room.password.as_deref().map(PasswordHash::new));
+        if let Err(e) = server.add_chatroom(chatroom) {
+            log::warn!("Skipping room {}: {}", room.name, e);
+        }
+    }
+    server
+}
+
+// Logs how far behind the connected clients are.
+async fn report_queue_metrics(server: ServerHandle) {
+    let mut interval = tokio::time::interval(METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let metrics = match server.queue_metrics().await {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        if metrics.is_empty() {
+            continue;
+        }
+
+        let deepest = metrics.iter().map(|(_, m)| m.depth).max().unwrap_or(0);
+        let dropped: u64 = metrics.iter().map(|(_, m)| m.dropped).sum();
+        log::info!(
+            "Outbound queues: {} connections, deepest {}, {} events dropped",
+            metrics.len(),
+            deepest,
+            dropped
+        );
+        for (user, m) in metrics {
+            log::debug!(
+                "Outbound queue of {}: {}/{} (high water {}), {} dropped",
+                user,
+                m.depth,
+                m.capacity,
+                m.high_water,
+                m.dropped
+            );
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let config = match ServerConfig::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
     SimpleLogger::new()
-        .with_level(LevelFilter::Info)
+        .with_level(config.log_level)
         .init()
         .unwrap();
 
-    let (sx, rx) = tokio::sync::mpsc::channel(64);
-    let server: ServerMutex<SimpleChatRoom> = Arc::new(Mutex::new(Server::build(rx)));
+    if let Err(e) = std::fs::create_dir_all(&config.storage_dir) {
+        log::error!(
+            "Could not create storage directory {}: {}",
+            config.storage_dir.display(),
+            e
+        );
+        process::exit(1);
+    }
+
+    let acceptor = match config.tls.as_ref().map(build_acceptor).transpose() {
+        Ok(a) => a.map(Arc::new),
+        Err(e) => {
+            log::error!("Could not load TLS certificate: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let bans = match Bans::load(&config.bans_path()) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!(
+                "Could not load bans from {}: {}",
+                config.bans_path().display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
 
-    let address = format!("{}:{}", IP, PORT);
-    let listener = TcpListener::bind(address).await.unwrap();
+    let devices = match Devices::load(&config.devices_path()) {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!(
+                "Could not load devices from {}: {}",
+                config.devices_path().display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
 
+    let blobs = match Blobs::load(&config.blobs_dir(), config.blobs) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!(
+                "Could not load attachments from {}: {}",
+                config.blobs_dir().display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    let (server, rx) = handle::channel(COMMAND_BUFFER);
+    let actor = tokio::spawn(build_server(&config, rx, bans.clone(), devices).receive_loop());
+
+    let listener = match TcpListener::bind(config.address()).await {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Could not bind {}: {}", config.address(), e);
+            process::exit(1);
+        }
+    };
+    let reporter = tokio::spawn(report_queue_metrics(server.clone()));
+    let admin = match admin::bind(&config.admin_socket) {
+        Ok(l) => tokio::spawn(admin::serve(l, server.clone(), bans.clone())),
+        Err(e) => {
+            log::error!(
+                "Could not bind admin socket {}: {}",
+                config.admin_socket.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    log::info!(
+        "Listening on {}{}",
+        config.address(),
+        if acceptor.is_some() { " (TLS)" } else { "" }
+    );
+
+    let limits = IpLimits::new(config.ip_limit, config.max_connections_per_ip);
+    let users = UserLimits::new(config.connection.user_limit);
+    let (trigger, shutdown) = shutdown::shutdown();
+    let mut stop = pin!(shutdown_signal());
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let (socket, peer) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            signal = &mut stop => {
+                log::info!("Received {}, shutting down", signal);
+                break;
+            }
+        };
+
+        if bans.is_banned(&BanTarget::Address(peer.ip())) {
+            log::info!("Refusing {}: address is banned", peer);
+            continue;
+        }
+        let Some(permit) = limits.admit(peer.ip()) else {
+            log::warn!("Refusing {}: too many connections from that address", peer);
+            continue;
+        };
 
         let s = server.clone();
-        tokio::spawn(async move { listeners::process(socket, s).await });
+        let blobs = blobs.clone();
+        let settings = config.connection;
+        let users = users.clone();
+        let shutdown = shutdown.clone();
+        match &acceptor {
+            None => {
+                tokio::spawn(async move {
+                    listeners::process(socket, s, blobs, settings, permit, users, shutdown).await
+                });
+            }
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = openssl::ssl::Ssl::new(acceptor.context())
+                        .and_then(|ssl| SslStream::new(ssl, socket));
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::warn!("Could not set up TLS for {}: {}", peer, e);
+                            return Ok(());
+                        }
+                    };
+                    match timeout(TLS_HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            log::warn!("TLS handshake with {} failed: {}", peer, e);
+                            return Ok(());
+                        }
+                        Err(_) => {
+                            log::warn!("TLS handshake with {} timed out", peer);
+                            return Ok(());
+                        }
+                    }
+                    listeners::process(stream, s, blobs, settings, permit, users, shutdown).await
+                });
+            }
+        }
+    }
+
+    // Stop taking connections, then give the open ones until the deadline to
+    // flush what is queued for them and say goodbye.
+    drop(listener);
+    drop(shutdown);
+    reporter.abort();
+    admin.abort();
+    let _ = std::fs::remove_file(&config.admin_socket);
+    let deadline = Instant::now() + config.shutdown_timeout;
+    let notice = ShutdownNotice {
+        reason: "Server is shutting down".to_string(),
+        reconnect_after: Some(config.reconnect_after),
+    };
+    if !trigger.shutdown(notice, config.shutdown_timeout).await {
+        log::warn!("Connections still open at the shutdown deadline, closing them");
+        return;
+    }
+
+    // The server actor finishes once the last handle is gone.
+    drop(server);
+    if tokio::time::timeout_at(deadline, actor).await.is_err() {
+        log::warn!("Server did not stop before the shutdown deadline");
+    }
+    log::info!("Shut down cleanly");
+}
+
+// Resolves with the name of the first termination signal received.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Could not listen for SIGTERM: {}", e);
+            process::exit(1);
+        }
+    };
+    select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
     }
 }
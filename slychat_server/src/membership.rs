@@ -0,0 +1,162 @@
+use slychat_common::types::{ChatRoomId, UserId};
+use std::collections::{BTreeSet, HashMap};
+
+/// Which rooms each user is in, and who is in each room. Kept in both
+/// directions so either question is answered without a scan.
+#[derive(Default)]
+pub struct Memberships {
+    by_user: HashMap<UserId, BTreeSet<ChatRoomId>>,
+    by_room: HashMap<ChatRoomId, BTreeSet<UserId>>,
+}
+
+impl Memberships {
+    /// Adds `user` to `room`. Returns false if they were in it already.
+    pub fn insert(&mut self, user: &UserId, room: &ChatRoomId) -> bool {
+        self.by_room
+            .entry(room.clone())
+            .or_default()
+            .insert(user.clone());
+        self.by_user
+            .entry(user.clone())
+            .or_default()
+            .insert(room.clone())
+    }
+
+    /// Removes `user` from `room`. Returns false if they weren't in it.
+    pub fn remove(&mut self, user: &UserId, room: &ChatRoomId) -> bool {
+        let Some(rooms) = self.by_user.get_mut(user) else {
+            return false;
+        };
+        if !rooms.remove(room) {
+            return false;
+        }
+        if rooms.is_empty() {
+            self.by_user.remove(user);
+        }
+        if let Some(members) = self.by_room.get_mut(room) {
+            members.remove(user);
+            if members.is_empty() {
+                self.by_room.remove(room);
+            }
+        }
+        true
+    }
+
+    /// Removes `user` from every room. Returns the rooms they were in.
+    pub fn remove_user(&mut self, user: &UserId) -> Vec<ChatRoomId> {
+        let rooms = self.by_user.remove(user).unwrap_or_default();
+        for room in &rooms {
+            if let Some(members) = self.by_room.get_mut(room) {
+                members.remove(user);
+                if members.is_empty() {
+                    self.by_room.remove(room);
+                }
+            }
+        }
+        rooms.into_iter().collect()
+    }
+
+    /// Removes everyone from `room`. Returns who was in it.
+    pub fn remove_room(&mut self, room: &ChatRoomId) -> Vec<UserId> {
+        let members = self.by_room.remove(room).unwrap_or_default();
+        for user in &members {
+            if let Some(rooms) = self.by_user.get_mut(user) {
+                rooms.remove(room);
+                if rooms.is_empty() {
+                    self.by_user.remove(user);
+                }
+            }
+        }
+        members.into_iter().collect()
+    }
+
+    /// Moves everyone in `from` to `to`, for when a room is renamed.
+    pub fn rename_room(&mut self, from: &ChatRoomId, to: &ChatRoomId) {
+        let Some(members) = self.by_room.remove(from) else {
+            return;
+        };
+        for user in &members {
+            if let Some(rooms) = self.by_user.get_mut(user) {
+                rooms.remove(from);
+                rooms.insert(to.clone());
+            }
+        }
+        self.by_room.insert(to.clone(), members);
+    }
+
+    pub fn contains(&self, user: &UserId, room: &ChatRoomId) -> bool {
+        self.by_user
+            .get(user)
+            .is_some_and(|rooms| rooms.contains(room))
+    }
+
+    /// The rooms `user` is in, sorted by name.
+    pub fn rooms_of(&self, user: &UserId) -> Vec<ChatRoomId> {
+        self.by_user
+            .get(user)
+            .map(|rooms| rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Everyone who shares at least one room with `user`, once each.
+    pub fn neighbors_of(&self, user: &UserId) -> BTreeSet<UserId> {
+        let mut neighbors: BTreeSet<UserId> = self
+            .by_user
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|room| self.by_room.get(room))
+            .flatten()
+            .cloned()
+            .collect();
+        neighbors.remove(user);
+        neighbors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memberships_are_many_to_many() {
+        let (alice, bob, carol) = (
+            UserId::from("alice"),
+            UserId::from("bob"),
+            UserId::from("carol"),
+        );
+        let (ops, lobby) = (ChatRoomId::from("ops"), ChatRoomId::from("lobby"));
+        let mut memberships = Memberships::default();
+        assert!(memberships.insert(&alice, &ops));
+        assert!(memberships.insert(&alice, &lobby));
+        assert!(!memberships.insert(&alice, &lobby));
+        assert!(memberships.insert(&bob, &ops));
+        assert!(memberships.insert(&carol, &lobby));
+
+        assert_eq!(
+            memberships.rooms_of(&alice),
+            vec![lobby.clone(), ops.clone()]
+        );
+        let neighbors: Vec<UserId> = memberships.neighbors_of(&alice).into_iter().collect();
+        assert_eq!(neighbors, vec![bob.clone(), carol.clone()]);
+
+        assert_eq!(
+            memberships.remove_room(&ops),
+            vec![alice.clone(), bob.clone()]
+        );
+        assert_eq!(memberships.rooms_of(&alice), vec![lobby.clone()]);
+        assert!(memberships.rooms_of(&bob).is_empty());
+
+        let hall = ChatRoomId::from("hall");
+        memberships.rename_room(&lobby, &hall);
+        assert_eq!(memberships.rooms_of(&carol), vec![hall.clone()]);
+        assert!(!memberships.contains(&alice, &lobby));
+        let lobby = hall;
+
+        assert!(!memberships.remove(&bob, &lobby));
+        assert_eq!(memberships.remove_user(&carol), vec![lobby.clone()]);
+        assert!(memberships.neighbors_of(&alice).is_empty());
+        assert!(memberships.remove(&alice, &lobby));
+        assert!(!memberships.contains(&alice, &lobby));
+    }
+}
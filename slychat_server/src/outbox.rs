@@ -0,0 +1,320 @@
+use clap::ValueEnum;
+use log::warn;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+use tokio_util::sync::CancellationToken;
+
+use crate::server::UserMessage;
+
+pub const DEFAULT_OUTBOUND_QUEUE: usize = 64;
+
+/// What happens to events for a connection whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    /// Senders wait for space, up to their own timeout. A room waits with
+    /// them, so one slow member holds up everyone else in it meanwhile.
+    Block,
+    /// The oldest queued event is discarded to make room. Clients notice the
+    /// gap in sequence numbers and fetch what they missed from history.
+    #[default]
+    DropOldest,
+    /// The connection is closed.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxSettings {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_OUTBOUND_QUEUE,
+            policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboxError {
+    /// The queue stayed full; the event was not queued.
+    Full,
+    /// The connection is gone or was disconnected for falling behind.
+    Closed,
+}
+
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full => write!(f, "Outbound queue full"),
+            Self::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueMetrics {
+    pub depth: usize,
+    pub high_water: usize,
+    pub dropped: u64,
+    pub capacity: usize,
+}
+
+struct Queue {
+    messages: VecDeque<UserMessage>,
+    high_water: usize,
+    dropped: u64,
+}
+
+struct Shared {
+    settings: OutboxSettings,
+    queue: Mutex<Queue>,
+    // One permit per free slot in `queue`.
+    slots: Semaphore,
+    readable: Notify,
+    closed: CancellationToken,
+}
+
+/// Creates the bounded queue of events waiting to be written to a connection.
+pub fn outbox(settings: OutboxSettings) -> (Outbox, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        settings,
+        queue: Mutex::new(Queue {
+            messages: VecDeque::with_capacity(settings.capacity),
+            high_water: 0,
+            dropped: 0,
+        }),
+        slots: Semaphore::new(settings.capacity),
+        readable: Notify::new(),
+        closed: CancellationToken::new(),
+    });
+    (
+        Outbox {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+/// The sending side of a connection's outbound queue. Cheap to clone.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+impl Outbox {
+    /// Queues `message`, applying the connection's slow-consumer policy when
+    /// the queue is full. Under `Block`, gives up after `timeout`.
+    pub async fn send(&self, message: UserMessage, timeout: Duration) -> Result<(), OutboxError> {
+        if self.shared.settings.policy == SlowConsumerPolicy::Block {
+            match tokio::time::timeout(timeout, self.shared.slots.acquire()).await {
+                Ok(Ok(permit)) => permit.forget(),
+                Ok(Err(_)) => return Err(OutboxError::Closed),
+                Err(_) => return Err(OutboxError::Full),
+            }
+            self.push(message);
+            return Ok(());
+        }
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        match self.shared.slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::Closed) => return Err(OutboxError::Closed),
+            Err(TryAcquireError::NoPermits) => match self.shared.settings.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    // The slot freed here is taken by the new message.
+                    queue.messages.pop_front();
+                    queue.dropped += 1;
+                }
+                _ => {
+                    drop(queue);
+                    warn!("Disconnecting {}: outbound queue full", message.user_id);
+                    self.close();
+                    return Err(OutboxError::Closed);
+                }
+            },
+        }
+        Self::enqueue(&mut queue, message);
+        drop(queue);
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queues `message` only if there is room right now, whatever the policy.
+    /// For events that are worthless once late.
+    pub fn try_send(&self, message: UserMessage) -> Result<(), OutboxError> {
+        match self.shared.slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::Closed) => return Err(OutboxError::Closed),
+            Err(TryAcquireError::NoPermits) => return Err(OutboxError::Full),
+        }
+        self.push(message);
+        Ok(())
+    }
+
+    fn push(&self, message: UserMessage) {
+        Self::enqueue(&mut self.shared.queue.lock().unwrap(), message);
+        self.shared.readable.notify_one();
+    }
+
+    fn enqueue(queue: &mut Queue, message: UserMessage) {
+        queue.messages.push_back(message);
+        queue.high_water = queue.high_water.max(queue.messages.len());
+    }
+
+    /// Drops the queued events `discard` picks, such as copies of a message
+    /// deleted before they went out. Returns how many were dropped.
+    pub fn discard(&self, discard: impl Fn(&UserMessage) -> bool) -> usize {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let queued = queue.messages.len();
+        queue.messages.retain(|message| !discard(message));
+        let dropped = queued - queue.messages.len();
+        drop(queue);
+        self.shared.slots.add_permits(dropped);
+        dropped
+    }
+
+    /// Closes the queue. The connection is dropped once it notices.
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.is_cancelled()
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        let queue = self.shared.queue.lock().unwrap();
+        QueueMetrics {
+            depth: queue.messages.len(),
+            high_water: queue.high_water,
+            dropped: queue.dropped,
+            capacity: self.shared.settings.capacity,
+        }
+    }
+}
+
+impl Shared {
+    fn close(&self) {
+        self.closed.cancel();
+        self.slots.close();
+    }
+}
+
+/// The connection's side of its outbound queue.
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboxReceiver {
+    /// Waits for the next event. Returns `None` once the queue is closed,
+    /// discarding whatever is still queued.
+    pub async fn recv(&mut self) -> Option<UserMessage> {
+        loop {
+            if self.shared.closed.is_cancelled() {
+                return None;
+            }
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            select! {
+                _ = self.shared.readable.notified() => {}
+                _ = self.shared.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    /// Takes the next queued event without waiting.
+    pub fn try_recv(&mut self) -> Option<UserMessage> {
+        if self.shared.closed.is_cancelled() {
+            return None;
+        }
+        let message = self.shared.queue.lock().unwrap().messages.pop_front()?;
+        self.shared.slots.add_permits(1);
+        Some(message)
+    }
+
+    /// Resolves once the queue is closed.
+    pub async fn closed(&self) {
+        self.shared.closed.cancelled().await
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slychat_common::types::{APIResponse, Response};
+
+    fn event(n: u64) -> UserMessage {
+        UserMessage {
+            user_id: "alice".into(),
+            message: APIResponse::SetReadReceiptsResponse(Response::Error(n.to_string())),
+        }
+    }
+
+    fn label(message: UserMessage) -> String {
+        match message.message {
+            APIResponse::SetReadReceiptsResponse(Response::Error(n)) => n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn settings(policy: SlowConsumerPolicy) -> OutboxSettings {
+        OutboxSettings {
+            capacity: 2,
+            policy,
+        }
+    }
+
+    const WAIT: Duration = Duration::from_millis(10);
+
+    #[tokio::test]
+    async fn block_gives_up_after_the_timeout() {
+        let (outbox, mut receiver) = outbox(settings(SlowConsumerPolicy::Block));
+        outbox.send(event(1), WAIT).await.unwrap();
+        outbox.send(event(2), WAIT).await.unwrap();
+        assert_eq!(outbox.send(event(3), WAIT).await, Err(OutboxError::Full));
+
+        assert_eq!(label(receiver.recv().await.unwrap()), "1");
+        outbox.send(event(3), WAIT).await.unwrap();
+        assert_eq!(outbox.metrics().high_water, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest_events() {
+        let (outbox, mut receiver) = outbox(settings(SlowConsumerPolicy::DropOldest));
+        for n in 1..=5 {
+            outbox.send(event(n), WAIT).await.unwrap();
+        }
+        assert_eq!(outbox.metrics().dropped, 3);
+        assert_eq!(label(receiver.recv().await.unwrap()), "4");
+        assert_eq!(label(receiver.recv().await.unwrap()), "5");
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let (outbox, mut receiver) = outbox(settings(SlowConsumerPolicy::Disconnect));
+        outbox.send(event(1), WAIT).await.unwrap();
+        outbox.send(event(2), WAIT).await.unwrap();
+        assert_eq!(outbox.send(event(3), WAIT).await, Err(OutboxError::Closed));
+
+        assert!(outbox.is_closed());
+        assert!(receiver.recv().await.is_none());
+    }
+}
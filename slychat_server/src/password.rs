@@ -0,0 +1,61 @@
+//! Room passwords. Only a salted hash is kept, derived with the same
+//! PBKDF2 parameters the client uses for its key files.
+
+use openssl::memcmp;
+use slychat_common::encryption::{derive_key, random_bytes};
+use tokio::task::spawn_blocking;
+
+const SALT_LEN: usize = 16;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a fresh salt.
+    pub fn new(password: &str) -> Self {
+        let salt = random_bytes(SALT_LEN);
+        let hash = derive_key(password.as_bytes(), &salt);
+        Self { salt, hash }
+    }
+
+    /// Whether `password` is the one this was made from. Takes the same time
+    /// however much of the hash matches.
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = derive_key(password.as_bytes(), &self.salt);
+        memcmp::eq(&hash, &self.hash)
+    }
+
+    /// `new` on the blocking thread pool. PBKDF2 is slow on purpose, and
+    /// would otherwise hold up every task sharing the thread.
+    pub async fn hash(password: String) -> Self {
+        spawn_blocking(move || Self::new(&password))
+            .await
+            .expect("Password hashing panicked")
+    }
+
+    /// `verify` on the blocking thread pool, for the same reason as `hash`.
+    pub async fn check(&self, password: String) -> bool {
+        let hash = self.clone();
+        spawn_blocking(move || hash.verify(&password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_salted_and_verified() {
+        let first = PasswordHash::new("hunter2");
+        let second = PasswordHash::new("hunter2");
+        assert_ne!(first.hash, second.hash);
+        assert!(first.verify("hunter2"));
+        assert!(!first.verify("hunter3"));
+        assert!(!first.verify(""));
+    }
+}
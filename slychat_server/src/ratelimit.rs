@@ -0,0 +1,372 @@
+use slychat_common::types::{APIRequest, UserId};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Requests rejected in a row, once limited, before a client is considered to
+/// be flooding and is disconnected.
+const FLOOD_STRIKES: u32 = 20;
+
+/// A sustained rate with room for short bursts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub const USER_DEFAULT: Self = Self {
+        per_second: 5.0,
+        burst: 20,
+    };
+    pub const IP_DEFAULT: Self = Self {
+        per_second: 20.0,
+        burst: 60,
+    };
+    /// For attachment chunks, which clients send one after the other as fast
+    /// as they are answered.
+    pub const TRANSFER_DEFAULT: Self = Self {
+        per_second: 32.0,
+        burst: 64,
+    };
+    /// For delivery and read receipts, one of each per message received, and
+    /// sent in a batch for everything shown while the user was away.
+    pub const RECEIPT_DEFAULT: Self = Self {
+        per_second: 20.0,
+        burst: 200,
+    };
+}
+
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+
+/// Token bucket enforcing a `RateLimit`.
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes a token if there is one, otherwise returns how long until there
+    /// will be.
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    /// Whether the bucket has refilled by `now`, so starting over with a new
+    /// one would make no difference.
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second >= self.limit.burst as f64
+    }
+}
+
+struct Address {
+    connections: usize,
+    bucket: TokenBucket,
+}
+
+/// Limits shared by every connection from the same address. An address is
+/// forgotten once its last connection closes and its bucket has refilled, so
+/// reconnecting doesn't start it over.
+#[derive(Clone)]
+pub struct IpLimits {
+    rate: RateLimit,
+    max_connections: usize,
+    addresses: Arc<Mutex<HashMap<IpAddr, Address>>>,
+}
+
+impl IpLimits {
+    pub fn new(rate: RateLimit, max_connections: usize) -> Self {
+        Self {
+            rate,
+            max_connections,
+            addresses: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Lets a new connection from `ip` in, unless it already has the maximum
+    /// number open. The connection counts until the permit is dropped.
+    pub fn admit(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock().unwrap();
+        addresses.retain(|_, address| address.connections > 0 || !address.bucket.is_full(now));
+        let address = addresses.entry(ip).or_insert_with(|| Address {
+            connections: 0,
+            bucket: TokenBucket::new(self.rate, now),
+        });
+        if address.connections >= self.max_connections {
+            return None;
+        }
+        address.connections += 1;
+        Some(ConnectionPermit {
+            ip,
+            limits: self.clone(),
+        })
+    }
+}
+
+/// A connection's share of its address's limits.
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    limits: IpLimits,
+}
+
+impl ConnectionPermit {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    fn take(&self, now: Instant) -> Result<(), Duration> {
+        let mut addresses = self.limits.addresses.lock().unwrap();
+        match addresses.get_mut(&self.ip) {
+            Some(address) => address.bucket.take(now),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut addresses = self.limits.addresses.lock().unwrap();
+        if let Some(address) = addresses.get_mut(&self.ip) {
+            address.connections -= 1;
+        }
+    }
+}
+
+// A user's buckets. Attachment chunks and receipts are limited on their own,
+// so a transfer or catching up on a busy room doesn't hold up everything else
+// the user does.
+struct UserBuckets {
+    requests: TokenBucket,
+    transfers: TokenBucket,
+    receipts: TokenBucket,
+}
+
+impl UserBuckets {
+    fn new(rate: RateLimit, now: Instant) -> Self {
+        Self {
+            requests: TokenBucket::new(rate, now),
+            transfers: TokenBucket::new(RateLimit::TRANSFER_DEFAULT, now),
+            receipts: TokenBucket::new(RateLimit::RECEIPT_DEFAULT, now),
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.requests.is_full(now) && self.transfers.is_full(now) && self.receipts.is_full(now)
+    }
+}
+
+/// Limits shared by every connection of the same user. Like addresses, a
+/// user's buckets are kept across reconnects until they have refilled.
+#[derive(Clone)]
+pub struct UserLimits {
+    rate: RateLimit,
+    users: Arc<Mutex<HashMap<UserId, UserBuckets>>>,
+}
+
+impl UserLimits {
+    pub fn new(rate: RateLimit) -> Self {
+        Self {
+            rate,
+            users: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The limiter for a connection of `user` from `address`.
+    pub fn limiter(&self, user: &UserId, address: ConnectionPermit) -> RequestLimiter {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        users.retain(|_, buckets| !buckets.is_full(now));
+        users
+            .entry(user.clone())
+            .or_insert_with(|| UserBuckets::new(self.rate, now));
+        RequestLimiter {
+            user: user.clone(),
+            users: self.clone(),
+            address,
+            strikes: 0,
+        }
+    }
+}
+
+/// What to do with a request.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Reject it; the client may try again after the given time.
+    Limited(Duration),
+    /// The client keeps going while limited. Disconnect it.
+    Flooding,
+}
+
+/// Applies the per-user and per-address limits to a connection's requests.
+pub struct RequestLimiter {
+    user: UserId,
+    users: UserLimits,
+    address: ConnectionPermit,
+    strikes: u32,
+}
+
+impl RequestLimiter {
+    pub fn check(&mut self, request: &APIRequest, now: Instant) -> Verdict {
+        if !is_limited(request) {
+            return Verdict::Allow;
+        }
+        let taken = {
+            let mut users = self.users.users.lock().unwrap();
+            let buckets = users
+                .entry(self.user.clone())
+                .or_insert_with(|| UserBuckets::new(self.users.rate, now));
+            match request {
+                APIRequest::UploadChunkRequest { .. } | APIRequest::DownloadChunkRequest { .. } => {
+                    buckets.transfers.take(now)
+                }
+                APIRequest::AcknowledgeMessage { .. } => buckets.receipts.take(now),
+                _ => buckets
+                    .requests
+                    .take(now)
+                    .and_then(|()| self.address.take(now)),
+            }
+        };
+        match taken {
+            Ok(()) => {
+                self.strikes = 0;
+                Verdict::Allow
+            }
+            Err(_) if self.strikes >= FLOOD_STRIKES => Verdict::Flooding,
+            Err(retry_after) => {
+                self.strikes += 1;
+                Verdict::Limited(retry_after)
+            }
+        }
+    }
+}
+
+// Heartbeats and logging out are cheap and needed to keep a well-behaved
+// client working, so they don't count.
+fn is_limited(request: &APIRequest) -> bool {
+    !matches!(request, APIRequest::Ping | APIRequest::Logout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slychat_common::types::{MessageId, ReceiptKind};
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn bucket_refills_at_the_configured_rate() {
+        let start = Instant::now();
+        let limit = RateLimit {
+            per_second: 2.0,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(limit, start);
+
+        assert!(bucket.take(start).is_ok());
+        assert!(bucket.take(start).is_ok());
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        assert!(bucket.take(start + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn addresses_are_limited_across_connections() {
+        let limits = IpLimits::new(
+            RateLimit {
+                per_second: 1.0,
+                burst: 1,
+            },
+            2,
+        );
+        let first = limits.admit(LOCALHOST).unwrap();
+        let second = limits.admit(LOCALHOST).unwrap();
+        assert!(limits.admit(LOCALHOST).is_none());
+
+        let now = Instant::now();
+        let users = UserLimits::new(RateLimit::USER_DEFAULT);
+        let mut first = users.limiter(&"alice".into(), first);
+        let mut second = users.limiter(&"bob".into(), second);
+        let request = APIRequest::RefreshRoomKeysRequest("waiting".to_string());
+        assert_eq!(first.check(&request, now), Verdict::Allow);
+        assert!(matches!(second.check(&request, now), Verdict::Limited(_)));
+        assert_eq!(second.check(&APIRequest::Ping, now), Verdict::Allow);
+
+        drop(first);
+        assert!(limits.admit(LOCALHOST).is_some());
+    }
+
+    #[test]
+    fn receipts_have_a_bucket_of_their_own() {
+        let limits = IpLimits::new(RateLimit::IP_DEFAULT, 1);
+        let users = UserLimits::new(RateLimit::USER_DEFAULT);
+        let mut limiter = users.limiter(&"alice".into(), limits.admit(LOCALHOST).unwrap());
+        let now = Instant::now();
+        let ack = APIRequest::AcknowledgeMessage {
+            room: "waiting".to_string(),
+            id: MessageId(1),
+            kind: ReceiptKind::Read,
+        };
+        for _ in 0..RateLimit::RECEIPT_DEFAULT.burst {
+            assert_eq!(limiter.check(&ack, now), Verdict::Allow);
+        }
+        assert!(matches!(limiter.check(&ack, now), Verdict::Limited(_)));
+        let request = APIRequest::RefreshRoomKeysRequest("waiting".to_string());
+        assert_eq!(limiter.check(&request, now), Verdict::Allow);
+    }
+
+    #[test]
+    fn reconnecting_does_not_refill_the_buckets() {
+        let one = RateLimit {
+            per_second: 1.0,
+            burst: 1,
+        };
+        let request = APIRequest::RefreshRoomKeysRequest("waiting".to_string());
+
+        let limits = IpLimits::new(RateLimit::IP_DEFAULT, 1);
+        let users = UserLimits::new(one);
+        let mut limiter = users.limiter(&"alice".into(), limits.admit(LOCALHOST).unwrap());
+        assert_eq!(limiter.check(&request, Instant::now()), Verdict::Allow);
+        drop(limiter);
+        let mut limiter = users.limiter(&"alice".into(), limits.admit(LOCALHOST).unwrap());
+        assert!(matches!(
+            limiter.check(&request, Instant::now()),
+            Verdict::Limited(_)
+        ));
+        drop(limiter);
+
+        let limits = IpLimits::new(one, 1);
+        let users = UserLimits::new(RateLimit::USER_DEFAULT);
+        let mut limiter = users.limiter(&"bob".into(), limits.admit(LOCALHOST).unwrap());
+        assert_eq!(limiter.check(&request, Instant::now()), Verdict::Allow);
+        drop(limiter);
+        let mut limiter = users.limiter(&"carol".into(), limits.admit(LOCALHOST).unwrap());
+        assert!(matches!(
+            limiter.check(&request, Instant::now()),
+            Verdict::Limited(_)
+        ));
+    }
+}
@@ -0,0 +1,1205 @@
+use log::{info, warn};
+use slychat_common::types::{
+    APIResponse, ChatRoomId, DeviceKey, EncryptedCopy, MessageId, Permission, PublishedMessage,
+    Receipt, ReceiptKind, Role, RoleAssignment, RoomInfo, RoomKeys, UserId, Visibility,
+};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::chatroom::{ChatRoom, ChatRoomError};
+use crate::handle::{request, Reply};
+use crate::outbox::Outbox;
+use crate::password::PasswordHash;
+use crate::server::{unix_millis, ServerError};
+
+/// Commands queued for a room before senders wait.
+const ROOM_BUFFER: usize = 64;
+/// Upper bound on the number of entries returned by a single history fetch.
+const MAX_HISTORY_PAGE: usize = 100;
+/// Upper bound on the number of messages replayed to a resumed session. Any
+/// further ones are backfilled by the client from history.
+const MAX_REPLAY: usize = 500;
+
+/// A request to a room's task.
+pub enum RoomCommand {
+    Join {
+        key: DeviceKey,
+        sender: Outbox,
+        reply: Reply<()>,
+    },
+    // The hash of the password `user` has to give to join, if any. Checking
+    // it is left to the caller, so PBKDF2 doesn't hold up the room.
+    Admit {
+        user: String,
+        reply: Reply<Option<PasswordHash>>,
+    },
+    // Joins the device's user if the room admits them. `password` is the
+    // hash whose password they gave, so a change in between is noticed.
+    Enter {
+        key: DeviceKey,
+        sender: Outbox,
+        password: Option<PasswordHash>,
+        reply: Reply<()>,
+    },
+    Leave {
+        user: String,
+        reply: Reply<()>,
+    },
+    // Removes a member and takes back their role and invitation.
+    Kick {
+        user: String,
+        reply: Reply<()>,
+    },
+    // Takes the new name the server registered the room under.
+    Rename {
+        by: String,
+        name: String,
+    },
+    // Removes a member's device, or with `None` all of them, only if its
+    // connection is gone.
+    Forget {
+        user: String,
+        device: Option<String>,
+    },
+    Publish {
+        sender: String,
+        copies: Vec<EncryptedCopy>,
+        ttl: Option<Duration>,
+        reply: Reply<MessageId>,
+    },
+    Edit {
+        sender: String,
+        id: MessageId,
+        copies: Vec<EncryptedCopy>,
+        reply: Reply<()>,
+    },
+    // The signature has been checked against the sender's device already.
+    Delete {
+        sender: String,
+        id: MessageId,
+        signature: Vec<u8>,
+        reply: Reply<()>,
+    },
+    Broadcast(Broadcast),
+    Receipt {
+        user: String,
+        id: MessageId,
+        kind: ReceiptKind,
+        reply: Reply<()>,
+    },
+    FetchHistory {
+        user: String,
+        device: String,
+        before: Option<u64>,
+        limit: usize,
+        reply: Reply<Vec<PublishedMessage>>,
+    },
+    Replay {
+        user: String,
+        device: String,
+        after: Option<u64>,
+        reply: Reply<Vec<PublishedMessage>>,
+    },
+    // Both are only answered for members, or for operators if `viewer` is
+    // `None`.
+    RoomKeys {
+        viewer: String,
+        reply: Reply<RoomKeys>,
+    },
+    Members {
+        viewer: Option<String>,
+        reply: Reply<Vec<UserId>>,
+    },
+    // Stops `user` from publishing, or lets them again.
+    Mute {
+        user: String,
+        muted: bool,
+        reply: Reply<()>,
+    },
+    // Checks that `user` has `permission`, and outranks `target` if given.
+    Authorize {
+        user: String,
+        permission: Permission,
+        target: Option<String>,
+        reply: Reply<()>,
+    },
+    SetRole {
+        // `None` for operators, who are not held to the room's rules.
+        by: Option<String>,
+        user: String,
+        role: Role,
+        reply: Reply<()>,
+    },
+    Roles {
+        viewer: String,
+        reply: Reply<Vec<RoleAssignment>>,
+    },
+    SetCapacity {
+        by: String,
+        capacity: usize,
+        reply: Reply<()>,
+    },
+    Invite {
+        by: String,
+        user: String,
+        reply: Reply<()>,
+    },
+    Info {
+        reply: Reply<RoomInfo>,
+    },
+    SetTopic {
+        by: String,
+        topic: Option<String>,
+        reply: Reply<()>,
+    },
+    SetVisibility {
+        by: String,
+        visibility: Visibility,
+        reply: Reply<()>,
+    },
+    SetPassword {
+        by: String,
+        password: Option<PasswordHash>,
+        reply: Reply<()>,
+    },
+    SetMessageTtl {
+        by: String,
+        ttl: Option<Duration>,
+        reply: Reply<()>,
+    },
+}
+
+/// An event for every member of a room other than `except`.
+pub struct Broadcast {
+    pub room: RoomHandle,
+    pub except: UserId,
+    pub message: APIResponse,
+    /// Ephemeral events are dropped for members who are behind instead of
+    /// waiting for them.
+    pub ephemeral: bool,
+}
+
+impl Broadcast {
+    pub async fn send(self) {
+        let room = self.room.clone();
+        // A room that was deleted meanwhile has nobody left to tell.
+        let _ = room.commands.send(RoomCommand::Broadcast(self)).await;
+    }
+}
+
+/// Moves `chatroom` into its own task and returns the handle used to reach it.
+/// The task stops once every handle has been dropped.
+pub fn spawn<G: ChatRoom>(chatroom: G) -> RoomHandle {
+    let (commands, receiver) = mpsc::channel(ROOM_BUFFER);
+    let handle = RoomHandle {
+        id: chatroom.id().into(),
+        commands,
+    };
+    tokio::spawn(run(chatroom, receiver));
+    handle
+}
+
+async fn run<G: ChatRoom>(mut room: G, mut commands: Receiver<RoomCommand>) {
+    // Muted users, who may be in the room but not publish to it.
+    let mut muted = HashSet::new();
+    loop {
+        // Messages are dropped as they expire, whether or not anything else
+        // is going on in the room.
+        let command = match room.next_expiry() {
+            Some(expires) => {
+                let wait = Duration::from_millis(expires.saturating_sub(unix_millis()));
+                select! {
+                    command = commands.recv() => command,
+                    _ = tokio::time::sleep(wait) => {
+                        room.expire_messages(unix_millis());
+                        continue;
+                    }
+                }
+            }
+            None => commands.recv().await,
+        };
+        let Some(command) = command else { break };
+        match command {
+            RoomCommand::Join { key, sender, reply } => {
+                let joined = room.register_user(key, sender);
+                let _ = reply.send(joined.map_err(ServerError::from));
+            }
+            RoomCommand::Admit { user, reply } => {
+                let admitted = check_not_member(&room, &user).and_then(|()| {
+                    room.admit(&user)
+                        .map(|hash| hash.cloned())
+                        .map_err(ServerError::from)
+                });
+                let _ = reply.send(admitted);
+            }
+            RoomCommand::Enter {
+                key,
+                sender,
+                password,
+                reply,
+            } => {
+                let user = key.user.clone();
+                let entered =
+                    check_not_member(&room, &user).and_then(|()| match room.admit(&user)? {
+                        Some(required) if password.as_ref() != Some(required) => {
+                            Err(ServerError::UserError(format!(
+                                "The password for #{} just changed, try again.",
+                                room.id()
+                            )))
+                        }
+                        _ => Ok(room.register_user(key, sender)?),
+                    });
+                let _ = reply.send(entered);
+            }
+            RoomCommand::Leave { user, reply } => {
+                let left = room.unregister_user(&user);
+                let _ = reply.send(left.map_err(ServerError::from));
+            }
+            RoomCommand::Kick { user, reply } => {
+                let kicked = room.unregister_user(&user);
+                room.revoke(&user);
+                let _ = reply.send(kicked.map_err(ServerError::from));
+            }
+            RoomCommand::Rename { by, name } => {
+                let renamed = APIResponse::RoomRenamed {
+                    from: room.id().into(),
+                    to: name.clone().into(),
+                    by: by.into(),
+                };
+                room.rename(&name);
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(|member| (UserId::from(member).into(), renamed.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::Forget { user, device } => {
+                if room.forget_devices(&user, device.as_deref()) {
+                    info!("Room {}: {} did not come back", room.id(), user);
+                }
+            }
+            RoomCommand::Publish {
+                sender,
+                copies,
+                ttl,
+                reply,
+            } => {
+                if muted.contains(&sender) {
+                    let error = ServerError::UserError("You are muted in this room.".to_string());
+                    let _ = reply.send(Err(error));
+                    continue;
+                }
+                if let Err(e) = authorize(&room, &sender, Permission::Post, None) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                let published = match room.store_message(&sender, copies, ttl, unix_millis()) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        let _ = reply.send(Err(e.into()));
+                        continue;
+                    }
+                };
+                let _ = reply.send(Ok(published[0].1.id));
+
+                let messages = published
+                    .into_iter()
+                    .map(|(user, p)| (user, APIResponse::PublishMessage(p)))
+                    .collect();
+                // The room takes no further commands until the fan-out is done,
+                // which keeps a flood of messages from outrunning the members.
+                let publish = room.publish_message(messages);
+                let skipped = publish.await;
+                if !skipped.is_empty() {
+                    warn!(
+                        "Room {}: {} member(s) too slow to receive a message",
+                        room.id(),
+                        skipped.len()
+                    );
+                }
+            }
+            RoomCommand::Edit {
+                sender,
+                id,
+                copies,
+                reply,
+            } => {
+                // Editing says something new, so it takes what posting does.
+                if muted.contains(&sender) {
+                    let error = ServerError::UserError("You are muted in this room.".to_string());
+                    let _ = reply.send(Err(error));
+                    continue;
+                }
+                let edited = authorize(&room, &sender, Permission::Post, None).and_then(|()| {
+                    room.edit_message(&sender, id, copies)
+                        .map_err(ServerError::from)
+                });
+                let edited = match edited {
+                    Ok(edited) => edited,
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                        continue;
+                    }
+                };
+                let _ = reply.send(Ok(()));
+
+                let messages = edited
+                    .into_iter()
+                    .map(|(user, p)| (user, APIResponse::MessageEdited(p)))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::Delete {
+                sender,
+                id,
+                signature,
+                reply,
+            } => {
+                if let Err(e) = room.delete_message(&sender, id) {
+                    let _ = reply.send(Err(e.into()));
+                    continue;
+                }
+                let _ = reply.send(Ok(()));
+
+                let deleted = APIResponse::MessageDeleted {
+                    room: room.id().into(),
+                    id,
+                    sender: sender.into(),
+                    signature,
+                };
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(|member| (UserId::from(member).into(), deleted.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::Broadcast(broadcast) => {
+                if broadcast.ephemeral {
+                    room.notify(broadcast.except.as_str(), broadcast.message);
+                    continue;
+                }
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(UserId::from)
+                    .filter(|member| *member != broadcast.except)
+                    .map(|member| (member.into(), broadcast.message.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::Receipt {
+                user,
+                id,
+                kind,
+                reply,
+            } => {
+                let receipt = build_receipt(&mut room, &user, id, kind);
+                let result = match receipt {
+                    Ok(Some((sender, event))) => {
+                        let publish = room.publish_message(vec![(sender.into(), event)]);
+                        publish.await;
+                        Ok(())
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                let _ = reply.send(result);
+            }
+            RoomCommand::FetchHistory {
+                user,
+                device,
+                before,
+                limit,
+                reply,
+            } => {
+                let limit = limit.min(MAX_HISTORY_PAGE);
+                let history = room.fetch_history(&user, &device, before, limit, unix_millis());
+                let _ = reply.send(Ok(history));
+            }
+            RoomCommand::Replay {
+                user,
+                device,
+                after,
+                reply,
+            } => {
+                let missed = room.fetch_since(&user, &device, after, MAX_REPLAY, unix_millis());
+                let _ = reply.send(Ok(missed));
+            }
+            RoomCommand::RoomKeys { viewer, reply } => {
+                if let Err(e) = check_member(&room, &viewer) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                let keys = room.get_roomkeys().map(|keys| RoomKeys {
+                    room: room.id().into(),
+                    keys: keys.into_iter().cloned().collect(),
+                });
+                let _ = reply.send(keys.map_err(ServerError::from));
+            }
+            RoomCommand::Members { viewer, reply } => {
+                if let Some(Err(e)) = viewer.map(|viewer| check_member(&room, &viewer)) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                let members = room.members().into_iter().map(UserId::from).collect();
+                let _ = reply.send(Ok(members));
+            }
+            RoomCommand::Mute {
+                user,
+                muted: mute,
+                reply,
+            } => {
+                if mute {
+                    muted.insert(user);
+                } else {
+                    muted.remove(&user);
+                }
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::Authorize {
+                user,
+                permission,
+                target,
+                reply,
+            } => {
+                let _ = reply.send(authorize(&room, &user, permission, target.as_deref()));
+            }
+            RoomCommand::SetRole {
+                by,
+                user,
+                role,
+                reply,
+            } => {
+                if let Some(by) = &by {
+                    let allowed = authorize(&room, by, Permission::ManageRoles, Some(&user))
+                        .and_then(|()| check_grant(room.role(by), role));
+                    if let Err(e) = allowed {
+                        let _ = reply.send(Err(e));
+                        continue;
+                    }
+                }
+                room.set_role(&user, role);
+                let _ = reply.send(Ok(()));
+                info!("Room {}: {} is now a {}", room.id(), user, role);
+
+                let changed = APIResponse::RoleChanged {
+                    room: room.id().into(),
+                    assignment: RoleAssignment {
+                        user: user.into(),
+                        role,
+                    },
+                };
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(|member| (UserId::from(member).into(), changed.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::Roles { viewer, reply } => {
+                if let Err(e) = check_member(&room, &viewer) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                let mut roles: Vec<RoleAssignment> = room
+                    .members()
+                    .into_iter()
+                    .map(|member| RoleAssignment {
+                        user: member.into(),
+                        role: room.role(member),
+                    })
+                    .collect();
+                roles.sort_by(|a, b| {
+                    b.role
+                        .cmp(&a.role)
+                        .then(a.user.as_str().cmp(b.user.as_str()))
+                });
+                let _ = reply.send(Ok(roles));
+            }
+            RoomCommand::SetCapacity {
+                by,
+                capacity,
+                reply,
+            } => {
+                let result = authorize(&room, &by, Permission::SetCapacity, None)
+                    .and_then(|()| room.set_capacity(capacity).map_err(ServerError::from));
+                let _ = reply.send(result);
+            }
+            RoomCommand::Invite { by, user, reply } => {
+                let result = authorize(&room, &by, Permission::Invite, None).and_then(|()| {
+                    if room.is_registered(&user) {
+                        return Err(ServerError::UserError(format!(
+                            "{} is already in #{}.",
+                            user,
+                            room.id()
+                        )));
+                    }
+                    room.invite(&user);
+                    info!("Room {}: {} invited {}", room.id(), by, user);
+                    Ok(())
+                });
+                let _ = reply.send(result);
+            }
+            RoomCommand::Info { reply } => {
+                let _ = reply.send(Ok(room.info()));
+            }
+            RoomCommand::SetTopic { by, topic, reply } => {
+                if let Err(e) = authorize(&room, &by, Permission::SetTopic, None) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                room.set_topic(topic.clone());
+                let _ = reply.send(Ok(()));
+
+                let changed = APIResponse::TopicChanged {
+                    room: room.id().into(),
+                    topic,
+                    by: by.into(),
+                };
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(|member| (UserId::from(member).into(), changed.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+            RoomCommand::SetVisibility {
+                by,
+                visibility,
+                reply,
+            } => {
+                let result = authorize(&room, &by, Permission::ChangeAccess, None);
+                if result.is_ok() {
+                    room.set_visibility(visibility);
+                }
+                let _ = reply.send(result);
+            }
+            RoomCommand::SetPassword {
+                by,
+                password,
+                reply,
+            } => {
+                let result = authorize(&room, &by, Permission::ChangeAccess, None);
+                if result.is_ok() {
+                    room.set_password(password);
+                }
+                let _ = reply.send(result);
+            }
+            RoomCommand::SetMessageTtl { by, ttl, reply } => {
+                if let Err(e) = authorize(&room, &by, Permission::SetMessageTtl, None) {
+                    let _ = reply.send(Err(e));
+                    continue;
+                }
+                room.set_message_ttl(ttl);
+                let _ = reply.send(Ok(()));
+                info!(
+                    "Room {}: {} set the message TTL to {:?}",
+                    room.id(),
+                    by,
+                    ttl
+                );
+
+                let changed = APIResponse::MessageTtlChanged {
+                    room: room.id().into(),
+                    ttl_secs: ttl.map(|ttl| ttl.as_secs()),
+                    by: by.into(),
+                };
+                let messages = room
+                    .members()
+                    .into_iter()
+                    .map(|member| (UserId::from(member).into(), changed.clone()))
+                    .collect();
+                let publish = room.publish_message(messages);
+                publish.await;
+            }
+        }
+    }
+    info!("Room {} closed", room.id());
+}
+
+// Checks that member `user` may do what `permission` covers, to `target` if
+// the action has one.
+fn authorize<G: ChatRoom>(
+    room: &G,
+    user: &str,
+    permission: Permission,
+    target: Option<&str>,
+) -> Result<(), ServerError> {
+    check_member(room, user)?;
+    let role = room.role(user);
+    if !role.allows(permission) {
+        return Err(ServerError::UserError(format!(
+            "Your role in #{} ({}) doesn't allow that.",
+            room.id(),
+            role
+        )));
+    }
+    match target {
+        Some(target) if target == user => Err(ServerError::UserError(
+            "You can't do that to yourself.".to_string(),
+        )),
+        Some(target) if !role.outranks(room.role(target)) => Err(ServerError::UserError(format!(
+            "You don't outrank {} in #{}.",
+            target,
+            room.id()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_not_member<G: ChatRoom>(room: &G, user: &str) -> Result<(), ServerError> {
+    if room.is_registered(user) {
+        Err(ServerError::UserError(format!(
+            "You are in #{} already.",
+            room.id()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_member<G: ChatRoom>(room: &G, user: &str) -> Result<(), ServerError> {
+    if room.is_registered(user) {
+        Ok(())
+    } else {
+        Err(ServerError::UserError(format!(
+            "You are not in #{}.",
+            room.id()
+        )))
+    }
+}
+
+// Members below owner may only hand out roles below their own.
+fn check_grant(granter: Role, role: Role) -> Result<(), ServerError> {
+    if granter.outranks(role) {
+        Ok(())
+    } else {
+        Err(ServerError::UserError(format!(
+            "Only owners can grant the {} role.",
+            role
+        )))
+    }
+}
+
+// Builds the receipt telling the sender of message `id` that `user` received
+// or read it. Returns `None` if the sender acknowledged their own message, or
+// `user` sent the same receipt before.
+fn build_receipt<G: ChatRoom>(
+    room: &mut G,
+    user: &str,
+    id: MessageId,
+    kind: ReceiptKind,
+) -> Result<Option<(UserId, APIResponse)>, ServerError> {
+    // Only recipients of a message may acknowledge it.
+    let message = room
+        .find_message(user, id)
+        .ok_or_else(|| ServerError::UserError("Unknown message.".to_string()))?;
+
+    let user_id: UserId = user.into();
+    let sender = message.sender.clone();
+    if sender == user_id || !room.acknowledge(user, id, kind) {
+        return Ok(None);
+    }
+
+    let receipt = Receipt {
+        id,
+        room: room.id().into(),
+        user: user_id,
+        timestamp: unix_millis(),
+    };
+    let event = match kind {
+        ReceiptKind::Delivered => APIResponse::DeliveredReceipt(receipt),
+        ReceiptKind::Read => APIResponse::ReadReceipt(receipt),
+    };
+    Ok(Some((sender, event)))
+}
+
+/// Cheap to clone handle to a room's task.
+#[derive(Clone)]
+pub struct RoomHandle {
+    pub id: ChatRoomId,
+    commands: Sender<RoomCommand>,
+}
+
+impl RoomHandle {
+    /// Adds the device with `key` to the room, making its user a member if
+    /// they weren't one.
+    pub async fn join(&self, key: DeviceKey, sender: Outbox) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Join {
+            key,
+            sender,
+            reply,
+        })
+        .await
+    }
+
+    /// Joins the user of device `key` to the room if they were invited, or
+    /// if the room is public and `password` is right or not needed.
+    pub async fn enter(
+        &self,
+        key: DeviceKey,
+        sender: Outbox,
+        password: Option<String>,
+    ) -> Result<(), ServerError> {
+        let required = request(&self.commands, |reply| RoomCommand::Admit {
+            user: key.user.clone(),
+            reply,
+        })
+        .await?;
+        if let Some(hash) = &required {
+            let Some(password) = password else {
+                let reason = format!("#{} needs a password.", self.id);
+                return Err(ChatRoomError::AccessDenied(reason).into());
+            };
+            if !hash.check(password).await {
+                let reason = format!("Wrong password for #{}.", self.id);
+                return Err(ChatRoomError::AccessDenied(reason).into());
+            }
+        }
+        request(&self.commands, |reply| RoomCommand::Enter {
+            key,
+            sender,
+            password: required,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn leave(&self, user: &str) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Leave {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Removes `user` like `leave`, and takes back their role and any
+    /// invitation, so they can't simply come back.
+    pub async fn kick(&self, user: &str) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Kick {
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// Takes on `name` once the server has registered the room under it, and
+    /// tells the members `by` renamed it.
+    pub async fn rename(&self, by: &str, name: &str) {
+        let rename = RoomCommand::Rename {
+            by: by.to_string(),
+            name: name.to_string(),
+        };
+        let _ = self.commands.send(rename).await;
+    }
+
+    /// Removes `user`'s `device`, or with `None` all their devices, unless
+    /// they reconnected meanwhile.
+    pub async fn forget(&self, user: &str, device: Option<&str>) {
+        let forget = RoomCommand::Forget {
+            user: user.to_string(),
+            device: device.map(str::to_string),
+        };
+        // A room that is gone has forgotten everyone already.
+        let _ = self.commands.send(forget).await;
+    }
+
+    /// Stores a message and fans it out to the members it was encrypted for.
+    /// Returns once the message has been assigned its id. The message is
+    /// dropped after `ttl`, or the room's default if it has one.
+    pub async fn publish(
+        &self,
+        sender: &str,
+        copies: Vec<EncryptedCopy>,
+        ttl: Option<Duration>,
+    ) -> Result<MessageId, ServerError> {
+        request(&self.commands, |reply| RoomCommand::Publish {
+            sender: sender.to_string(),
+            copies,
+            ttl,
+            reply,
+        })
+        .await
+    }
+
+    /// Replaces the content of message `id`, which `sender` published, and
+    /// delivers the new copies.
+    pub async fn edit(
+        &self,
+        sender: &str,
+        id: MessageId,
+        copies: Vec<EncryptedCopy>,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Edit {
+            sender: sender.to_string(),
+            id,
+            copies,
+            reply,
+        })
+        .await
+    }
+
+    /// Deletes message `id`, which `sender` published, and tells every
+    /// member. `signature` must have been checked by the caller.
+    pub async fn delete(
+        &self,
+        sender: &str,
+        id: MessageId,
+        signature: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Delete {
+            sender: sender.to_string(),
+            id,
+            signature,
+            reply,
+        })
+        .await
+    }
+
+    /// Tells the sender of message `id` that `user` received or read it.
+    pub async fn acknowledge(
+        &self,
+        user: &str,
+        id: MessageId,
+        kind: ReceiptKind,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Receipt {
+            user: user.to_string(),
+            id,
+            kind,
+            reply,
+        })
+        .await
+    }
+
+    /// Returns the room's retained messages that were encrypted for `user`'s
+    /// `device`.
+    pub async fn fetch_history(
+        &self,
+        user: &str,
+        device: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<PublishedMessage>, ServerError> {
+        request(&self.commands, |reply| RoomCommand::FetchHistory {
+            user: user.to_string(),
+            device: device.to_string(),
+            before,
+            limit,
+            reply,
+        })
+        .await
+    }
+
+    /// Returns the messages for `user`'s `device` published after sequence
+    /// number `after`, oldest first.
+    pub async fn replay(
+        &self,
+        user: &str,
+        device: &str,
+        after: Option<u64>,
+    ) -> Result<Vec<PublishedMessage>, ServerError> {
+        request(&self.commands, |reply| RoomCommand::Replay {
+            user: user.to_string(),
+            device: device.to_string(),
+            after,
+            reply,
+        })
+        .await
+    }
+
+    /// The keys of every device of every member, if `viewer` is one.
+    pub async fn room_keys(&self, viewer: &str) -> Result<RoomKeys, ServerError> {
+        request(&self.commands, |reply| RoomCommand::RoomKeys {
+            viewer: viewer.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    /// The members, if `viewer` is one of them or is `None`, for operators.
+    pub async fn members(&self, viewer: Option<&str>) -> Result<Vec<UserId>, ServerError> {
+        request(&self.commands, |reply| RoomCommand::Members {
+            viewer: viewer.map(str::to_string),
+            reply,
+        })
+        .await
+    }
+
+    /// Checks that `user` has `permission` in the room, and outranks
+    /// `target` if the action has one.
+    pub async fn authorize(
+        &self,
+        user: &str,
+        permission: Permission,
+        target: Option<&str>,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Authorize {
+            user: user.to_string(),
+            permission,
+            target: target.map(str::to_string),
+            reply,
+        })
+        .await
+    }
+
+    /// Gives `user` a new role. Unless `by` is `None`, for operators, the
+    /// change is checked against `by`'s own role.
+    pub async fn set_role(
+        &self,
+        by: Option<&str>,
+        user: &str,
+        role: Role,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::SetRole {
+            by: by.map(str::to_string),
+            user: user.to_string(),
+            role,
+            reply,
+        })
+        .await
+    }
+
+    /// The members with their roles, highest ranked first, if `viewer` is one
+    /// of them.
+    pub async fn roles(&self, viewer: &str) -> Result<Vec<RoleAssignment>, ServerError> {
+        request(&self.commands, |reply| RoomCommand::Roles {
+            viewer: viewer.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_capacity(&self, by: &str, capacity: usize) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::SetCapacity {
+            by: by.to_string(),
+            capacity,
+            reply,
+        })
+        .await
+    }
+
+    /// Lets `user` join the room, if `by` may invite people.
+    pub async fn invite(&self, by: &str, user: &str) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Invite {
+            by: by.to_string(),
+            user: user.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn info(&self) -> Result<RoomInfo, ServerError> {
+        request(&self.commands, |reply| RoomCommand::Info { reply }).await
+    }
+
+    /// Sets or clears the topic, if `by` may, and tells every member.
+    pub async fn set_topic(&self, by: &str, topic: Option<String>) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::SetTopic {
+            by: by.to_string(),
+            topic,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_visibility(
+        &self,
+        by: &str,
+        visibility: Visibility,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::SetVisibility {
+            by: by.to_string(),
+            visibility,
+            reply,
+        })
+        .await
+    }
+
+    /// Sets the room's password, or removes it if `password` is `None`. Only
+    /// its hash is passed on to the room.
+    pub async fn set_password(&self, by: &str, password: Option<&str>) -> Result<(), ServerError> {
+        let password = match password {
+            Some(password) => Some(PasswordHash::hash(password.to_string()).await),
+            None => None,
+        };
+        request(&self.commands, |reply| RoomCommand::SetPassword {
+            by: by.to_string(),
+            password,
+            reply,
+        })
+        .await
+    }
+
+    /// Sets how long messages last unless their sender says otherwise, if
+    /// `by` may, and tells every member.
+    pub async fn set_message_ttl(
+        &self,
+        by: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::SetMessageTtl {
+            by: by.to_string(),
+            ttl,
+            reply,
+        })
+        .await
+    }
+
+    /// Stops `user` from publishing to the room if `muted`, or lets them
+    /// again.
+    pub async fn set_muted(&self, user: &str, muted: bool) -> Result<(), ServerError> {
+        request(&self.commands, |reply| RoomCommand::Mute {
+            user: user.to_string(),
+            muted,
+            reply,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatroom::{SimpleChatRoom, DEFAULT_PUBLISH_TIMEOUT};
+    use crate::devices::unsigned_key;
+    use crate::outbox::{outbox, OutboxSettings};
+
+    #[test]
+    fn permissions_follow_roles_and_rank() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 8);
+        for user in ["olivia", "maria", "gus", "mel"] {
+            room.register_user(
+                unsigned_key(user, "laptop"),
+                outbox(OutboxSettings::default()).0,
+            )
+            .unwrap();
+        }
+        room.set_role("olivia", Role::Owner);
+        room.set_role("maria", Role::Moderator);
+        room.set_role("gus", Role::Guest);
+
+        assert!(authorize(&room, "mel", Permission::Post, None).is_ok());
+        assert!(authorize(&room, "gus", Permission::Post, None).is_err());
+        assert!(authorize(&room, "maria", Permission::Kick, Some("mel")).is_ok());
+        assert!(authorize(&room, "maria", Permission::Kick, Some("olivia")).is_err());
+        assert!(authorize(&room, "maria", Permission::Kick, Some("maria")).is_err());
+        assert!(authorize(&room, "maria", Permission::Delete, None).is_err());
+        assert!(authorize(&room, "olivia", Permission::Delete, None).is_ok());
+        assert!(authorize(&room, "maria", Permission::Rename, None).is_err());
+        assert!(authorize(&room, "olivia", Permission::Rename, None).is_ok());
+        assert!(authorize(&room, "maria", Permission::SetMessageTtl, None).is_err());
+        assert!(authorize(&room, "olivia", Permission::SetMessageTtl, None).is_ok());
+        assert!(authorize(&room, "stranger", Permission::Post, None).is_err());
+
+        assert!(check_grant(Role::Moderator, Role::Member).is_ok());
+        assert!(check_grant(Role::Moderator, Role::Moderator).is_err());
+        assert!(check_grant(Role::Owner, Role::Owner).is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_members_see_roles() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 8);
+        room.register_user(
+            unsigned_key("olivia", "laptop"),
+            outbox(OutboxSettings::default()).0,
+        )
+        .unwrap();
+        room.set_role("olivia", Role::Owner);
+        let handle = spawn(room);
+
+        let roles = handle.roles("olivia").await.unwrap();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].role, Role::Owner);
+        assert!(handle.roles("stranger").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn kicked_members_cannot_walk_back_in() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 8);
+        room.set_visibility(Visibility::Private);
+        room.set_role("gus", Role::Guest);
+        room.set_role("maria", Role::Moderator);
+        room.invite("gus");
+        let handle = spawn(room);
+        let enter = |user: &str| {
+            handle.enter(
+                unsigned_key(user, "laptop"),
+                outbox(OutboxSettings::default()).0,
+                None,
+            )
+        };
+
+        for user in ["gus", "maria"] {
+            enter(user).await.unwrap();
+            handle.kick(user).await.unwrap();
+            assert!(enter(user).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn entering_checks_the_password_outside_the_room() {
+        let mut room = SimpleChatRoom::build("vault".to_string(), 8);
+        room.set_password(Some(PasswordHash::new("hunter2")));
+        let handle = spawn(room);
+        let enter = |password: Option<&str>| {
+            handle.enter(
+                unsigned_key("alice", "laptop"),
+                outbox(OutboxSettings::default()).0,
+                password.map(str::to_string),
+            )
+        };
+
+        assert!(enter(None).await.is_err());
+        assert!(enter(Some("hunter3")).await.is_err());
+        enter(Some("hunter2")).await.unwrap();
+        assert!(enter(Some("hunter2")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_stalled_member_does_not_hold_up_the_room() {
+        let mut room = SimpleChatRoom::build("ops".to_string(), 8);
+        let stalled = OutboxSettings {
+            capacity: 1,
+            ..OutboxSettings::default()
+        };
+        let (alice, _alice_rx) = outbox(stalled);
+        let (bob, mut bob_rx) = outbox(OutboxSettings::default());
+        room.register_user(unsigned_key("alice", "laptop"), alice)
+            .unwrap();
+        room.register_user(unsigned_key("bob", "laptop"), bob)
+            .unwrap();
+        room.set_role("bob", Role::Owner);
+        let handle = spawn(room);
+
+        // Alice never reads, yet bob's topic changes go through right away.
+        let changes = async {
+            for topic in ["one", "two", "three", "four"] {
+                handle
+                    .set_topic("bob", Some(topic.to_string()))
+                    .await
+                    .unwrap();
+            }
+            handle.members(None).await.unwrap();
+        };
+        tokio::time::timeout(DEFAULT_PUBLISH_TIMEOUT / 2, changes)
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            assert!(matches!(
+                bob_rx.try_recv().unwrap().message,
+                APIResponse::TopicChanged { .. }
+            ));
+        }
+    }
+}
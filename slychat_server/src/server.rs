@@ -1,17 +1,37 @@
-use bimap::BiMap;
-use log::info;
-use slychat_common::types::UserKey;
-use std::{collections::HashMap, error::Error, fmt::Display, hash::Hash};
+use futures::future::join_all;
+use log::{info, warn};
+use slychat_common::encryption::{fingerprint, random_bytes};
+use slychat_common::types::{
+    APIResponse, ChatRoomId, DeviceInfo, DeviceKey, Invitation, Presence, PresenceState, UserId,
+};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+};
 
-use tokio::{io::AsyncWriteExt, sync::mpsc::Receiver};
+use tokio::select;
+use tokio::sync::mpsc::Receiver;
 
-use crate::chatroom::{self, ChatRoom, ChatRoomError};
+use crate::bans::{BanTarget, Bans};
+use crate::chatroom::{ChatRoom, ChatRoomError, DEFAULT_PUBLISH_TIMEOUT};
+use crate::devices::Devices;
+use crate::handle::ServerCommand;
+use crate::membership::Memberships;
+use crate::outbox::{Outbox, QueueMetrics};
+use crate::room::{self, Broadcast, RoomHandle};
+use crate::throttle::Throttle;
 
 #[derive(Debug, Clone)]
 pub enum ServerError {
     UserError(String),
     ChatRoomError(ChatRoomError),
     InvalidChatRoomError,
+    // The server actor stopped before answering.
+    Unavailable,
 }
 
 impl From<ChatRoomError> for ServerError {
@@ -24,147 +44,1262 @@ impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerError::UserError(s) => write!(f, "{}", s),
-            ChatRoomError => ChatRoomError.fmt(f),
+            ServerError::ChatRoomError(e) => e.fmt(f),
+            ServerError::InvalidChatRoomError => write!(f, "Invalid Chatroom"),
+            ServerError::Unavailable => write!(f, "Server unavailable"),
         }
     }
 }
 
 impl Error for ServerError {}
 
-const DEFAULT_CAPACITY: usize = 64;
-const WAITING_ROOM: &str = "waiting";
+pub const DEFAULT_CAPACITY: usize = 64;
+pub const WAITING_ROOM: &str = "waiting";
+/// Typing notifications more frequent than this are dropped.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+/// Presence changes more frequent than this are rejected.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_STATUS_LEN: usize = 64;
+/// How long after a connection drops its session can still be resumed.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How often sessions past their resume window are cleaned up.
+const SESSION_SWEEP: Duration = Duration::from_secs(30);
 
-#[derive(PartialEq, Eq, Hash, Clone)]
-pub struct UserId(String);
+/// Milliseconds since the unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the unix epoch")
+        .as_millis() as u64
+}
 
-impl<S> From<S> for UserId
-where
-    S: Into<String>,
-{
-    fn from(s: S) -> Self {
-        Self(s.into())
-    }
+/// An event pushed to a connected user, outside of the request/response flow.
+pub struct UserMessage {
+    pub user_id: UserId,
+    pub message: APIResponse,
 }
 
-impl PartialEq<String> for UserId {
-    fn eq(&self, other: &String) -> bool {
-        &self.0 == other
+/// An event for connected users picked by the server rather than by room,
+/// such as everyone sharing a room with someone whose presence changed. Each
+/// gets it once, however many rooms they share.
+pub struct Announcement {
+    recipients: Vec<(UserId, Outbox)>,
+    message: APIResponse,
+}
+
+impl Announcement {
+    /// Queues the event for every recipient, waiting a while for those who
+    /// are behind.
+    pub async fn send(self) {
+        let message = self.message;
+        let sends = self.recipients.into_iter().map(|(user_id, outbox)| {
+            let event = UserMessage {
+                user_id,
+                message: message.clone(),
+            };
+            async move {
+                let _ = outbox.send(event, DEFAULT_PUBLISH_TIMEOUT).await;
+            }
+        });
+        join_all(sends).await;
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone)]
-pub struct ChatRoomId(String);
+/// A successful registration.
+pub struct Login {
+    /// The rooms the user is in. Joining them is left to the caller.
+    pub rooms: Vec<RoomHandle>,
+    pub resume_token: String,
+    /// Whether the user was returned to the rooms of an earlier session.
+    pub resumed: bool,
+}
+
+/// A device's login that can be picked back up with its token after the
+/// connection drops. The device keeps its place in the user's rooms until
+/// the session expires.
+struct ResumableSession {
+    token: String,
+    // Set once the connection is gone.
+    expires: Option<Instant>,
+}
 
-impl<S> From<S> for ChatRoomId
-where
-    S: Into<String>,
-{
-    fn from(s: S) -> Self {
-        Self(s.into())
-    }
+/// One of a user's connected devices.
+struct Connection {
+    key: DeviceKey,
+    outbox: Outbox,
+    address: IpAddr,
 }
 
-pub struct UserMessage {
-    pub user_id: UserId,
-    pub message: Vec<u8>,
+/// A connected device, as listed to operators.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub user: UserId,
+    /// The name the user gave the device.
+    pub device: String,
+    pub address: IpAddr,
+    /// The device id, the fingerprint of its public key.
+    pub key: String,
+    pub rooms: Vec<ChatRoomId>,
+    pub presence: PresenceState,
+}
+
+fn generate_token() -> String {
+    random_bytes(16)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
-// I don't know what the fuck to do because
-// Accessing the users which need to mutate often because socket needs to be mutable
-// Is terrible! Terrible!
+
+/// Owns the server wide state: who is connected from which devices, their
+/// keys and presence, and which rooms exist. Once built, the server is moved
+/// into its own task with `receive_loop` and only reached through a
+/// `ServerHandle`. Each room runs in a task of its own, so traffic in one
+/// room never waits on another.
 pub struct Server<G: ChatRoom> {
-    // Public key registry
-    pub key_registry: HashMap<UserId, Vec<u8>>,
-    pub receiver: Receiver<String>,
-    pub user_handlers: HashMap<UserId, Receiver<String>>,
-    pub chat_rooms: HashMap<ChatRoomId, G>,
-    pub chatroom_registry: BiMap<UserId, ChatRoomId>,
+    pub receiver: Receiver<ServerCommand>,
+    // The connected devices of each user, by device id.
+    connected: HashMap<UserId, HashMap<String, Connection>>,
+    // Every device users have logged in from.
+    devices: Devices,
+    pub chat_rooms: HashMap<ChatRoomId, RoomHandle>,
+    // Rooms of every user with a session on any device, connected or not.
+    memberships: Memberships,
+    pub presence: HashMap<UserId, Presence>,
+    // Users who don't let others know when they read a message, whichever
+    // device they set it from.
+    hidden_reads: HashSet<UserId>,
+    // Room every user is placed in after logging in.
+    pub waiting_room: ChatRoomId,
+    pub default_capacity: usize,
+    // Sessions of each user, by device id.
+    sessions: HashMap<UserId, HashMap<String, ResumableSession>>,
+    // Invitations not used yet, kept whether or not the user is connected.
+    invitations: HashMap<UserId, Vec<Invitation>>,
+    bans: Bans,
+    typing_throttle: Throttle,
+    presence_throttle: Throttle,
+    room_type: PhantomData<fn() -> G>,
 }
 
 impl<G: ChatRoom> Server<G> {
-    pub fn build(receiver: Receiver<String>) -> Self {
-        let wr_str = WAITING_ROOM.to_string();
+    pub fn build(receiver: Receiver<ServerCommand>) -> Self {
+        Self::with_waiting_room(receiver, WAITING_ROOM, DEFAULT_CAPACITY)
+    }
 
+    /// Builds a server whose waiting room is called `waiting_room`. Rooms
+    /// created without an explicit capacity get `default_capacity`. Rooms are
+    /// spawned as tasks, so this has to be called from within the runtime.
+    pub fn with_waiting_room(
+        receiver: Receiver<ServerCommand>,
+        waiting_room: &str,
+        default_capacity: usize,
+    ) -> Self {
         let mut server = Self {
             receiver,
-            key_registry: HashMap::new(),
-            user_handlers: HashMap::new(),
+            connected: HashMap::new(),
+            devices: Devices::default(),
             chat_rooms: HashMap::new(),
-            chatroom_registry: BiMap::new(),
+            memberships: Memberships::default(),
+            presence: HashMap::new(),
+            hidden_reads: HashSet::new(),
+            waiting_room: waiting_room.into(),
+            default_capacity,
+            sessions: HashMap::new(),
+            invitations: HashMap::new(),
+            bans: Bans::default(),
+            typing_throttle: Throttle::new(TYPING_INTERVAL),
+            presence_throttle: Throttle::new(PRESENCE_INTERVAL),
+            room_type: PhantomData,
         };
 
+        // Create waiting room
         server
-            .create_chatroom(wr_str, DEFAULT_CAPACITY)
+            .create_chatroom(waiting_room.to_string(), default_capacity)
             .expect("Failed to create waiting room during server build.");
 
-        // Create waiting room
         server
     }
 
-    pub async fn receive_loop(&mut self) {
+    /// Refuses logins matching `bans` from now on.
+    pub fn set_bans(&mut self, bans: Bans) {
+        self.bans = bans;
+    }
+
+    /// Keeps track of users' devices in `devices` from now on.
+    pub fn set_devices(&mut self, devices: Devices) {
+        self.devices = devices;
+    }
+
+    /// Serves commands until every `ServerHandle` has been dropped.
+    pub async fn receive_loop(mut self) {
+        let mut sweep = tokio::time::interval(SESSION_SWEEP);
         loop {
-            if let Some(x) = self.receiver.recv().await {
-                todo!()
+            select! {
+                command = self.receiver.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => break,
+                },
+                _ = sweep.tick() => self.expire_sessions(Instant::now()),
             }
         }
+        info!("All server handles dropped, stopping.");
     }
 
+    // Replies are dropped if the requesting connection went away meanwhile.
+    fn handle_command(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::RegisterUser {
+                key,
+                address,
+                sender,
+                resume,
+                reply,
+            } => {
+                let registered = self.register_user(key, sender, address, resume.as_deref());
+                let _ = reply.send(registered);
+            }
+            ServerCommand::UnregisterUser {
+                user,
+                device,
+                resumable,
+                reply,
+            } => {
+                let _ = reply.send(self.unregister_user(&user, &device, resumable));
+            }
+            ServerCommand::AnnouncePresence { user, reply } => {
+                let _ = reply.send(Ok(self.announce_presence(&user)));
+            }
+            ServerCommand::SetPresence {
+                user,
+                state,
+                status,
+                reply,
+            } => {
+                let _ = reply.send(self.set_presence(&user, state, status));
+            }
+            ServerCommand::SetReadReceipts {
+                user,
+                enabled,
+                reply,
+            } => {
+                self.set_read_receipts(&user, enabled);
+                let _ = reply.send(Ok(()));
+            }
+            ServerCommand::SendsReadReceipts { user, reply } => {
+                let _ = reply.send(Ok(self.sends_read_receipts(&user)));
+            }
+            ServerCommand::Typing { user, room, reply } => {
+                let _ = reply.send(self.typing(&user, &room));
+            }
+            ServerCommand::PresenceOf { users, reply } => {
+                let _ = reply.send(Ok(self.presence_of(users)));
+            }
+            ServerCommand::GetRoom { room, reply } => {
+                let _ = reply.send(self.get_room(&room).cloned());
+            }
+            ServerCommand::WaitingRoom { reply } => {
+                let waiting = self.chat_rooms.get(&self.waiting_room).cloned();
+                let _ = reply.send(waiting.ok_or(ServerError::InvalidChatRoomError));
+            }
+            ServerCommand::JoinedRoom {
+                user,
+                device,
+                room,
+                reply,
+            } => {
+                let _ = reply.send(self.joined_room(&user, &device, room));
+            }
+            ServerCommand::LeftRoom {
+                user,
+                device,
+                room,
+                reply,
+            } => {
+                let _ = reply.send(self.left_room(&user, &device, &room));
+            }
+            ServerCommand::Invite {
+                user,
+                invitation,
+                reply,
+            } => {
+                self.invite(&user, invitation);
+                let _ = reply.send(Ok(()));
+            }
+            ServerCommand::Invitations { user, reply } => {
+                let invitations = self.invitations.get(&user.into()).cloned();
+                let _ = reply.send(Ok(invitations.unwrap_or_default()));
+            }
+            ServerCommand::QueueMetrics { reply } => {
+                let _ = reply.send(Ok(self.queue_metrics()));
+            }
+            ServerCommand::Connections { reply } => {
+                let _ = reply.send(Ok(self.connections()));
+            }
+            ServerCommand::Rooms { reply } => {
+                let _ = reply.send(Ok(self.rooms()));
+            }
+            ServerCommand::Kick {
+                target,
+                reason,
+                reply,
+            } => {
+                let _ = reply.send(Ok(self.kick(&target, &reason)));
+            }
+            ServerCommand::DeleteRoom { room, reply } => {
+                let deleted = self
+                    .delete_chatroom(room)
+                    .map_err(|e| ServerError::UserError(e.to_string()));
+                let _ = reply.send(deleted);
+            }
+            ServerCommand::RenameRoom {
+                room,
+                name,
+                by,
+                reply,
+            } => {
+                let _ = reply.send(self.rename_chatroom(&room, &name, &by));
+            }
+            ServerCommand::RemoveFromRoom {
+                user,
+                room,
+                reason,
+                reply,
+            } => {
+                let _ = reply.send(self.remove_from_room(&user, &room, &reason));
+            }
+            ServerCommand::Notice { text, reply } => {
+                let _ = reply.send(Ok(self.notice(&text)));
+            }
+            ServerCommand::ListDevices { user, reply } => {
+                let _ = reply.send(Ok(self.list_devices(&user)));
+            }
+            ServerCommand::RevokeDevice {
+                user,
+                device,
+                current,
+                reply,
+            } => {
+                let _ = reply.send(self.revoke_device(&user, &device, &current));
+            }
+        }
+    }
+
+    /// Registers a device connected from `address`. A valid `resume` token
+    /// returns it to the rooms of its earlier session. Otherwise it joins the
+    /// user's other devices in their rooms, or if there are none the user is
+    /// placed in the waiting room.
     pub fn register_user(
         &mut self,
-        mut user: &String,
-        mut sender: tokio::sync::mpsc::Sender<UserMessage>,
-        public: Vec<u8>,
+        key: DeviceKey,
+        sender: Outbox,
+        address: IpAddr,
+        resume: Option<&str>,
+    ) -> Result<Login, ServerError> {
+        let user = key.user.clone();
+        let device = key.id();
+        let banned = [
+            BanTarget::User(user.clone()),
+            BanTarget::Key(device.clone()),
+            BanTarget::Key(fingerprint(&key.identity)),
+            BanTarget::Address(address),
+        ];
+        if banned.iter().any(|target| self.bans.is_banned(target)) {
+            info!("Refusing banned user {} from {}", user, address);
+            return Err(ServerError::UserError(
+                "You are banned from this server.".to_string(),
+            ));
+        }
+
+        let user_key: UserId = user.as_str().into();
+        let now = Instant::now();
+        let resuming = resume.is_some_and(|token| {
+            self.session(&user_key, &device)
+                .is_some_and(|s| s.token == token && s.expires.is_none_or(|expires| expires > now))
+        });
+
+        if let Some(previous) = self.connection(&user_key, &device) {
+            if resuming {
+                // The old connection has not noticed it is dead yet. Close it
+                // and let the client retry once it has been cleaned up.
+                previous.outbox.close();
+                return Err(ServerError::UserError(
+                    "Previous connection still open, try again.".to_string(),
+                ));
+            }
+            return Err(ServerError::UserError(
+                "Device already connected.".to_string(),
+            ));
+        }
+        self.devices
+            .enroll(&key, unix_millis())
+            .map_err(|e| ServerError::UserError(e.to_string()))?;
+
+        if !resuming {
+            // Starting over, so give up the places held by an old session of
+            // this device.
+            self.end_session(&user_key, &device);
+            self.memberships.insert(&user_key, &self.waiting_room);
+        }
+        let rooms: Vec<RoomHandle> = self
+            .memberships
+            .rooms_of(&user_key)
+            .iter()
+            .filter_map(|room| self.chat_rooms.get(room).cloned())
+            .collect();
+
+        let resume_token = generate_token();
+        self.sessions.entry(user_key.clone()).or_default().insert(
+            device.clone(),
+            ResumableSession {
+                token: resume_token.clone(),
+                expires: None,
+            },
+        );
+
+        // Invitations that came in while the user was away.
+        for invitation in self.invitations.get(&user_key).into_iter().flatten() {
+            let invited = UserMessage {
+                user_id: user_key.clone(),
+                message: APIResponse::Invited(invitation.clone()),
+            };
+            let _ = sender.try_send(invited);
+        }
+        let connection = Connection {
+            key,
+            outbox: sender,
+            address,
+        };
+        self.connected
+            .entry(user_key.clone())
+            .or_default()
+            .insert(device, connection);
+        self.presence.entry(user_key.clone()).or_insert(Presence {
+            user: user_key,
+            state: PresenceState::Online,
+            status: None,
+        });
+
+        Ok(Login {
+            rooms,
+            resume_token,
+            resumed: resuming,
+        })
+    }
+
+    fn session(&self, user: &UserId, device: &str) -> Option<&ResumableSession> {
+        self.sessions.get(user)?.get(device)
+    }
+
+    fn connection(&self, user: &UserId, device: &str) -> Option<&Connection> {
+        self.connected.get(user)?.get(device)
+    }
+
+    // The connected devices of `user`.
+    fn connections_of(&self, user: &UserId) -> impl Iterator<Item = (&String, &Connection)> {
+        self.connected.get(user).into_iter().flatten()
+    }
+
+    // Queues `message` for every connected device of `user`.
+    fn tell(&self, user: &UserId, message: APIResponse) {
+        for (_, connection) in self.connections_of(user) {
+            let _ = connection.outbox.try_send(UserMessage {
+                user_id: user.clone(),
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// Records that `user` joined `room` from `device`, using up any
+    /// invitation to it, and returns the notification announcing them there.
+    /// Joining the room itself is up to the caller. The user's other
+    /// connected devices are added to the room as well.
+    pub fn joined_room(
+        &mut self,
+        user: &str,
+        device: &str,
+        room: ChatRoomId,
+    ) -> Result<Option<Broadcast>, ServerError> {
+        let user_key: UserId = user.into();
+        if self.session(&user_key, device).is_none() {
+            return Err(ServerError::UserError("User not registered.".to_string()));
+        }
+        if let Some(invitations) = self.invitations.get_mut(&user_key) {
+            invitations.retain(|invitation| invitation.room != room);
+            if invitations.is_empty() {
+                self.invitations.remove(&user_key);
+            }
+        }
+        self.memberships.insert(&user_key, &room);
+        if let Some(handle) = self.chat_rooms.get(&room) {
+            for (_, other) in self
+                .connections_of(&user_key)
+                .filter(|(id, _)| *id != device)
+            {
+                let (handle, key, outbox) =
+                    (handle.clone(), other.key.clone(), other.outbox.clone());
+                let added = UserMessage {
+                    user_id: user_key.clone(),
+                    message: APIResponse::AddedToRoom { room: room.clone() },
+                };
+                tokio::spawn(async move {
+                    if handle.join(key, outbox.clone()).await.is_ok() {
+                        let _ = outbox.try_send(added);
+                    }
+                });
+            }
+        }
+
+        let Some(presence) = self.presence.get(&user_key) else {
+            return Ok(None);
+        };
+        let announcement = APIResponse::PresenceUpdate(presence.clone());
+        Ok(self.room_broadcast(&room, user_key, announcement, false))
+    }
+
+    /// Records that `user` left `room` from `device`, and tells their other
+    /// devices. Leaving the room itself is up to the caller.
+    pub fn left_room(
+        &mut self,
+        user: &str,
+        device: &str,
+        room: &ChatRoomId,
     ) -> Result<(), ServerError> {
-        // Should do a membership check first
-        if self.user_handlers.contains_key(&UserId(user.to_string())) {
+        let user_key: UserId = user.into();
+        if !self.memberships.remove(&user_key, room) {
+            return Err(ServerError::UserError(format!("You are not in #{}.", room)));
+        }
+        for (_, other) in self
+            .connections_of(&user_key)
+            .filter(|(id, _)| *id != device)
+        {
+            let removed = UserMessage {
+                user_id: user_key.clone(),
+                message: APIResponse::RemovedFromRoom {
+                    room: room.clone(),
+                    reason: format!("You left #{} on another device", room),
+                },
+            };
+            let _ = other.outbox.try_send(removed);
+        }
+        Ok(())
+    }
+
+    /// Holds `invitation` until `user` uses it, replacing an earlier one to
+    /// the same room, and tells them about it if they are connected.
+    pub fn invite(&mut self, user: &str, invitation: Invitation) {
+        let user_key: UserId = user.into();
+        let invitations = self.invitations.entry(user_key.clone()).or_default();
+        invitations.retain(|earlier| earlier.room != invitation.room);
+        invitations.push(invitation.clone());
+        self.tell(&user_key, APIResponse::Invited(invitation));
+    }
+
+    /// Removes a user's device from the server. If `resumable`, its session
+    /// can be resumed for `RESUME_WINDOW`, otherwise the device is taken out
+    /// of the user's rooms, and the user with it if it was their last
+    /// session. Once the user's last device is gone, returns the notification
+    /// telling everyone sharing a room with them that they went offline.
+    pub fn unregister_user(
+        &mut self,
+        user: &str,
+        device: &str,
+        resumable: bool,
+    ) -> Result<Option<Announcement>, ServerError> {
+        let user_key: UserId = user.into();
+        let Some(devices) = self.connected.get_mut(&user_key) else {
+            return Err(ServerError::UserError("User not registered.".to_string()));
+        };
+        if devices.remove(device).is_none() {
+            return Err(ServerError::UserError("Device not registered.".to_string()));
+        }
+        let last_device = devices.is_empty();
+        if last_device {
+            self.connected.remove(&user_key);
+        }
+        if let Err(e) = self.devices.seen(user, device, unix_millis()) {
+            warn!("Could not record when {} was last seen: {}", user, e);
+        }
+        if resumable {
+            if let Some(session) = self
+                .sessions
+                .get_mut(&user_key)
+                .and_then(|s| s.get_mut(device))
+            {
+                session.expires = Some(Instant::now() + RESUME_WINDOW);
+            }
+        }
+
+        let mut departure = None;
+        if last_device {
+            self.presence.remove(&user_key);
+            self.typing_throttle.forget(&user_key);
+            self.presence_throttle.forget(&user_key);
+            let offline = Presence {
+                user: user_key.clone(),
+                state: PresenceState::Offline,
+                status: None,
+            };
+            // Worked out before the user leaves their rooms, so the people
+            // they shared them with still hear about it.
+            departure = self.announcement(&user_key, APIResponse::PresenceUpdate(offline));
+        }
+        if !resumable {
+            self.end_session(&user_key, device);
+        }
+
+        info!("Unregistered device {} of {}", device, user);
+        Ok(departure)
+    }
+
+    // Ends the session of `user`'s `device`, if there is one, giving up the
+    // places the device held in rooms. Once the user has no sessions left
+    // they are taken out of their rooms altogether.
+    fn end_session(&mut self, user: &UserId, device: &str) {
+        let ended = self
+            .sessions
+            .get_mut(user)
+            .is_some_and(|sessions| sessions.remove(device).is_some());
+        if self
+            .sessions
+            .get(user)
+            .is_some_and(|sessions| sessions.is_empty())
+        {
+            self.sessions.remove(user);
+        }
+        if !self.sessions.contains_key(user) {
+            self.forget_rooms(user);
+        } else if ended {
+            for room in self.memberships.rooms_of(user) {
+                if let Some(room) = self.chat_rooms.get(&room).cloned() {
+                    let (user, device) = (user.clone(), device.to_string());
+                    tokio::spawn(async move { room.forget(user.as_str(), Some(&device)).await });
+                }
+            }
+        }
+    }
+
+    // Takes `user` out of all their rooms, giving up the places they held
+    // there unless they are connected to them again meanwhile.
+    fn forget_rooms(&mut self, user: &UserId) {
+        for room in self.memberships.remove_user(user) {
+            if let Some(room) = self.chat_rooms.get(&room).cloned() {
+                let user = user.clone();
+                tokio::spawn(async move { room.forget(user.as_str(), None).await });
+            }
+        }
+    }
+
+    // Drops sessions whose resume window has passed, along with the places
+    // their device was holding in rooms.
+    fn expire_sessions(&mut self, now: Instant) {
+        let expired: Vec<(UserId, String)> = self
+            .sessions
+            .iter()
+            .flat_map(|(user, sessions)| sessions.iter().map(move |(device, s)| (user, device, s)))
+            .filter(|(_, _, s)| s.expires.is_some_and(|expires| expires <= now))
+            .map(|(user, device, _)| (user.clone(), device.clone()))
+            .collect();
+
+        for (user, device) in expired {
+            self.end_session(&user, &device);
+        }
+    }
+
+    fn room_broadcast(
+        &self,
+        room: &ChatRoomId,
+        except: UserId,
+        message: APIResponse,
+        ephemeral: bool,
+    ) -> Option<Broadcast> {
+        Some(Broadcast {
+            room: self.chat_rooms.get(room)?.clone(),
+            except,
+            message,
+            ephemeral,
+        })
+    }
+
+    // `message` for every connected device of everyone sharing a room with
+    // `user`.
+    fn announcement(&self, user: &UserId, message: APIResponse) -> Option<Announcement> {
+        let recipients: Vec<(UserId, Outbox)> = self
+            .memberships
+            .neighbors_of(user)
+            .into_iter()
+            .flat_map(|neighbor| {
+                self.connections_of(&neighbor)
+                    .map(|(_, connection)| (neighbor.clone(), connection.outbox.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        (!recipients.is_empty()).then_some(Announcement {
+            recipients,
+            message,
+        })
+    }
+
+    /// Returns the notification telling everyone sharing a room with `user`
+    /// about their current presence.
+    pub fn announce_presence(&self, user: &str) -> Option<Announcement> {
+        let user_key: UserId = user.into();
+        let presence = self.presence.get(&user_key)?;
+        self.announcement(&user_key, APIResponse::PresenceUpdate(presence.clone()))
+    }
+
+    pub fn set_presence(
+        &mut self,
+        user: &str,
+        state: PresenceState,
+        status: Option<String>,
+    ) -> Result<Option<Announcement>, ServerError> {
+        let user_key: UserId = user.into();
+        if state == PresenceState::Offline {
+            return Err(ServerError::UserError(
+                "Offline is set by disconnecting.".to_string(),
+            ));
+        }
+        if status
+            .as_ref()
+            .is_some_and(|s| s.chars().count() > MAX_STATUS_LEN)
+        {
+            return Err(ServerError::UserError(format!(
+                "Status text is limited to {} characters.",
+                MAX_STATUS_LEN
+            )));
+        }
+        if !self.presence_throttle.allow(&user_key, Instant::now()) {
             return Err(ServerError::UserError(
-                "User already registered.".to_string(),
+                "Presence changed too recently.".to_string(),
             ));
         }
 
-        self.chat_rooms
-            .get_mut(&WAITING_ROOM.into())
-            .expect("Could not find waiting room")
-            .register_user(user, public)?;
+        self.presence.insert(
+            user_key.clone(),
+            Presence {
+                user: user_key,
+                state,
+                status,
+            },
+        );
+        Ok(self.announce_presence(user))
+    }
+
+    /// Lets others know when `user` reads their messages, or stops to.
+    pub fn set_read_receipts(&mut self, user: &str, enabled: bool) {
+        let user_key: UserId = user.into();
+        if enabled {
+            self.hidden_reads.remove(&user_key);
+        } else {
+            self.hidden_reads.insert(user_key);
+        }
+    }
+
+    /// Whether `user` lets others know when they read a message.
+    pub fn sends_read_receipts(&self, user: &str) -> bool {
+        !self.hidden_reads.contains(&UserId::from(user))
+    }
 
-        self.chatroom_registry
-            .insert(user.into(), WAITING_ROOM.into());
+    /// Returns the typing notification for `room`, or nothing if the user
+    /// already sent one recently.
+    pub fn typing(&mut self, user: &str, room: &str) -> Result<Option<Broadcast>, ServerError> {
+        let user_key: UserId = user.into();
+        let room: ChatRoomId = room.into();
+        if !self.memberships.contains(&user_key, &room) {
+            return Err(ServerError::UserError(format!("You are not in #{}.", room)));
+        }
+
+        if !self.typing_throttle.allow(&user_key, Instant::now()) {
+            return Ok(None);
+        }
+
+        let notification = APIResponse::TypingNotification {
+            room: room.clone(),
+            user: user_key.clone(),
+        };
+        // Typing notifications are ephemeral, so recipients that are behind
+        // simply miss them.
+        Ok(self.room_broadcast(&room, user_key, notification, true))
+    }
+
+    /// Looks up the presence of each of `users`, sorted by user.
+    pub fn presence_of(&self, users: Vec<UserId>) -> Vec<Presence> {
+        let mut presence: Vec<Presence> = users
+            .into_iter()
+            .map(|user| {
+                self.presence.get(&user).cloned().unwrap_or(Presence {
+                    user,
+                    state: PresenceState::Offline,
+                    status: None,
+                })
+            })
+            .collect();
+        presence.sort_by(|a, b| a.user.as_str().cmp(b.user.as_str()));
+        presence
+    }
 
+    /// Outbound queue metrics of every connected device.
+    pub fn queue_metrics(&self) -> Vec<(UserId, QueueMetrics)> {
+        self.connected
+            .iter()
+            .flat_map(|(user, devices)| {
+                devices
+                    .values()
+                    .map(|connection| (user.clone(), connection.outbox.metrics()))
+            })
+            .collect()
+    }
+
+    /// Every connected device, sorted by user.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .connected
+            .iter()
+            .flat_map(|(user, devices)| {
+                devices.iter().map(|(id, connection)| ConnectionInfo {
+                    user: user.clone(),
+                    device: connection.key.name.clone(),
+                    address: connection.address,
+                    key: id.clone(),
+                    rooms: self.memberships.rooms_of(user),
+                    presence: self
+                        .presence
+                        .get(user)
+                        .map_or(PresenceState::Online, |p| p.state),
+                })
+            })
+            .collect();
+        connections.sort_by(|a, b| (a.user.as_str(), &a.device).cmp(&(b.user.as_str(), &b.device)));
+        connections
+    }
+
+    /// `user`'s devices, most recently seen first.
+    pub fn list_devices(&self, user: &str) -> Vec<DeviceInfo> {
+        let user_key: UserId = user.into();
+        let mut devices = self.devices.list(user);
+        for device in &mut devices {
+            device.online = self.connection(&user_key, &device.id).is_some();
+        }
+        devices
+    }
+
+    /// Revokes `user`'s device whose id starts with `prefix`, disconnecting
+    /// it. The device `current` can't revoke itself.
+    pub fn revoke_device(
+        &mut self,
+        user: &str,
+        prefix: &str,
+        current: &str,
+    ) -> Result<DeviceInfo, ServerError> {
+        let user_key: UserId = user.into();
+        let device = self
+            .devices
+            .find(user, prefix)
+            .map_err(|e| ServerError::UserError(e.to_string()))?;
+        if device.id == current {
+            return Err(ServerError::UserError(
+                "You can't revoke the device you are using.".to_string(),
+            ));
+        }
+        self.devices
+            .revoke(user, &device.id)
+            .map_err(|e| ServerError::UserError(e.to_string()))?;
+        if self.connection(&user_key, &device.id).is_some() {
+            self.disconnect(&user_key, &device.id, "This device was revoked");
+        } else {
+            self.end_session(&user_key, &device.id);
+        }
+        info!("{} revoked their device {}", user, device.name);
+        Ok(device)
+    }
+
+    /// Every room, sorted by name.
+    pub fn rooms(&self) -> Vec<RoomHandle> {
+        let mut rooms: Vec<RoomHandle> = self.chat_rooms.values().cloned().collect();
+        rooms.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+        rooms
+    }
+
+    /// Disconnects every connected device matching `target`, telling it
+    /// `reason`. Their sessions can't be resumed. Returns whose devices were
+    /// kicked.
+    pub fn kick(&mut self, target: &BanTarget, reason: &str) -> Vec<UserId> {
+        let kicked: Vec<(UserId, String)> = self
+            .connected
+            .iter()
+            .flat_map(|(user, devices)| devices.iter().map(move |(id, c)| (user, id, c)))
+            .filter(|(user, id, connection)| match target {
+                BanTarget::User(name) => user.as_str() == name,
+                BanTarget::Key(key) => *id == key || fingerprint(&connection.key.identity) == *key,
+                BanTarget::Address(ip) => connection.address == *ip,
+            })
+            .map(|(user, id, _)| (user.clone(), id.clone()))
+            .collect();
+        for (user, device) in &kicked {
+            self.disconnect(user, device, reason);
+        }
+        let mut users: Vec<UserId> = kicked.into_iter().map(|(user, _)| user).collect();
+        users.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        users.dedup();
+        users
+    }
+
+    /// Takes `user` out of `room`, telling them `reason`. They lose their role
+    /// and any invitation there.
+    pub fn remove_from_room(
+        &mut self,
+        user: &str,
+        room: &str,
+        reason: &str,
+    ) -> Result<(), ServerError> {
+        if room == self.waiting_room.as_str() {
+            return Err(ServerError::UserError(
+                "Nobody can be removed from the waiting room.".to_string(),
+            ));
+        }
+        let user_key: UserId = user.into();
+        let room_key: ChatRoomId = room.into();
+        if !self.memberships.remove(&user_key, &room_key) {
+            return Err(ServerError::UserError(format!(
+                "{} is not in #{}.",
+                user, room
+            )));
+        }
+        if let Some(handle) = self.chat_rooms.get(&room_key).cloned() {
+            let user = user.to_string();
+            tokio::spawn(async move { handle.kick(&user).await });
+        }
+        self.tell_removed(&user_key, room_key, reason);
         Ok(())
     }
 
-    pub fn create_chatroom(&mut self, chatroom_name: String, capacity: usize) -> Result<&G, &str> {
-        info!("Creating chatroom: {}", chatroom_name);
+    // Lets `user` know, on every connected device, that they are no longer
+    // in `room`.
+    fn tell_removed(&self, user: &UserId, room: ChatRoomId, reason: &str) {
+        let removed = APIResponse::RemovedFromRoom {
+            room,
+            reason: reason.to_string(),
+        };
+        self.tell(user, removed);
+    }
 
-        let chatroom_key: ChatRoomId = chatroom_name.clone().into();
+    // Ends the session of `user`'s `device` and queues the event that makes
+    // its connection close.
+    fn disconnect(&mut self, user: &UserId, device: &str, reason: &str) {
+        let told = match self.connection(user, device) {
+            Some(connection) => {
+                let kicked = UserMessage {
+                    user_id: user.clone(),
+                    message: APIResponse::Kicked {
+                        reason: reason.to_string(),
+                        reconnect: false,
+                    },
+                };
+                let sent = connection.outbox.try_send(kicked).is_ok();
+                if !sent {
+                    // No room to say why; drop the connection regardless.
+                    connection.outbox.close();
+                }
+                sent
+            }
+            None => false,
+        };
+        // A connection that got the event leaves its rooms on its way out, so
+        // the session only has to be made impossible to resume. Otherwise the
+        // places it holds there have to be given up here.
+        if told {
+            if let Some(sessions) = self.sessions.get_mut(user) {
+                sessions.remove(device);
+            }
+        } else {
+            self.end_session(user, device);
+        }
+        info!("Disconnected {}: {}", user, reason);
+    }
+
+    /// Sends `text` to every connected device. Returns how many it was
+    /// queued for; devices that are too far behind miss it.
+    pub fn notice(&self, text: &str) -> usize {
+        self.connected
+            .iter()
+            .flat_map(|(user, devices)| devices.values().map(move |c| (user, c)))
+            .filter(|(user, connection)| {
+                let notice = UserMessage {
+                    user_id: (*user).clone(),
+                    message: APIResponse::ServerNotice(text.to_string()),
+                };
+                connection.outbox.try_send(notice).is_ok()
+            })
+            .count()
+    }
+
+    pub fn get_room(&self, room: &str) -> Result<&RoomHandle, ServerError> {
+        self.chat_rooms
+            .get(&room.into())
+            .ok_or(ServerError::InvalidChatRoomError)
+    }
+
+    pub fn create_chatroom(
+        &mut self,
+        chatroom_name: String,
+        capacity: usize,
+    ) -> Result<&RoomHandle, &str> {
+        self.add_chatroom(G::build(chatroom_name, capacity))
+    }
+
+    /// Starts the task for an already configured room.
+    pub fn add_chatroom(&mut self, chatroom: G) -> Result<&RoomHandle, &str> {
+        info!("Creating chatroom: {}", chatroom.id());
+
+        let chatroom_key: ChatRoomId = chatroom.id().into();
         if self.chat_rooms.contains_key(&chatroom_key) {
             return Err("Chatroom could not be created.");
         }
 
-        let chatroom = ChatRoom::build(chatroom_name, capacity);
-        self.chat_rooms.insert(chatroom_key.clone(), chatroom);
-
-        // Probably inefficient
-        Ok(&self.chat_rooms[&chatroom_key])
+        let handle = room::spawn(chatroom);
+        Ok(self.chat_rooms.entry(chatroom_key).or_insert(handle))
     }
 
+    /// Forgets a room, telling its connected members they are no longer in
+    /// it. The room's task stops once they have left.
     pub fn delete_chatroom(&mut self, chatroom_name: String) -> Result<(), &str> {
         let chatroom_key: ChatRoomId = chatroom_name.into();
-        if self.chat_rooms.contains_key(&chatroom_key) {
-            self.chat_rooms.remove(&chatroom_key);
-            Ok(())
-        } else {
-            Err("Chatroom not found.")
+        if chatroom_key == self.waiting_room {
+            return Err("The waiting room can't be deleted.");
+        }
+        if self.chat_rooms.remove(&chatroom_key).is_none() {
+            return Err("Chatroom not found.");
+        }
+
+        let members = self.memberships.remove_room(&chatroom_key);
+        for invitations in self.invitations.values_mut() {
+            invitations.retain(|invitation| invitation.room != chatroom_key);
         }
+        self.invitations
+            .retain(|_, invitations| !invitations.is_empty());
+        let reason = format!("Room {} was deleted", chatroom_key);
+        for user in &members {
+            self.tell_removed(user, chatroom_key.clone(), &reason);
+        }
+        info!("Deleted chatroom {}", chatroom_key);
+        Ok(())
     }
 
-    pub fn get_active_room(&self, username: &String) -> Result<&ChatRoomId, ServerError> {
-        let user_key: UserId = username.into();
-        if let Some(c) = self.chatroom_registry.get_by_left(&user_key) {
-            Ok(c)
-        } else {
-            Err(ServerError::InvalidChatRoomError)
+    /// Registers room `from` under the name `to`, along with its members and
+    /// open invitations, then lets the room know `by` renamed it.
+    pub fn rename_chatroom(&mut self, from: &str, to: &str, by: &str) -> Result<(), ServerError> {
+        let (from, to): (ChatRoomId, ChatRoomId) = (from.into(), to.into());
+        if from == self.waiting_room {
+            return Err(ServerError::UserError(
+                "The waiting room can't be renamed.".to_string(),
+            ));
+        }
+        if self.chat_rooms.contains_key(&to) {
+            return Err(ServerError::UserError(format!("#{} already exists.", to)));
+        }
+        let Some(mut handle) = self.chat_rooms.remove(&from) else {
+            return Err(ServerError::UserError("Chatroom not found.".to_string()));
+        };
+        handle.id = to.clone();
+        self.chat_rooms.insert(to.clone(), handle.clone());
+
+        self.memberships.rename_room(&from, &to);
+        for invitation in self.invitations.values_mut().flatten() {
+            if invitation.room == from {
+                invitation.room = to.clone();
+            }
+        }
+        let (by, name) = (by.to_string(), to.to_string());
+        tokio::spawn(async move { handle.rename(&by, &name).await });
+        info!("Renamed chatroom {} to {}", from, to);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatroom::SimpleChatRoom;
+    use crate::devices::unsigned_key;
+    use crate::outbox::{outbox, OutboxSettings};
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn typing_notifies_the_rest_of_the_room_once_per_interval() {
+        let (_handle, rx) = crate::handle::channel(1);
+        let mut server: Server<SimpleChatRoom> = Server::build(rx);
+
+        let mut connections = Vec::new();
+        for user in ["alice", "bob", "carol"] {
+            let (sender, receiver) = outbox(OutboxSettings::default());
+            let key = unsigned_key(user, "laptop");
+            let room = server
+                .register_user(key.clone(), sender.clone(), LOCALHOST, None)
+                .unwrap()
+                .rooms
+                .remove(0);
+            room.join(key, sender.clone()).await.unwrap();
+            connections.push((sender, receiver));
+        }
+
+        server
+            .typing("carol", WAITING_ROOM)
+            .unwrap()
+            .unwrap()
+            .send()
+            .await;
+        // Round trip through the room so the notification has been handed out.
+        server
+            .get_room(WAITING_ROOM)
+            .unwrap()
+            .members(None)
+            .await
+            .unwrap();
+
+        let depths: Vec<usize> = connections.iter().map(|(s, _)| s.metrics().depth).collect();
+        assert_eq!(depths, vec![1, 1, 0]);
+
+        assert!(server.typing("carol", WAITING_ROOM).unwrap().is_none());
+        server.create_chatroom("ops".to_string(), 8).unwrap();
+        assert!(server.typing("carol", "ops").is_err());
+    }
+
+    #[tokio::test]
+    async fn kicked_users_are_told_and_cannot_resume() {
+        let (_handle, rx) = crate::handle::channel(1);
+        let mut server: Server<SimpleChatRoom> = Server::build(rx);
+        let (sender, mut receiver) = outbox(OutboxSettings::default());
+        let key = unsigned_key("mallory", "laptop");
+        let login = server
+            .register_user(key.clone(), sender.clone(), LOCALHOST, None)
+            .unwrap();
+
+        let kicked = server.kick(&BanTarget::Address(LOCALHOST), "Go away");
+        assert_eq!(kicked, vec![UserId::from("mallory")]);
+        match receiver.recv().await.unwrap().message {
+            APIResponse::Kicked { reason, reconnect } => {
+                assert_eq!((reason.as_str(), reconnect), ("Go away", false))
+            }
+            other => panic!("Unexpected event {:?}", other),
         }
+
+        server.unregister_user("mallory", &key.id(), true).unwrap();
+        let (sender, _receiver) = outbox(OutboxSettings::default());
+        let token = Some(login.resume_token.as_str());
+        let again = server.register_user(key, sender, LOCALHOST, token);
+        assert!(!again.unwrap().resumed);
     }
 
-    async fn send_roomkeys<W: AsyncWriteExt + Unpin + Send + 'static>(&self, socket: &W) {}
+    #[tokio::test]
+    async fn devices_share_rooms_and_presence_until_the_last_one_leaves() {
+        let (_handle, rx) = crate::handle::channel(1);
+        let mut server: Server<SimpleChatRoom> = Server::build(rx);
+        server.create_chatroom("ops".to_string(), 8).unwrap();
+        let (laptop, desktop) = (
+            unsigned_key("alice", "laptop"),
+            unsigned_key("alice", "desktop"),
+        );
+
+        let (sender, _laptop_rx) = outbox(OutboxSettings::default());
+        server
+            .register_user(laptop.clone(), sender, LOCALHOST, None)
+            .unwrap();
+        server
+            .joined_room("alice", &laptop.id(), "ops".into())
+            .unwrap();
+        let (sender, _desktop_rx) = outbox(OutboxSettings::default());
+        let login = server
+            .register_user(desktop.clone(), sender, LOCALHOST, None)
+            .unwrap();
+        let mut rooms: Vec<&str> = login.rooms.iter().map(|room| room.id.as_str()).collect();
+        rooms.sort();
+        assert_eq!(rooms, vec!["ops", "waiting"]);
+
+        let listed = server.list_devices("alice");
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|device| device.online));
+        assert!(server
+            .revoke_device("alice", &desktop.id(), &desktop.id())
+            .is_err());
+
+        // Still online on the desktop, so nobody is told otherwise.
+        server
+            .unregister_user("alice", &laptop.id(), false)
+            .unwrap();
+        assert_eq!(
+            server.presence_of(vec!["alice".into()])[0].state,
+            PresenceState::Online
+        );
+        assert_eq!(server.memberships.rooms_of(&"alice".into()).len(), 2);
+
+        server
+            .revoke_device("alice", &laptop.id()[..12], &desktop.id())
+            .unwrap();
+        let (sender, _laptop_rx) = outbox(OutboxSettings::default());
+        assert!(server
+            .register_user(laptop, sender, LOCALHOST, None)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn renamed_rooms_keep_their_members_and_invitations() {
+        let (_handle, rx) = crate::handle::channel(1);
+        let mut server: Server<SimpleChatRoom> = Server::build(rx);
+        server.create_chatroom("ops".to_string(), 8).unwrap();
+        let (sender, mut receiver) = outbox(OutboxSettings::default());
+        let key = unsigned_key("alice", "laptop");
+        server
+            .register_user(key.clone(), sender.clone(), LOCALHOST, None)
+            .unwrap();
+        let ops = server.get_room("ops").unwrap().clone();
+        ops.join(key.clone(), sender).await.unwrap();
+        server
+            .joined_room("alice", &key.id(), "ops".into())
+            .unwrap();
+        let invitation = Invitation {
+            room: "ops".into(),
+            from: "alice".into(),
+        };
+        server.invite("bob", invitation);
+
+        assert!(server
+            .rename_chatroom("ops", WAITING_ROOM, "alice")
+            .is_err());
+        assert!(server
+            .rename_chatroom(WAITING_ROOM, "lobby", "alice")
+            .is_err());
+        server.rename_chatroom("ops", "incidents", "alice").unwrap();
+
+        let incidents = ChatRoomId::from("incidents");
+        assert!(server.get_room("ops").is_err());
+        assert_eq!(server.get_room("incidents").unwrap().id, incidents);
+        assert!(server.memberships.contains(&"alice".into(), &incidents));
+        assert_eq!(server.invitations[&UserId::from("bob")][0].room, incidents);
+        loop {
+            match receiver.recv().await.unwrap().message {
+                APIResponse::RoomRenamed { from, to, by } => {
+                    assert_eq!(
+                        (from.as_str(), to, by.as_str()),
+                        ("ops", incidents, "alice")
+                    );
+                    break;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_receipts_are_turned_off_for_every_device() {
+        let (_handle, rx) = crate::handle::channel(1);
+        let mut server: Server<SimpleChatRoom> = Server::build(rx);
+        assert!(server.sends_read_receipts("alice"));
+        server.set_read_receipts("alice", false);
+        assert!(!server.sends_read_receipts("alice"));
+        assert!(server.sends_read_receipts("bob"));
+        server.set_read_receipts("alice", true);
+        assert!(server.sends_read_receipts("alice"));
+    }
 }
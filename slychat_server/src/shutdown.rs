@@ -0,0 +1,109 @@
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_RECONNECT_AFTER: Duration = Duration::from_secs(5);
+
+/// What connected clients are told when the server goes down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownNotice {
+    pub reason: String,
+    /// How long clients should wait before reconnecting, if the server is
+    /// expected back.
+    pub reconnect_after: Option<Duration>,
+}
+
+/// Creates the pair used to tell connections to wind down and to wait until
+/// they have.
+pub fn shutdown() -> (ShutdownTrigger, ShutdownListener) {
+    let (notice, notified) = watch::channel(None);
+    let (alive, finished) = mpsc::channel(1);
+    (
+        ShutdownTrigger { notice, finished },
+        ShutdownListener {
+            notice: notified,
+            _alive: alive,
+        },
+    )
+}
+
+/// Held by the accept loop.
+pub struct ShutdownTrigger {
+    notice: watch::Sender<Option<ShutdownNotice>>,
+    // Yields `None` once every listener has been dropped.
+    finished: mpsc::Receiver<()>,
+}
+
+impl ShutdownTrigger {
+    /// Tells every connection to shut down, then waits for them to finish.
+    /// Listeners held by the caller must be dropped first, or this only
+    /// returns when `deadline` runs out. Returns whether every connection
+    /// finished in time.
+    pub async fn shutdown(mut self, notice: ShutdownNotice, deadline: Duration) -> bool {
+        self.notice.send_replace(Some(notice));
+        tokio::time::timeout(deadline, self.finished.recv())
+            .await
+            .is_ok()
+    }
+}
+
+/// Held by every connection for as long as it is being served. Clone one per
+/// connection.
+#[derive(Clone)]
+pub struct ShutdownListener {
+    notice: watch::Receiver<Option<ShutdownNotice>>,
+    _alive: mpsc::Sender<()>,
+}
+
+impl ShutdownListener {
+    /// Resolves once the server starts shutting down.
+    pub async fn notified(&mut self) -> ShutdownNotice {
+        loop {
+            if let Some(notice) = self.notice.borrow_and_update().clone() {
+                return notice;
+            }
+            if self.notice.changed().await.is_err() {
+                // The trigger is gone without a shutdown; never resolve.
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_waits_for_every_listener() {
+        let (trigger, listener) = shutdown();
+        let mut connection = listener.clone();
+        drop(listener);
+
+        let served = tokio::spawn(async move {
+            let notice = connection.notified().await;
+            drop(connection);
+            notice
+        });
+        let notice = ShutdownNotice {
+            reason: "Restarting".to_string(),
+            reconnect_after: None,
+        };
+        assert!(
+            trigger
+                .shutdown(notice.clone(), Duration::from_secs(1))
+                .await
+        );
+        assert_eq!(served.await.unwrap(), notice);
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_at_the_deadline() {
+        let (trigger, _stuck) = shutdown();
+        let notice = ShutdownNotice {
+            reason: "Restarting".to_string(),
+            reconnect_after: None,
+        };
+        assert!(!trigger.shutdown(notice, Duration::from_millis(10)).await);
+    }
+}
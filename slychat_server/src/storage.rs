@@ -0,0 +1,12 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Saves `value` as JSON to `path`. It is written next to the real file and
+/// renamed over it, so a crash never leaves a truncated file behind.
+pub fn write_json_atomically<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&partial, path)
+}
@@ -0,0 +1,33 @@
+use slychat_common::types::UserId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Limits how often each user may perform an action.
+pub struct Throttle {
+    interval: Duration,
+    last: HashMap<UserId, Instant>,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Returns whether `user` may act at `now`, and if so records that they did.
+    pub fn allow(&mut self, user: &UserId, now: Instant) -> bool {
+        match self.last.get(user) {
+            Some(&last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last.insert(user.clone(), now);
+                true
+            }
+        }
+    }
+
+    pub fn forget(&mut self, user: &UserId) {
+        self.last.remove(user);
+    }
+}